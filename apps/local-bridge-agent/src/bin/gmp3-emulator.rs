@@ -0,0 +1,78 @@
+//! `gmp3-emulator` — a scriptable GMP-3 ÖKC on a TCP port, for testing the
+//! bridge's `gmp3` driver and crash recovery without a device.
+//!
+//! Point a `gmp3.toml` entry at it with `mode = "emulator"` and the same
+//! `serial`, then script what the "device" does with each transaction:
+//!
+//! ```sh
+//! gmp3-emulator --serial EMU-1 --listen 127.0.0.1:59000 \
+//!     --script approve,drop-after-approval,hang --then decline
+//! ```
+//!
+//! See `drivers::gmp3::emulator` for what each step does.
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use hummytummy_local_bridge::drivers::gmp3::{
+    emulator::{Emulator, Step},
+    journal::SimJournal,
+};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Scriptable GMP-3 ÖKC emulator for integration testing", long_about = None)]
+struct Cli {
+    /// Address to listen on.
+    #[arg(long, default_value = "127.0.0.1:59000")]
+    listen: String,
+
+    /// Device serial the emulator answers the handshake with (the
+    /// `gmp3.toml` entry's `serial`).
+    #[arg(long)]
+    serial: String,
+
+    /// Steps for the first transactions, comma-separated: approve, decline,
+    /// hang, drop-after-approval, duplicate-seq.
+    #[arg(long, value_delimiter = ',')]
+    script: Vec<Step>,
+
+    /// What every transaction after the script gets.
+    #[arg(long, default_value = "approve")]
+    then: Step,
+
+    /// Keep the device's transaction memory in this file, so a restarted
+    /// emulator still answers status queries for earlier transactions.
+    #[arg(long)]
+    journal: Option<PathBuf>,
+
+    /// Sequence number the device reports having seen at the handshake.
+    #[arg(long, default_value_t = 0)]
+    last_seq: u32,
+}
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .with_writer(std::io::stderr)
+        .init();
+    let cli = Cli::parse();
+
+    let mut emu = Emulator::new(cli.serial.as_str())?
+        .with_script(cli.script)
+        .with_then(cli.then)
+        .with_last_seq(cli.last_seq);
+    if let Some(path) = &cli.journal {
+        let journal = SimJournal::open(path)
+            .with_context(|| format!("opening emulator journal {}", path.display()))?;
+        emu = emu.with_journal(journal);
+    }
+    let listener =
+        TcpListener::bind(&cli.listen).with_context(|| format!("binding {}", cli.listen))?;
+    tracing::info!(serial = %cli.serial, listen = %cli.listen, "gmp3-emulator: ready");
+    emu.serve(listener);
+    Ok(())
+}
@@ -0,0 +1,221 @@
+//! What the client does when the cloud stops accepting the bearer token.
+//!
+//! A bearer token is long-lived, not eternal: the cloud revokes it when a
+//! bridge is re-provisioned and expires it on rotation. Every authenticated
+//! REST call runs through [`CloudClient`](super::CloudClient), which turns an
+//! HTTP 401 ([`Unauthorized`]) into exactly one `POST /v1/bridges/token/refresh`
+//! with the current token:
+//!
+//!   - refresh succeeds → the new token goes to the [`TokenSink`] (applied to
+//!     this process and persisted through the credential store) and the call
+//!     is retried once;
+//!   - refresh fails → the bridge is *unauthenticated*. That is recorded in
+//!     memory and as [`MARKER_FILE`] in `data_dir`, so `--health` (a separate
+//!     process) reports it. Cloud calls keep failing honestly; local execution
+//!     — the queue, drivers, LAN API, offline cache — carries on.
+//!
+//! While unauthenticated, a 401 retries the refresh at most once per
+//! [`REFRESH_COOLDOWN`] instead of once per call. The first authenticated call
+//! that succeeds again clears the state and the marker.
+
+use crate::{command_queue::chrono_unix_now, config, credentials::Credentials};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+use super::CloudTransport;
+
+/// File in `data_dir` that marks the bridge as unauthenticated.
+pub const MARKER_FILE: &str = "unauthenticated.json";
+
+/// Minimum gap between refresh attempts once a refresh has failed. A revoked
+/// token stays revoked; hammering the refresh route on every heartbeat and
+/// poll would only add noise to the cloud's auth logs.
+pub const REFRESH_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// The cloud answered HTTP 401: it does not accept the bearer token.
+/// Transports return this (rather than a generic status error) so the client
+/// can tell "refresh the token" apart from every other failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("cloud rejected the bearer token (HTTP 401)")]
+pub struct Unauthorized;
+
+/// True if `e` (or anything it wraps) is [`Unauthorized`].
+pub fn is_unauthorized(e: &anyhow::Error) -> bool {
+    e.chain().any(|c| c.is::<Unauthorized>())
+}
+
+/// Decoded `POST /v1/bridges/token/refresh` response. The backend also sends
+/// `tokenExpiresAt`; nothing here schedules on it, so it is not declared.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenRefreshResponse {
+    pub token: String,
+}
+
+/// Contents of [`MARKER_FILE`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnauthenticatedMarker {
+    /// Unix millis when the bridge became unauthenticated.
+    pub since: i64,
+    /// Why the refresh failed, as logged.
+    pub reason: String,
+}
+
+/// The marker in `data_dir`, if the bridge was last seen unauthenticated. An
+/// unreadable marker still counts — the agent only writes it when it is.
+pub fn read_marker(data_dir: &Path) -> Option<UnauthenticatedMarker> {
+    let raw = std::fs::read(data_dir.join(MARKER_FILE)).ok()?;
+    Some(
+        serde_json::from_slice(&raw).unwrap_or_else(|_| UnauthenticatedMarker {
+            since: 0,
+            reason: format!("{MARKER_FILE} is present but unreadable"),
+        }),
+    )
+}
+
+/// Where a refreshed token goes.
+pub trait TokenSink: Send + Sync {
+    /// Make `token` current. Returns the name of the store that persisted it;
+    /// `Err` means it is in use but will not survive a restart.
+    fn rotated(&self, token: &str) -> Result<&'static str>;
+}
+
+/// Production sink: the process token slot plus the configured credential
+/// stores — the same two steps as the first-boot claim.
+pub struct ProcessTokenSink(pub Credentials);
+
+impl TokenSink for ProcessTokenSink {
+    fn rotated(&self, token: &str) -> Result<&'static str> {
+        config::set_bearer_token(token);
+        self.0.save(token)
+    }
+}
+
+/// Refresh bookkeeping shared by every clone of a [`CloudClient`](super::CloudClient).
+pub struct AuthState {
+    sink: Option<Box<dyn TokenSink>>,
+    /// `data_dir/MARKER_FILE`; `None` keeps the state in memory only.
+    marker: Option<PathBuf>,
+    unauthenticated: AtomicBool,
+    /// Bumped on every successful refresh, so a caller whose 401 raced
+    /// another caller's refresh retries with the new token instead of
+    /// refreshing a second time.
+    generation: AtomicU64,
+    /// Serialises refreshes; holds when the last one failed.
+    last_failure: tokio::sync::Mutex<Option<Instant>>,
+}
+
+impl Default for AuthState {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+impl AuthState {
+    /// `sink` receives refreshed tokens (`None`: they are used for nothing but
+    /// the retry — tests). `data_dir` holds the marker; a marker left by a
+    /// previous run starts this one unauthenticated until a call succeeds.
+    pub fn new(sink: Option<Box<dyn TokenSink>>, data_dir: Option<&Path>) -> Self {
+        let marker = data_dir.map(|d| d.join(MARKER_FILE));
+        let unauthenticated = marker.as_deref().is_some_and(Path::exists);
+        Self {
+            sink,
+            marker,
+            unauthenticated: AtomicBool::new(unauthenticated),
+            generation: AtomicU64::new(0),
+            last_failure: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn is_unauthenticated(&self) -> bool {
+        self.unauthenticated.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// An authenticated call went through: the token is good.
+    pub(super) fn authenticated(&self) {
+        if self.unauthenticated.swap(false, Ordering::SeqCst) {
+            info!("cloud accepts the bearer token again — leaving unauthenticated state");
+            if let Some(path) = &self.marker {
+                if let Err(e) = std::fs::remove_file(path) {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        warn!(error = %e, path = %path.display(), "could not remove unauthenticated marker");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Record that the cloud will not take any token we have.
+    pub(crate) fn enter_unauthenticated(&self, reason: &str) {
+        let was = self.unauthenticated.swap(true, Ordering::SeqCst);
+        if !was {
+            warn!(reason, "bridge is UNAUTHENTICATED — cloud calls will fail until it is re-provisioned or given a new HUMMY_BRIDGE_TOKEN; local execution continues");
+        }
+        let Some(path) = &self.marker else { return };
+        if was && path.exists() {
+            return; // keep the original `since`
+        }
+        let marker = UnauthenticatedMarker {
+            since: chrono_unix_now(),
+            reason: reason.to_string(),
+        };
+        let write = serde_json::to_vec(&marker)
+            .map_err(anyhow::Error::from)
+            .and_then(|b| std::fs::write(path, b).context("write marker"));
+        if let Err(e) = write {
+            warn!(error = %e, path = %path.display(), "could not record unauthenticated state for --health");
+        }
+    }
+
+    /// The one refresh a 401 earns. `seen` is [`AuthState::generation`] from
+    /// before the rejected call. `Ok` means retry the call; `Err` wraps
+    /// [`Unauthorized`].
+    pub(super) async fn recover(&self, seen: u64, transport: &dyn CloudTransport) -> Result<()> {
+        let mut last_failure = self.last_failure.lock().await;
+        if self.generation() != seen {
+            return Ok(()); // refreshed while we waited for the lock
+        }
+        if let Some(at) = *last_failure {
+            if self.is_unauthenticated() && at.elapsed() < REFRESH_COOLDOWN {
+                return Err(anyhow!(Unauthorized)
+                    .context("bridge is unauthenticated — token refresh failed recently"));
+            }
+        }
+        let refreshed = match transport.post_token_refresh().await {
+            Ok(resp) if resp.token.is_empty() => Err(anyhow!("refresh returned an empty token")),
+            other => other.map(|r| r.token),
+        };
+        match refreshed {
+            Ok(token) => {
+                if let Some(sink) = &self.sink {
+                    match sink.rotated(&token) {
+                        Ok(store) => info!(store, "bearer token refreshed and persisted"),
+                        Err(e) => {
+                            warn!(error = %e, "bearer token refreshed but could NOT be persisted — it is lost on restart")
+                        }
+                    }
+                }
+                self.generation.fetch_add(1, Ordering::SeqCst);
+                *last_failure = None;
+                Ok(())
+            }
+            Err(e) => {
+                *last_failure = Some(Instant::now());
+                let reason = format!("token refresh failed: {e:#}");
+                self.enter_unauthenticated(&reason);
+                Err(anyhow!(Unauthorized).context(format!("bridge is unauthenticated — {reason}")))
+            }
+        }
+    }
+}
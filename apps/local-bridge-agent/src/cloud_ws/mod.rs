@@ -0,0 +1,1306 @@
+//! Cloud transport for the bridge.
+//!
+//! Primary channel is WSS to `/ws/bridge`. The cloud pushes commands; the
+//! bridge sends acks back on the same socket. REST fallback via the
+//! bridge-scoped fan-in loop (`GET /v1/bridges/commands/next` +
+//! `POST /v1/bridges/commands/:id/ack`, `Authorization: Bridge`) is used when
+//! the WSS is down — slower, but resilient against captive portals or weird LAN
+//! proxies that drop upgrade headers. The backend claims/acks across the devices
+//! attached to this bridge (Device.bridgeId); the pre-existing per-device loop
+//! (`/v1/devices/*`, `Authorization: Device`) is unaffected.
+//!
+//! ## The transport seam
+//!
+//! The *logic* of the cloud client — how a `commands/next` response is turned
+//! into queue pushes, how a non-success status is tolerated, how a failed
+//! command's [`CommandOutcome`] is shaped for the ack — should not require a
+//! live HTTP server (or even `reqwest`) to test. So the raw HTTP calls live
+//! behind the [`CloudTransport`] trait, which speaks only in plain data
+//! (status codes, parsed [`PendingCommand`] lists). The real implementation is
+//! [`ReqwestTransport`]; tests drive [`CloudClient`] against an in-memory fake.
+//!
+//! Production behavior is unchanged: [`CloudClient::new`] wires up
+//! [`ReqwestTransport`] exactly as the old direct-reqwest code did.
+//!
+//! ## The push channel
+//!
+//! The WSS side lives in [`push`]. [`CloudClient::new`] attaches a
+//! [`push::PushChannel`] derived from `cloud_url`; the agent starts its session
+//! task with [`CloudClient::spawn_push`]. While the socket is up the main loop
+//! waits on [`CloudClient::wait_for_push`] instead of polling, and
+//! [`CloudClient::ack`] sends acks over the socket; while it is down both fall
+//! back to the [`CloudTransport`] REST calls above.
+//!
+//! ## Token refresh
+//!
+//! A 401 on any authenticated call earns one token refresh and one retry; a
+//! failed refresh leaves the bridge explicitly unauthenticated. See [`auth`].
+//!
+//! ## Signed commands
+//!
+//! Commands from either channel pass [`signing::admit`] on their way into the
+//! queue: with a key pinned, anything not validly signed by it is quarantined
+//! instead of queued.
+
+pub mod auth;
+pub mod push;
+pub mod signing;
+
+use crate::{
+    command_queue::{
+        CommandOrigin, CommandOutcome, CommandQueue, PendingCommand, QuarantinedCommand,
+    },
+    config::BridgeConfig,
+    metrics::Metrics,
+    network::Network,
+    offline_cache::{JournaledOrder, SnapshotEnvelope},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use auth::{is_unauthorized, AuthState, TokenRefreshResponse, Unauthorized};
+use push::PushChannel;
+use serde::{Deserialize, Serialize};
+use signing::{CommandVerifier, SignedCommand};
+use std::{future::Future, sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/// Self-describing identity the bridge sends with `claim` and `heartbeat`.
+///
+/// The backend `ClaimBridgeDto` accepts exactly `hostname` / `os` /
+/// `agentVersion` (all optional), persisted on the `LocalBridgeAgent` row so
+/// operators can see what's running where; `BridgeHeartbeatDto` also takes
+/// `devices`. We
+/// serialize with `camelCase` to match the NestJS DTO field names and skip
+/// `None` so the ValidationPipe whitelist doesn't see stray nulls.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BridgeIdentity {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent_version: Option<String>,
+    /// Heartbeat only: last reported state of each local device (printer
+    /// status). Skipped when empty, so `claim` keeps its three-field shape.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<crate::drivers::DeviceStatus>,
+}
+
+impl BridgeIdentity {
+    /// Best-effort self-description from the running process. `os` and
+    /// `agent_version` are always known at compile time; `hostname` is
+    /// looked up from the environment and is `None` on hosts that don't
+    /// export it (the backend column is optional, so that's fine).
+    pub fn detect() -> Self {
+        Self {
+            hostname: std::env::var("HOSTNAME").ok().filter(|s| !s.is_empty()),
+            os: Some(std::env::consts::OS.to_string()),
+            agent_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            devices: Vec::new(),
+        }
+    }
+}
+
+/// The provisioning-token → bearer-token exchange request body sent to
+/// `POST /v1/bridges/claim`. `provisioningToken` is required; the identity
+/// fields are flattened alongside it to match `ClaimBridgeDto`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClaimRequest {
+    pub provisioning_token: String,
+    #[serde(flatten)]
+    pub identity: BridgeIdentity,
+}
+
+/// Decoded `POST /v1/bridges/claim` response. The backend returns
+/// `{ bridgeId, tenantId, branchId, token, tokenExpiresAt }`; we only need
+/// the bearer `token` (and surface `bridge_id` for logging). The remaining
+/// fields are simply not declared here, so serde ignores them by default.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClaimResponse {
+    pub bridge_id: String,
+    /// The long-lived bearer token to use as `Authorization: Bridge <token>`.
+    pub token: String,
+}
+
+/// Decoded `/v1/bridges/heartbeat` reply. Backends that predate the offline
+/// cache answer `{ ok: true }`, which decodes to no snapshots.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HeartbeatResponse {
+    #[serde(default)]
+    pub snapshots: Vec<SnapshotEnvelope>,
+}
+
+/// How the cloud settled one replayed offline order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderReplayResponse {
+    /// 2xx — the order now exists cloud-side under `order_id`.
+    Accepted { order_id: String },
+    /// 409 — the base order changed (or closed) in a way the cloud will not
+    /// merge. Left for a human; never retried.
+    Conflict { reason: String },
+    /// Any other 4xx — the order is invalid as sent and retrying won't help.
+    Rejected { status: u16, reason: String },
+}
+
+/// Result of a `commands/next` poll, decoded into plain data so the
+/// queue-push logic in [`CloudClient::fetch_more`] is hardware/HTTP-free.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FetchResponse {
+    /// HTTP 204 — the cloud has nothing queued for us right now.
+    NoContent,
+    /// 2xx with a (possibly empty) batch of commands to verify and enqueue.
+    Commands(Vec<SignedCommand>),
+    /// Any non-success status. Carried so the client can log it and move on
+    /// without treating a transient 5xx as fatal.
+    NonSuccess(u16),
+    /// deep-review NH2/NH6: a 2xx whose body could not be decoded into a command
+    /// batch (schema drift, truncated body, weird LAN proxy interstitial). This
+    /// is NOT an empty batch — the cloud may actually have queued
+    /// payment/print commands. Surfaced as its own variant so `fetch_more` can
+    /// back off and leave the commands server-side for re-offer, instead of the
+    /// old `unwrap_or_default()` which silently dropped the whole batch.
+    DecodeError,
+}
+
+/// The transport seam over the cloud HTTP API.
+///
+/// Every method returns plain data, so the implementation can be the real
+/// `reqwest` client ([`ReqwestTransport`]) or an in-memory fake. The trait is
+/// object-safe so [`CloudClient`] can hold it as `Arc<dyn CloudTransport>` and
+/// stay `Clone` (telemetry clones the client into its heartbeat task).
+#[async_trait]
+pub trait CloudTransport: Send + Sync {
+    /// GET `/healthz`. Returns the HTTP status code.
+    async fn get_healthz(&self) -> Result<u16>;
+
+    /// GET `/v1/bridges/commands/next` (bridge-scoped fan-in), decoded into a
+    /// [`FetchResponse`].
+    async fn get_next_commands(&self) -> Result<FetchResponse>;
+
+    /// POST an outcome to `/v1/bridges/commands/:id/ack` (bridge-scoped). Errors
+    /// on a non-success HTTP status so the caller can retry/fail uniformly.
+    async fn post_ack(&self, cmd_id: &str, outcome: &CommandOutcome) -> Result<()>;
+
+    /// POST `/v1/bridges/heartbeat` with the bridge bearer token + identity.
+    /// This is the call that keeps the bridge marked `online` cloud-side
+    /// (60s grace). Errors on a non-success HTTP status so the caller can log
+    /// it; the heartbeat loop treats failures as best-effort. The decoded body
+    /// carries any offline-cache snapshots the cloud piggybacks on the reply.
+    async fn post_heartbeat(&self, identity: &BridgeIdentity) -> Result<HeartbeatResponse>;
+
+    /// POST `/v1/bridges/claim` to exchange a one-shot provisioning token for
+    /// a long-lived bearer token. Returns the decoded [`ClaimResponse`].
+    /// Errors on a non-success HTTP status (e.g. an already-used token → 404).
+    async fn post_claim(&self, req: &ClaimRequest) -> Result<ClaimResponse>;
+
+    /// POST a journaled offline order to `/v1/bridges/offline-orders`. A 409
+    /// and other 4xx are decoded into [`OrderReplayResponse`] (the order is
+    /// settled); 5xx and network errors are `Err` so the order stays pending.
+    async fn post_offline_order(&self, order: &JournaledOrder) -> Result<OrderReplayResponse>;
+
+    /// POST a locally originated command and its outcome to
+    /// `/v1/bridges/local-commands` for the cloud's audit trail. Errors on a
+    /// non-success status (409 = already recorded counts as success) so the
+    /// row stays ack-pending and is retried.
+    async fn post_local_audit(&self, cmd: &PendingCommand, outcome: &CommandOutcome) -> Result<()>;
+
+    /// POST `/v1/bridges/token/refresh` with the current bearer token and
+    /// decode the replacement. Errors on any non-success status, including the
+    /// cloud refusing a revoked token.
+    async fn post_token_refresh(&self) -> Result<TokenRefreshResponse>;
+
+    /// POST a quarantined command to `/v1/bridges/quarantine` so the cloud
+    /// sees what was refused at the bridge and why. Errors on a non-success
+    /// status (409 = already reported counts as success) so it is retried.
+    async fn post_quarantine_report(&self, q: &QuarantinedCommand) -> Result<()>;
+}
+
+#[derive(Clone)]
+pub struct CloudClient {
+    inner: Arc<Inner>,
+}
+
+#[derive(Clone)]
+struct Inner {
+    transport: Arc<dyn CloudTransport>,
+    /// WSS push channel; `None` means REST-only (tests, or a cloud_url the
+    /// WSS endpoint cannot be derived from).
+    push: Option<PushChannel>,
+    auth: Arc<AuthState>,
+    verifier: Arc<CommandVerifier>,
+    metrics: Arc<Metrics>,
+}
+
+impl CloudClient {
+    /// Production constructor — wires the real `reqwest`-backed transport and
+    /// the WSS push channel at `<cloud_url>/ws/bridge`, both over the same
+    /// `[network]` settings.
+    pub fn new(cfg: BridgeConfig) -> Result<Self> {
+        let network = Network::from_config(&cfg.network, &cfg.cloud_url)?;
+        let push =
+            push::PushConfig::for_cloud_url(&cfg.cloud_url, network.clone()).map(PushChannel::new);
+        let auth = AuthState::new(
+            Some(Box::new(auth::ProcessTokenSink(
+                crate::credentials::Credentials::from_config(&cfg),
+            ))),
+            Some(&cfg.data_dir),
+        );
+        let verifier = CommandVerifier::from_config(&cfg.command_signing)?;
+        let transport = ReqwestTransport::new(cfg, &network)?;
+        let client = Self::with_transport(Arc::new(transport))
+            .with_auth(auth)
+            .with_verifier(verifier);
+        Ok(match push {
+            Ok(p) => client.with_push(p),
+            Err(e) => {
+                warn!(error = %e, "push channel disabled — REST polling only");
+                client
+            }
+        })
+    }
+
+    /// The seam: construct a client over any [`CloudTransport`]. Tests pass a
+    /// fake; production passes [`ReqwestTransport`] via [`CloudClient::new`].
+    pub fn with_transport(transport: Arc<dyn CloudTransport>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                transport,
+                push: None,
+                auth: Arc::default(),
+                verifier: Arc::default(),
+                metrics: Arc::default(),
+            }),
+        }
+    }
+
+    fn rebuilt(self, edit: impl FnOnce(&mut Inner)) -> Self {
+        let mut inner = (*self.inner).clone();
+        edit(&mut inner);
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Attach a WSS push channel to this client (REST stays the fallback).
+    pub fn with_push(self, push: PushChannel) -> Self {
+        self.rebuilt(|inner| inner.push = Some(push))
+    }
+
+    /// Replace the token-refresh state (where refreshed tokens go, where the
+    /// unauthenticated marker lives). The default keeps both in memory.
+    pub fn with_auth(self, auth: AuthState) -> Self {
+        self.rebuilt(|inner| inner.auth = Arc::new(auth))
+    }
+
+    /// Verify cloud commands against pinned keys. The default pins none and
+    /// admits everything.
+    pub fn with_verifier(self, verifier: CommandVerifier) -> Self {
+        self.rebuilt(|inner| inner.verifier = Arc::new(verifier))
+    }
+
+    /// Count every cloud call, by call and result, in `metrics`.
+    pub fn with_metrics(self, metrics: Arc<Metrics>) -> Self {
+        self.rebuilt(|inner| inner.metrics = metrics)
+    }
+
+    /// True once a 401 could not be fixed by a token refresh, until an
+    /// authenticated call succeeds again.
+    pub fn is_unauthenticated(&self) -> bool {
+        self.inner.auth.is_unauthenticated()
+    }
+
+    /// Run an authenticated transport call. A 401 gets one token refresh and
+    /// one retry; a second 401 with a freshly refreshed token means the cloud
+    /// will not take any token we can get, same as a failed refresh.
+    ///
+    /// `name` labels the call in the metrics; it is counted once, with its
+    /// final result.
+    async fn authed<T, F, Fut>(&self, name: &'static str, call: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let res = self.authed_once(call).await;
+        self.inner.metrics.cloud_request(name, res.is_ok());
+        res
+    }
+
+    async fn authed_once<T, F, Fut>(&self, call: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let auth = &self.inner.auth;
+        let seen = auth.generation();
+        match call().await {
+            Ok(v) => {
+                auth.authenticated();
+                return Ok(v);
+            }
+            Err(e) if !is_unauthorized(&e) => return Err(e),
+            Err(_) => {}
+        }
+        auth.recover(seen, self.inner.transport.as_ref()).await?;
+        match call().await {
+            Ok(v) => {
+                auth.authenticated();
+                Ok(v)
+            }
+            Err(e) if is_unauthorized(&e) => {
+                auth.enter_unauthenticated("the refreshed bearer token was rejected too");
+                Err(e.context("bridge is unauthenticated — refreshed token rejected"))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Start the push channel's session task. `None` when no channel is
+    /// attached; the main loop then keeps polling REST.
+    pub fn spawn_push(&self, queue: Arc<CommandQueue>) -> Option<JoinHandle<()>> {
+        self.inner
+            .push
+            .as_ref()
+            .map(|p| p.spawn(queue, self.inner.verifier.clone()))
+    }
+
+    /// True while the WSS session is up and delivering commands.
+    pub fn push_is_live(&self) -> bool {
+        self.inner.push.as_ref().is_some_and(PushChannel::is_live)
+    }
+
+    /// Wait for the cloud to push work (or `max` to elapse). Returns immediately
+    /// when no push channel is attached.
+    pub async fn wait_for_push(&self, max: Duration) {
+        if let Some(p) = &self.inner.push {
+            p.wait_for_push(max).await;
+        }
+    }
+
+    /// Quick GET to confirm the cloud is reachable. Used at boot so the agent
+    /// can switch to "offline mode" UI hints if the cloud is unavailable.
+    pub async fn warm_up(&self) -> Result<()> {
+        let status = self.inner.transport.get_healthz().await?;
+        if !(200..300).contains(&status) {
+            anyhow::bail!("cloud warm-up returned HTTP {}", status);
+        }
+        Ok(())
+    }
+
+    /// Pull more commands when the local queue is empty and enqueue them.
+    ///
+    /// 204 → nothing to do. Non-success → log and tolerate (a transient 5xx
+    /// must not crash the agent). 2xx → admit every returned command: verified
+    /// ones into the durable queue (dedup is the queue's job), the rest into
+    /// quarantine.
+    pub async fn fetch_more(&self, queue: &CommandQueue) -> Result<()> {
+        match self
+            .authed("fetch", || self.inner.transport.get_next_commands())
+            .await?
+        {
+            FetchResponse::NoContent => Ok(()),
+            FetchResponse::NonSuccess(status) => {
+                warn!(status, "cloud fetch_more non-success");
+                Ok(())
+            }
+            FetchResponse::DecodeError => {
+                // deep-review NH2/NH6: do NOT treat an undecodable body as "no
+                // work". Return Err so the main loop logs it and engages its 5s
+                // backoff; the commands were never acked, so the cloud re-offers
+                // them on the next poll instead of being silently lost.
+                warn!("cloud fetch_more: undecodable command body — backing off, commands stay queued server-side");
+                anyhow::bail!("undecodable commands/next body")
+            }
+            FetchResponse::Commands(commands) => {
+                for c in commands {
+                    signing::admit(&self.inner.verifier, queue, &c).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Ack a completed command's outcome back to the cloud. Goes over the WSS
+    /// socket when it is up, otherwise (or when the socket cannot deliver it)
+    /// over REST. A command from the LAN API has no cloud-side row to ack, so
+    /// its outcome is forwarded as an audit record instead — through the same
+    /// durable ack-pending path, so it reaches the cloud once it is reachable.
+    pub async fn ack(&self, cmd: &PendingCommand, outcome: &CommandOutcome) -> Result<()> {
+        if cmd.origin == CommandOrigin::Local {
+            return self
+                .authed("local_audit", || {
+                    self.inner.transport.post_local_audit(cmd, outcome)
+                })
+                .await;
+        }
+        if let Some(p) = &self.inner.push {
+            if let Some(res) = p.try_ack(&cmd.id, outcome).await {
+                self.inner.metrics.cloud_request("ack_ws", res.is_ok());
+                return res;
+            }
+        }
+        self.authed("ack", || self.inner.transport.post_ack(&cmd.id, outcome))
+            .await
+    }
+
+    /// Ack a failed command. Shapes the canonical `failed` outcome (null
+    /// result + error string) and forwards it through [`CloudClient::ack`].
+    pub async fn ack_failed(&self, cmd: &PendingCommand, error: &str) -> Result<()> {
+        self.ack(
+            cmd,
+            &CommandOutcome {
+                status: "failed".to_string(),
+                result: serde_json::Value::Null,
+                error: Some(error.to_string()),
+            },
+        )
+        .await
+    }
+
+    /// Post a heartbeat to the cloud so the bridge stays `online`. This is the
+    /// real liveness signal — distinct from [`CloudClient::warm_up`], which is
+    /// only a one-shot boot reachability probe and never updates `lastSeenAt`.
+    pub async fn post_heartbeat(&self, identity: &BridgeIdentity) -> Result<HeartbeatResponse> {
+        self.authed("heartbeat", || {
+            self.inner.transport.post_heartbeat(identity)
+        })
+        .await
+    }
+
+    /// Upload one journaled offline order (see [`crate::offline_cache`]).
+    pub async fn replay_order(&self, order: &JournaledOrder) -> Result<OrderReplayResponse> {
+        self.authed("order_replay", || {
+            self.inner.transport.post_offline_order(order)
+        })
+        .await
+    }
+
+    /// Tell the cloud about a command refused at the trust boundary.
+    pub async fn report_quarantined(&self, q: &QuarantinedCommand) -> Result<()> {
+        self.authed("quarantine_report", || {
+            self.inner.transport.post_quarantine_report(q)
+        })
+        .await
+    }
+
+    /// First-boot claim: exchange a provisioning token for a bearer token.
+    /// Returns the decoded [`ClaimResponse`] (carrying the new bearer token).
+    pub async fn claim(&self, provisioning_token: &str) -> Result<ClaimResponse> {
+        let req = ClaimRequest {
+            provisioning_token: provisioning_token.to_string(),
+            identity: BridgeIdentity::detect(),
+        };
+        self.inner.transport.post_claim(&req).await
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Real reqwest transport (thin HTTP adapter behind the seam).
+// ---------------------------------------------------------------------------
+
+/// Real `reqwest`-backed [`CloudTransport`]. Holds the HTTP client + the
+/// config it needs to build URLs and auth headers. This is the only part of
+/// the module that touches the network; everything above is HTTP-free and
+/// unit-tested.
+pub struct ReqwestTransport {
+    cfg: BridgeConfig,
+    http: reqwest::Client,
+}
+
+impl ReqwestTransport {
+    /// `network` carries the proxy, extra CAs and pins; the push channel gets
+    /// the same value so both paths to the cloud match.
+    pub fn new(cfg: BridgeConfig, network: &Network) -> Result<Self> {
+        // rustls / OS TLS root init can fail (e.g. malformed CA bundle on a
+        // misconfigured host). Returning Result propagates the cause through
+        // `main` instead of aborting the process.
+        let http = network.http_client(
+            reqwest::Client::builder()
+                .https_only(true)
+                // Conservative timeouts so a wedged proxy doesn't stall the agent.
+                .timeout(std::time::Duration::from_secs(30))
+                .connect_timeout(std::time::Duration::from_secs(10)),
+        )?;
+        Ok(Self { cfg, http })
+    }
+}
+
+/// Map a 401 to [`Unauthorized`] before any other status handling, so the
+/// client refreshes the token instead of logging the call as a plain failure
+/// (or, for offline orders, settling it as rejected).
+fn reject_unauthorized(resp: reqwest::Response) -> Result<reqwest::Response> {
+    if resp.status() == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(Unauthorized.into());
+    }
+    Ok(resp)
+}
+
+#[async_trait]
+impl CloudTransport for ReqwestTransport {
+    async fn get_healthz(&self) -> Result<u16> {
+        let url = format!("{}/healthz", self.cfg.cloud_url);
+        let resp = self.http.get(url).send().await?;
+        Ok(resp.status().as_u16())
+    }
+
+    async fn get_next_commands(&self) -> Result<FetchResponse> {
+        // Bridge command fan-in: the backend claims the next queued command
+        // across all devices attached to this bridge (Device.bridgeId), scoping
+        // by the bridge bearer token — so the bridge id is NOT in the path. The
+        // route returns HTTP 200 with a JSON array (0 or 1 command); an empty
+        // batch is `[]` (decodes to zero commands), not 204. The 204 branch below
+        // is retained defensively for other/legacy endpoints.
+        let url = format!("{}/v1/bridges/commands/next", self.cfg.cloud_url);
+        let token = crate::config::resolve_bearer_token().unwrap_or_default();
+        let resp = self
+            .http
+            .get(url)
+            .header("Authorization", format!("Bridge {}", token))
+            .send()
+            .await?;
+        let resp = reject_unauthorized(resp)?;
+        if resp.status().as_u16() == 204 {
+            return Ok(FetchResponse::NoContent);
+        }
+        if !resp.status().is_success() {
+            return Ok(FetchResponse::NonSuccess(resp.status().as_u16()));
+        }
+        // deep-review NH2/NH6: never `unwrap_or_default()` a command-bearing 2xx
+        // body — that silently turned schema drift / a truncated body into "0
+        // commands", losing whole batches of queued payment/print commands with
+        // no log. Read the bytes (network error still propagates via `?`), then
+        // decode explicitly and surface a decode failure as `DecodeError` so the
+        // loop backs off and the commands remain server-side for re-offer.
+        let body = resp.bytes().await?;
+        match serde_json::from_slice::<Vec<SignedCommand>>(&body) {
+            Ok(commands) => Ok(FetchResponse::Commands(commands)),
+            Err(e) => {
+                warn!(error = %e, len = body.len(), "commands/next body failed to decode; treating as fetch failure, not empty");
+                Ok(FetchResponse::DecodeError)
+            }
+        }
+    }
+
+    async fn post_ack(&self, cmd_id: &str, outcome: &CommandOutcome) -> Result<()> {
+        // Ack via the bridge-scoped route (Authorization: Bridge). The backend
+        // verifies the command's device belongs to THIS bridge before acking.
+        let url = format!("{}/v1/bridges/commands/{}/ack", self.cfg.cloud_url, cmd_id);
+        let token = crate::config::resolve_bearer_token().unwrap_or_default();
+        let resp = self
+            .http
+            .post(url)
+            .header("Authorization", format!("Bridge {}", token))
+            .json(outcome)
+            .send()
+            .await?;
+        reject_unauthorized(resp)?.error_for_status()?;
+        Ok(())
+    }
+
+    async fn post_heartbeat(&self, identity: &BridgeIdentity) -> Result<HeartbeatResponse> {
+        let url = format!("{}/v1/bridges/heartbeat", self.cfg.cloud_url);
+        let token = crate::config::resolve_bearer_token().unwrap_or_default();
+        let resp = self
+            .http
+            .post(url)
+            .header("Authorization", format!("Bridge {}", token))
+            .json(identity)
+            .send()
+            .await?;
+        let resp = reject_unauthorized(resp)?.error_for_status()?;
+        // The heartbeat itself succeeded at this point; an undecodable body
+        // (older backend, proxy page) only costs us this round of snapshots.
+        let body = resp.bytes().await?;
+        Ok(serde_json::from_slice(&body).unwrap_or_else(|e| {
+            warn!(error = %e, "heartbeat response body not decodable; no snapshots this round");
+            HeartbeatResponse::default()
+        }))
+    }
+
+    async fn post_claim(&self, req: &ClaimRequest) -> Result<ClaimResponse> {
+        // /v1/bridges/claim is @Public (no bearer yet — that's the whole
+        // point of the exchange). error_for_status() turns an already-used
+        // token (404) or a malformed request (400) into an Err the caller
+        // logs; a success body is decoded into the bearer token.
+        let url = format!("{}/v1/bridges/claim", self.cfg.cloud_url);
+        let resp = self
+            .http
+            .post(url)
+            .json(req)
+            .send()
+            .await?
+            .error_for_status()?;
+        let claim: ClaimResponse = resp.json().await?;
+        Ok(claim)
+    }
+
+    async fn post_offline_order(&self, order: &JournaledOrder) -> Result<OrderReplayResponse> {
+        let url = format!("{}/v1/bridges/offline-orders", self.cfg.cloud_url);
+        let token = crate::config::resolve_bearer_token().unwrap_or_default();
+        let resp = self
+            .http
+            .post(url)
+            .header("Authorization", format!("Bridge {}", token))
+            .json(&serde_json::json!({
+                "localId": order.local_id,
+                "baseOrderId": order.base_order_id,
+                "baseVersion": order.base_version,
+                "table": order.table,
+                "lines": order.lines,
+                "totalCents": order.total_cents,
+                "createdAt": order.created_at,
+            }))
+            .send()
+            .await?;
+        let resp = reject_unauthorized(resp)?;
+        let status = resp.status();
+        if status.is_server_error() {
+            anyhow::bail!("offline order upload failed: HTTP {status}");
+        }
+        let body: serde_json::Value = resp.json().await.unwrap_or_default();
+        let reason = body["message"].as_str().unwrap_or_default().to_string();
+        Ok(match status.as_u16() {
+            409 => OrderReplayResponse::Conflict { reason },
+            s if status.is_success() => match body["orderId"].as_str() {
+                Some(id) => OrderReplayResponse::Accepted {
+                    order_id: id.to_string(),
+                },
+                // Accepted without an id is not something we can reconcile
+                // against; keep it pending rather than guess.
+                None => anyhow::bail!("offline order accepted (HTTP {s}) without an orderId"),
+            },
+            s => OrderReplayResponse::Rejected { status: s, reason },
+        })
+    }
+
+    async fn post_local_audit(&self, cmd: &PendingCommand, outcome: &CommandOutcome) -> Result<()> {
+        let url = format!("{}/v1/bridges/local-commands", self.cfg.cloud_url);
+        let token = crate::config::resolve_bearer_token().unwrap_or_default();
+        let resp = self
+            .http
+            .post(url)
+            .header("Authorization", format!("Bridge {}", token))
+            .json(&serde_json::json!({
+                "command": cmd,
+                "outcome": outcome,
+            }))
+            .send()
+            .await?;
+        let resp = reject_unauthorized(resp)?;
+        // 409: an earlier attempt landed but its response was lost.
+        if resp.status().as_u16() != 409 {
+            resp.error_for_status()?;
+        }
+        Ok(())
+    }
+
+    async fn post_token_refresh(&self) -> Result<TokenRefreshResponse> {
+        // Authenticated with the token being replaced; whether that token can
+        // still be refreshed (expired vs revoked) is the cloud's call.
+        let url = format!("{}/v1/bridges/token/refresh", self.cfg.cloud_url);
+        let token = crate::config::resolve_bearer_token().unwrap_or_default();
+        let resp = self
+            .http
+            .post(url)
+            .header("Authorization", format!("Bridge {}", token))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(resp.json().await?)
+    }
+
+    async fn post_quarantine_report(&self, q: &QuarantinedCommand) -> Result<()> {
+        let url = format!("{}/v1/bridges/quarantine", self.cfg.cloud_url);
+        let token = crate::config::resolve_bearer_token().unwrap_or_default();
+        let resp = self
+            .http
+            .post(url)
+            .header("Authorization", format!("Bridge {}", token))
+            .json(&serde_json::json!({
+                "commandId": q.command_id,
+                "kind": q.kind,
+                "reason": q.reason,
+                "detail": q.detail,
+                "receivedAt": q.received_at,
+                "command": q.command,
+            }))
+            .send()
+            .await?;
+        let resp = reject_unauthorized(resp)?;
+        if resp.status().as_u16() != 409 {
+            resp.error_for_status()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+
+    /// In-memory [`CloudTransport`]. Seed the canned responses; it records the
+    /// acks it received so we can assert the exact outcome shape the client
+    /// posted — without any network.
+    #[derive(Default)]
+    struct FakeTransport {
+        healthz_status: u16,
+        next: Mutex<Option<FetchResponse>>,
+        acks: Mutex<Vec<(String, CommandOutcome)>>,
+        /// If true, post_ack returns an error (simulates the cloud rejecting).
+        ack_fails: bool,
+        /// Identities received via post_heartbeat, so tests can assert the
+        /// heartbeat tick actually posts (and what it posts).
+        heartbeats: Mutex<Vec<BridgeIdentity>>,
+        /// Provisioning tokens received via post_claim.
+        claims: Mutex<Vec<String>>,
+        /// Ids of locally originated commands forwarded via post_local_audit.
+        audits: Mutex<Vec<String>>,
+        /// If true, post_claim returns an error (simulates an invalid /
+        /// already-used provisioning token → 4xx).
+        claim_fails: bool,
+        /// While true, post_ack and post_heartbeat answer 401. A successful
+        /// refresh clears it, as the cloud accepting the new token would.
+        rejecting: Mutex<bool>,
+        /// If true, post_token_refresh is refused (revoked token).
+        refresh_fails: bool,
+        /// If true, the refreshed token is rejected as well.
+        rejects_refreshed: bool,
+        refreshes: Mutex<usize>,
+        /// Command ids received via post_quarantine_report.
+        quarantine_reports: Mutex<Vec<String>>,
+    }
+
+    impl FakeTransport {
+        fn acks(&self) -> Vec<(String, CommandOutcome)> {
+            self.acks.lock().unwrap().clone()
+        }
+        fn heartbeat_count(&self) -> usize {
+            self.heartbeats.lock().unwrap().len()
+        }
+        fn claims(&self) -> Vec<String> {
+            self.claims.lock().unwrap().clone()
+        }
+        fn refreshes(&self) -> usize {
+            *self.refreshes.lock().unwrap()
+        }
+        fn check_token(&self) -> Result<()> {
+            if *self.rejecting.lock().unwrap() {
+                return Err(Unauthorized.into());
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl CloudTransport for FakeTransport {
+        async fn get_healthz(&self) -> Result<u16> {
+            Ok(self.healthz_status)
+        }
+        async fn get_next_commands(&self) -> Result<FetchResponse> {
+            Ok(self
+                .next
+                .lock()
+                .unwrap()
+                .clone()
+                .unwrap_or(FetchResponse::NoContent))
+        }
+        async fn post_ack(&self, cmd_id: &str, outcome: &CommandOutcome) -> Result<()> {
+            self.check_token()?;
+            if self.ack_fails {
+                anyhow::bail!("cloud rejected ack for {cmd_id}");
+            }
+            self.acks
+                .lock()
+                .unwrap()
+                .push((cmd_id.to_string(), outcome.clone()));
+            Ok(())
+        }
+        async fn post_heartbeat(&self, identity: &BridgeIdentity) -> Result<HeartbeatResponse> {
+            self.check_token()?;
+            self.heartbeats.lock().unwrap().push(identity.clone());
+            Ok(HeartbeatResponse::default())
+        }
+        async fn post_claim(&self, req: &ClaimRequest) -> Result<ClaimResponse> {
+            if self.claim_fails {
+                anyhow::bail!("cloud rejected claim (invalid/used provisioning token)");
+            }
+            self.claims
+                .lock()
+                .unwrap()
+                .push(req.provisioning_token.clone());
+            Ok(ClaimResponse {
+                bridge_id: "bridge-xyz".to_string(),
+                token: "bearer-from-claim".to_string(),
+            })
+        }
+        async fn post_offline_order(&self, _order: &JournaledOrder) -> Result<OrderReplayResponse> {
+            anyhow::bail!("offline orders are not part of these tests")
+        }
+        async fn post_local_audit(
+            &self,
+            cmd: &PendingCommand,
+            _outcome: &CommandOutcome,
+        ) -> Result<()> {
+            self.audits.lock().unwrap().push(cmd.id.clone());
+            Ok(())
+        }
+        async fn post_token_refresh(&self) -> Result<TokenRefreshResponse> {
+            let n = {
+                let mut refreshes = self.refreshes.lock().unwrap();
+                *refreshes += 1;
+                *refreshes
+            };
+            if self.refresh_fails {
+                anyhow::bail!("HTTP status client error (401 Unauthorized) for token/refresh");
+            }
+            if !self.rejects_refreshed {
+                *self.rejecting.lock().unwrap() = false;
+            }
+            Ok(TokenRefreshResponse {
+                token: format!("bearer-refreshed-{n}"),
+            })
+        }
+        async fn post_quarantine_report(&self, q: &QuarantinedCommand) -> Result<()> {
+            self.quarantine_reports
+                .lock()
+                .unwrap()
+                .push(q.command_id.clone());
+            Ok(())
+        }
+    }
+
+    /// Records the tokens a refresh hands over, instead of touching the
+    /// process-global token slot the config tests read.
+    #[derive(Clone, Default)]
+    struct RecordingSink(Arc<Mutex<Vec<String>>>);
+
+    impl auth::TokenSink for RecordingSink {
+        fn rotated(&self, token: &str) -> Result<&'static str> {
+            self.0.lock().unwrap().push(token.to_string());
+            Ok("recording")
+        }
+    }
+
+    fn cmd(id: &str) -> PendingCommand {
+        PendingCommand {
+            id: id.to_string(),
+            kind: "print_receipt".to_string(),
+            payload: json!({ "target": "escpos" }),
+            priority: 0,
+            attempts: 0,
+            idempotency_key: None,
+            origin: Default::default(),
+        }
+    }
+
+    fn done() -> CommandOutcome {
+        CommandOutcome {
+            status: "done".to_string(),
+            result: serde_json::Value::Null,
+            error: None,
+        }
+    }
+
+    fn client_with(t: FakeTransport) -> (CloudClient, Arc<FakeTransport>) {
+        let arc = Arc::new(t);
+        (CloudClient::with_transport(arc.clone()), arc)
+    }
+
+    #[tokio::test]
+    async fn warm_up_ok_on_2xx() {
+        let (client, _) = client_with(FakeTransport {
+            healthz_status: 200,
+            ..Default::default()
+        });
+        assert!(client.warm_up().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn warm_up_errors_on_non_2xx() {
+        let (client, _) = client_with(FakeTransport {
+            healthz_status: 503,
+            ..Default::default()
+        });
+        let err = client.warm_up().await.unwrap_err();
+        assert!(err.to_string().contains("503"));
+    }
+
+    #[tokio::test]
+    async fn fetch_more_pushes_returned_commands_into_the_queue() {
+        // The load-bearing logic: a 2xx batch must land every command in the
+        // durable queue, ready for the dispatch loop to pop.
+        let dir = TempDir::new().unwrap();
+        let queue = CommandQueue::open(dir.path().join("q.db")).unwrap();
+
+        let (client, _) = client_with(FakeTransport {
+            next: Mutex::new(Some(FetchResponse::Commands(vec![
+                cmd("a").into(),
+                cmd("b").into(),
+            ]))),
+            ..Default::default()
+        });
+
+        client.fetch_more(&queue).await.unwrap();
+
+        // Both commands should now be poppable from the queue.
+        let first = queue.pop_next().await.unwrap().expect("first queued");
+        let second = queue.pop_next().await.unwrap().expect("second queued");
+        let mut ids = [first.id, second.id];
+        ids.sort();
+        assert_eq!(ids, ["a".to_string(), "b".to_string()]);
+        assert!(queue.pop_next().await.unwrap().is_none(), "only two pushed");
+    }
+
+    #[tokio::test]
+    async fn fetch_more_quarantines_what_fails_verification() {
+        use signing::tests::{pinned, sign, SEED};
+        let dir = TempDir::new().unwrap();
+        let queue = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        let expires = crate::command_queue::chrono_unix_now() + 60_000;
+        let (client, _) = client_with(FakeTransport {
+            next: Mutex::new(Some(FetchResponse::Commands(vec![
+                sign(cmd("a"), SEED, expires),
+                cmd("b").into(),
+                sign(cmd("c"), [9; 32], expires),
+            ]))),
+            ..Default::default()
+        });
+        let client = client.with_verifier(pinned());
+
+        client.fetch_more(&queue).await.unwrap();
+
+        assert_eq!(queue.pop_next().await.unwrap().unwrap().id, "a");
+        assert!(queue.pop_next().await.unwrap().is_none());
+        let held: Vec<_> = queue
+            .unreported_quarantine(10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|q| (q.command_id, q.reason))
+            .collect();
+        assert_eq!(
+            held,
+            [
+                ("b".to_string(), "unsigned".to_string()),
+                ("c".to_string(), "bad_signature".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_more_no_content_pushes_nothing() {
+        let dir = TempDir::new().unwrap();
+        let queue = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        let (client, _) = client_with(FakeTransport {
+            next: Mutex::new(Some(FetchResponse::NoContent)),
+            ..Default::default()
+        });
+
+        client.fetch_more(&queue).await.unwrap();
+        assert!(queue.pop_next().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn fetch_more_tolerates_non_success_without_erroring() {
+        // A transient 503 must NOT bubble an error (that would crash the loop)
+        // and must enqueue nothing.
+        let dir = TempDir::new().unwrap();
+        let queue = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        let (client, _) = client_with(FakeTransport {
+            next: Mutex::new(Some(FetchResponse::NonSuccess(503))),
+            ..Default::default()
+        });
+
+        client
+            .fetch_more(&queue)
+            .await
+            .expect("non-success is tolerated, not fatal");
+        assert!(queue.pop_next().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn fetch_more_errors_on_decode_failure_and_enqueues_nothing() {
+        // deep-review NH2/NH6: an undecodable 2xx body must NOT look like "no
+        // work". fetch_more returns Err (so main.rs backs off) and queues nothing
+        // — the commands stay server-side for re-offer rather than being lost.
+        let dir = TempDir::new().unwrap();
+        let queue = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        let (client, _) = client_with(FakeTransport {
+            next: Mutex::new(Some(FetchResponse::DecodeError)),
+            ..Default::default()
+        });
+
+        let err = client
+            .fetch_more(&queue)
+            .await
+            .expect_err("decode failure must surface as Err, not silent empty");
+        assert!(err.to_string().contains("undecodable"));
+        assert!(
+            queue.pop_next().await.unwrap().is_none(),
+            "nothing enqueued on decode failure"
+        );
+    }
+
+    #[tokio::test]
+    async fn ack_forwards_outcome_verbatim() {
+        let (client, fake) = client_with(FakeTransport::default());
+        let outcome = CommandOutcome {
+            status: "done".to_string(),
+            result: json!({ "ok": true }),
+            error: None,
+        };
+        client.ack(&cmd("c-1"), &outcome).await.unwrap();
+
+        let acks = fake.acks();
+        assert_eq!(acks.len(), 1);
+        assert_eq!(acks[0].0, "c-1");
+        assert_eq!(acks[0].1.status, "done");
+        assert_eq!(acks[0].1.result, json!({ "ok": true }));
+    }
+
+    #[tokio::test]
+    async fn ack_failed_shapes_the_failed_outcome() {
+        // ack_failed must construct status="failed", null result, and carry the
+        // error string — this is the shape the cloud's retry policy reads.
+        let (client, fake) = client_with(FakeTransport::default());
+        client
+            .ack_failed(&cmd("c-2"), "printer offline")
+            .await
+            .unwrap();
+
+        let acks = fake.acks();
+        assert_eq!(acks.len(), 1);
+        assert_eq!(acks[0].0, "c-2");
+        assert_eq!(acks[0].1.status, "failed");
+        assert_eq!(acks[0].1.result, serde_json::Value::Null);
+        assert_eq!(acks[0].1.error.as_deref(), Some("printer offline"));
+    }
+
+    #[tokio::test]
+    async fn local_commands_are_audited_not_acked() {
+        // The cloud has never seen a LAN-issued id; acking it would 404 forever
+        // and keep the row ack-pending. It goes to the audit endpoint instead.
+        let (client, fake) = client_with(FakeTransport::default());
+        let local = PendingCommand {
+            origin: CommandOrigin::Local,
+            ..cmd("l-1")
+        };
+        client.ack_failed(&local, "paper out").await.unwrap();
+
+        assert!(fake.acks().is_empty());
+        assert_eq!(*fake.audits.lock().unwrap(), vec!["l-1".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn ack_propagates_transport_errors() {
+        let (client, _) = client_with(FakeTransport {
+            ack_fails: true,
+            ..Default::default()
+        });
+        let err = client
+            .ack(
+                &cmd("c-3"),
+                &CommandOutcome {
+                    status: "done".to_string(),
+                    result: serde_json::Value::Null,
+                    error: None,
+                },
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("c-3"));
+    }
+
+    #[tokio::test]
+    async fn ack_falls_back_to_rest_when_push_channel_is_down() {
+        // A push channel that never connected must not swallow the ack — it
+        // goes out over the REST transport instead.
+        let (client, fake) = client_with(FakeTransport::default());
+        let cfg =
+            push::PushConfig::for_cloud_url("https://cloud.invalid", Network::default()).unwrap();
+        let client = client.with_push(PushChannel::new(cfg));
+        assert!(!client.push_is_live());
+
+        client
+            .ack(
+                &cmd("c-ws"),
+                &CommandOutcome {
+                    status: "done".to_string(),
+                    result: serde_json::Value::Null,
+                    error: None,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(fake.acks().len(), 1);
+        assert_eq!(fake.acks()[0].0, "c-ws");
+    }
+
+    #[tokio::test]
+    async fn post_heartbeat_forwards_identity_to_the_transport() {
+        // M8: the heartbeat tick must reach the cloud (not just /healthz). A
+        // single post_heartbeat call records exactly one identity payload.
+        let (client, fake) = client_with(FakeTransport::default());
+        let identity = BridgeIdentity {
+            hostname: Some("box-01".to_string()),
+            os: Some("linux".to_string()),
+            agent_version: Some("9.9.9".to_string()),
+            devices: Vec::new(),
+        };
+        client.post_heartbeat(&identity).await.unwrap();
+
+        assert_eq!(fake.heartbeat_count(), 1);
+        let beats = fake.heartbeats.lock().unwrap();
+        assert_eq!(beats[0].hostname.as_deref(), Some("box-01"));
+        assert_eq!(beats[0].agent_version.as_deref(), Some("9.9.9"));
+    }
+
+    #[tokio::test]
+    async fn claim_exchanges_provisioning_token_for_bearer() {
+        // M9: claim posts the provisioning token once and returns the new
+        // bearer token from the response body.
+        let (client, fake) = client_with(FakeTransport::default());
+        let resp = client.claim("prov-token-123").await.unwrap();
+
+        assert_eq!(resp.token, "bearer-from-claim");
+        assert_eq!(resp.bridge_id, "bridge-xyz");
+        assert_eq!(fake.claims(), vec!["prov-token-123".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn claim_propagates_transport_errors() {
+        // An invalid / already-used provisioning token (4xx) must surface as
+        // an Err so main.rs can log it and continue in offline mode rather
+        // than pretending it has a bearer.
+        let (client, _) = client_with(FakeTransport {
+            claim_fails: true,
+            ..Default::default()
+        });
+        let err = client.claim("bad-token").await.unwrap_err();
+        assert!(err.to_string().contains("rejected claim"));
+    }
+
+    #[tokio::test]
+    async fn a_401_refreshes_the_token_once_and_retries() {
+        let sink = RecordingSink::default();
+        let (client, fake) = client_with(FakeTransport {
+            rejecting: Mutex::new(true),
+            ..Default::default()
+        });
+        let client = client.with_auth(AuthState::new(Some(Box::new(sink.clone())), None));
+
+        client
+            .post_heartbeat(&BridgeIdentity::detect())
+            .await
+            .expect("the retry with the refreshed token goes through");
+        assert_eq!(fake.refreshes(), 1);
+        assert_eq!(fake.heartbeat_count(), 1);
+        assert_eq!(*sink.0.lock().unwrap(), ["bearer-refreshed-1"]);
+        assert!(!client.is_unauthenticated());
+
+        // The new token keeps working; no further refreshes.
+        client.ack(&cmd("c-1"), &done()).await.unwrap();
+        assert_eq!(fake.refreshes(), 1);
+    }
+
+    #[tokio::test]
+    async fn other_failures_never_trigger_a_refresh() {
+        let (client, fake) = client_with(FakeTransport {
+            ack_fails: true,
+            ..Default::default()
+        });
+        assert!(client.ack(&cmd("c-1"), &done()).await.is_err());
+        assert_eq!(fake.refreshes(), 0);
+        assert!(!client.is_unauthenticated());
+    }
+
+    /// A revoked token: the refresh is refused, the bridge says so explicitly
+    /// (in memory and on disk for `--health`), and does not hammer the refresh
+    /// route on every call. Once the cloud takes the token again, it recovers.
+    #[tokio::test]
+    async fn failed_refresh_enters_the_unauthenticated_state() {
+        let dir = TempDir::new().unwrap();
+        let (client, fake) = client_with(FakeTransport {
+            rejecting: Mutex::new(true),
+            refresh_fails: true,
+            ..Default::default()
+        });
+        let client = client.with_auth(AuthState::new(None, Some(dir.path())));
+
+        let err = client
+            .post_heartbeat(&BridgeIdentity::detect())
+            .await
+            .unwrap_err();
+        assert!(auth::is_unauthorized(&err), "{err:#}");
+        assert!(err.to_string().contains("unauthenticated"), "{err}");
+        assert!(client.is_unauthenticated());
+        let marker = auth::read_marker(dir.path()).expect("marker for --health");
+        assert!(
+            marker.reason.contains("token refresh failed"),
+            "{}",
+            marker.reason
+        );
+
+        // Within the cooldown a 401 fails fast, without another refresh.
+        assert!(client.ack(&cmd("c-1"), &done()).await.is_err());
+        assert_eq!(fake.refreshes(), 1);
+
+        // A new process sees the marker and starts out unauthenticated.
+        let restarted = AuthState::new(None, Some(dir.path()));
+        assert!(restarted.is_unauthenticated());
+
+        *fake.rejecting.lock().unwrap() = false;
+        client
+            .post_heartbeat(&BridgeIdentity::detect())
+            .await
+            .unwrap();
+        assert!(!client.is_unauthenticated());
+        assert!(auth::read_marker(dir.path()).is_none(), "marker cleared");
+    }
+
+    #[tokio::test]
+    async fn refreshed_token_rejected_too_is_unauthenticated() {
+        let (client, fake) = client_with(FakeTransport {
+            rejecting: Mutex::new(true),
+            rejects_refreshed: true,
+            ..Default::default()
+        });
+        let err = client.ack(&cmd("c-1"), &done()).await.unwrap_err();
+        assert!(auth::is_unauthorized(&err));
+        assert_eq!(fake.refreshes(), 1, "one refresh per 401, never a loop");
+        assert!(fake.acks().is_empty());
+        assert!(client.is_unauthenticated());
+    }
+
+    #[test]
+    fn bridge_identity_detect_fills_os_and_version() {
+        // detect() always knows os + agentVersion at compile time; hostname is
+        // best-effort. The serialized JSON must use camelCase to match the
+        // NestJS ClaimBridgeDto / BridgeHeartbeatDto field names.
+        let id = BridgeIdentity::detect();
+        assert!(id.os.is_some(), "os is known from std::env::consts::OS");
+        assert_eq!(id.agent_version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+
+        let json = serde_json::to_value(&BridgeIdentity {
+            hostname: None,
+            os: Some("linux".to_string()),
+            agent_version: Some("1.2.3".to_string()),
+            devices: Vec::new(),
+        })
+        .unwrap();
+        // camelCase + None hostname skipped (no stray null for the whitelist).
+        assert_eq!(json["agentVersion"], "1.2.3");
+        assert_eq!(json["os"], "linux");
+        assert!(json.get("hostname").is_none());
+        assert!(
+            json.get("devices").is_none(),
+            "claim body stays whitelisted"
+        );
+    }
+}
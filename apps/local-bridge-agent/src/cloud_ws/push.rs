@@ -0,0 +1,722 @@
+//! Persistent WSS push channel to `/ws/bridge`.
+//!
+//! The REST fan-in (`GET /v1/bridges/commands/next`) only learns about new work
+//! when the main loop polls, which adds up to one poll interval of latency to
+//! every kitchen ticket. This channel keeps one upgraded socket open instead:
+//! the cloud pushes command batches as they are created, the bridge lands them
+//! in the durable [`CommandQueue`] and wakes the dispatch loop, and acks travel
+//! back on the same socket.
+//!
+//! ## Wire protocol (JSON text frames, tagged by `type`)
+//!
+//! ```jsonc
+//! // cloud → bridge
+//! { "type": "commands",   "commands": [ /* signed PendingCommand */ ] }
+//! { "type": "ack_result", "id": "<command id>", "ok": true, "error": null }
+//! // bridge → cloud
+//! { "type": "received",   "ids": ["<command id>", ...] }
+//! { "type": "ack",        "id": "<command id>", "outcome": { /* CommandOutcome */ } }
+//! ```
+//!
+//! `received` is sent only AFTER every command in the batch is committed to
+//! SQLite — to the queue, or to quarantine if it fails verification (see
+//! [`super::signing`]) — so a crash between the socket read and the INSERT leaves the batch
+//! un-received and the cloud re-offers it (same contract as an un-acked REST
+//! poll). Unknown frame types are ignored so the cloud can add new ones
+//! without breaking deployed bridges.
+//!
+//! The upgrade goes out over the same `[network]` settings as REST (see
+//! [`crate::network`]): through the proxy's `CONNECT` tunnel if one is set,
+//! with the same extra CAs and SPKI pins.
+//!
+//! ## Fallback
+//!
+//! The channel never replaces REST outright. While it is down (upgrade refused
+//! by a captive portal / proxy, socket dropped, cloud restarting) the main loop
+//! polls `commands/next` exactly as before, and [`PushChannel::try_ack`] returns
+//! `None` so [`super::CloudClient::ack`] posts the ack over REST. The session
+//! task reconnects with capped exponential backoff and the loop switches back
+//! to push delivery as soon as the upgrade succeeds again.
+
+use super::signing::{self, CommandVerifier, SignedCommand};
+use crate::{
+    command_queue::{CommandOutcome, CommandQueue},
+    network::{self, Network},
+};
+use anyhow::{anyhow, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, oneshot, Notify},
+    task::JoinHandle,
+};
+use tokio_tungstenite::{
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+    Connector, MaybeTlsStream, WebSocketStream,
+};
+use tracing::{debug, info, warn};
+
+/// Tunables for the push channel. [`PushConfig::for_cloud_url`] gives the
+/// production values; tests shorten the backoff and ack timeout.
+#[derive(Debug, Clone)]
+pub struct PushConfig {
+    /// `wss://<cloud>/ws/bridge` (or `ws://` for a plain-HTTP dev cloud).
+    pub url: String,
+    /// Bound on the TCP connect + TLS + HTTP upgrade.
+    pub connect_timeout: Duration,
+    /// First reconnect delay after a failed/closed session; doubles per
+    /// consecutive failure up to `max_backoff`.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// How long an ack waits for its `ack_result` before falling back to REST.
+    pub ack_timeout: Duration,
+    /// Client ping cadence — keeps NAT / proxy idle timers from reaping the
+    /// socket between bursts of orders.
+    pub ping_interval: Duration,
+    /// Proxy and TLS settings — the same [`Network`] the REST client uses.
+    pub network: Network,
+}
+
+impl PushConfig {
+    pub fn for_cloud_url(cloud_url: &str, network: Network) -> Result<Self> {
+        Ok(Self {
+            url: ws_url(cloud_url)?,
+            connect_timeout: Duration::from_secs(10),
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(60),
+            ack_timeout: Duration::from_secs(10),
+            ping_interval: Duration::from_secs(20),
+            network,
+        })
+    }
+}
+
+/// Derive the WSS endpoint from the REST base URL: `https` → `wss`, `http` →
+/// `ws`, path replaced with `/ws/bridge`.
+pub fn ws_url(cloud_url: &str) -> Result<String> {
+    let mut url =
+        url::Url::parse(cloud_url).with_context(|| format!("parse cloud_url {cloud_url}"))?;
+    let scheme = match url.scheme() {
+        "https" => "wss",
+        "http" => "ws",
+        other => anyhow::bail!("cloud_url has unsupported scheme '{other}'"),
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| anyhow!("cannot derive {scheme}:// url from {cloud_url}"))?;
+    url.set_path("/ws/bridge");
+    url.set_query(None);
+    Ok(url.to_string())
+}
+
+/// Frames the cloud sends down the socket.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum CloudFrame {
+    Commands {
+        commands: Vec<SignedCommand>,
+    },
+    AckResult {
+        id: String,
+        ok: bool,
+        #[serde(default)]
+        error: Option<String>,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+/// Frames the bridge sends up the socket.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BridgeFrame<'a> {
+    Received {
+        ids: Vec<String>,
+    },
+    Ack {
+        id: &'a str,
+        outcome: &'a CommandOutcome,
+    },
+}
+
+/// The cloud's verdict on an ack sent over the socket.
+type AckReply = std::result::Result<(), String>;
+
+struct Shared {
+    cfg: PushConfig,
+    /// True while a session is upgraded and its writer task is running.
+    live: AtomicBool,
+    /// Writer half of the current session; `None` between sessions.
+    outbound: Mutex<Option<mpsc::UnboundedSender<Message>>>,
+    /// Acks awaiting an `ack_result`, keyed by command id.
+    pending: Mutex<HashMap<String, oneshot::Sender<AckReply>>>,
+    /// Signalled whenever a pushed batch lands in the queue.
+    pushed: Notify,
+}
+
+/// Handle to the push channel. Cheap to clone; every clone shares the same
+/// session state.
+#[derive(Clone)]
+pub struct PushChannel {
+    inner: Arc<Shared>,
+}
+
+impl PushChannel {
+    pub fn new(cfg: PushConfig) -> Self {
+        Self {
+            inner: Arc::new(Shared {
+                cfg,
+                live: AtomicBool::new(false),
+                outbound: Mutex::new(None),
+                pending: Mutex::new(HashMap::new()),
+                pushed: Notify::new(),
+            }),
+        }
+    }
+
+    /// Whether a session is currently upgraded. The main loop skips REST polling
+    /// while this holds.
+    pub fn is_live(&self) -> bool {
+        self.inner.live.load(Ordering::SeqCst)
+    }
+
+    /// Wait until the cloud pushes something, or `max` elapses. The timeout keeps
+    /// the main loop ticking so pending-ack retries still run on a quiet socket.
+    pub async fn wait_for_push(&self, max: Duration) {
+        let _ = tokio::time::timeout(max, self.inner.pushed.notified()).await;
+    }
+
+    /// Spawn the session task: connect, pump frames until the socket drops, back
+    /// off, reconnect — for the lifetime of the agent. Pushed commands are
+    /// admitted through `verifier` like polled ones.
+    pub fn spawn(
+        &self,
+        queue: Arc<CommandQueue>,
+        verifier: Arc<CommandVerifier>,
+    ) -> JoinHandle<()> {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut backoff = this.inner.cfg.initial_backoff;
+            loop {
+                match this.connect().await {
+                    Ok(ws) => {
+                        info!(url = %this.inner.cfg.url, "push channel connected");
+                        backoff = this.inner.cfg.initial_backoff;
+                        if let Err(e) = this.run_session(ws, &queue, &verifier).await {
+                            warn!(error = %e, "push channel session ended with error");
+                        } else {
+                            info!("push channel closed by cloud");
+                        }
+                    }
+                    Err(e) => {
+                        warn!(error = %e, retry_in_ms = backoff.as_millis() as u64, "push channel upgrade failed — REST polling stays active");
+                    }
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(this.inner.cfg.max_backoff);
+            }
+        })
+    }
+
+    /// Send an ack over the socket and wait for the cloud's `ack_result`.
+    ///
+    /// `None` means the channel could not deliver it (not connected, socket
+    /// dropped mid-wait, or no reply within `ack_timeout`) and the caller should
+    /// use REST. `Some(Err)` is an explicit rejection from the cloud and is NOT
+    /// retried over REST — the cloud already answered.
+    pub async fn try_ack(&self, cmd_id: &str, outcome: &CommandOutcome) -> Option<Result<()>> {
+        if !self.is_live() {
+            return None;
+        }
+        let text = serde_json::to_string(&BridgeFrame::Ack {
+            id: cmd_id,
+            outcome,
+        })
+        .ok()?;
+        let (tx, rx) = oneshot::channel();
+        self.pending().insert(cmd_id.to_string(), tx);
+        let sent = self
+            .outbound()
+            .as_ref()
+            .map(|out| out.send(Message::Text(text)).is_ok())
+            .unwrap_or(false);
+        if !sent {
+            self.pending().remove(cmd_id);
+            return None;
+        }
+        match tokio::time::timeout(self.inner.cfg.ack_timeout, rx).await {
+            Ok(Ok(Ok(()))) => Some(Ok(())),
+            Ok(Ok(Err(e))) => Some(Err(anyhow!("cloud rejected ack for {cmd_id}: {e}"))),
+            // Sender dropped (session torn down) or timed out.
+            _ => {
+                self.pending().remove(cmd_id);
+                None
+            }
+        }
+    }
+
+    async fn connect(&self) -> Result<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>> {
+        let cfg = &self.inner.cfg;
+        let url = url::Url::parse(&cfg.url).with_context(|| format!("parse {}", cfg.url))?;
+        let host = network::url_host(&url).context("push url has no host")?;
+        let port = url.port_or_known_default().unwrap_or(443);
+        let mut req = cfg
+            .url
+            .as_str()
+            .into_client_request()
+            .context("build WSS upgrade request")?;
+        let token = crate::config::resolve_bearer_token().unwrap_or_default();
+        req.headers_mut().insert(
+            "Authorization",
+            HeaderValue::from_str(&format!("Bridge {token}")).context("bearer header")?,
+        );
+        let upgrade = async {
+            let tcp = cfg.network.connect(&host, port).await?;
+            let connector = Connector::Rustls(cfg.network.tls_config());
+            tokio_tungstenite::client_async_tls_with_config(req, tcp, None, Some(connector))
+                .await
+                .context("WSS upgrade")
+        };
+        let (ws, _resp) = tokio::time::timeout(cfg.connect_timeout, upgrade)
+            .await
+            .map_err(|_| anyhow!("WSS upgrade timed out"))??;
+        Ok(ws)
+    }
+
+    /// Pump one upgraded session until it closes. Always tears the shared state
+    /// back down so acks fall back to REST the moment the socket is gone.
+    async fn run_session<S>(
+        &self,
+        ws: WebSocketStream<S>,
+        queue: &CommandQueue,
+        verifier: &CommandVerifier,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut sink, mut stream) = ws.split();
+        let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+        *self.outbound() = Some(tx.clone());
+        self.inner.live.store(true, Ordering::SeqCst);
+
+        let mut ping = tokio::time::interval(self.inner.cfg.ping_interval);
+        ping.tick().await; // first tick is immediate
+        let result: Result<()> = loop {
+            tokio::select! {
+                frame = stream.next() => match frame {
+                    None | Some(Ok(Message::Close(_))) => break Ok(()),
+                    Some(Err(e)) => break Err(e.into()),
+                    Some(Ok(Message::Text(text))) => {
+                        if let Err(e) = self.handle_text(&text, queue, verifier, &tx).await {
+                            break Err(e);
+                        }
+                    }
+                    Some(Ok(_)) => {} // ping/pong/binary
+                },
+                Some(msg) = rx.recv() => {
+                    if let Err(e) = sink.send(msg).await {
+                        break Err(e.into());
+                    }
+                }
+                _ = ping.tick() => {
+                    if let Err(e) = sink.send(Message::Ping(Vec::new())).await {
+                        break Err(e.into());
+                    }
+                }
+            }
+        };
+
+        self.inner.live.store(false, Ordering::SeqCst);
+        *self.outbound() = None;
+        // Dropping the senders wakes every waiting try_ack with None → REST.
+        self.pending().clear();
+        result
+    }
+
+    async fn handle_text(
+        &self,
+        text: &str,
+        queue: &CommandQueue,
+        verifier: &CommandVerifier,
+        out: &mpsc::UnboundedSender<Message>,
+    ) -> Result<()> {
+        let frame: CloudFrame = match serde_json::from_str(text) {
+            Ok(f) => f,
+            Err(e) => {
+                // Same stance as FetchResponse::DecodeError: never guess. The
+                // batch is not marked received, so the cloud re-offers it.
+                warn!(error = %e, len = text.len(), "push channel: undecodable frame ignored");
+                return Ok(());
+            }
+        };
+        match frame {
+            CloudFrame::Commands { commands } => {
+                let mut ids = Vec::with_capacity(commands.len());
+                for c in &commands {
+                    signing::admit(verifier, queue, c).await?;
+                    ids.push(c.command.id.clone());
+                }
+                debug!(count = ids.len(), "push channel: commands admitted");
+                let received = serde_json::to_string(&BridgeFrame::Received { ids })?;
+                let _ = out.send(Message::Text(received));
+                self.inner.pushed.notify_one();
+            }
+            CloudFrame::AckResult { id, ok, error } => {
+                if let Some(waiter) = self.pending().remove(&id) {
+                    let reply = if ok {
+                        Ok(())
+                    } else {
+                        Err(error.unwrap_or_else(|| "rejected".to_string()))
+                    };
+                    let _ = waiter.send(reply);
+                }
+            }
+            CloudFrame::Unknown => debug!("push channel: unknown frame type ignored"),
+        }
+        Ok(())
+    }
+
+    fn pending(&self) -> std::sync::MutexGuard<'_, HashMap<String, oneshot::Sender<AckReply>>> {
+        self.inner
+            .pending
+            .lock()
+            .expect("push pending mutex poisoned")
+    }
+
+    fn outbound(&self) -> std::sync::MutexGuard<'_, Option<mpsc::UnboundedSender<Message>>> {
+        self.inner
+            .outbound
+            .lock()
+            .expect("push outbound mutex poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::TempDir;
+    use tokio::net::TcpListener;
+
+    fn test_cfg(port: u16) -> PushConfig {
+        PushConfig {
+            url: format!("ws://127.0.0.1:{port}/ws/bridge"),
+            connect_timeout: Duration::from_secs(2),
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(100),
+            ack_timeout: Duration::from_millis(500),
+            ping_interval: Duration::from_secs(30),
+            network: Network::default(),
+        }
+    }
+
+    fn cmd_json(id: &str) -> serde_json::Value {
+        json!({
+            "id": id,
+            "kind": "print_receipt",
+            "payload": { "target": "escpos" },
+            "priority": 0,
+            "attempts": 0
+        })
+    }
+
+    async fn wait_until(mut cond: impl FnMut() -> bool) {
+        for _ in 0..200 {
+            if cond() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition not reached in time");
+    }
+
+    async fn next_text<S>(ws: &mut WebSocketStream<S>) -> serde_json::Value
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        loop {
+            match ws.next().await.expect("socket open").expect("frame") {
+                Message::Text(t) => return serde_json::from_str(&t).unwrap(),
+                _ => continue,
+            }
+        }
+    }
+
+    #[test]
+    fn ws_url_maps_scheme_and_path() {
+        assert_eq!(
+            ws_url("https://api.hummytummy.com").unwrap(),
+            "wss://api.hummytummy.com/ws/bridge"
+        );
+        assert_eq!(
+            ws_url("http://127.0.0.1:3000/api").unwrap(),
+            "ws://127.0.0.1:3000/ws/bridge"
+        );
+        assert!(ws_url("ftp://x").is_err());
+    }
+
+    #[tokio::test]
+    async fn pushed_commands_land_in_the_queue_and_are_confirmed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(sock).await.unwrap();
+            let frame =
+                json!({ "type": "commands", "commands": [cmd_json("w-1"), cmd_json("w-2")] });
+            ws.send(Message::Text(frame.to_string())).await.unwrap();
+            let received = next_text(&mut ws).await;
+            assert_eq!(received["type"], "received");
+            assert_eq!(received["ids"], json!(["w-1", "w-2"]));
+        });
+
+        let dir = TempDir::new().unwrap();
+        let queue = Arc::new(CommandQueue::open(dir.path().join("q.db")).unwrap());
+        let push = PushChannel::new(test_cfg(port));
+        let handle = push.spawn(queue.clone(), Arc::default());
+
+        push.wait_for_push(Duration::from_secs(5)).await;
+        server.await.unwrap();
+        let first = queue.pop_next().await.unwrap().expect("w-1 queued");
+        let second = queue.pop_next().await.unwrap().expect("w-2 queued");
+        assert_eq!((first.id.as_str(), second.id.as_str()), ("w-1", "w-2"));
+        handle.abort();
+    }
+
+    /// A site behind an authenticating proxy gets push delivery too: the
+    /// upgrade is tunnelled with `CONNECT` and the TLS inside it is checked
+    /// against the extra CA and the pin, exactly like REST.
+    #[tokio::test]
+    async fn wss_upgrade_goes_through_the_proxy_with_pinned_tls() {
+        use crate::{config::NetworkConfig, network::tests as net};
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            let tls = net::acceptor().accept(sock).await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(tls).await.unwrap();
+            let frame = json!({ "type": "commands", "commands": [cmd_json("p-1")] });
+            ws.send(Message::Text(frame.to_string())).await.unwrap();
+            let received = next_text(&mut ws).await;
+            assert_eq!(received["ids"], json!(["p-1"]));
+        });
+        let (proxy, targets) = net::connect_proxy(Some("bridge:hunter2")).await;
+
+        let dir = TempDir::new().unwrap();
+        let network = Network::new(
+            &NetworkConfig {
+                proxy: Some(format!("http://{proxy}")),
+                proxy_username: Some("bridge".into()),
+                ca_bundles: vec![net::ca_file(&dir)],
+                spki_pins: vec![net::LEAF_PIN.into()],
+                ..Default::default()
+            },
+            &format!("https://localhost:{port}"),
+            Some("hunter2".into()),
+        )
+        .unwrap();
+        let queue = Arc::new(CommandQueue::open(dir.path().join("q.db")).unwrap());
+        let push = PushChannel::new(PushConfig {
+            url: format!("wss://localhost:{port}/ws/bridge"),
+            network,
+            ..test_cfg(port)
+        });
+        let handle = push.spawn(queue.clone(), Arc::default());
+
+        push.wait_for_push(Duration::from_secs(5)).await;
+        server.await.unwrap();
+        assert_eq!(queue.pop_next().await.unwrap().expect("queued").id, "p-1");
+        assert_eq!(*targets.lock().unwrap(), vec![format!("localhost:{port}")]);
+        handle.abort();
+    }
+
+    /// With a key pinned, an unsigned command in a pushed batch is quarantined
+    /// — and still confirmed as received, so the cloud stops re-offering it.
+    #[tokio::test]
+    async fn pushed_batches_are_verified_before_they_are_queued() {
+        use crate::cloud_ws::signing::tests::{pinned, sign, SEED};
+        let signed = sign(
+            serde_json::from_value(cmd_json("signed")).unwrap(),
+            SEED,
+            crate::command_queue::chrono_unix_now() + 60_000,
+        );
+        let mut signed_json = cmd_json("signed");
+        signed_json["signature"] = serde_json::to_value(&signed.signature).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(sock).await.unwrap();
+            let frame =
+                json!({ "type": "commands", "commands": [signed_json, cmd_json("forged")] });
+            ws.send(Message::Text(frame.to_string())).await.unwrap();
+            let received = next_text(&mut ws).await;
+            assert_eq!(received["ids"], json!(["signed", "forged"]));
+        });
+
+        let dir = TempDir::new().unwrap();
+        let queue = Arc::new(CommandQueue::open(dir.path().join("q.db")).unwrap());
+        let push = PushChannel::new(test_cfg(port));
+        let handle = push.spawn(queue.clone(), Arc::new(pinned()));
+
+        push.wait_for_push(Duration::from_secs(5)).await;
+        server.await.unwrap();
+        assert_eq!(queue.pop_next().await.unwrap().unwrap().id, "signed");
+        assert!(queue.pop_next().await.unwrap().is_none());
+        let held = queue.unreported_quarantine(10).await.unwrap();
+        assert_eq!(held.len(), 1);
+        assert_eq!(
+            (held[0].command_id.as_str(), held[0].reason.as_str()),
+            ("forged", "unsigned")
+        );
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn ack_travels_over_the_socket_and_waits_for_the_verdict() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(sock).await.unwrap();
+            for verdict in [true, false] {
+                let ack = next_text(&mut ws).await;
+                assert_eq!(ack["type"], "ack");
+                assert_eq!(ack["outcome"]["status"], "done");
+                let reply = json!({ "type": "ack_result", "id": ack["id"], "ok": verdict, "error": "stale" });
+                ws.send(Message::Text(reply.to_string())).await.unwrap();
+            }
+            // Hold the socket open until the client is done.
+            let _ = ws.next().await;
+        });
+
+        let dir = TempDir::new().unwrap();
+        let queue = Arc::new(CommandQueue::open(dir.path().join("q.db")).unwrap());
+        let push = PushChannel::new(test_cfg(port));
+        let handle = push.spawn(queue, Arc::default());
+        wait_until(|| push.is_live()).await;
+
+        let outcome = CommandOutcome {
+            status: "done".to_string(),
+            result: json!({ "ok": true }),
+            error: None,
+        };
+        let accepted = push.try_ack("a-1", &outcome).await;
+        assert!(matches!(accepted, Some(Ok(()))), "cloud accepted the ack");
+        let rejected = push.try_ack("a-2", &outcome).await.expect("cloud answered");
+        assert!(rejected.unwrap_err().to_string().contains("stale"));
+
+        handle.abort();
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn failed_upgrade_leaves_channel_down_so_acks_fall_back() {
+        // A plain HTTP server that refuses the upgrade — what a captive portal or
+        // an upgrade-stripping proxy looks like to the client.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let seen = attempts.clone();
+        let server = tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            loop {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                seen.fetch_add(1, Ordering::SeqCst);
+                let mut buf = [0u8; 1024];
+                let _ = sock.read(&mut buf).await;
+                let _ = sock
+                    .write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n")
+                    .await;
+            }
+        });
+
+        let dir = TempDir::new().unwrap();
+        let queue = Arc::new(CommandQueue::open(dir.path().join("q.db")).unwrap());
+        let push = PushChannel::new(test_cfg(port));
+        let handle = push.spawn(queue, Arc::default());
+
+        // It keeps retrying in the background...
+        wait_until(|| attempts.load(Ordering::SeqCst) >= 2).await;
+        // ...but never goes live, and acks report "use REST".
+        assert!(!push.is_live());
+        let outcome = CommandOutcome {
+            status: "done".to_string(),
+            result: serde_json::Value::Null,
+            error: None,
+        };
+        assert!(push.try_ack("x", &outcome).await.is_none());
+        handle.abort();
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_the_cloud_drops_the_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            // First session: close immediately.
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(sock).await.unwrap();
+            ws.close(None).await.unwrap();
+            drop(ws);
+            // Second session: deliver a command.
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(sock).await.unwrap();
+            let frame = json!({ "type": "commands", "commands": [cmd_json("after-reconnect")] });
+            ws.send(Message::Text(frame.to_string())).await.unwrap();
+            let _ = next_text(&mut ws).await;
+        });
+
+        let dir = TempDir::new().unwrap();
+        let queue = Arc::new(CommandQueue::open(dir.path().join("q.db")).unwrap());
+        let push = PushChannel::new(test_cfg(port));
+        let handle = push.spawn(queue.clone(), Arc::default());
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("client reconnected")
+            .unwrap();
+        let popped = queue.pop_next().await.unwrap().expect("delivered");
+        assert_eq!(popped.id, "after-reconnect");
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn undecodable_and_unknown_frames_do_not_kill_the_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(sock).await.unwrap();
+            ws.send(Message::Text("{not json".into())).await.unwrap();
+            ws.send(Message::Text(json!({ "type": "future_thing" }).to_string()))
+                .await
+                .unwrap();
+            let frame = json!({ "type": "commands", "commands": [cmd_json("still-alive")] });
+            ws.send(Message::Text(frame.to_string())).await.unwrap();
+            let received = next_text(&mut ws).await;
+            assert_eq!(received["ids"], json!(["still-alive"]));
+        });
+
+        let dir = TempDir::new().unwrap();
+        let queue = Arc::new(CommandQueue::open(dir.path().join("q.db")).unwrap());
+        let push = PushChannel::new(test_cfg(port));
+        let handle = push.spawn(queue.clone(), Arc::default());
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(queue.pop_next().await.unwrap().unwrap().id, "still-alive");
+        handle.abort();
+    }
+}
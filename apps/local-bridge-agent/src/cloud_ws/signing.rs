@@ -0,0 +1,460 @@
+//! Signature check on every command the cloud sends, before it is queued.
+//!
+//! The bridge is the trust boundary between the cloud and the restaurant LAN:
+//! what lands in the [`CommandQueue`] moves money and opens cash drawers. TLS
+//! alone leaves a gap wherever it is terminated early (a corporate proxy with
+//! its own CA, a compromised LB), so the cloud also signs each command and the
+//! bridge pins the keys in `[command_signing] public_keys`.
+//!
+//! ## Wire format
+//!
+//! A signed command carries one extra field next to the usual ones:
+//!
+//! ```jsonc
+//! { "id": "…", "kind": "charge_card", "payload": { … }, "priority": 0, "attempts": 0,
+//!   "signature": { "expiresAt": 1767225600000, "sig": "<hex ed25519 signature>" } }
+//! ```
+//!
+//! `sig` covers [`SIGNING_CONTEXT`] followed by the canonical JSON of
+//! `{ "expiresAt", "id", "kind", "payload" }`: object keys sorted by their
+//! UTF-8 bytes at every level, no whitespace, strings and numbers as
+//! `serde_json` writes them. `priority`, `attempts` and a top-level
+//! `idempotency_key` are not covered: a money command's idempotency key belongs
+//! in the payload, where the signature covers it. The queue prefers the
+//! top-level key, so a signed command whose top-level key differs from the
+//! payload's is rejected: otherwise anyone on the path could attach the key of
+//! an earlier approved charge and have the driver replay that approval.
+//!
+//! A replayed command is not a new one: the queue deduplicates by id, so a
+//! signature only has to outlive its delivery, not the command.
+//!
+//! ## Verdicts
+//!
+//! Unsigned, expired, malformed and bad signatures are [`Rejection`]s. A
+//! rejected command never reaches [`CommandQueue::push`]: it is written to the
+//! quarantine table with the reason, reported to the cloud, and shows up in
+//! `--health`. With no key pinned nothing is verified (the rollout state for a
+//! cloud that does not sign yet) and `--health` reports that instead.
+//!
+//! Unlike the update keys (compiled in, see [`crate::updater`]), these live in
+//! bridge.toml: the threat here is the network path, not someone who can
+//! already write to the bridge's disk.
+
+use crate::{
+    command_queue::{chrono_unix_now, CommandQueue, PendingCommand},
+    config::CommandSigningConfig,
+    updater::hex_decode,
+};
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+/// Domain separator, so a signature made for something else with the same key
+/// can never pass as a command signature.
+pub const SIGNING_CONTEXT: &[u8] = b"hummytummy-bridge-command-v1\n";
+
+/// A command as it arrives from the cloud: the queued fields plus the
+/// signature, which is checked and then dropped.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SignedCommand {
+    #[serde(flatten)]
+    pub command: PendingCommand,
+    #[serde(default)]
+    pub signature: Option<CommandSignature>,
+}
+
+impl From<PendingCommand> for SignedCommand {
+    fn from(command: PendingCommand) -> Self {
+        Self {
+            command,
+            signature: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandSignature {
+    /// Unix ms after which the signature no longer admits the command.
+    pub expires_at: i64,
+    /// Hex ed25519 signature, see the module doc.
+    pub sig: String,
+}
+
+/// Why a command was not queued.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Rejection {
+    #[error("command is not signed")]
+    Unsigned,
+    #[error("signature expired at {expires_at} (unix ms)")]
+    Expired { expires_at: i64 },
+    #[error("signature is malformed: {0}")]
+    Malformed(String),
+    #[error("signature does not verify against any pinned key")]
+    BadSignature,
+    #[error("top-level idempotency_key '{0}' is not the signed payload's idempotencyKey")]
+    UnsignedIdempotencyKey(String),
+}
+
+impl Rejection {
+    /// Stable machine-readable code, stored with the quarantined command and
+    /// reported to the cloud.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Rejection::Unsigned => "unsigned",
+            Rejection::Expired { .. } => "expired",
+            Rejection::Malformed(_) => "malformed_signature",
+            Rejection::BadSignature => "bad_signature",
+            Rejection::UnsignedIdempotencyKey(_) => "unsigned_idempotency_key",
+        }
+    }
+}
+
+/// The pinned keys, parsed once per client.
+#[derive(Debug, Clone, Default)]
+pub struct CommandVerifier {
+    keys: Vec<VerifyingKey>,
+    skew_ms: i64,
+}
+
+impl CommandVerifier {
+    /// A key that does not parse is an error, never skipped: a typo must not
+    /// quietly leave the bridge with fewer keys (or none) than configured.
+    pub fn from_config(cfg: &CommandSigningConfig) -> Result<Self> {
+        let keys = cfg
+            .public_keys
+            .iter()
+            .map(|k| {
+                let bytes: [u8; 32] = hex_decode(k)
+                    .with_context(|| format!("command signing key {k}"))?
+                    .try_into()
+                    .map_err(|_| anyhow!("command signing key {k} is not 32 bytes"))?;
+                VerifyingKey::from_bytes(&bytes).with_context(|| format!("command signing key {k}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            keys,
+            skew_ms: i64::try_from(cfg.max_clock_skew_secs.saturating_mul(1000))
+                .unwrap_or(i64::MAX),
+        })
+    }
+
+    /// False when no key is pinned: every command is admitted unverified.
+    pub fn is_enforcing(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Check `cmd` as of `now_ms`.
+    pub fn verify(&self, cmd: &SignedCommand, now_ms: i64) -> Result<(), Rejection> {
+        if !self.is_enforcing() {
+            return Ok(());
+        }
+        let sig = cmd.signature.as_ref().ok_or(Rejection::Unsigned)?;
+        let bytes: [u8; 64] = hex_decode(&sig.sig)
+            .map_err(|e| Rejection::Malformed(e.to_string()))?
+            .try_into()
+            .map_err(|_| Rejection::Malformed("not 64 bytes".to_string()))?;
+        let signature = Signature::from_bytes(&bytes);
+        let message = signed_message(&cmd.command, sig.expires_at);
+        // Signature first: `expiresAt` is only worth reading once it is known
+        // to be the cloud's, and a forgery must be reported as one.
+        if !self
+            .keys
+            .iter()
+            .any(|k| k.verify_strict(&message, &signature).is_ok())
+        {
+            return Err(Rejection::BadSignature);
+        }
+        if now_ms > sig.expires_at.saturating_add(self.skew_ms) {
+            return Err(Rejection::Expired {
+                expires_at: sig.expires_at,
+            });
+        }
+        if let Some(key) = &cmd.command.idempotency_key {
+            let signed = cmd
+                .command
+                .payload
+                .get("idempotencyKey")
+                .and_then(Value::as_str);
+            if signed != Some(key.as_str()) {
+                return Err(Rejection::UnsignedIdempotencyKey(key.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The exact bytes a command signature covers.
+pub fn signed_message(cmd: &PendingCommand, expires_at: i64) -> Vec<u8> {
+    let covered = serde_json::json!({
+        "expiresAt": expires_at,
+        "id": cmd.id,
+        "kind": cmd.kind,
+        "payload": cmd.payload,
+    });
+    let mut out = String::new();
+    canonical_json(&covered, &mut out);
+    let mut message = SIGNING_CONTEXT.to_vec();
+    message.extend_from_slice(out.as_bytes());
+    message
+}
+
+/// Sorted keys, no whitespace — independent of whether serde_json was built
+/// with `preserve_order`.
+fn canonical_json(v: &Value, out: &mut String) {
+    match v {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+            out.push('{');
+            for (i, (k, v)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(k.clone()).to_string());
+                out.push(':');
+                canonical_json(v, out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, v) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                canonical_json(v, out);
+            }
+            out.push(']');
+        }
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+/// Queue `cmd` if it verifies, otherwise quarantine it. `Ok(true)` = queued.
+/// Either way it is durably handled, so the caller may confirm receipt.
+pub async fn admit(
+    verifier: &CommandVerifier,
+    queue: &CommandQueue,
+    cmd: &SignedCommand,
+) -> Result<bool> {
+    match verifier.verify(cmd, chrono_unix_now()) {
+        Ok(()) => {
+            queue.push(&cmd.command).await?;
+            Ok(true)
+        }
+        Err(rejection) => {
+            warn!(
+                cmd = %cmd.command.id,
+                kind = %cmd.command.kind,
+                reason = rejection.code(),
+                "cloud command REJECTED at the trust boundary — quarantined, not queued: {rejection}"
+            );
+            let raw = serde_json::json!({
+                "command": cmd.command,
+                "signature": cmd.signature,
+            });
+            queue
+                .quarantine(
+                    &cmd.command.id,
+                    &cmd.command.kind,
+                    rejection.code(),
+                    &rejection.to_string(),
+                    &raw,
+                )
+                .await?;
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    pub(crate) const SEED: [u8; 32] = [7; 32];
+
+    pub(crate) fn key_hex(seed: [u8; 32]) -> String {
+        SigningKey::from_bytes(&seed)
+            .verifying_key()
+            .as_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    /// A verifier pinning the key of [`SEED`].
+    pub(crate) fn pinned() -> CommandVerifier {
+        CommandVerifier::from_config(&CommandSigningConfig {
+            public_keys: vec![key_hex(SEED)],
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    /// Sign `cmd` the way the cloud does.
+    pub(crate) fn sign(cmd: PendingCommand, seed: [u8; 32], expires_at: i64) -> SignedCommand {
+        let sig = SigningKey::from_bytes(&seed).sign(&signed_message(&cmd, expires_at));
+        SignedCommand {
+            command: cmd,
+            signature: Some(CommandSignature {
+                expires_at,
+                sig: sig.to_bytes().iter().map(|b| format!("{b:02x}")).collect(),
+            }),
+        }
+    }
+
+    fn charge(id: &str) -> PendingCommand {
+        PendingCommand {
+            id: id.to_string(),
+            kind: "charge_card".to_string(),
+            payload: json!({ "target": "ingenico", "amountCents": 1250, "idempotencyKey": "k-1" }),
+            priority: 0,
+            attempts: 0,
+            idempotency_key: None,
+            origin: Default::default(),
+        }
+    }
+
+    const NOW: i64 = 1_767_225_600_000;
+
+    #[test]
+    fn canonical_form_sorts_keys_at_every_level() {
+        let mut out = String::new();
+        canonical_json(
+            &json!({ "b": [ { "z": 1, "a": "é" } ], "a": null }),
+            &mut out,
+        );
+        assert_eq!(out, r#"{"a":null,"b":[{"a":"é","z":1}]}"#);
+    }
+
+    #[test]
+    fn a_valid_signature_admits_the_command() {
+        let signed = sign(charge("c-1"), SEED, NOW + 60_000);
+        assert_eq!(pinned().verify(&signed, NOW), Ok(()));
+
+        // Survives the trip through the wire format unchanged.
+        let wire = json!({
+            "id": "c-1", "kind": "charge_card", "priority": 0, "attempts": 0,
+            "payload": { "idempotencyKey": "k-1", "amountCents": 1250, "target": "ingenico" },
+            "signature": signed.signature,
+        });
+        let decoded: SignedCommand = serde_json::from_value(wire).unwrap();
+        assert_eq!(pinned().verify(&decoded, NOW), Ok(()));
+    }
+
+    /// What a proxy in the middle could try: drop the signature, alter the
+    /// amount, swap the kind, sign with its own key, or replay a stale one.
+    #[test]
+    fn tampered_unsigned_foreign_and_stale_commands_are_rejected() {
+        let verifier = pinned();
+        let unsigned = SignedCommand::from(charge("c-1"));
+        assert_eq!(verifier.verify(&unsigned, NOW), Err(Rejection::Unsigned));
+
+        let mut amount = sign(charge("c-1"), SEED, NOW + 60_000);
+        amount.command.payload["amountCents"] = json!(999_999);
+        assert_eq!(verifier.verify(&amount, NOW), Err(Rejection::BadSignature));
+
+        let mut kind = sign(charge("c-1"), SEED, NOW + 60_000);
+        kind.command.kind = "open_drawer".to_string();
+        assert_eq!(verifier.verify(&kind, NOW), Err(Rejection::BadSignature));
+
+        let foreign = sign(charge("c-1"), [9; 32], NOW + 60_000);
+        assert_eq!(verifier.verify(&foreign, NOW), Err(Rejection::BadSignature));
+
+        let mut extended = sign(charge("c-1"), SEED, NOW - 60_000);
+        extended.signature.as_mut().unwrap().expires_at = NOW + 60_000;
+        assert_eq!(
+            verifier.verify(&extended, NOW),
+            Err(Rejection::BadSignature)
+        );
+
+        let stale = sign(charge("c-1"), SEED, NOW - 60_000);
+        assert_eq!(
+            verifier.verify(&stale, NOW),
+            Err(Rejection::Expired {
+                expires_at: NOW - 60_000
+            })
+        );
+        // Within the clock-skew grace it still passes.
+        let just = sign(charge("c-1"), SEED, NOW - 10_000);
+        assert_eq!(verifier.verify(&just, NOW), Ok(()));
+
+        // Re-using a signed charge under the key of an earlier approved one.
+        let mut rekeyed = sign(charge("c-1"), SEED, NOW + 60_000);
+        rekeyed.command.idempotency_key = Some("k-approved".to_string());
+        assert_eq!(
+            verifier.verify(&rekeyed, NOW),
+            Err(Rejection::UnsignedIdempotencyKey("k-approved".to_string()))
+        );
+        // The same key as the payload's adds nothing unsigned.
+        let mut echoed = sign(charge("c-1"), SEED, NOW + 60_000);
+        echoed.command.idempotency_key = Some("k-1".to_string());
+        assert_eq!(verifier.verify(&echoed, NOW), Ok(()));
+
+        let mut garbled = sign(charge("c-1"), SEED, NOW + 60_000);
+        garbled.signature.as_mut().unwrap().sig = "zz".to_string();
+        assert_eq!(
+            verifier.verify(&garbled, NOW).unwrap_err().code(),
+            "malformed_signature"
+        );
+    }
+
+    #[test]
+    fn bad_pinned_key_is_a_config_error() {
+        let cfg = CommandSigningConfig {
+            public_keys: vec![key_hex(SEED), "not-hex".to_string()],
+            ..Default::default()
+        };
+        assert!(CommandVerifier::from_config(&cfg).is_err());
+    }
+
+    #[test]
+    fn no_pinned_key_verifies_nothing() {
+        let verifier = CommandVerifier::from_config(&CommandSigningConfig::default()).unwrap();
+        assert!(!verifier.is_enforcing());
+        assert_eq!(
+            verifier.verify(&SignedCommand::from(charge("c-1")), NOW),
+            Ok(())
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_commands_are_quarantined_never_queued() {
+        let dir = TempDir::new().unwrap();
+        let queue = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        let verifier = pinned();
+
+        let good = sign(charge("good"), SEED, chrono_unix_now() + 60_000);
+        assert!(admit(&verifier, &queue, &good).await.unwrap());
+        let forged = SignedCommand::from(PendingCommand {
+            kind: "open_drawer".to_string(),
+            ..charge("forged")
+        });
+        assert!(!admit(&verifier, &queue, &forged).await.unwrap());
+        let mut rekeyed = sign(charge("rekeyed"), SEED, chrono_unix_now() + 60_000);
+        rekeyed.command.idempotency_key = Some("k-approved".to_string());
+        assert!(!admit(&verifier, &queue, &rekeyed).await.unwrap());
+        // Re-offered: still one quarantine row.
+        assert!(!admit(&verifier, &queue, &forged).await.unwrap());
+
+        assert_eq!(queue.pop_next().await.unwrap().unwrap().id, "good");
+        assert!(queue.pop_next().await.unwrap().is_none());
+        assert!(queue.get("forged").await.unwrap().is_none());
+        assert!(queue.get("rekeyed").await.unwrap().is_none());
+
+        let held = queue.unreported_quarantine(10).await.unwrap();
+        assert_eq!(held.len(), 2);
+        assert_eq!(held[0].command_id, "forged");
+        assert_eq!(held[0].kind, "open_drawer");
+        assert_eq!(held[0].reason, "unsigned");
+        assert_eq!(held[0].command["command"]["kind"], "open_drawer");
+        assert_eq!(held[1].command_id, "rekeyed");
+        assert_eq!(held[1].reason, "unsigned_idempotency_key");
+    }
+}
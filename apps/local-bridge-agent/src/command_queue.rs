@@ -9,10 +9,12 @@
 //! the full command shape; the bridge only stores what it needs to execute
 //! and ack.
 
+use crate::config::{CommandTtlConfig, RetryConfig};
 use anyhow::Result;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OptionalExtension};
 use serde::{Deserialize, Serialize};
-use std::{path::Path, sync::Mutex};
+use std::{collections::BTreeMap, path::Path, sync::Mutex, time::Duration};
+use tokio::sync::Notify;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PendingCommand {
@@ -21,21 +23,181 @@ pub struct PendingCommand {
     pub payload: serde_json::Value,
     pub priority: i32,
     pub attempts: i32,
+    /// Idempotency key for side-effecting (money/fiscal) kinds, carried from the
+    /// queue row to `LocalDriver::execute` so a driver can ask the device
+    /// "did transaction N already happen?" before re-sending it. Taken from the
+    /// cloud (top-level field or payload `idempotencyKey`) when present,
+    /// otherwise derived from the command id at `push` time. `None` for
+    /// side-effect-free kinds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    /// Where the command came from. Bridge-side bookkeeping only — never read
+    /// from or written to the wire, so the cloud cannot claim a command is
+    /// local (or vice versa).
+    #[serde(skip)]
+    pub origin: CommandOrigin,
+}
+
+/// Who issued a command. Cloud commands are acked to the cloud by id; local
+/// ones (from the LAN API, [`crate::local_api`]) have ids the cloud has never
+/// seen, so their outcome is forwarded as an audit record instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandOrigin {
+    #[default]
+    Cloud,
+    Local,
+}
+
+impl CommandOrigin {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CommandOrigin::Cloud => "cloud",
+            CommandOrigin::Local => "local",
+        }
+    }
+
+    fn from_column(s: &str) -> Self {
+        match s {
+            "local" => CommandOrigin::Local,
+            _ => CommandOrigin::Cloud,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommandOutcome {
-    pub status: String, // "done" | "failed"
+    pub status: String, // "done" | "failed" | "expired"
     pub result: serde_json::Value,
     pub error: Option<String>,
 }
 
+/// A full `commands` row, for operator tooling (`review list|show`). The hot
+/// path never needs this — it works on [`PendingCommand`].
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandRecord {
+    pub id: String,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub priority: i32,
+    pub status: String,
+    pub attempts: i32,
+    pub error: Option<String>,
+    pub result: Option<serde_json::Value>,
+    pub idempotency_key: Option<String>,
+    pub origin: CommandOrigin,
+    /// Queued retries only: unix ms before which the row is not leased.
+    pub next_attempt_at: Option<i64>,
+    /// Unix ms after which the command is too late to run (see
+    /// [`CommandQueue::with_ttl`]); `None` never expires.
+    pub expires_at: Option<i64>,
+    /// When the cloud confirmed the ack, for rows acked since this column
+    /// was added.
+    pub acked_at: Option<i64>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// One step of a composite `workflow` command as persisted in
+/// `workflow_steps` (see [`crate::workflow`]), so a restarted agent resumes
+/// the workflow at the step it was on.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WorkflowStepRecord {
+    pub step_id: String,
+    pub kind: String,
+    /// `running` (dispatched, no outcome yet), `error` (the driver returned
+    /// an error), `skipped`, or the driver outcome's status (`done`).
+    pub status: String,
+    /// Dispatches of this step, across every attempt of the workflow.
+    pub attempts: i32,
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+    pub updated_at: i64,
+}
+
+/// How an operator settles a `needs_review` row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// The side effect DID happen (e.g. the charge is on the acquirer report):
+    /// move to `done` with an operator-supplied result and ack it.
+    Done,
+    /// The side effect did NOT happen and must not be retried: move to `failed`
+    /// and ack the failure.
+    Failed,
+    /// The side effect did NOT happen and should be retried: back to `queued`
+    /// with a fresh attempt count. Nothing is acked — the command is still owed.
+    Requeue,
+}
+
+impl Resolution {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Resolution::Done => "done",
+            Resolution::Failed => "failed",
+            Resolution::Requeue => "requeue",
+        }
+    }
+}
+
+/// One operator decision on a parked row, as written to `review_audit`.
+#[derive(Debug, Clone)]
+pub struct ReviewDecision {
+    pub resolution: Resolution,
+    /// Who decided (free text — the OS user by default).
+    pub operator: String,
+    pub note: Option<String>,
+    /// `Resolution::Done` only: the result blob acked to the cloud (e.g. the
+    /// approval code read off the terminal's end-of-day slip).
+    pub result: Option<serde_json::Value>,
+}
+
+/// A `review_audit` row.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReviewAuditEntry {
+    pub id: i64,
+    pub command_id: String,
+    pub kind: String,
+    pub resolution: String,
+    pub operator: String,
+    pub note: Option<String>,
+    pub previous_error: Option<String>,
+    pub cloud_acked: bool,
+    pub decided_at: i64,
+}
+
+/// A cloud command refused at the trust boundary (see
+/// [`crate::cloud_ws::signing`]). It lives in its own table, never in
+/// `commands`, so nothing can lease it.
+#[derive(Debug, Clone, Serialize)]
+pub struct QuarantinedCommand {
+    pub id: i64,
+    pub command_id: String,
+    pub kind: String,
+    /// [`crate::cloud_ws::signing::Rejection::code`].
+    pub reason: String,
+    pub detail: String,
+    /// The command (and signature, if any) as received.
+    pub command: serde_json::Value,
+    pub received_at: i64,
+    pub reported: bool,
+}
+
 /// Money/fiscal substrings that mark a command kind as side-effecting. Shared
 /// by `is_side_effecting` (mark_failed) AND `side_effecting_sql`
 /// (recover()/pop_next()) so the three classifiers can NEVER diverge — a
 /// `charge_card` parked by one but auto-retried by another is exactly the
 /// double-charge this guards.
-const MONEY_TOKENS: &[&str] = &["payment", "charge", "refund", "void", "reversal", "fiscal"];
+const MONEY_TOKENS: &[&str] = &[
+    "payment",
+    "charge",
+    "refund",
+    "void",
+    "reversal",
+    "fiscal",
+    "preauth",
+    "tip_adjust",
+    "batch_close",
+];
 
 /// SQL predicate (on column `kind`) equivalent to `is_side_effecting`, built
 /// from MONEY_TOKENS so recover()/pop_next() stay in lockstep with mark_failed.
@@ -50,9 +212,10 @@ fn side_effecting_sql() -> String {
 /// deep-review NH1/NH4/NH5/NM1: a side-effecting command (card charge, fiscal
 /// receipt, refund) must NEVER be auto-re-executed without an idempotency
 /// guarantee — re-running it double-charges the customer or double-prints a
-/// legally-binding fiscal receipt. Until the driver/acquirer layer carries an
-/// idempotency key end-to-end, these kinds are parked in `needs_review` instead
-/// of being requeued.
+/// legally-binding fiscal receipt. A failed side-effecting command is parked in
+/// `needs_review`; a crash-orphaned one is only requeued when it carries an
+/// idempotency key its driver checks against the device (see `recover`), and
+/// the registry refuses to re-dispatch it to any driver that cannot.
 ///
 /// Matched by SUBSTRING on money/fiscal tokens, not exact strings: the cloud
 /// enqueues concrete kinds like `charge_card`, `void_card`, `fiscal_cancel`,
@@ -60,10 +223,48 @@ fn side_effecting_sql() -> String {
 /// let slip back onto the auto-retry path. Erring toward over-matching is the
 /// safe direction — a false positive only parks a benign command for review;
 /// a false negative double-charges a customer.
-fn is_side_effecting(kind: &str) -> bool {
+pub(crate) fn is_side_effecting(kind: &str) -> bool {
     MONEY_TOKENS.iter().any(|tok| kind.contains(tok))
 }
 
+/// The idempotency key stored with a command: an explicit cloud-supplied key
+/// wins (top-level `idempotency_key`, then payload `idempotencyKey`); otherwise
+/// side-effecting kinds fall back to the command id, which is stable for the
+/// life of the row. Side-effect-free kinds get no key.
+pub(crate) fn resolve_idempotency_key(cmd: &PendingCommand) -> Option<String> {
+    let explicit = cmd
+        .idempotency_key
+        .clone()
+        .or_else(|| {
+            cmd.payload
+                .get("idempotencyKey")
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+        })
+        .filter(|k| !k.is_empty());
+    if explicit.is_some() {
+        return explicit;
+    }
+    is_side_effecting(&cmd.kind).then(|| cmd.id.clone())
+}
+
+/// Unix ms expiry for `cmd`: the payload's `expiresAt` if it has one (the
+/// issuer knows when the order was placed; the bridge only knows when it
+/// arrived), otherwise `now` plus the kind's `[command_ttl]`.
+fn resolve_expires_at(cmd: &PendingCommand, ttl: &CommandTtlConfig, now: i64) -> Option<i64> {
+    match cmd.payload.get("expiresAt") {
+        Some(v) if v.as_i64().is_some() => return v.as_i64(),
+        Some(v) => tracing::warn!(
+            id = %cmd.id,
+            expires_at = %v,
+            "command_queue: payload expiresAt is not unix ms — using the kind default"
+        ),
+        None => {}
+    }
+    ttl.for_kind(&cmd.kind)
+        .map(|secs| now.saturating_add((secs as i64).saturating_mul(1000)))
+}
+
 /// Lease TTL for inflight rows. A dispatch that has held a command longer than
 /// this is treated as wedged/dead and reclaimed by the runtime reaper in
 /// `pop_next`. Chosen longer than the cloud HTTP timeout (30s in cloud_ws) so a
@@ -71,6 +272,14 @@ fn is_side_effecting(kind: &str) -> bool {
 const INFLIGHT_LEASE_MS: i64 = 60_000;
 
 pub struct CommandQueue {
+    /// Signalled on every `push`, so a dispatcher idling on
+    /// [`CommandQueue::wait_for_push`] picks up local commands without waiting
+    /// out a cloud poll interval.
+    pushed: Notify,
+    /// Backoff between automatic retries of failed side-effect-free commands.
+    retry: RetryConfig,
+    /// Per-kind expiry for commands whose payload sets none.
+    ttl: CommandTtlConfig,
     // Mutex is fine here — the queue is a low-throughput coordination point.
     // If we ever need higher concurrency, a Tokio mpsc channel layered on top
     // would slot in without changing the API.
@@ -78,7 +287,31 @@ pub struct CommandQueue {
 }
 
 impl CommandQueue {
+    /// Open the queue as its owner (the agent): schema + migrations, then crash
+    /// recovery of rows a previous process left inflight. With no driver to
+    /// ask, every orphaned money/fiscal row parks — see
+    /// [`CommandQueue::open_replaying`].
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_replaying(path, |_| false)
+    }
+
+    /// [`CommandQueue::open`], requeueing an orphaned keyed money/fiscal row
+    /// when `replayable` says its driver verifies the key on the device
+    /// ([`crate::drivers::Registry::verifies_idempotency`]).
+    pub fn open_replaying<P: AsRef<Path>>(
+        path: P,
+        replayable: impl Fn(&PendingCommand) -> bool,
+    ) -> Result<Self> {
+        let queue = Self::open_shared(path)?;
+        queue.recover(&replayable)?;
+        Ok(queue)
+    }
+
+    /// Open the queue ALONGSIDE a running agent (operator CLI, `--health`):
+    /// same schema and migrations, but NO crash recovery — the inflight rows
+    /// belong to the live agent, and "recovering" them here would re-execute
+    /// commands it is dispatching right now.
+    pub fn open_shared<P: AsRef<Path>>(path: P) -> Result<Self> {
         let conn = Connection::open(path.as_ref())?;
         // deep-review NL1 + NM2: durability + contention hygiene, set BEFORE the
         // first table is created so auto_vacuum takes effect on a fresh DB.
@@ -106,22 +339,105 @@ impl CommandQueue {
                 attempts INTEGER NOT NULL DEFAULT 0,
                 error TEXT,
                 result TEXT,
+                idempotency_key TEXT,
                 created_at INTEGER NOT NULL,
                 updated_at INTEGER NOT NULL
             );
             CREATE INDEX IF NOT EXISTS idx_commands_queue
-              ON commands (status, priority DESC, created_at);",
+              ON commands (status, priority DESC, created_at);
+            CREATE TABLE IF NOT EXISTS review_audit (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                command_id TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                resolution TEXT NOT NULL,
+                operator TEXT NOT NULL,
+                note TEXT,
+                previous_error TEXT,
+                cloud_acked INTEGER NOT NULL DEFAULT 0,
+                decided_at INTEGER NOT NULL
+            );",
         )?;
         // Migration for DBs created before the `result` column existed (so the
         // outcome can be persisted for durable, restart-surviving acks — NH3/NH7).
         // ALTER ... ADD COLUMN errors if it already exists; ignore that case.
         let _ = conn.execute("ALTER TABLE commands ADD COLUMN result TEXT", []);
+        // Same for `idempotency_key` (money/fiscal replay verification).
+        let _ = conn.execute("ALTER TABLE commands ADD COLUMN idempotency_key TEXT", []);
+        // And `origin` (cloud vs LAN API); pre-existing rows all came from the cloud.
+        let _ = conn.execute(
+            "ALTER TABLE commands ADD COLUMN origin TEXT NOT NULL DEFAULT 'cloud'",
+            [],
+        );
+        // And `device_key` (per-device dispatch shard). Rows queued by an older
+        // build share the '' shard: they still run, just serially.
+        let _ = conn.execute(
+            "ALTER TABLE commands ADD COLUMN device_key TEXT NOT NULL DEFAULT ''",
+            [],
+        );
+        conn.execute_batch(
+            "CREATE INDEX IF NOT EXISTS idx_commands_device
+               ON commands (status, device_key, priority DESC, created_at);",
+        )?;
+
+        // And `next_attempt_at` (retry backoff). NULL = due now, which is what
+        // every row queued by an older build should be.
+        let _ = conn.execute(
+            "ALTER TABLE commands ADD COLUMN next_attempt_at INTEGER",
+            [],
+        );
+        // And `expires_at` (command TTL; NULL = never) plus `acked_at`, which
+        // tells an acked `expired` row from one whose ack is still owed.
+        let _ = conn.execute("ALTER TABLE commands ADD COLUMN expires_at INTEGER", []);
+        let _ = conn.execute("ALTER TABLE commands ADD COLUMN acked_at INTEGER", []);
+        // Commands refused at the trust boundary. One row per command id: a
+        // re-offered forgery is the same incident, not a new one.
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS quarantine (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                command_id TEXT NOT NULL UNIQUE,
+                kind TEXT NOT NULL,
+                reason TEXT NOT NULL,
+                detail TEXT NOT NULL,
+                command TEXT NOT NULL,
+                reported INTEGER NOT NULL DEFAULT 0,
+                received_at INTEGER NOT NULL
+            );",
+        )?;
+        // Per-step progress of `workflow` commands; rows go with their
+        // command in `sweep`.
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS workflow_steps (
+                command_id TEXT NOT NULL,
+                step_id TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                status TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                result TEXT,
+                error TEXT,
+                updated_at INTEGER NOT NULL,
+                PRIMARY KEY (command_id, step_id)
+            );",
+        )?;
 
-        let queue = Self {
+        Ok(Self {
             conn: Mutex::new(conn),
-        };
-        queue.recover()?;
-        Ok(queue)
+            pushed: Notify::new(),
+            retry: RetryConfig::default(),
+            ttl: CommandTtlConfig::default(),
+        })
+    }
+
+    /// Use `retry` (the `[retry]` table) instead of the built-in backoff.
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Expire commands per `ttl` (the `[command_ttl]` table). Applies to
+    /// commands pushed from now on; a row keeps the expiry it was queued with.
+    pub fn with_ttl(mut self, ttl: CommandTtlConfig) -> Self {
+        self.ttl = ttl;
+        self
     }
 
     /// deep-review NH1/NH4: crash-recovery sweep. A power cut or kill between
@@ -130,47 +446,111 @@ impl CommandQueue {
     /// never re-selects it, so the charge/print is silently lost with no signal.
     ///
     /// Kind-aware on purpose (NH4 step 3): re-executing a payment/fiscal command
-    /// could double-charge or double-print, so those go to a terminal
-    /// `needs_review` state for human reconciliation, surfaced to the cloud.
+    /// could double-charge or double-print. One WITHOUT an idempotency key goes
+    /// to a terminal `needs_review` state for human reconciliation, surfaced to
+    /// the cloud. One WITH a key is requeued only when `replayable` says its
+    /// driver can look the key up on the device: its next dispatch has
+    /// `attempts > 1`, so that driver either returns the recorded outcome or
+    /// sends it fresh. Under any other driver it parks like an unkeyed one —
+    /// requeued, it would only be refused by `Registry::dispatch`.
     /// Side-effect-free kinds (e.g. escpos order tickets) are safe to retry and
-    /// go back to 'queued', still bounded by the same 5-attempt cap as
+    /// go back to 'queued'. Both requeue paths keep the same 5-attempt cap as
     /// `mark_failed` so a poison command can't loop forever.
-    fn recover(&self) -> Result<()> {
+    fn recover(&self, replayable: &dyn Fn(&PendingCommand) -> bool) -> Result<()> {
+        let (parked, requeued) =
+            self.reclaim_inflight("recovered from inflight after restart", replayable)?;
+        if parked > 0 || requeued > 0 {
+            tracing::warn!(
+                parked_needs_review = parked,
+                requeued,
+                "command_queue: recovered inflight rows orphaned by a previous crash/reboot"
+            );
+        }
+        Ok(())
+    }
+
+    /// Give back every inflight row of this (stopping) process under the same
+    /// kind-aware rules as [`CommandQueue::recover`]. For a graceful shutdown
+    /// whose deadline cut dispatches off: the rows are settled now instead of
+    /// at next boot, and `--health` shows them for what they are. Returns
+    /// `(parked, requeued)`.
+    pub async fn release_inflight(
+        &self,
+        replayable: impl Fn(&PendingCommand) -> bool,
+    ) -> Result<(usize, usize)> {
+        self.reclaim_inflight("cut off by shutdown before it finished", &replayable)
+    }
+
+    fn reclaim_inflight(
+        &self,
+        why: &str,
+        replayable: &dyn Fn(&PendingCommand) -> bool,
+    ) -> Result<(usize, usize)> {
         let conn = self.conn.lock().expect("queue mutex poisoned");
         let now = chrono_unix_now();
-        // Money/fiscal kinds: park, do NOT auto-requeue. Uses the shared
-        // side_effecting_sql() so the concrete cloud kinds (charge_card,
-        // void_card, fiscal_cancel, fiscal_report) — which the old hardcoded
-        // IN-list silently let slip onto the requeue path — are parked too.
-        let parked = conn.execute(
+        // Keyed money/fiscal rows under the cap: only a driver that checks the
+        // key on the device may see them again. The rest park here.
+        let keyed: Vec<PendingCommand> = conn
+            .prepare(&format!(
+                "SELECT id, kind, payload, priority, attempts, idempotency_key, origin
+                   FROM commands
+                  WHERE status = 'inflight'
+                    AND ({})
+                    AND idempotency_key IS NOT NULL AND attempts < 5",
+                side_effecting_sql()
+            ))?
+            .query_map([], |row| {
+                let payload_s: String = row.get(2)?;
+                Ok(PendingCommand {
+                    id: row.get(0)?,
+                    kind: row.get(1)?,
+                    payload: serde_json::from_str(&payload_s).unwrap_or(serde_json::Value::Null),
+                    priority: row.get(3)?,
+                    attempts: row.get(4)?,
+                    idempotency_key: row.get(5)?,
+                    origin: CommandOrigin::from_column(&row.get::<_, String>(6)?),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        let mut parked = 0;
+        for cmd in keyed.iter().filter(|cmd| !replayable(cmd)) {
+            parked += conn.execute(
+                "UPDATE commands
+                    SET status = 'needs_review',
+                        error = COALESCE(error, ?2 || ' — driver cannot verify its idempotency key, needs reconciliation'),
+                        updated_at = ?1
+                  WHERE id = ?3",
+                params![now, why, cmd.id],
+            )?;
+        }
+        // Money/fiscal kinds without an idempotency key: park, do NOT
+        // auto-requeue. Uses the shared side_effecting_sql() so the concrete
+        // cloud kinds (charge_card, void_card, fiscal_cancel, fiscal_report) —
+        // which the old hardcoded IN-list silently let slip onto the requeue
+        // path — are parked too. Keyed rows past the attempt cap park as well.
+        parked += conn.execute(
             &format!(
                 "UPDATE commands
                     SET status = 'needs_review',
-                        error = COALESCE(error, 'recovered from inflight after restart — needs reconciliation'),
+                        error = COALESCE(error, ?2 || ' — needs reconciliation'),
                         updated_at = ?1
                   WHERE status = 'inflight'
-                    AND ({})",
+                    AND ({})
+                    AND (idempotency_key IS NULL OR attempts >= 5)",
                 side_effecting_sql()
             ),
-            params![now],
+            params![now, why],
         )?;
         // Safe-to-retry kinds: requeue, honouring the 5-attempt cap.
         let requeued = conn.execute(
             "UPDATE commands
                 SET status = CASE WHEN attempts >= 5 THEN 'failed' ELSE 'queued' END,
-                    error = COALESCE(error, 'recovered from inflight after restart'),
+                    error = COALESCE(error, ?2),
                     updated_at = ?1
               WHERE status = 'inflight'",
-            params![now],
+            params![now, why],
         )?;
-        if parked > 0 || requeued > 0 {
-            tracing::warn!(
-                parked_needs_review = parked,
-                requeued,
-                "command_queue: recovered inflight rows orphaned by a previous crash/reboot"
-            );
-        }
-        Ok(())
+        Ok((parked, requeued))
     }
 
     pub async fn push(&self, cmd: &PendingCommand) -> Result<()> {
@@ -178,20 +558,62 @@ impl CommandQueue {
         let now = chrono_unix_now();
         conn.execute(
             "INSERT OR IGNORE INTO commands
-              (id, kind, payload, priority, status, attempts, created_at, updated_at)
-             VALUES (?1, ?2, ?3, ?4, 'queued', 0, ?5, ?5)",
+              (id, kind, payload, priority, status, attempts, idempotency_key, origin, device_key,
+               expires_at, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, 'queued', 0, ?5, ?6, ?7, ?8, ?9, ?9)",
             params![
                 cmd.id,
                 cmd.kind,
                 serde_json::to_string(&cmd.payload)?,
                 cmd.priority,
+                resolve_idempotency_key(cmd),
+                cmd.origin.as_str(),
+                crate::drivers::device_key(cmd),
+                resolve_expires_at(cmd, &self.ttl, now),
                 now,
             ],
         )?;
+        drop(conn);
+        self.pushed.notify_one();
         Ok(())
     }
 
+    /// Wait until something is pushed (or `max` elapses). A push that happened
+    /// since the last wait returns immediately.
+    pub async fn wait_for_push(&self, max: Duration) {
+        let _ = tokio::time::timeout(max, self.pushed.notified()).await;
+    }
+
     pub async fn pop_next(&self) -> Result<Option<PendingCommand>> {
+        self.lease(None)
+    }
+
+    /// [`CommandQueue::pop_next`] restricted to one dispatch shard (see
+    /// [`crate::drivers::device_key`]). The per-device worker calls this, so a
+    /// device's commands run in queue order while other devices proceed.
+    pub async fn pop_next_for_device(&self, device_key: &str) -> Result<Option<PendingCommand>> {
+        self.lease(Some(device_key))
+    }
+
+    /// Device shards with queued work that is due, most urgent first (highest
+    /// priority, then oldest) — the order the dispatcher hands out worker
+    /// slots in. A shard whose only work is backing off is left out, so no
+    /// worker is started just to find nothing it may lease.
+    pub async fn devices_with_work(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        expire_due(&conn, chrono_unix_now())?;
+        let mut stmt = conn.prepare(
+            "SELECT device_key FROM commands
+              WHERE status = 'queued'
+                AND (next_attempt_at IS NULL OR next_attempt_at <= ?1)
+              GROUP BY device_key
+              ORDER BY MAX(priority) DESC, MIN(created_at), MIN(rowid)",
+        )?;
+        let rows = stmt.query_map(params![chrono_unix_now()], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<String>>>()?)
+    }
+
+    fn lease(&self, device_key: Option<&str>) -> Result<Option<PendingCommand>> {
         let conn = self.conn.lock().expect("queue mutex poisoned");
         let now = chrono_unix_now();
         let lease_cutoff = now - INFLIGHT_LEASE_MS;
@@ -204,23 +626,36 @@ impl CommandQueue {
         // Side-effecting (money/fiscal) kinds are deliberately EXCLUDED from
         // lease reclaim — re-popping them could double-charge; they stay inflight
         // and are surfaced/parked at next startup recovery or via reconciliation.
+        //
+        // A queued row backing off after a failure (`next_attempt_at` in the
+        // future) is skipped until it is due. Rows past their `expires_at` are
+        // moved to `expired` first and never leased; a wedged inflight row past
+        // it is not reclaimed either. Nor is a workflow: it may be holding a
+        // charge mid-step, and boot recovery resumes it step by step instead.
+        expire_due(&conn, now)?;
         let reclaim_sql = format!(
             "UPDATE commands
                 SET status = 'inflight',
                     attempts = attempts + 1,
                     updated_at = ?1
               WHERE id = (SELECT id FROM commands
-                           WHERE status = 'queued'
-                              OR (status = 'inflight'
-                                  AND updated_at < ?2
-                                  AND NOT ({}))
-                           ORDER BY priority DESC, created_at
+                           WHERE (?3 IS NULL OR device_key = ?3)
+                             AND ((status = 'queued'
+                                   AND (next_attempt_at IS NULL OR next_attempt_at <= ?1))
+                                  OR (status = 'inflight'
+                                      AND updated_at < ?2
+                                      AND (expires_at IS NULL OR expires_at > ?1)
+                                      AND kind <> '{}'
+                                      AND NOT ({})))
+                           -- rowid breaks same-millisecond ties in arrival order
+                           ORDER BY priority DESC, created_at, rowid
                            LIMIT 1)
-            RETURNING id, kind, payload, priority, attempts",
+            RETURNING id, kind, payload, priority, attempts, idempotency_key, origin",
+            crate::workflow::KIND,
             side_effecting_sql()
         );
         let mut stmt = conn.prepare(&reclaim_sql)?;
-        let mut rows = stmt.query(params![now, lease_cutoff])?;
+        let mut rows = stmt.query(params![now, lease_cutoff, device_key])?;
         if let Some(row) = rows.next()? {
             let payload_s: String = row.get(2)?;
             return Ok(Some(PendingCommand {
@@ -229,6 +664,8 @@ impl CommandQueue {
                 payload: serde_json::from_str(&payload_s)?,
                 priority: row.get(3)?,
                 attempts: row.get(4)?,
+                idempotency_key: row.get(5)?,
+                origin: CommandOrigin::from_column(&row.get::<_, String>(6)?),
             }));
         }
         Ok(None)
@@ -260,10 +697,16 @@ impl CommandQueue {
     /// deep-review NH3/NH7: terminal state reached only once the cloud has
     /// confirmed the ack. A row is "settled" (eligible for retention sweep) only
     /// after this transition; until then it is replayable via `pending_acks`.
+    /// An `expired` row keeps its status — it never ran — and is settled by
+    /// `acked_at` alone.
     pub async fn mark_acked(&self, id: &str) -> Result<()> {
         let conn = self.conn.lock().expect("queue mutex poisoned");
         conn.execute(
-            "UPDATE commands SET status = 'acked', updated_at = ?2 WHERE id = ?1",
+            "UPDATE commands
+                SET status = CASE WHEN status = 'expired' THEN 'expired' ELSE 'acked' END,
+                    acked_at = ?2,
+                    updated_at = ?2
+              WHERE id = ?1",
             params![id, chrono_unix_now()],
         )?;
         Ok(())
@@ -275,13 +718,14 @@ impl CommandQueue {
     /// connectivity blip — preventing the cloud from reissuing the logical
     /// command under a fresh id and double-executing it. Returns each command
     /// with its PERSISTED outcome so the exact original outcome is re-acked
-    /// (never a freshly-fabricated one).
+    /// (never a freshly-fabricated one). Expired rows whose `expired` ack is
+    /// still owed come back the same way, with outcome status `expired`.
     pub async fn pending_acks(&self, limit: i64) -> Result<Vec<(PendingCommand, CommandOutcome)>> {
         let conn = self.conn.lock().expect("queue mutex poisoned");
         let mut stmt = conn.prepare(
-            "SELECT id, kind, payload, priority, attempts, status, error, result
+            "SELECT id, kind, payload, priority, attempts, status, error, result, idempotency_key, origin
                FROM commands
-              WHERE status = 'done'
+              WHERE status = 'done' OR (status = 'expired' AND acked_at IS NULL)
               ORDER BY updated_at
               LIMIT ?1",
         )?;
@@ -297,10 +741,13 @@ impl CommandQueue {
                     payload: serde_json::from_str(&payload_s).unwrap_or(serde_json::Value::Null),
                     priority: row.get(3)?,
                     attempts: row.get(4)?,
+                    idempotency_key: row.get(8)?,
+                    origin: CommandOrigin::from_column(&row.get::<_, String>(9)?),
                 },
                 CommandOutcome {
                     // a `done` row was executed successfully; the ack outcome is
-                    // "done" regardless of the (null) error column.
+                    // "done" regardless of the (null) error column. An
+                    // `expired` row acks as "expired" — not a failure, it never ran.
                     status,
                     result: result_s
                         .and_then(|s| serde_json::from_str(&s).ok())
@@ -316,6 +763,18 @@ impl CommandQueue {
         Ok(out)
     }
 
+    /// How many rows [`CommandQueue::pending_acks`] would return, unlimited.
+    pub async fn pending_ack_count(&self) -> Result<i64> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        let n: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM commands
+              WHERE status = 'done' OR (status = 'expired' AND acked_at IS NULL)",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(n)
+    }
+
     pub async fn mark_failed(&self, id: &str, error: &str) -> Result<()> {
         let conn = self.conn.lock().expect("queue mutex poisoned");
         // deep-review NM1/NH5: kind-aware requeue. A side-effecting command
@@ -324,29 +783,44 @@ impl CommandQueue {
         // captured case). Auto-requeuing it would double-charge / double-print.
         // So such kinds go straight to a terminal `needs_review` state for human
         // reconciliation, regardless of attempts. Only idempotent/side-effect-
-        // free kinds keep the original 5-attempt auto-retry policy.
-        let kind: Option<String> = conn
+        // free kinds keep the original 5-attempt auto-retry policy, waiting out
+        // the kind's backoff (`[retry]`) between attempts. A workflow counts as
+        // side-effecting when the step it stopped on is.
+        let row: Option<(String, i32)> = conn
             .query_row(
-                "SELECT kind FROM commands WHERE id = ?1",
+                "SELECT kind, attempts FROM commands WHERE id = ?1",
                 params![id],
-                |row| row.get(0),
+                |row| Ok((row.get(0)?, row.get(1)?)),
             )
             .ok();
+        let kind = row.as_ref().map(|(kind, _)| kind.as_str());
         let now = chrono_unix_now();
-        if kind.as_deref().map(is_side_effecting).unwrap_or(false) {
+        let park = match kind {
+            Some(crate::workflow::KIND) => unsettled_money_step(&conn, id)?,
+            Some(kind) => is_side_effecting(kind),
+            None => false,
+        };
+        if park {
             conn.execute(
                 "UPDATE commands SET status = 'needs_review', error = ?2, updated_at = ?3 WHERE id = ?1",
                 params![id, error, now],
             )?;
         } else {
             // 5-attempt cap matches the cloud-side retry policy.
+            let delay = row
+                .as_ref()
+                .map(|(kind, attempts)| {
+                    self.retry.for_kind(kind).delay_ms(*attempts, unit_random())
+                })
+                .unwrap_or(0);
             conn.execute(
                 "UPDATE commands
                     SET status = CASE WHEN attempts >= 5 THEN 'failed' ELSE 'queued' END,
+                        next_attempt_at = CASE WHEN attempts >= 5 THEN NULL ELSE ?3 + ?4 END,
                         error = ?2,
                         updated_at = ?3
                   WHERE id = ?1",
-                params![id, error, now],
+                params![id, error, now, delay],
             )?;
         }
         Ok(())
@@ -358,20 +832,222 @@ impl CommandQueue {
     /// charges/prints fail). Only fully-settled rows are eligible:
     ///   - `acked`: executed AND cloud-confirmed — safe to drop.
     ///   - `failed`: terminal failure, already attempted to ack.
+    ///   - `expired` with `acked_at` set: never ran, and the cloud knows.
     ///
-    /// `done` (ack still pending) and `needs_review` (awaiting human action) are
-    /// deliberately retained until they reach a settled state.
+    /// `done` and unacked `expired` (ack still pending) and `needs_review`
+    /// (awaiting human action) are deliberately retained until they reach a
+    /// settled state.
     pub async fn sweep(&self, max_age_ms: i64) -> Result<usize> {
         let conn = self.conn.lock().expect("queue mutex poisoned");
         let cutoff = chrono_unix_now() - max_age_ms;
         let n = conn.execute(
-            "DELETE FROM commands WHERE status IN ('acked','failed') AND updated_at < ?1",
+            "DELETE FROM commands
+              WHERE (status IN ('acked','failed') OR (status = 'expired' AND acked_at IS NOT NULL))
+                AND updated_at < ?1",
+            params![cutoff],
+        )?;
+        conn.execute(
+            "DELETE FROM workflow_steps WHERE command_id NOT IN (SELECT id FROM commands)",
+            [],
+        )?;
+        // Quarantined commands go once the cloud has the report.
+        conn.execute(
+            "DELETE FROM quarantine WHERE reported = 1 AND received_at < ?1",
             params![cutoff],
         )?;
         conn.execute_batch("PRAGMA incremental_vacuum;")?;
         Ok(n)
     }
 
+    /// Every row parked in `needs_review`, oldest first — `review list`.
+    pub async fn list_needs_review(&self) -> Result<Vec<CommandRecord>> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {RECORD_COLUMNS} FROM commands WHERE status = 'needs_review' ORDER BY created_at"
+        ))?;
+        let rows = stmt.query_map([], record_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// One row by id, whatever its status — `review show`.
+    pub async fn get(&self, id: &str) -> Result<Option<CommandRecord>> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        Ok(conn
+            .query_row(
+                &format!("SELECT {RECORD_COLUMNS} FROM commands WHERE id = ?1"),
+                params![id],
+                record_from_row,
+            )
+            .optional()?)
+    }
+
+    /// The local command stored under idempotency key `key`, if any — the
+    /// LAN API's check for a reused key.
+    pub async fn find_local_by_key(&self, key: &str) -> Result<Option<CommandRecord>> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        Ok(conn
+            .query_row(
+                &format!(
+                    "SELECT {RECORD_COLUMNS} FROM commands
+                      WHERE idempotency_key = ?1 AND origin = 'local'
+                      ORDER BY created_at LIMIT 1"
+                ),
+                params![key],
+                record_from_row,
+            )
+            .optional()?)
+    }
+
+    /// Settle a `needs_review` row and write the decision to `review_audit` in
+    /// one transaction. Returns the audit id and, for `Done`/`Failed`, the
+    /// outcome to ack to the cloud (`Requeue` acks nothing — the command is
+    /// still owed). Errors if the row is missing or no longer parked, so two
+    /// operators cannot settle the same charge twice.
+    pub async fn resolve_review(
+        &self,
+        id: &str,
+        decision: &ReviewDecision,
+    ) -> Result<(i64, PendingCommand, Option<CommandOutcome>)> {
+        let mut conn = self.conn.lock().expect("queue mutex poisoned");
+        let tx = conn.transaction()?;
+        let row = tx
+            .query_row(
+                &format!("SELECT {RECORD_COLUMNS} FROM commands WHERE id = ?1"),
+                params![id],
+                record_from_row,
+            )
+            .optional()?
+            .ok_or_else(|| anyhow::anyhow!("no command with id '{id}'"))?;
+        if row.status != "needs_review" {
+            anyhow::bail!(
+                "command '{id}' is '{}', not needs_review — nothing to resolve",
+                row.status
+            );
+        }
+        let now = chrono_unix_now();
+        if let Some(at) = row
+            .expires_at
+            .filter(|at| *at <= now && decision.resolution == Resolution::Requeue)
+        {
+            anyhow::bail!(
+                "command '{id}' expired at {at} (unix ms) — requeued, it would only expire again; resolve it as done or failed"
+            );
+        }
+        let note = decision
+            .note
+            .as_deref()
+            .unwrap_or("operator reconciliation");
+        let outcome = match decision.resolution {
+            Resolution::Done => {
+                let mut result = decision
+                    .result
+                    .clone()
+                    .unwrap_or_else(|| serde_json::json!({}));
+                if let Some(obj) = result.as_object_mut() {
+                    obj.insert("reconciled".to_string(), serde_json::Value::Bool(true));
+                }
+                tx.execute(
+                    "UPDATE commands SET status = 'done', error = NULL, result = ?2, updated_at = ?3 WHERE id = ?1",
+                    params![id, serde_json::to_string(&result)?, now],
+                )?;
+                Some(CommandOutcome {
+                    status: "done".to_string(),
+                    result,
+                    error: None,
+                })
+            }
+            Resolution::Failed => {
+                let error = format!(
+                    "{note} (was: {})",
+                    row.error.as_deref().unwrap_or("unknown")
+                );
+                tx.execute(
+                    "UPDATE commands SET status = 'failed', error = ?2, updated_at = ?3 WHERE id = ?1",
+                    params![id, error, now],
+                )?;
+                Some(CommandOutcome {
+                    status: "failed".to_string(),
+                    result: serde_json::Value::Null,
+                    error: Some(error),
+                })
+            }
+            Resolution::Requeue => {
+                // attempts back to 0: the operator has confirmed the side effect
+                // did not happen, so this is a first attempt, not a replay. For a
+                // workflow that goes for the step it stopped on; settled steps
+                // keep their outcome and are not run again.
+                tx.execute(
+                    "UPDATE commands SET status = 'queued', attempts = 0, error = NULL, next_attempt_at = NULL, updated_at = ?2 WHERE id = ?1",
+                    params![id, now],
+                )?;
+                tx.execute(
+                    "DELETE FROM workflow_steps WHERE command_id = ?1 AND status IN ('running','error')",
+                    params![id],
+                )?;
+                None
+            }
+        };
+        tx.execute(
+            "INSERT INTO review_audit
+               (command_id, kind, resolution, operator, note, previous_error, decided_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                id,
+                row.kind,
+                decision.resolution.as_str(),
+                decision.operator,
+                decision.note,
+                row.error,
+                now
+            ],
+        )?;
+        let audit_id = tx.last_insert_rowid();
+        tx.commit()?;
+        let cmd = PendingCommand {
+            id: row.id,
+            kind: row.kind,
+            payload: row.payload,
+            priority: row.priority,
+            attempts: row.attempts,
+            idempotency_key: row.idempotency_key,
+            origin: row.origin,
+        };
+        Ok((audit_id, cmd, outcome))
+    }
+
+    /// Record that the cloud confirmed the ack for a review decision.
+    pub async fn mark_review_acked(&self, audit_id: i64) -> Result<()> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        conn.execute(
+            "UPDATE review_audit SET cloud_acked = 1 WHERE id = ?1",
+            params![audit_id],
+        )?;
+        Ok(())
+    }
+
+    /// The audit trail for one command, oldest decision first.
+    pub async fn review_audit(&self, command_id: &str) -> Result<Vec<ReviewAuditEntry>> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        let mut stmt = conn.prepare(
+            "SELECT id, command_id, kind, resolution, operator, note, previous_error, cloud_acked, decided_at
+               FROM review_audit WHERE command_id = ?1 ORDER BY id",
+        )?;
+        let rows = stmt.query_map(params![command_id], |row| {
+            Ok(ReviewAuditEntry {
+                id: row.get(0)?,
+                command_id: row.get(1)?,
+                kind: row.get(2)?,
+                resolution: row.get(3)?,
+                operator: row.get(4)?,
+                note: row.get(5)?,
+                previous_error: row.get(6)?,
+                cloud_acked: row.get::<_, i64>(7)? != 0,
+                decided_at: row.get(8)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
     /// deep-review NH1: count of rows parked for human reconciliation, so the
     /// main loop can surface them to the cloud/operator via telemetry.
     pub async fn needs_review_count(&self) -> Result<i64> {
@@ -383,9 +1059,311 @@ impl CommandQueue {
         )?;
         Ok(n)
     }
+
+    /// Queued rows that have failed at least once and are waiting to retry,
+    /// soonest first — the retry schedule `review retries` and `--health` show.
+    pub async fn list_retrying(&self) -> Result<Vec<CommandRecord>> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {RECORD_COLUMNS} FROM commands
+              WHERE status = 'queued' AND next_attempt_at IS NOT NULL
+              ORDER BY next_attempt_at, rowid"
+        ))?;
+        let rows = stmt.query_map([], record_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Every `expired` row, most recent first — `review expired`. `acked_at`
+    /// tells whether the cloud has been told yet.
+    pub async fn list_expired(&self) -> Result<Vec<CommandRecord>> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {RECORD_COLUMNS} FROM commands
+              WHERE status = 'expired'
+              ORDER BY expires_at DESC, rowid DESC"
+        ))?;
+        let rows = stmt.query_map([], record_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// How long until the earliest backing-off row becomes due, if any row is
+    /// still waiting. Lets an idle dispatcher wake up for it.
+    pub async fn next_retry_in(&self) -> Result<Option<Duration>> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        let now = chrono_unix_now();
+        let at: Option<i64> = conn.query_row(
+            "SELECT MIN(next_attempt_at) FROM commands
+              WHERE status = 'queued' AND next_attempt_at > ?1",
+            params![now],
+            |row| row.get(0),
+        )?;
+        Ok(at.map(|at| Duration::from_millis((at - now) as u64)))
+    }
+
+    /// Hold a command that failed verification. `Ok(false)` if this command id
+    /// is already quarantined.
+    pub async fn quarantine(
+        &self,
+        command_id: &str,
+        kind: &str,
+        reason: &str,
+        detail: &str,
+        command: &serde_json::Value,
+    ) -> Result<bool> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        let n = conn.execute(
+            "INSERT OR IGNORE INTO quarantine (command_id, kind, reason, detail, command, received_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                command_id,
+                kind,
+                reason,
+                detail,
+                serde_json::to_string(command)?,
+                chrono_unix_now()
+            ],
+        )?;
+        Ok(n > 0)
+    }
+
+    /// Quarantined commands the cloud has not been told about, oldest first.
+    pub async fn unreported_quarantine(&self, limit: i64) -> Result<Vec<QuarantinedCommand>> {
+        self.query_quarantine("WHERE reported = 0 ORDER BY id LIMIT ?1", params![limit])
+    }
+
+    /// Quarantined commands received at or after `since` (unix ms), newest
+    /// first — what `--health` shows.
+    pub async fn quarantined_since(&self, since: i64) -> Result<Vec<QuarantinedCommand>> {
+        self.query_quarantine(
+            "WHERE received_at >= ?1 ORDER BY received_at DESC, id DESC",
+            params![since],
+        )
+    }
+
+    pub async fn mark_quarantine_reported(&self, id: i64) -> Result<()> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        conn.execute(
+            "UPDATE quarantine SET reported = 1 WHERE id = ?1",
+            params![id],
+        )?;
+        Ok(())
+    }
+
+    fn query_quarantine(
+        &self,
+        tail: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<QuarantinedCommand>> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, command_id, kind, reason, detail, command, received_at, reported
+               FROM quarantine {tail}"
+        ))?;
+        let rows = stmt.query_map(params, |row| {
+            let command_s: String = row.get(5)?;
+            Ok(QuarantinedCommand {
+                id: row.get(0)?,
+                command_id: row.get(1)?,
+                kind: row.get(2)?,
+                reason: row.get(3)?,
+                detail: row.get(4)?,
+                command: serde_json::from_str(&command_s).unwrap_or(serde_json::Value::Null),
+                received_at: row.get(6)?,
+                reported: row.get(7)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Progress of workflow `command_id`, in the order its steps started.
+    pub async fn workflow_steps(&self, command_id: &str) -> Result<Vec<WorkflowStepRecord>> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        let mut stmt = conn.prepare(
+            "SELECT step_id, kind, status, attempts, result, error, updated_at
+               FROM workflow_steps WHERE command_id = ?1 ORDER BY rowid",
+        )?;
+        let rows = stmt.query_map(params![command_id], |row| {
+            let result_s: Option<String> = row.get(4)?;
+            Ok(WorkflowStepRecord {
+                step_id: row.get(0)?,
+                kind: row.get(1)?,
+                status: row.get(2)?,
+                attempts: row.get(3)?,
+                result: result_s.and_then(|s| serde_json::from_str(&s).ok()),
+                error: row.get(5)?,
+                updated_at: row.get(6)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Record that a workflow step is about to be dispatched, BEFORE it is —
+    /// a crash mid-step then leaves it `running`, and the resumed workflow
+    /// dispatches it as a replay. Returns the step's attempt number.
+    pub async fn begin_step(&self, command_id: &str, step_id: &str, kind: &str) -> Result<i32> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        Ok(conn.query_row(
+            "INSERT INTO workflow_steps (command_id, step_id, kind, status, attempts, updated_at)
+             VALUES (?1, ?2, ?3, 'running', 1, ?4)
+             ON CONFLICT (command_id, step_id) DO UPDATE
+                SET status = 'running', attempts = attempts + 1, updated_at = ?4
+             RETURNING attempts",
+            params![command_id, step_id, kind, chrono_unix_now()],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Record a workflow step's outcome (or `error` / `skipped`).
+    pub async fn settle_step(
+        &self,
+        command_id: &str,
+        step_id: &str,
+        kind: &str,
+        status: &str,
+        result: Option<&serde_json::Value>,
+        error: Option<&str>,
+    ) -> Result<()> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        conn.execute(
+            "INSERT INTO workflow_steps (command_id, step_id, kind, status, result, error, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT (command_id, step_id) DO UPDATE
+                SET status = ?4, result = ?5, error = ?6, updated_at = ?7",
+            params![
+                command_id,
+                step_id,
+                kind,
+                status,
+                result.map(serde_json::to_string).transpose()?,
+                error,
+                chrono_unix_now()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Row count per status (only statuses that have rows appear). Read-only —
+    /// safe against a live agent's database, which is what `--health` does.
+    pub async fn status_counts(&self) -> Result<BTreeMap<String, i64>> {
+        let conn = self.conn.lock().expect("queue mutex poisoned");
+        let mut stmt = conn.prepare("SELECT status, COUNT(*) FROM commands GROUP BY status")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<BTreeMap<_, _>>>()?)
+    }
+}
+
+/// Column list matching [`record_from_row`].
+const RECORD_COLUMNS: &str =
+    "id, kind, payload, priority, status, attempts, error, result, idempotency_key, created_at, updated_at, origin, next_attempt_at, expires_at, acked_at";
+
+fn record_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<CommandRecord> {
+    let payload_s: String = row.get(2)?;
+    let result_s: Option<String> = row.get(7)?;
+    Ok(CommandRecord {
+        id: row.get(0)?,
+        kind: row.get(1)?,
+        payload: serde_json::from_str(&payload_s).unwrap_or(serde_json::Value::Null),
+        priority: row.get(3)?,
+        status: row.get(4)?,
+        attempts: row.get(5)?,
+        error: row.get(6)?,
+        result: result_s.and_then(|s| serde_json::from_str(&s).ok()),
+        idempotency_key: row.get(8)?,
+        created_at: row.get(9)?,
+        updated_at: row.get(10)?,
+        origin: CommandOrigin::from_column(&row.get::<_, String>(11)?),
+        next_attempt_at: row.get(12)?,
+        expires_at: row.get(13)?,
+        acked_at: row.get(14)?,
+    })
 }
 
-fn chrono_unix_now() -> i64 {
+/// Move every queued row past its `expires_at` to `expired`, with the outcome
+/// the cloud is acked (`pending_acks` picks them up). Runs under the caller's
+/// lock, before anything is leased, so an expired row can never be handed to a
+/// driver. Only `queued` rows: an `inflight` one is already running, and a
+/// `needs_review` one waits for a person either way.
+///
+/// `expired` tells the cloud the command never ran, so only a row that never
+/// ran gets it. A money/fiscal row dispatched before (a charge requeued for a
+/// verified replay) and a workflow with a money step already dispatched may
+/// have charged: past their deadline they park in `needs_review` instead.
+fn expire_due(conn: &Connection, now: i64) -> Result<usize> {
+    let parked = conn.execute(
+        &format!(
+            "UPDATE commands
+                SET status = 'needs_review',
+                    error = 'passed its deadline after it may already have run — needs reconciliation (last error: '
+                            || COALESCE(error, 'none') || ')',
+                    next_attempt_at = NULL,
+                    updated_at = ?1
+              WHERE status = 'queued' AND expires_at IS NOT NULL AND expires_at <= ?1
+                AND ((attempts > 0 AND ({money}))
+                     OR (kind = '{workflow}'
+                         AND EXISTS (SELECT 1 FROM workflow_steps
+                                      WHERE command_id = commands.id
+                                        AND status <> 'skipped'
+                                        AND ({money}))))",
+            money = side_effecting_sql(),
+            workflow = crate::workflow::KIND,
+        ),
+        params![now],
+    )?;
+    if parked > 0 {
+        tracing::warn!(
+            parked_needs_review = parked,
+            "command_queue: commands that may already have run passed their deadline — parked for reconciliation"
+        );
+    }
+    let n = conn.execute(
+        "UPDATE commands
+            SET status = 'expired',
+                error = CASE WHEN attempts = 0
+                             THEN 'expired before it could run'
+                             ELSE 'expired while waiting to retry (last error: '
+                                  || COALESCE(error, 'none') || ')' END,
+                result = json_object('expiresAt', expires_at),
+                next_attempt_at = NULL,
+                updated_at = ?1
+          WHERE status = 'queued' AND expires_at IS NOT NULL AND expires_at <= ?1",
+        params![now],
+    )?;
+    if n > 0 {
+        tracing::warn!(
+            expired = n,
+            "command_queue: commands expired before they could run — acking them as expired"
+        );
+    }
+    Ok(n)
+}
+
+/// Whether workflow `id` stopped on a money/fiscal step — one dispatched
+/// (`running`) or failed (`error`) without an outcome. Such a workflow parks in
+/// `needs_review` like the step would on its own.
+fn unsettled_money_step(conn: &Connection, id: &str) -> Result<bool> {
+    let n: i64 = conn.query_row(
+        &format!(
+            "SELECT COUNT(*) FROM workflow_steps
+              WHERE command_id = ?1 AND status IN ('running','error') AND ({})",
+            side_effecting_sql()
+        ),
+        params![id],
+        |row| row.get(0),
+    )?;
+    Ok(n > 0)
+}
+
+/// A draw in `[0, 1)` for retry jitter. std's `RandomState` is seeded per
+/// process and stepped per instance, which is plenty for spreading retries
+/// and saves a `rand` dependency.
+fn unit_random() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+    h.write_i64(chrono_unix_now());
+    (h.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+pub(crate) fn chrono_unix_now() -> i64 {
     // Deliberate small helper instead of pulling in chrono crate.
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
@@ -406,6 +1384,8 @@ mod tests {
             payload: json!({ "target": "escpos" }),
             priority: 0,
             attempts: 0,
+            idempotency_key: None,
+            origin: Default::default(),
         }
     }
 
@@ -417,9 +1397,21 @@ mod tests {
         }
     }
 
+    /// Insert a money row the way a pre-idempotency bridge did: no key column.
+    fn push_unkeyed(q: &CommandQueue, id: &str, kind: &str) {
+        let conn = q.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO commands (id, kind, payload, created_at, updated_at)
+             VALUES (?1, ?2, '{}', 0, 0)",
+            params![id, kind],
+        )
+        .unwrap();
+    }
+
     /// deep-review NH1/NH4: reopening the queue must reclaim orphaned inflight
     /// rows — but kind-aware: a side-effect-free row becomes re-poppable while a
-    /// money/fiscal row lands in `needs_review` (never auto-re-executed).
+    /// money/fiscal row without an idempotency key lands in `needs_review`
+    /// (never auto-re-executed).
     #[tokio::test]
     async fn recover_requeues_safe_kinds_but_parks_money_kinds() {
         let dir = TempDir::new().unwrap();
@@ -428,7 +1420,7 @@ mod tests {
             let q = CommandQueue::open(&path).unwrap();
             q.push(&cmd("safe", "print_receipt")).await.unwrap();
             // Concrete cloud card kind — the old hardcoded IN-list omitted it.
-            q.push(&cmd("money", "charge_card")).await.unwrap();
+            push_unkeyed(&q, "money", "charge_card");
             // Claim both → both go 'inflight'.
             q.pop_next().await.unwrap().unwrap();
             q.pop_next().await.unwrap().unwrap();
@@ -446,6 +1438,81 @@ mod tests {
         assert_eq!(q.needs_review_count().await.unwrap(), 1);
     }
 
+    #[tokio::test]
+    async fn push_assigns_idempotency_keys_to_money_kinds_only() {
+        let dir = TempDir::new().unwrap();
+        let q = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        q.push(&cmd("pay", "charge_card")).await.unwrap();
+        let mut explicit = cmd("fis", "fiscal_receipt");
+        explicit.payload["idempotencyKey"] = json!("order-42-fis");
+        q.push(&explicit).await.unwrap();
+        q.push(&cmd("tkt", "print_receipt")).await.unwrap();
+
+        let mut keys = Vec::new();
+        while let Some(c) = q.pop_next().await.unwrap() {
+            keys.push((c.id, c.idempotency_key));
+        }
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                ("fis".to_string(), Some("order-42-fis".to_string())),
+                ("pay".to_string(), Some("pay".to_string())),
+                ("tkt".to_string(), None),
+            ]
+        );
+    }
+
+    /// A crash-orphaned charge that carries an idempotency key is requeued for
+    /// a verified replay (attempts > 1) instead of waiting for a human — when
+    /// its driver can verify the key.
+    #[tokio::test]
+    async fn recover_requeues_keyed_money_rows_for_verified_replay() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("q.db");
+        {
+            let q = CommandQueue::open(&path).unwrap();
+            q.push(&cmd("cc", "charge_card")).await.unwrap();
+            let first = q.pop_next().await.unwrap().unwrap();
+            assert_eq!(first.attempts, 1);
+        }
+        let q = CommandQueue::open_replaying(&path, |c| c.id == "cc").unwrap();
+        assert_eq!(q.needs_review_count().await.unwrap(), 0);
+        let replay = q.pop_next().await.unwrap().expect("keyed row requeued");
+        assert_eq!(replay.id, "cc");
+        assert_eq!(replay.attempts, 2, "the driver sees this is a replay");
+        assert_eq!(replay.idempotency_key.as_deref(), Some("cc"));
+    }
+
+    /// A keyed charge whose driver cannot verify the key parks at recovery
+    /// rather than being requeued only to be refused on dispatch. The same
+    /// goes for a shutdown cutting one off.
+    #[tokio::test]
+    async fn reclaim_parks_keyed_money_rows_whose_driver_cannot_verify() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("q.db");
+        {
+            let q = CommandQueue::open(&path).unwrap();
+            q.push(&cmd("cc", "charge_card")).await.unwrap();
+            q.pop_next().await.unwrap().unwrap();
+        }
+        let q = CommandQueue::open_replaying(&path, |_| false).unwrap();
+        assert!(q.pop_next().await.unwrap().is_none(), "not requeued");
+        let row = q.get("cc").await.unwrap().unwrap();
+        assert_eq!(row.status, "needs_review");
+        assert!(
+            row.error
+                .unwrap()
+                .contains("cannot verify its idempotency key"),
+            "says why"
+        );
+
+        q.push(&cmd("cc2", "charge_card")).await.unwrap();
+        q.pop_next().await.unwrap().unwrap();
+        assert_eq!(q.release_inflight(|_| false).await.unwrap(), (1, 0));
+        assert_eq!(q.get("cc2").await.unwrap().unwrap().status, "needs_review");
+    }
+
     /// deep-review NM1/NH5: a failed side-effecting command must NOT return to
     /// 'queued' (which would double-charge); it goes to terminal needs_review.
     #[tokio::test]
@@ -476,6 +1543,12 @@ mod tests {
             "fiscal_receipt",
             "fiscal_cancel",
             "fiscal_report",
+            "refund_card",
+            "preauth_card",
+            "preauth_complete",
+            "tip_adjust",
+            "charge_split_tender",
+            "batch_close",
             "pos_charge",
             "refund",
             "reversal",
@@ -488,6 +1561,7 @@ mod tests {
             "open_drawer",
             "noop",
             "capability_probe",
+            "discover_printers",
         ] {
             assert!(!is_side_effecting(kind), "{kind} must be auto-retryable");
         }
@@ -520,12 +1594,105 @@ mod tests {
         // First failure → back to queued, re-poppable.
         let c = q.pop_next().await.unwrap().unwrap();
         q.mark_failed(&c.id, "printer offline").await.unwrap();
+        force_due(&q, "p1");
         assert!(
             q.pop_next().await.unwrap().is_some(),
             "safe command requeues"
         );
     }
 
+    /// Make a backing-off row due now, standing in for the clock moving on.
+    fn force_due(q: &CommandQueue, id: &str) {
+        let conn = q.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE commands SET next_attempt_at = ?2 WHERE id = ?1",
+            params![id, chrono_unix_now() - 1],
+        )
+        .unwrap();
+    }
+
+    fn fixed_backoff(base_ms: u64) -> RetryConfig {
+        RetryConfig {
+            default: crate::config::Backoff {
+                base_ms,
+                max_ms: 600_000,
+                jitter: 0.0,
+            },
+            ..Default::default()
+        }
+    }
+
+    /// A failed safe command is not leased again until its backoff has passed:
+    /// neither `pop_next` nor `devices_with_work` (which would otherwise start a
+    /// worker that finds nothing) sees it, and the schedule is visible through
+    /// `list_retrying` / `next_retry_in`. Other rows keep flowing meanwhile.
+    #[tokio::test]
+    async fn failed_command_backs_off_before_retry() {
+        let dir = TempDir::new().unwrap();
+        let q = CommandQueue::open(dir.path().join("q.db"))
+            .unwrap()
+            .with_retry(fixed_backoff(60_000));
+        q.push(&cmd("slow", "print_receipt")).await.unwrap();
+        let c = q.pop_next().await.unwrap().unwrap();
+        let before = chrono_unix_now();
+        q.mark_failed(&c.id, "paper out").await.unwrap();
+
+        assert!(q.pop_next().await.unwrap().is_none(), "not due yet");
+        assert!(q.devices_with_work().await.unwrap().is_empty());
+        let retrying = q.list_retrying().await.unwrap();
+        assert_eq!(retrying.len(), 1);
+        let due = retrying[0].next_attempt_at.expect("scheduled");
+        assert!(due >= before + 60_000 && due <= chrono_unix_now() + 60_000);
+        let wait = q.next_retry_in().await.unwrap().expect("waiting");
+        assert!(wait > Duration::from_secs(59) && wait <= Duration::from_secs(60));
+
+        q.push(&cmd("fresh", "print_receipt")).await.unwrap();
+        assert_eq!(q.pop_next().await.unwrap().unwrap().id, "fresh");
+
+        force_due(&q, "slow");
+        assert_eq!(q.devices_with_work().await.unwrap().len(), 1);
+        assert_eq!(q.next_retry_in().await.unwrap(), None, "nothing waiting");
+        let again = q.pop_next().await.unwrap().expect("due now");
+        assert_eq!((again.id.as_str(), again.attempts), ("slow", 2));
+    }
+
+    /// The second failure waits twice as long as the first, and a per-kind
+    /// override replaces the default for that kind only.
+    #[tokio::test]
+    async fn backoff_grows_per_attempt_and_honours_kind_overrides() {
+        let dir = TempDir::new().unwrap();
+        let mut retry = fixed_backoff(10_000);
+        retry.kinds.insert(
+            "open_drawer".to_string(),
+            crate::config::Backoff {
+                base_ms: 1_000,
+                max_ms: 1_000,
+                jitter: 0.0,
+            },
+        );
+        let q = CommandQueue::open(dir.path().join("q.db"))
+            .unwrap()
+            .with_retry(retry);
+        q.push(&cmd("r", "print_receipt")).await.unwrap();
+        q.push(&cmd("d", "open_drawer")).await.unwrap();
+
+        let delay_of = |rec: &CommandRecord| rec.next_attempt_at.unwrap() - rec.updated_at;
+        for _ in 0..2 {
+            let c = q.pop_next().await.unwrap().unwrap();
+            q.mark_failed(&c.id, "offline").await.unwrap();
+        }
+        let r = q.get("r").await.unwrap().unwrap();
+        let d = q.get("d").await.unwrap().unwrap();
+        assert_eq!(delay_of(&r), 10_000);
+        assert_eq!(delay_of(&d), 1_000);
+
+        force_due(&q, "r");
+        let c = q.pop_next().await.unwrap().unwrap();
+        assert_eq!(c.id, "r");
+        q.mark_failed(&c.id, "offline").await.unwrap();
+        assert_eq!(delay_of(&q.get("r").await.unwrap().unwrap()), 20_000);
+    }
+
     /// deep-review NH3/NH7: mark_done persists the outcome and leaves the row in
     /// a non-terminal 'done' state surfaced by pending_acks until mark_acked.
     #[tokio::test]
@@ -540,12 +1707,422 @@ mod tests {
         assert_eq!(pending.len(), 1);
         assert_eq!(pending[0].0.id, "c1");
         assert_eq!(pending[0].1.result, json!({ "ok": true }));
+        assert_eq!(q.pending_ack_count().await.unwrap(), 1);
 
         q.mark_acked("c1").await.unwrap();
         assert!(
             q.pending_acks(10).await.unwrap().is_empty(),
             "acked row no longer pending"
         );
+        assert_eq!(q.pending_ack_count().await.unwrap(), 0);
+    }
+
+    fn decision(resolution: Resolution) -> ReviewDecision {
+        ReviewDecision {
+            resolution,
+            operator: "ayse".to_string(),
+            note: Some("checked the Z report".to_string()),
+            result: None,
+        }
+    }
+
+    async fn parked(q: &CommandQueue, id: &str) {
+        q.push(&cmd(id, "charge_card")).await.unwrap();
+        let c = q.pop_next().await.unwrap().unwrap();
+        q.mark_failed(&c.id, "terminal timeout").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn review_done_settles_row_and_audits_the_decision() {
+        let dir = TempDir::new().unwrap();
+        let q = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        parked(&q, "cc1").await;
+        assert_eq!(q.list_needs_review().await.unwrap()[0].id, "cc1");
+
+        let mut d = decision(Resolution::Done);
+        d.result = Some(json!({ "approvalCode": "123456" }));
+        let (audit_id, cmd, outcome) = q.resolve_review("cc1", &d).await.unwrap();
+        assert_eq!(cmd.id, "cc1");
+        let outcome = outcome.expect("done is acked");
+        assert_eq!(outcome.status, "done");
+        assert_eq!(outcome.result["approvalCode"], "123456");
+        assert_eq!(outcome.result["reconciled"], true);
+
+        // The row is now ack-pending, so the agent's drain retries the ack if
+        // the CLI's own ack attempt fails.
+        assert_eq!(q.pending_acks(10).await.unwrap().len(), 1);
+        assert_eq!(q.needs_review_count().await.unwrap(), 0);
+
+        q.mark_review_acked(audit_id).await.unwrap();
+        let audit = q.review_audit("cc1").await.unwrap();
+        assert_eq!(audit.len(), 1);
+        assert_eq!(audit[0].resolution, "done");
+        assert_eq!(audit[0].operator, "ayse");
+        assert_eq!(audit[0].previous_error.as_deref(), Some("terminal timeout"));
+        assert!(audit[0].cloud_acked);
+
+        // A second decision on the same row is refused.
+        assert!(q.resolve_review("cc1", &d).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn review_failed_and_requeue_transitions() {
+        let dir = TempDir::new().unwrap();
+        let q = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        parked(&q, "f1").await;
+        parked(&q, "r1").await;
+
+        let (_, _, outcome) = q
+            .resolve_review("f1", &decision(Resolution::Failed))
+            .await
+            .unwrap();
+        let outcome = outcome.unwrap();
+        assert_eq!(outcome.status, "failed");
+        assert!(outcome.error.unwrap().contains("terminal timeout"));
+        assert_eq!(q.get("f1").await.unwrap().unwrap().status, "failed");
+
+        let (_, _, outcome) = q
+            .resolve_review("r1", &decision(Resolution::Requeue))
+            .await
+            .unwrap();
+        assert!(outcome.is_none(), "requeue acks nothing");
+        let again = q.pop_next().await.unwrap().expect("requeued");
+        assert_eq!(again.id, "r1");
+        assert_eq!(again.attempts, 1, "fresh attempt, not a replay");
+    }
+
+    #[tokio::test]
+    async fn open_shared_does_not_steal_inflight_rows() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("q.db");
+        let agent = CommandQueue::open(&path).unwrap();
+        agent.push(&cmd("live", "charge_card")).await.unwrap();
+        agent.pop_next().await.unwrap().unwrap();
+
+        let _cli = CommandQueue::open_shared(&path).unwrap();
+        assert_eq!(agent.get("live").await.unwrap().unwrap().status, "inflight");
+    }
+
+    #[tokio::test]
+    async fn status_counts_groups_rows_by_status() {
+        let dir = TempDir::new().unwrap();
+        let q = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        assert!(q.status_counts().await.unwrap().is_empty());
+
+        q.push(&cmd("a", "print_receipt")).await.unwrap();
+        q.push(&cmd("b", "print_receipt")).await.unwrap();
+        q.push(&cmd("c", "charge_card")).await.unwrap();
+        let popped = q.pop_next().await.unwrap().unwrap();
+
+        let counts = q.status_counts().await.unwrap();
+        assert_eq!(counts.get("queued"), Some(&2));
+        assert_eq!(counts.get("inflight"), Some(&1));
+        assert_eq!(counts.get("needs_review"), None);
+
+        q.mark_failed(&popped.id, "boom").await.unwrap();
+        let counts = q.status_counts().await.unwrap();
+        assert_eq!(counts.values().sum::<i64>(), 3);
+    }
+
+    fn expiring(id: &str, kind: &str, expires_at: serde_json::Value) -> PendingCommand {
+        PendingCommand {
+            payload: json!({ "target": "escpos", "expiresAt": expires_at }),
+            ..cmd(id, kind)
+        }
+    }
+
+    /// A lunch ticket still queued at dinner is never leased: it moves to
+    /// `expired`, is acked as `expired` (not `failed`), keeps that status once
+    /// acked, and only then becomes sweepable.
+    #[tokio::test]
+    async fn expired_commands_are_skipped_and_acked_as_expired() {
+        let dir = TempDir::new().unwrap();
+        let q = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        let past = chrono_unix_now() - 1;
+        q.push(&PendingCommand {
+            priority: 5,
+            ..expiring("stale", "print_receipt", json!(past))
+        })
+        .await
+        .unwrap();
+        q.push(&expiring("charge", "charge_card", json!(past)))
+            .await
+            .unwrap();
+        q.push(&cmd("fresh", "print_receipt")).await.unwrap();
+
+        assert_eq!(q.pop_next().await.unwrap().unwrap().id, "fresh");
+        assert!(q.pop_next().await.unwrap().is_none());
+
+        let stale = q.get("stale").await.unwrap().unwrap();
+        assert_eq!(stale.status, "expired");
+        assert_eq!(stale.expires_at, Some(past));
+        assert_eq!(stale.error.as_deref(), Some("expired before it could run"));
+
+        let acks = q.pending_acks(10).await.unwrap();
+        assert_eq!(acks.len(), 2);
+        for (_, outcome) in &acks {
+            assert_eq!(outcome.status, "expired");
+            assert_eq!(outcome.result, json!({ "expiresAt": past }));
+        }
+
+        q.mark_acked("stale").await.unwrap();
+        let stale = q.get("stale").await.unwrap().unwrap();
+        assert_eq!(stale.status, "expired");
+        assert!(stale.acked_at.is_some());
+        assert_eq!(q.pending_acks(10).await.unwrap().len(), 1);
+        assert_eq!(q.list_expired().await.unwrap().len(), 2);
+
+        // Only the acked one is settled; the other still owes its ack.
+        assert_eq!(q.sweep(-1).await.unwrap(), 1);
+        assert!(q.get("stale").await.unwrap().is_none());
+        assert_eq!(q.get("charge").await.unwrap().unwrap().status, "expired");
+    }
+
+    /// A failed ticket waiting out its backoff expires in place, keeping the
+    /// error that put it there.
+    #[tokio::test]
+    async fn retrying_commands_expire_with_their_last_error() {
+        let dir = TempDir::new().unwrap();
+        let q = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        q.push(&expiring(
+            "t",
+            "print_receipt",
+            json!(chrono_unix_now() + 60_000),
+        ))
+        .await
+        .unwrap();
+        q.pop_next().await.unwrap().unwrap();
+        q.mark_failed("t", "printer offline").await.unwrap();
+        q.conn
+            .lock()
+            .unwrap()
+            .execute("UPDATE commands SET expires_at = 1 WHERE id = 't'", [])
+            .unwrap();
+
+        assert!(q.devices_with_work().await.unwrap().is_empty());
+        let t = q.get("t").await.unwrap().unwrap();
+        assert_eq!(t.status, "expired");
+        assert_eq!(t.next_attempt_at, None);
+        assert!(t.error.unwrap().contains("printer offline"));
+    }
+
+    #[tokio::test]
+    async fn expiry_comes_from_the_payload_or_the_kind_default() {
+        let dir = TempDir::new().unwrap();
+        let ttl = CommandTtlConfig {
+            default_secs: Some(3600),
+            kinds: [
+                ("charge_card".to_string(), 600),
+                ("open_drawer".to_string(), 0),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        let q = CommandQueue::open(dir.path().join("q.db"))
+            .unwrap()
+            .with_ttl(ttl);
+        q.push(&cmd("ticket", "print_receipt")).await.unwrap();
+        q.push(&cmd("charge", "charge_card")).await.unwrap();
+        q.push(&cmd("drawer", "open_drawer")).await.unwrap();
+        q.push(&expiring(
+            "issuer",
+            "charge_card",
+            json!(4_102_444_800_000i64),
+        ))
+        .await
+        .unwrap();
+        q.push(&expiring("garbled", "charge_card", json!("tomorrow")))
+            .await
+            .unwrap();
+
+        let ttl_of = |r: CommandRecord| r.expires_at.map(|at| at - r.created_at);
+        let get = |id: &'static str| {
+            let q = &q;
+            async move { q.get(id).await.unwrap().unwrap() }
+        };
+        assert_eq!(ttl_of(get("ticket").await), Some(3_600_000));
+        assert_eq!(ttl_of(get("charge").await), Some(600_000));
+        assert_eq!(ttl_of(get("drawer").await), None);
+        assert_eq!(get("issuer").await.expires_at, Some(4_102_444_800_000));
+        assert_eq!(ttl_of(get("garbled").await), Some(600_000));
+    }
+
+    /// A charge requeued for a verified replay may have gone through the first
+    /// time; past its deadline it parks instead of being acked `expired`.
+    #[tokio::test]
+    async fn a_requeued_charge_past_its_deadline_parks_instead_of_expiring() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("q.db");
+        {
+            let q = CommandQueue::open(&path).unwrap();
+            q.push(&expiring(
+                "c",
+                "charge_card",
+                json!(chrono_unix_now() + 60_000),
+            ))
+            .await
+            .unwrap();
+            q.pop_next().await.unwrap().unwrap();
+        }
+        let q = CommandQueue::open_replaying(&path, |_| true).unwrap();
+        q.conn
+            .lock()
+            .unwrap()
+            .execute("UPDATE commands SET expires_at = 1 WHERE id = 'c'", [])
+            .unwrap();
+
+        assert!(q.pop_next().await.unwrap().is_none());
+        let c = q.get("c").await.unwrap().unwrap();
+        assert_eq!(c.status, "needs_review");
+        assert!(c.error.unwrap().contains("may already have run"));
+        assert!(
+            q.pending_acks(10).await.unwrap().is_empty(),
+            "not acked expired"
+        );
+    }
+
+    /// A workflow whose charge went through and whose receipt printer is
+    /// being retried must not be acked `expired` — the customer was charged.
+    #[tokio::test]
+    async fn a_partly_settled_workflow_past_its_deadline_parks() {
+        let dir = TempDir::new().unwrap();
+        let q = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        q.push(&PendingCommand {
+            payload: json!({ "expiresAt": chrono_unix_now() + 60_000 }),
+            ..cmd("wf", crate::workflow::KIND)
+        })
+        .await
+        .unwrap();
+        q.push(&PendingCommand {
+            payload: json!({ "expiresAt": chrono_unix_now() + 60_000 }),
+            ..cmd("wf-fresh", crate::workflow::KIND)
+        })
+        .await
+        .unwrap();
+        q.pop_next().await.unwrap().unwrap();
+        q.begin_step("wf", "charge", "charge_card").await.unwrap();
+        q.settle_step("wf", "charge", "charge_card", "done", None, None)
+            .await
+            .unwrap();
+        q.begin_step("wf", "ticket", "print_receipt").await.unwrap();
+        q.settle_step(
+            "wf",
+            "ticket",
+            "print_receipt",
+            "error",
+            None,
+            Some("printer offline"),
+        )
+        .await
+        .unwrap();
+        q.mark_failed("wf", "printer offline").await.unwrap();
+        assert_eq!(q.get("wf").await.unwrap().unwrap().status, "queued");
+        q.conn
+            .lock()
+            .unwrap()
+            .execute("UPDATE commands SET expires_at = 1", [])
+            .unwrap();
+
+        assert!(q.pop_next().await.unwrap().is_none());
+        assert_eq!(q.get("wf").await.unwrap().unwrap().status, "needs_review");
+        assert_eq!(
+            q.get("wf-fresh").await.unwrap().unwrap().status,
+            "expired",
+            "a workflow that never ran still expires"
+        );
+    }
+
+    /// Requeueing a parked command that has since expired would only expire
+    /// it again; the operator has to say whether it happened.
+    #[tokio::test]
+    async fn expired_parked_commands_cannot_be_requeued() {
+        let dir = TempDir::new().unwrap();
+        let q = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        q.push(&expiring(
+            "c",
+            "charge_card",
+            json!(chrono_unix_now() + 60_000),
+        ))
+        .await
+        .unwrap();
+        q.pop_next().await.unwrap().unwrap();
+        q.mark_failed("c", "terminal timeout").await.unwrap();
+        q.conn
+            .lock()
+            .unwrap()
+            .execute("UPDATE commands SET expires_at = 1 WHERE id = 'c'", [])
+            .unwrap();
+        let decide = |resolution| ReviewDecision {
+            resolution,
+            operator: "ops".to_string(),
+            note: None,
+            result: None,
+        };
+
+        let err = q
+            .resolve_review("c", &decide(Resolution::Requeue))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("expired"), "{err}");
+        assert_eq!(q.get("c").await.unwrap().unwrap().status, "needs_review");
+        q.resolve_review("c", &decide(Resolution::Failed))
+            .await
+            .unwrap();
+    }
+
+    /// A requeued workflow reruns only the step it stopped on; swept
+    /// workflows take their step rows with them.
+    #[tokio::test]
+    async fn workflow_steps_follow_requeue_and_sweep() {
+        let dir = TempDir::new().unwrap();
+        let q = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        q.push(&PendingCommand {
+            payload: json!({ "steps": [{ "id": "charge", "kind": "charge_card" }] }),
+            ..cmd("wf", crate::workflow::KIND)
+        })
+        .await
+        .unwrap();
+        q.pop_next().await.unwrap().unwrap();
+        q.settle_step(
+            "wf",
+            "print",
+            "print_receipt",
+            "done",
+            Some(&json!({})),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            q.begin_step("wf", "charge", "charge_card").await.unwrap(),
+            1
+        );
+        assert_eq!(
+            q.begin_step("wf", "charge", "charge_card").await.unwrap(),
+            2
+        );
+
+        q.mark_failed("wf", "terminal timeout").await.unwrap();
+        assert_eq!(q.get("wf").await.unwrap().unwrap().status, "needs_review");
+        let decide = ReviewDecision {
+            resolution: Resolution::Requeue,
+            operator: "ops".to_string(),
+            note: None,
+            result: None,
+        };
+        q.resolve_review("wf", &decide).await.unwrap();
+        let steps = q.workflow_steps("wf").await.unwrap();
+        assert_eq!(steps.len(), 1);
+        assert_eq!(
+            (steps[0].step_id.as_str(), steps[0].status.as_str()),
+            ("print", "done")
+        );
+
+        q.pop_next().await.unwrap().unwrap();
+        q.mark_done("wf", &done_outcome()).await.unwrap();
+        q.mark_acked("wf").await.unwrap();
+        q.sweep(-1).await.unwrap();
+        assert!(q.workflow_steps("wf").await.unwrap().is_empty());
     }
 
     /// deep-review NM2: sweep removes settled rows past the cutoff but leaves
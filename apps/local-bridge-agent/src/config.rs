@@ -1,18 +1,18 @@
 //! Configuration loading.
 //!
-//! The bridge reads `bridge.toml` from its config directory. Bearer tokens
-//! are stored in the OS keyring (linux: secret-tool, macos: Keychain,
-//! windows: DPAPI); the file holds only non-secret config.
-//!
-//! For MVP and CI we accept the token via environment variable too —
-//! HUMMY_BRIDGE_TOKEN — so headless deploys don't need a keyring. Production
-//! should always use the keyring.
+//! The bridge reads `bridge.toml` from its config directory; the file holds
+//! only non-secret config. The bearer token lives in a credential store (OS
+//! keyring, an encrypted file in the data dir, or `HUMMY_BRIDGE_TOKEN` — see
+//! [`crate::credentials`]) and is loaded into the process once at boot.
 
 use anyhow::{Context, Result};
 use serde::Deserialize;
 use std::{
+    collections::HashMap,
     env,
+    net::SocketAddr,
     path::{Path, PathBuf},
+    sync::RwLock,
 };
 
 #[derive(Debug, Clone, Deserialize)]
@@ -26,6 +26,332 @@ pub struct BridgeConfig {
     pub provisioning_token: Option<String>,
     /// Data directory for the SQLite queue + offline cache.
     pub data_dir: PathBuf,
+    /// Auto-update channel / pinning (`[update]` table; optional).
+    #[serde(default)]
+    pub update: UpdateConfig,
+    /// LAN API for POS terminals (`[local_api]` table). Absent = not served.
+    #[serde(default)]
+    pub local_api: Option<LocalApiConfig>,
+    /// Per-device dispatch (`[dispatch]` table; optional).
+    #[serde(default)]
+    pub dispatch: DispatchConfig,
+    /// Retry backoff for failed side-effect-free commands (`[retry]`; optional).
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// How long a command may wait before it is too late to run
+    /// (`[command_ttl]`; optional).
+    #[serde(default)]
+    pub command_ttl: CommandTtlConfig,
+    /// Where the bearer token is kept (`[credentials]`; optional).
+    #[serde(default)]
+    pub credentials: CredentialsConfig,
+    /// Keys cloud commands must be signed with (`[command_signing]`; optional).
+    #[serde(default)]
+    pub command_signing: CommandSigningConfig,
+    /// Proxy, extra CAs and certificate pins for cloud traffic (`[network]`;
+    /// optional).
+    #[serde(default)]
+    pub network: NetworkConfig,
+    /// Prometheus `/metrics` endpoint (`[metrics]` table). Absent = not served.
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+}
+
+/// `[network]` in bridge.toml. Applies to every connection to the cloud — REST,
+/// the WSS push channel and update downloads — so they all take the same path
+/// through the site's network. See [`crate::network`].
+///
+/// The proxy password is a secret, so it is never in this file: it comes from
+/// `HUMMY_PROXY_PASSWORD` or from `proxy_password_file` (see
+/// [`resolve_proxy_password`]).
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct NetworkConfig {
+    /// `http://host:port` of a forward proxy. Cloud TLS is tunnelled through it
+    /// with `CONNECT`, so the proxy never sees plaintext (unless it intercepts
+    /// TLS — then its CA goes in `ca_bundles`). Unset: connect directly;
+    /// `HTTPS_PROXY` is not consulted.
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// Basic-auth user for the proxy.
+    #[serde(default)]
+    pub proxy_username: Option<String>,
+    /// File holding the proxy password (first line, trimmed).
+    #[serde(default)]
+    pub proxy_password_file: Option<PathBuf>,
+    /// PEM files of CAs to trust on top of the built-in webpki roots.
+    #[serde(default)]
+    pub ca_bundles: Vec<PathBuf>,
+    /// `sha256/<base64>` SPKI hashes. When set, the cloud host's leaf
+    /// certificate key must hash to one of them.
+    #[serde(default)]
+    pub spki_pins: Vec<String>,
+}
+
+/// `[command_signing]` in bridge.toml. With no key pinned, cloud commands are
+/// queued unverified (and `--health` says so); with one or more, every cloud
+/// command must carry a valid, unexpired signature by one of them. See
+/// [`crate::cloud_ws::signing`].
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CommandSigningConfig {
+    /// Hex ed25519 public keys; any one may sign. Two during a key rotation.
+    #[serde(default)]
+    pub public_keys: Vec<String>,
+    /// Grace past a signature's `expiresAt`, for a bridge whose clock runs
+    /// ahead of the cloud's.
+    #[serde(default = "default_clock_skew_secs")]
+    pub max_clock_skew_secs: u64,
+}
+
+impl Default for CommandSigningConfig {
+    fn default() -> Self {
+        Self {
+            public_keys: Vec::new(),
+            max_clock_skew_secs: default_clock_skew_secs(),
+        }
+    }
+}
+
+/// `[credentials]` in bridge.toml.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct CredentialsConfig {
+    #[serde(default)]
+    pub store: CredentialStoreKind,
+}
+
+/// Which credential store holds the bearer token. `HUMMY_BRIDGE_TOKEN` is
+/// honoured under every choice.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CredentialStoreKind {
+    /// Keyring if one answers, otherwise the encrypted file.
+    #[default]
+    Auto,
+    Keyring,
+    /// Encrypted `bridge_token.enc` in `data_dir`.
+    File,
+    /// `HUMMY_BRIDGE_TOKEN` only; a claimed token is not persisted.
+    Env,
+}
+
+/// `[retry]` in bridge.toml. The top-level keys are the backoff every kind
+/// uses; `[retry.kinds.<kind>]` overrides it for one command kind (fields left
+/// out there take the built-in defaults, not the top-level ones).
+///
+/// Only side-effect-free kinds ever retry: a failed money/fiscal command is
+/// parked in `needs_review` whatever this says.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct RetryConfig {
+    #[serde(flatten)]
+    pub default: Backoff,
+    #[serde(default)]
+    pub kinds: HashMap<String, Backoff>,
+}
+
+impl RetryConfig {
+    /// The backoff that applies to `kind`.
+    pub fn for_kind(&self, kind: &str) -> &Backoff {
+        self.kinds.get(kind).unwrap_or(&self.default)
+    }
+}
+
+/// `[command_ttl]` in bridge.toml: how long a command may sit in the queue
+/// before running it would be worse than not running it (a lunch ticket at
+/// dinner, a charge the customer already paid in cash). `default_secs` covers
+/// every kind; `[command_ttl.kinds]` overrides it per kind, `0` meaning "never
+/// expires". Counted from when the bridge queued the command.
+///
+/// A command whose payload carries `expiresAt` (unix ms, set by whoever issued
+/// it) uses that instead. Unset everywhere: commands never expire.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct CommandTtlConfig {
+    #[serde(default)]
+    pub default_secs: Option<u64>,
+    #[serde(default)]
+    pub kinds: HashMap<String, u64>,
+}
+
+impl CommandTtlConfig {
+    /// Seconds a `kind` command may wait, or `None` if it never expires.
+    pub fn for_kind(&self, kind: &str) -> Option<u64> {
+        self.kinds
+            .get(kind)
+            .copied()
+            .or(self.default_secs)
+            .filter(|secs| *secs > 0)
+    }
+}
+
+/// Exponential backoff: the n-th failure waits `base_ms * 2^(n-1)`, capped at
+/// `max_ms`, then shortened by a random fraction of up to `jitter` so a batch
+/// of tickets that failed together (printer unplugged) does not retry in
+/// lockstep.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Backoff {
+    #[serde(default = "default_retry_base_ms")]
+    pub base_ms: u64,
+    #[serde(default = "default_retry_max_ms")]
+    pub max_ms: u64,
+    /// 0.0 (none) ..= 1.0 (anywhere between zero and the full delay).
+    #[serde(default = "default_retry_jitter")]
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            base_ms: default_retry_base_ms(),
+            max_ms: default_retry_max_ms(),
+            jitter: default_retry_jitter(),
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before the next try of a command that has failed `attempts`
+    /// times. `unit` is a random draw in `[0, 1)`; taking it as an argument
+    /// keeps this deterministic for tests.
+    pub fn delay_ms(&self, attempts: i32, unit: f64) -> i64 {
+        let doublings = attempts.saturating_sub(1).clamp(0, 32) as u32;
+        let full = self
+            .base_ms
+            .saturating_mul(1u64 << doublings)
+            .min(self.max_ms);
+        let jitter = self.jitter.clamp(0.0, 1.0) * unit.clamp(0.0, 1.0);
+        (full as f64 * (1.0 - jitter)).round() as i64
+    }
+}
+
+/// `[dispatch]` in bridge.toml.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DispatchConfig {
+    /// Devices dispatched to at the same time. Each device still runs its own
+    /// commands one by one; this caps how many devices are busy at once.
+    #[serde(default = "default_max_concurrency")]
+    pub max_concurrency: usize,
+    /// On SIGTERM, how long running commands get to finish before they are
+    /// cut off and released. Keep it under the unit's `TimeoutStopSec`.
+    #[serde(default = "default_shutdown_grace_secs")]
+    pub shutdown_grace_secs: u64,
+}
+
+impl Default for DispatchConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrency: default_max_concurrency(),
+            shutdown_grace_secs: default_shutdown_grace_secs(),
+        }
+    }
+}
+
+/// `[local_api]` in bridge.toml. The bearer token terminals present is a
+/// secret, so it is never in this file: it comes from `HUMMY_LOCAL_API_TOKEN`
+/// or from `token_file` (see [`resolve_local_api_token`]).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LocalApiConfig {
+    /// Address to bind. Terminals reach the bridge over the LAN, so the
+    /// default listens on all interfaces.
+    #[serde(default = "default_local_api_listen")]
+    pub listen: SocketAddr,
+    /// File holding the terminals' bearer token (first line, trimmed).
+    #[serde(default)]
+    pub token_file: Option<PathBuf>,
+}
+
+/// `[metrics]` in bridge.toml: where `/metrics` is served (see
+/// [`crate::metrics`]). The scrape token, like the LAN API's, comes from
+/// `HUMMY_METRICS_TOKEN` or `token_file` (see [`resolve_metrics_token`]).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MetricsConfig {
+    /// Address to bind. Loopback by default: a node exporter or tunnel on the
+    /// box scrapes it. Any other address needs a token.
+    #[serde(default = "default_metrics_listen")]
+    pub listen: SocketAddr,
+    /// File holding the scrape bearer token (first line, trimmed).
+    #[serde(default)]
+    pub token_file: Option<PathBuf>,
+}
+
+/// Shortest local API token we accept. Terminal tokens are typed into POS
+/// setup screens once, not remembered, so there is no reason to allow weak ones.
+pub const MIN_LOCAL_API_TOKEN_LEN: usize = 16;
+
+/// `[update]` in bridge.toml. Every field is optional; an absent table means
+/// "follow the stable channel".
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct UpdateConfig {
+    /// Set false to freeze this site on its installed build.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Release channel to follow ("stable", "beta", …).
+    #[serde(default = "default_channel")]
+    pub channel: String,
+    /// Exact version to run. When set, the bridge installs that version (even a
+    /// downgrade) and nothing else.
+    #[serde(default)]
+    pub pin: Option<String>,
+    /// Manifest endpoint override. Defaults to
+    /// `{cloud_url}/v1/bridges/updates/manifest`.
+    #[serde(default)]
+    pub manifest_url: Option<String>,
+    /// How often to ask for a new manifest.
+    #[serde(default = "default_check_interval")]
+    pub check_interval_minutes: u64,
+}
+
+impl Default for UpdateConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            channel: default_channel(),
+            pin: None,
+            manifest_url: None,
+            check_interval_minutes: default_check_interval(),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_channel() -> String {
+    "stable".to_string()
+}
+
+fn default_check_interval() -> u64 {
+    360
+}
+
+fn default_max_concurrency() -> usize {
+    4
+}
+
+fn default_shutdown_grace_secs() -> u64 {
+    30
+}
+
+fn default_retry_base_ms() -> u64 {
+    2_000
+}
+
+fn default_retry_max_ms() -> u64 {
+    300_000
+}
+
+fn default_retry_jitter() -> f64 {
+    0.2
+}
+
+fn default_clock_skew_secs() -> u64 {
+    30
+}
+
+fn default_local_api_listen() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 8787))
+}
+
+fn default_metrics_listen() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 9469))
 }
 
 pub fn load(config_dir: Option<&str>) -> Result<BridgeConfig> {
@@ -41,38 +367,96 @@ pub fn load(config_dir: Option<&str>) -> Result<BridgeConfig> {
     Ok(cfg)
 }
 
-/// Token resolution: env var beats keyring. Never logged.
+/// The bearer token this process authenticates with, set at boot from the
+/// credential store (or after a claim) by [`set_bearer_token`].
+static BEARER_TOKEN: RwLock<Option<String>> = RwLock::new(None);
+
+/// Token resolution: the process slot, then `HUMMY_BRIDGE_TOKEN`. Never logged.
+///
+/// The env token still beats the stored one — the boot-time load reads
+/// `HUMMY_BRIDGE_TOKEN` first and puts it in the slot — unless a saved
+/// rotation replaced it. The slot is checked first so a token the cloud
+/// refreshed mid-run replaces a revoked env token.
 pub fn resolve_bearer_token() -> Option<String> {
-    env::var("HUMMY_BRIDGE_TOKEN").ok()
-    // TODO: keyring lookup via the `keyring` crate, gated by a feature so
-    // unit tests don't need a keyring service running.
+    BEARER_TOKEN
+        .read()
+        .expect("bearer token lock poisoned")
+        .clone()
+        .or_else(|| {
+            env::var("HUMMY_BRIDGE_TOKEN")
+                .ok()
+                .filter(|t| !t.is_empty())
+        })
 }
 
-/// Persist a bearer token issued by a successful first-boot claim so the rest
-/// of this process (and, ideally, subsequent boots) authenticate without the
-/// provisioning token.
-///
-/// Today we set the in-process `HUMMY_BRIDGE_TOKEN` env var, which is exactly
-/// the slot [`resolve_bearer_token`] reads first — so every authenticated call
-/// for the remainder of this run (heartbeat, commands/next, ack) immediately
-/// picks it up. The token is written to the same place a headless deploy would
-/// supply it, keeping a single source of truth.
-///
-/// NOTE: this is in-process only and does NOT survive a restart. The durable
-/// store is the OS keyring (the same TODO that gates [`resolve_bearer_token`]):
-/// once the `keyring` crate is wired behind its feature flag, this function
-/// should also write the bearer to the keyring so a re-boot resolves it without
-/// re-claiming. Until then, headless deploys should set `HUMMY_BRIDGE_TOKEN`
-/// from the claim output, or accept that a single-use provisioning token can
-/// only be claimed once (the server invalidates it on first claim).
-pub fn persist_bearer_token(token: &str) -> Result<()> {
-    if token.is_empty() {
-        anyhow::bail!("refusing to persist an empty bearer token");
-    }
-    // SAFETY/threading: called once, on the single-threaded tokio runtime,
-    // during first-boot bootstrap before any other task reads the token.
-    env::set_var("HUMMY_BRIDGE_TOKEN", token);
-    Ok(())
+/// Make `token` the one every authenticated call in this process uses. This
+/// is in-process only — persisting it is [`crate::credentials::Credentials::save`].
+pub fn set_bearer_token(token: &str) {
+    *BEARER_TOKEN.write().expect("bearer token lock poisoned") = Some(token.to_string());
+}
+
+/// Token the LAN API requires from terminals: `HUMMY_LOCAL_API_TOKEN` beats
+/// `token_file`. `Ok(None)` means none is configured — the caller must not
+/// serve the API unauthenticated. A token shorter than
+/// [`MIN_LOCAL_API_TOKEN_LEN`] is an error, never silently accepted.
+pub fn resolve_local_api_token(cfg: &LocalApiConfig) -> Result<Option<String>> {
+    resolve_token(
+        "HUMMY_LOCAL_API_TOKEN",
+        cfg.token_file.as_deref(),
+        "local API",
+    )
+}
+
+/// The `/metrics` bearer token: `HUMMY_METRICS_TOKEN` beats `token_file`.
+/// `Ok(None)` means neither is set; held to the same minimum length as the
+/// LAN API token.
+pub fn resolve_metrics_token(cfg: &MetricsConfig) -> Result<Option<String>> {
+    resolve_token("HUMMY_METRICS_TOKEN", cfg.token_file.as_deref(), "metrics")
+}
+
+fn resolve_token(var: &str, file: Option<&Path>, what: &str) -> Result<Option<String>> {
+    let token = match env::var(var) {
+        Ok(t) => Some(t.trim().to_string()),
+        Err(_) => match file {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("read {what} token {}", path.display()))?
+                    .lines()
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string(),
+            ),
+            None => None,
+        },
+    };
+    match token {
+        Some(t) if t.len() < MIN_LOCAL_API_TOKEN_LEN => anyhow::bail!(
+            "{what} token is {} chars; at least {MIN_LOCAL_API_TOKEN_LEN} required",
+            t.len()
+        ),
+        other => Ok(other),
+    }
+}
+
+/// Password for `[network] proxy_username`: `HUMMY_PROXY_PASSWORD` beats
+/// `proxy_password_file`. `Ok(None)` means neither is set.
+pub fn resolve_proxy_password(cfg: &NetworkConfig) -> Result<Option<String>> {
+    if let Ok(p) = env::var("HUMMY_PROXY_PASSWORD") {
+        return Ok(Some(p));
+    }
+    match &cfg.proxy_password_file {
+        Some(path) => Ok(Some(
+            std::fs::read_to_string(path)
+                .with_context(|| format!("read proxy password {}", path.display()))?
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string(),
+        )),
+        None => Ok(None),
+    }
 }
 
 fn dirs_config_dir() -> Option<PathBuf> {
@@ -139,6 +523,76 @@ mod tests {
         assert_eq!(cfg.data_dir, PathBuf::from("/var/lib/hummy-bridge"));
         // Optional, omitted in the file -> None (one-shot provisioning only).
         assert!(cfg.provisioning_token.is_none());
+        // No [update] table -> follow stable, unpinned.
+        assert_eq!(cfg.update, UpdateConfig::default());
+        assert_eq!(cfg.dispatch.max_concurrency, 4);
+        assert_eq!(cfg.dispatch.shutdown_grace_secs, 30);
+        assert_eq!(cfg.retry, RetryConfig::default());
+        assert_eq!(cfg.credentials.store, CredentialStoreKind::Auto);
+        assert_eq!(cfg.network, NetworkConfig::default());
+        assert_eq!(cfg.command_ttl.for_kind("print_receipt"), None);
+    }
+
+    #[test]
+    fn command_ttl_defaults_per_kind() {
+        let toml_src = r#"
+            cloud_url = "https://api.example.com"
+            bridge_id = "b1"
+            data_dir = "/tmp/x"
+
+            [command_ttl]
+            default_secs = 7200
+
+            [command_ttl.kinds]
+            charge_card = 600
+            fiscal_report = 0
+        "#;
+        let cfg: BridgeConfig = toml::from_str(toml_src).expect("valid toml");
+        let ttl = &cfg.command_ttl;
+        assert_eq!(ttl.for_kind("print_receipt"), Some(7200));
+        assert_eq!(ttl.for_kind("charge_card"), Some(600));
+        assert_eq!(ttl.for_kind("fiscal_report"), None);
+    }
+
+    #[test]
+    fn bridge_config_parses_network_table() {
+        let toml_src = r#"
+            cloud_url = "https://api.example.com"
+            bridge_id = "b1"
+            data_dir = "/tmp/x"
+
+            [network]
+            proxy = "http://proxy.corp.local:3128"
+            proxy_username = "bridge"
+            proxy_password_file = "/etc/hummytummy/proxy.pass"
+            ca_bundles = ["/etc/hummytummy/corp-ca.pem"]
+            spki_pins = ["sha256/rWKNZzypjLjuiKO2EgfTxjXlHM+NQM5gnnzorcHbDHQ="]
+        "#;
+        let cfg: BridgeConfig = toml::from_str(toml_src).expect("valid toml");
+        let net = &cfg.network;
+        assert_eq!(net.proxy.as_deref(), Some("http://proxy.corp.local:3128"));
+        assert_eq!(net.proxy_username.as_deref(), Some("bridge"));
+        assert_eq!(
+            net.ca_bundles,
+            vec![PathBuf::from("/etc/hummytummy/corp-ca.pem")]
+        );
+        assert_eq!(net.spki_pins.len(), 1);
+    }
+
+    #[test]
+    fn bridge_config_parses_credentials_table() {
+        let toml_src = r#"
+            cloud_url = "https://api.example.com"
+            bridge_id = "b1"
+            data_dir = "/tmp/x"
+
+            [credentials]
+            store = "file"
+        "#;
+        let cfg: BridgeConfig = toml::from_str(toml_src).expect("valid toml");
+        assert_eq!(cfg.credentials.store, CredentialStoreKind::File);
+        let bad = toml_src.replace("\"file\"", "\"vault\"");
+        assert!(toml::from_str::<BridgeConfig>(&bad).is_err());
     }
 
     #[test]
@@ -153,6 +607,103 @@ mod tests {
         assert_eq!(cfg.provisioning_token.as_deref(), Some("one-shot-secret"));
     }
 
+    #[test]
+    fn bridge_config_parses_update_table() {
+        let toml_src = r#"
+            cloud_url = "https://api.example.com"
+            bridge_id = "b1"
+            data_dir = "/tmp/x"
+
+            [update]
+            channel = "beta"
+            pin = "0.3.1"
+        "#;
+        let cfg: BridgeConfig = toml::from_str(toml_src).expect("valid toml");
+        assert!(cfg.update.enabled);
+        assert_eq!(cfg.update.channel, "beta");
+        assert_eq!(cfg.update.pin.as_deref(), Some("0.3.1"));
+        assert_eq!(cfg.update.check_interval_minutes, 360);
+    }
+
+    #[test]
+    fn bridge_config_parses_local_api_table() {
+        let toml_src = r#"
+            cloud_url = "https://api.example.com"
+            bridge_id = "b1"
+            data_dir = "/tmp/x"
+
+            [local_api]
+            token_file = "/etc/hummytummy/local_api.token"
+        "#;
+        let cfg: BridgeConfig = toml::from_str(toml_src).expect("valid toml");
+        let api = cfg.local_api.expect("table present");
+        assert_eq!(api.listen, "0.0.0.0:8787".parse().unwrap());
+        assert_eq!(
+            api.token_file,
+            Some(PathBuf::from("/etc/hummytummy/local_api.token"))
+        );
+    }
+
+    #[test]
+    fn bridge_config_parses_metrics_table() {
+        let toml_src = r#"
+            cloud_url = "https://api.example.com"
+            bridge_id = "b1"
+            data_dir = "/tmp/x"
+
+            [metrics]
+        "#;
+        let cfg: BridgeConfig = toml::from_str(toml_src).expect("valid toml");
+        let metrics = cfg.metrics.expect("table present");
+        assert_eq!(metrics.listen, "127.0.0.1:9469".parse().unwrap());
+        assert_eq!(metrics.token_file, None);
+    }
+
+    #[test]
+    fn bridge_config_parses_retry_table_with_kind_overrides() {
+        let toml_src = r#"
+            cloud_url = "https://api.example.com"
+            bridge_id = "b1"
+            data_dir = "/tmp/x"
+
+            [retry]
+            base_ms = 1000
+            jitter = 0.0
+
+            [retry.kinds.print_receipt]
+            base_ms = 5000
+            max_ms = 60000
+        "#;
+        let cfg: BridgeConfig = toml::from_str(toml_src).expect("valid toml");
+        let default = cfg.retry.for_kind("open_drawer");
+        assert_eq!((default.base_ms, default.max_ms), (1000, 300_000));
+        assert_eq!(default.jitter, 0.0);
+        let receipt = cfg.retry.for_kind("print_receipt");
+        assert_eq!((receipt.base_ms, receipt.max_ms), (5000, 60_000));
+        // Override fields left out fall back to the built-in defaults.
+        assert_eq!(receipt.jitter, 0.2);
+    }
+
+    /// Doubling from `base_ms`, capped at `max_ms`, and jitter only ever
+    /// shortens the wait (by at most the configured fraction).
+    #[test]
+    fn backoff_doubles_caps_and_jitters_downward() {
+        let b = Backoff {
+            base_ms: 1000,
+            max_ms: 10_000,
+            jitter: 0.5,
+        };
+        assert_eq!(b.delay_ms(1, 0.0), 1000);
+        assert_eq!(b.delay_ms(2, 0.0), 2000);
+        assert_eq!(b.delay_ms(4, 0.0), 8000);
+        assert_eq!(b.delay_ms(5, 0.0), 10_000);
+        assert_eq!(b.delay_ms(500, 0.0), 10_000);
+        assert_eq!(b.delay_ms(2, 1.0), 1000);
+        assert_eq!(b.delay_ms(2, 0.5), 1500);
+        // attempts is never 0 after a failure, but must not underflow if it is.
+        assert_eq!(b.delay_ms(0, 0.0), 1000);
+    }
+
     #[test]
     fn bridge_config_rejects_missing_required_field() {
         // cloud_url is required (no serde default) — parsing must fail rather
@@ -176,6 +727,57 @@ mod tests {
         assert!(resolve_bearer_token().is_none());
         drop(_tok_absent);
 
+        // --- resolve_local_api_token: env beats file; short tokens refused ---
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = dir.path().join("local_api.token");
+        std::fs::write(&file, "file-token-0123456789\n").unwrap();
+        let api = LocalApiConfig {
+            listen: default_local_api_listen(),
+            token_file: Some(file),
+        };
+        let _lt_absent = EnvGuard::unset("HUMMY_LOCAL_API_TOKEN");
+        assert_eq!(
+            resolve_local_api_token(&api).unwrap().as_deref(),
+            Some("file-token-0123456789")
+        );
+        let no_file = LocalApiConfig {
+            token_file: None,
+            ..api.clone()
+        };
+        assert!(resolve_local_api_token(&no_file).unwrap().is_none());
+        drop(_lt_absent);
+        let _lt = EnvGuard::set("HUMMY_LOCAL_API_TOKEN", "env-token-0123456789");
+        assert_eq!(
+            resolve_local_api_token(&api).unwrap().as_deref(),
+            Some("env-token-0123456789")
+        );
+        drop(_lt);
+        let _lt_short = EnvGuard::set("HUMMY_LOCAL_API_TOKEN", "short");
+        assert!(resolve_local_api_token(&api).is_err());
+        drop(_lt_short);
+
+        // --- resolve_proxy_password: env beats file ---
+        std::fs::write(dir.path().join("proxy.pass"), "file-pass\n").unwrap();
+        let net = NetworkConfig {
+            proxy_password_file: Some(dir.path().join("proxy.pass")),
+            ..Default::default()
+        };
+        let _pp_absent = EnvGuard::unset("HUMMY_PROXY_PASSWORD");
+        assert_eq!(
+            resolve_proxy_password(&net).unwrap().as_deref(),
+            Some("file-pass")
+        );
+        assert!(resolve_proxy_password(&NetworkConfig::default())
+            .unwrap()
+            .is_none());
+        drop(_pp_absent);
+        let _pp = EnvGuard::set("HUMMY_PROXY_PASSWORD", "env-pass");
+        assert_eq!(
+            resolve_proxy_password(&net).unwrap().as_deref(),
+            Some("env-pass")
+        );
+        drop(_pp);
+
         // --- dirs_config_dir: XDG_CONFIG_HOME wins over HOME ---
         let _xdg = EnvGuard::set("XDG_CONFIG_HOME", "/xdg/conf");
         let _home = EnvGuard::set("HOME", "/home/user");
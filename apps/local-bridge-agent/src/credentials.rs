@@ -0,0 +1,618 @@
+//! Durable storage for the bridge's bearer token.
+//!
+//! The provisioning token is single-use server-side, so the bearer a claim
+//! returns is the bridge's only identity: losing it on reboot strands the box
+//! until someone re-provisions it. It is written once at claim time and read
+//! at every boot, through a [`CredentialStore`]:
+//!
+//!   - [`EnvStore`] — `HUMMY_BRIDGE_TOKEN`. Read-only: an operator-supplied
+//!     token wins, and the bridge never writes into its own environment.
+//!   - [`KeyringStore`] — the OS keyring, via the platform CLI (`secret-tool`
+//!     on Linux, `security` on macOS) so no D-Bus / Security.framework binding
+//!     is linked in. Unavailable on a headless box with no Secret Service,
+//!     and on Windows, which has no keyring CLI.
+//!   - [`EncryptedFileStore`] — `bridge_token.enc` in `data_dir`, sealed with
+//!     ChaCha20-Poly1305 under a key derived from the machine id. It keeps the
+//!     token out of backups and copies of the data dir that land on another
+//!     machine; it does not protect against root on this one (nothing stored
+//!     on the box can).
+//!
+//! `[credentials] store` in bridge.toml picks one; the default `auto` reads
+//! env → keyring → file and writes to the keyring, falling back to the file
+//! when the keyring is unavailable.
+//!
+//! A rotation (a 401 answered with a fresh token) is saved to a writable store
+//! while the old token may still sit in `HUMMY_BRIDGE_TOKEN`, first in line and
+//! now revoked. So saving also records the SHA-256 of the token it replaced in
+//! [`SUPERSEDED_FILE`], and loading skips a store still holding that token.
+//! An operator who sets a different token in the environment wins again.
+
+use crate::config::{self, BridgeConfig, CredentialStoreKind};
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// Keyring service / attribute every store uses for the bridge token.
+const SERVICE: &str = "hummytummy-local-bridge";
+/// File name of the encrypted store inside `data_dir`.
+pub const TOKEN_FILE: &str = "bridge_token.enc";
+/// File in `data_dir` holding the SHA-256 of the token a saved rotation
+/// replaced.
+pub const SUPERSEDED_FILE: &str = "bridge_token.superseded";
+/// Where systemd (and dbus before it) keep the machine id.
+const MACHINE_ID_PATHS: &[&str] = &["/etc/machine-id", "/var/lib/dbus/machine-id"];
+
+/// One place a bearer token can live.
+pub trait CredentialStore: Send + Sync {
+    /// Short name for logs ("env", "keyring", "file").
+    fn name(&self) -> &'static str;
+    /// The stored token, `Ok(None)` if this store holds none. `Err` means the
+    /// store itself could not be read (no keyring service, corrupt file).
+    fn load(&self) -> Result<Option<String>>;
+    fn store(&self, token: &str) -> Result<()>;
+}
+
+/// `HUMMY_BRIDGE_TOKEN`.
+pub struct EnvStore;
+
+impl CredentialStore for EnvStore {
+    fn name(&self) -> &'static str {
+        "env"
+    }
+
+    fn load(&self) -> Result<Option<String>> {
+        Ok(std::env::var("HUMMY_BRIDGE_TOKEN")
+            .ok()
+            .filter(|t| !t.is_empty()))
+    }
+
+    fn store(&self, _token: &str) -> Result<()> {
+        bail!("the env store is read-only — set HUMMY_BRIDGE_TOKEN in the service unit")
+    }
+}
+
+/// The OS keyring, driven through its command-line front end.
+pub struct KeyringStore {
+    account: String,
+    /// The CLI to run; overridable so tests can stand in a fake.
+    program: PathBuf,
+}
+
+impl KeyringStore {
+    /// The platform keyring, keyed by bridge id so two bridges on one dev
+    /// machine do not share a token.
+    pub fn new(bridge_id: &str) -> Self {
+        let program = if cfg!(target_os = "macos") {
+            "security"
+        } else {
+            "secret-tool"
+        };
+        Self::with_program(bridge_id, program)
+    }
+
+    pub fn with_program(bridge_id: &str, program: impl Into<PathBuf>) -> Self {
+        Self {
+            account: bridge_id.to_string(),
+            program: program.into(),
+        }
+    }
+
+    fn run(&self, args: &[&str], stdin: Option<&str>) -> Result<std::process::Output> {
+        let mut child = Command::new(&self.program)
+            .args(args)
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("keyring: running {}", self.program.display()))?;
+        if let Some(input) = stdin {
+            child
+                .stdin
+                .take()
+                .context("keyring: stdin")?
+                .write_all(input.as_bytes())?;
+        }
+        Ok(child.wait_with_output()?)
+    }
+}
+
+impl CredentialStore for KeyringStore {
+    fn name(&self) -> &'static str {
+        "keyring"
+    }
+
+    fn load(&self) -> Result<Option<String>> {
+        let out = if cfg!(target_os = "macos") {
+            self.run(
+                &[
+                    "find-generic-password",
+                    "-s",
+                    SERVICE,
+                    "-a",
+                    &self.account,
+                    "-w",
+                ],
+                None,
+            )?
+        } else {
+            self.run(
+                &["lookup", "service", SERVICE, "account", &self.account],
+                None,
+            )?
+        };
+        if !out.status.success() {
+            // Both CLIs exit non-zero for "no such item" AND for "no keyring
+            // service"; only the latter prints anything.
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            if stderr.trim().is_empty() || stderr.contains("could not be found") {
+                return Ok(None);
+            }
+            bail!("keyring: {}", stderr.trim());
+        }
+        let token = String::from_utf8(out.stdout).context("keyring: token is not UTF-8")?;
+        let token = token.trim_end_matches(['\r', '\n']);
+        Ok((!token.is_empty()).then(|| token.to_string()))
+    }
+
+    fn store(&self, token: &str) -> Result<()> {
+        // The token goes over stdin on Linux; `security` only takes it as an
+        // argument, which is visible in the process table for the length of
+        // the call — the accepted trade-off of the macOS CLI.
+        let out = if cfg!(target_os = "macos") {
+            self.run(
+                &[
+                    "add-generic-password",
+                    "-U",
+                    "-s",
+                    SERVICE,
+                    "-a",
+                    &self.account,
+                    "-w",
+                    token,
+                ],
+                None,
+            )?
+        } else {
+            self.run(
+                &[
+                    "store",
+                    "--label",
+                    "HummyTummy bridge token",
+                    "service",
+                    SERVICE,
+                    "account",
+                    &self.account,
+                ],
+                Some(token),
+            )?
+        };
+        if !out.status.success() {
+            bail!(
+                "keyring: storing token failed: {}",
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// `bridge_token.enc`: magic, salt, nonce, then the sealed token.
+pub struct EncryptedFileStore {
+    path: PathBuf,
+    /// Secret the per-file key is derived from (the machine id in production).
+    secret: Vec<u8>,
+}
+
+/// File header; the trailing digit is the format version.
+const MAGIC: &[u8; 5] = b"HTBT1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+impl EncryptedFileStore {
+    /// The store in `data_dir`, keyed to this machine. Fails if the machine has
+    /// no id — sealing under a key kept next to the file would be theatre.
+    pub fn for_data_dir(data_dir: &Path) -> Result<Self> {
+        let secret = MACHINE_ID_PATHS
+            .iter()
+            .find_map(|p| std::fs::read_to_string(p).ok())
+            .map(|id| id.trim().to_string())
+            .filter(|id| !id.is_empty())
+            .ok_or_else(|| {
+                anyhow!(
+                    "no machine id in {} — cannot key the token file; use the keyring or HUMMY_BRIDGE_TOKEN",
+                    MACHINE_ID_PATHS.join(" or ")
+                )
+            })?;
+        Ok(Self::with_secret(
+            data_dir.join(TOKEN_FILE),
+            secret.into_bytes(),
+        ))
+    }
+
+    pub fn with_secret(path: PathBuf, secret: Vec<u8>) -> Self {
+        Self { path, secret }
+    }
+
+    fn cipher(&self, salt: &[u8]) -> Result<ChaCha20Poly1305> {
+        let mut key = [0u8; 32];
+        hkdf::Hkdf::<sha2::Sha256>::new(Some(salt), &self.secret)
+            .expand(b"hummytummy-bridge-token v1", &mut key)
+            .map_err(|_| anyhow!("token file: key derivation failed"))?;
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+}
+
+impl CredentialStore for EncryptedFileStore {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn load(&self) -> Result<Option<String>> {
+        let raw = match std::fs::read(&self.path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", self.path.display())),
+        };
+        let header = MAGIC.len() + SALT_LEN + NONCE_LEN;
+        if raw.len() <= header || &raw[..MAGIC.len()] != MAGIC {
+            bail!("{} is not a bridge token file", self.path.display());
+        }
+        let (salt, rest) = raw[MAGIC.len()..].split_at(SALT_LEN);
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        // Authenticated: a flipped bit, a file from another machine or a
+        // changed machine id all fail here, never yield a garbled token.
+        let plain = self
+            .cipher(salt)?
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .map_err(|_| {
+                anyhow!(
+                    "{} does not decrypt on this machine (copied from another box, or tampered with)",
+                    self.path.display()
+                )
+            })?;
+        Ok(Some(
+            String::from_utf8(plain).context("token file: token is not UTF-8")?,
+        ))
+    }
+
+    fn store(&self, token: &str) -> Result<()> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::getrandom(&mut salt).map_err(|e| anyhow!("token file: {e}"))?;
+        getrandom::getrandom(&mut nonce).map_err(|e| anyhow!("token file: {e}"))?;
+        let sealed = self
+            .cipher(&salt)?
+            .encrypt(Nonce::from_slice(&nonce), token.as_bytes())
+            .map_err(|_| anyhow!("token file: encryption failed"))?;
+        let mut out = Vec::with_capacity(MAGIC.len() + SALT_LEN + NONCE_LEN + sealed.len());
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&salt);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&sealed);
+        write_private(&self.path, &out)
+    }
+}
+
+/// Write `bytes` to `path` atomically (temp file + rename), owner-only.
+fn write_private(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    let mut file = opts
+        .open(&tmp)
+        .with_context(|| format!("creating {}", tmp.display()))?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path).with_context(|| format!("installing {}", path.display()))?;
+    Ok(())
+}
+
+/// The stores a config selects: read in order, write to the first writable.
+pub struct Credentials {
+    stores: Vec<Box<dyn CredentialStore>>,
+    /// Where the hash of a replaced token is kept ([`SUPERSEDED_FILE`]).
+    superseded: Option<PathBuf>,
+}
+
+impl Credentials {
+    pub fn from_config(cfg: &BridgeConfig) -> Self {
+        let keyring = || Box::new(KeyringStore::new(&cfg.bridge_id)) as Box<dyn CredentialStore>;
+        let file = || -> Option<Box<dyn CredentialStore>> {
+            match EncryptedFileStore::for_data_dir(&cfg.data_dir) {
+                Ok(store) => Some(Box::new(store)),
+                Err(e) => {
+                    tracing::warn!(error = %e, "credentials: encrypted file store unavailable");
+                    None
+                }
+            }
+        };
+        let stores = match cfg.credentials.store {
+            CredentialStoreKind::Env => vec![Box::new(EnvStore) as Box<dyn CredentialStore>],
+            CredentialStoreKind::Keyring => vec![Box::new(EnvStore), keyring()],
+            CredentialStoreKind::File => std::iter::once(Box::new(EnvStore) as _)
+                .chain(file())
+                .collect(),
+            CredentialStoreKind::Auto => [Box::new(EnvStore) as _, keyring()]
+                .into_iter()
+                .chain(file())
+                .collect(),
+        };
+        Self {
+            stores,
+            superseded: Some(cfg.data_dir.join(SUPERSEDED_FILE)),
+        }
+    }
+
+    pub fn from_stores(stores: Vec<Box<dyn CredentialStore>>) -> Self {
+        Self {
+            stores,
+            superseded: None,
+        }
+    }
+
+    /// Keep the hash of a token replaced by [`Credentials::save`] at `path`.
+    pub fn with_superseded_file(mut self, path: PathBuf) -> Self {
+        self.superseded = Some(path);
+        self
+    }
+
+    /// The first token any store holds, passing over one a saved rotation
+    /// replaced. A store that cannot be read is logged and skipped — the next
+    /// one may still have the token.
+    pub fn load(&self) -> Option<(String, &'static str)> {
+        let superseded = self
+            .superseded
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok());
+        for store in &self.stores {
+            match store.load() {
+                Ok(Some(token)) if superseded.as_deref() == Some(&*fingerprint(&token)) => {
+                    tracing::info!(
+                        store = store.name(),
+                        "credentials: token was replaced by a saved rotation — skipping it"
+                    )
+                }
+                Ok(Some(token)) => return Some((token, store.name())),
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::debug!(store = store.name(), error = %e, "credentials: store unreadable")
+                }
+            }
+        }
+        None
+    }
+
+    /// Write `token` to the first store that accepts it; the name of that store.
+    /// Every store refusing is an error: the caller must know the token will
+    /// not survive a restart.
+    pub fn save(&self, token: &str) -> Result<&'static str> {
+        if token.is_empty() {
+            bail!("refusing to persist an empty bearer token");
+        }
+        let previous = self.load();
+        let mut failures = Vec::new();
+        for store in &self.stores {
+            match store.store(token) {
+                Ok(()) => {
+                    // The token this replaces lives on in a store read before
+                    // this one (HUMMY_BRIDGE_TOKEN): mark it, or the next boot
+                    // loads it again.
+                    if let (Some(path), Some((old, from))) = (&self.superseded, previous) {
+                        if from != store.name() && old != token {
+                            if let Err(e) = write_private(path, fingerprint(&old).as_bytes()) {
+                                tracing::warn!(
+                                    error = %e,
+                                    store = from,
+                                    "credentials: could not mark the replaced token — the next boot may load it from {from}"
+                                );
+                            }
+                        }
+                    }
+                    return Ok(store.name());
+                }
+                Err(e) => failures.push(format!("{}: {e:#}", store.name())),
+            }
+        }
+        bail!(
+            "no credential store took the token ({})",
+            failures.join("; ")
+        )
+    }
+}
+
+/// SHA-256 of `token`, hex — what [`SUPERSEDED_FILE`] holds.
+fn fingerprint(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Boot-time: load the stored token (if any) into the process slot that
+/// [`config::resolve_bearer_token`] reads. Returns the store it came from.
+pub fn load_into_process(cfg: &BridgeConfig) -> Option<&'static str> {
+    let (token, from) = Credentials::from_config(cfg).load()?;
+    config::set_bearer_token(&token);
+    Some(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn file_store(dir: &TempDir, secret: &str) -> EncryptedFileStore {
+        EncryptedFileStore::with_secret(dir.path().join(TOKEN_FILE), secret.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn encrypted_file_round_trips_and_hides_the_token() {
+        let dir = TempDir::new().unwrap();
+        let store = file_store(&dir, "machine-a");
+        assert_eq!(store.load().unwrap(), None, "nothing stored yet");
+
+        store.store("bearer-0123456789abcdef").unwrap();
+        assert_eq!(
+            store.load().unwrap().as_deref(),
+            Some("bearer-0123456789abcdef")
+        );
+        let raw = std::fs::read(dir.path().join(TOKEN_FILE)).unwrap();
+        assert!(!raw.windows(6).any(|w| w == b"bearer"), "sealed on disk");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir.path().join(TOKEN_FILE))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // Overwrite: a re-claim replaces the token.
+        store.store("bearer-second").unwrap();
+        assert_eq!(store.load().unwrap().as_deref(), Some("bearer-second"));
+    }
+
+    /// A data dir restored onto another box, or a flipped byte, is an error —
+    /// never a wrong token sent to the cloud.
+    #[test]
+    fn token_file_is_bound_to_the_machine_and_tamper_evident() {
+        let dir = TempDir::new().unwrap();
+        file_store(&dir, "machine-a").store("bearer-x").unwrap();
+
+        let err = file_store(&dir, "machine-b").load().unwrap_err();
+        assert!(err.to_string().contains("does not decrypt"), "{err}");
+
+        let path = dir.path().join(TOKEN_FILE);
+        let mut raw = std::fs::read(&path).unwrap();
+        *raw.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, raw).unwrap();
+        assert!(file_store(&dir, "machine-a").load().is_err());
+
+        std::fs::write(&path, b"plaintext-token").unwrap();
+        let err = file_store(&dir, "machine-a").load().unwrap_err();
+        assert!(err.to_string().contains("not a bridge token file"), "{err}");
+    }
+
+    /// A keyring CLI that is not installed reads as "unavailable", and the
+    /// chain falls through to the file for both reading and writing.
+    #[test]
+    fn missing_keyring_falls_back_to_the_file() {
+        let dir = TempDir::new().unwrap();
+        let creds = Credentials::from_stores(vec![
+            Box::new(KeyringStore::with_program(
+                "b1",
+                dir.path().join("no-such-secret-tool"),
+            )),
+            Box::new(file_store(&dir, "machine-a")),
+        ]);
+        assert_eq!(creds.load(), None);
+        assert_eq!(creds.save("bearer-y").unwrap(), "file");
+        assert_eq!(creds.load(), Some(("bearer-y".to_string(), "file")));
+    }
+
+    /// A read-only store holding a fixed token, like `HUMMY_BRIDGE_TOKEN`.
+    struct Env(&'static str);
+
+    impl CredentialStore for Env {
+        fn name(&self) -> &'static str {
+            "env"
+        }
+        fn load(&self) -> Result<Option<String>> {
+            Ok(Some(self.0.to_string()))
+        }
+        fn store(&self, _token: &str) -> Result<()> {
+            bail!("read-only")
+        }
+    }
+
+    /// A rotation saved over an env token survives a reboot: the revoked env
+    /// token is passed over. A new token set in the env still wins.
+    #[test]
+    fn a_saved_rotation_outranks_the_env_token_it_replaced() {
+        let dir = TempDir::new().unwrap();
+        let creds = |env: &'static str| {
+            Credentials::from_stores(vec![
+                Box::new(Env(env)),
+                Box::new(file_store(&dir, "machine-a")),
+            ])
+            .with_superseded_file(dir.path().join(SUPERSEDED_FILE))
+        };
+        let boot = creds("bearer-env");
+        assert_eq!(boot.load(), Some(("bearer-env".to_string(), "env")));
+        assert_eq!(boot.save("bearer-rotated").unwrap(), "file");
+
+        let reboot = creds("bearer-env");
+        assert_eq!(reboot.load(), Some(("bearer-rotated".to_string(), "file")));
+        assert_eq!(reboot.save("bearer-rotated-2").unwrap(), "file");
+        assert_eq!(
+            creds("bearer-env").load(),
+            Some(("bearer-rotated-2".to_string(), "file"))
+        );
+
+        assert_eq!(
+            creds("bearer-reprovisioned").load(),
+            Some(("bearer-reprovisioned".to_string(), "env"))
+        );
+    }
+
+    #[test]
+    fn save_fails_loudly_when_nothing_can_hold_the_token() {
+        let creds = Credentials::from_stores(vec![Box::new(EnvStore)]);
+        let err = creds.save("bearer-z").unwrap_err().to_string();
+        assert!(err.contains("env: the env store is read-only"), "{err}");
+        assert!(creds.save("").is_err());
+    }
+
+    /// `secret-tool` stand-in: a shell script keeping the secret in a file, so
+    /// the store's argument and stdin handling run for real.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn keyring_store_speaks_the_secret_tool_protocol() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new().unwrap();
+        let vault = dir.path().join("vault");
+        let tool = dir.path().join("secret-tool");
+        std::fs::write(
+            &tool,
+            format!(
+                "#!/bin/sh\n\
+                 echo \"$@\" >> {log}\n\
+                 case \"$1\" in\n\
+                   store) cat > {vault} ;;\n\
+                   lookup) [ -f {vault} ] && cat {vault} || exit 1 ;;\n\
+                 esac\n",
+                log = dir.path().join("args").display(),
+                vault = vault.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let store = KeyringStore::with_program("bridge-7", &tool);
+        assert_eq!(store.load().unwrap(), None, "no item yet");
+        store.store("bearer-kr").unwrap();
+        assert_eq!(store.load().unwrap().as_deref(), Some("bearer-kr"));
+
+        let args = std::fs::read_to_string(dir.path().join("args")).unwrap();
+        assert!(args.contains("service hummytummy-local-bridge account bridge-7"));
+        assert!(
+            !args.contains("bearer-kr"),
+            "token never on the command line"
+        );
+    }
+}
@@ -0,0 +1,641 @@
+//! Concurrent per-device dispatch.
+//!
+//! The queue is sharded by device ([`drivers::device_key`]: printer id, fiscal
+//! serial, …). Each shard gets at most one worker, which leases that device's
+//! commands in queue order ([`CommandQueue::pop_next_for_device`]) and settles
+//! each exactly as the old serial loop did: persist the outcome, then ack.
+//! Different devices run in parallel, up to `[dispatch] max_concurrency`
+//! workers, so a kitchen printer sitting in its 10s TCP timeout never holds up
+//! a card charge at the counter.
+//!
+//! Slots go to the most urgent shard first (highest queued priority, then
+//! oldest). A worker exits once its device has nothing queued; the main loop
+//! calls [`Dispatcher::spawn_ready`] on every tick to start new ones.
+
+use crate::{
+    cloud_ws::CloudClient,
+    command_queue::{CommandQueue, PendingCommand},
+    drivers::{self, Registry},
+    metrics::Metrics,
+    workflow::{self, StepFailed},
+};
+use anyhow::Result;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{sync::Notify, task::JoinHandle};
+use tracing::{debug, warn};
+
+pub struct Dispatcher {
+    shared: Arc<Shared>,
+    max_concurrency: usize,
+    /// device key → its worker.
+    workers: Mutex<HashMap<String, JoinHandle<()>>>,
+    /// Counts [`Dispatcher::retry_acks`] attempts.
+    metrics: Arc<Metrics>,
+}
+
+struct Shared {
+    queue: Arc<CommandQueue>,
+    drivers: Arc<Registry>,
+    cloud: CloudClient,
+    /// Set by [`Dispatcher::shutdown`]; workers stop between commands.
+    stopping: AtomicBool,
+    /// Signalled whenever a worker exits, freeing a slot.
+    finished: Notify,
+    /// Ids a worker has executed and is acking right now. The ack-retry drain
+    /// skips them so one outcome is not acked twice concurrently.
+    settling: Mutex<HashSet<String>>,
+}
+
+impl Dispatcher {
+    pub fn new(
+        queue: Arc<CommandQueue>,
+        drivers: Arc<Registry>,
+        cloud: CloudClient,
+        max_concurrency: usize,
+    ) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                queue,
+                drivers,
+                cloud,
+                stopping: AtomicBool::new(false),
+                finished: Notify::new(),
+                settling: Mutex::new(HashSet::new()),
+            }),
+            max_concurrency: max_concurrency.max(1),
+            workers: Mutex::new(HashMap::new()),
+            metrics: Arc::default(),
+        }
+    }
+
+    /// Record ack retries in `metrics` (the agent's shared instance).
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Start a worker for each device that has queued work and no worker yet,
+    /// most urgent first, until the concurrency cap. Returns how many started.
+    pub async fn spawn_ready(&self) -> Result<usize> {
+        if self.shared.stopping.load(Ordering::SeqCst) {
+            return Ok(0);
+        }
+        let devices = self.shared.queue.devices_with_work().await?;
+        let mut workers = self.workers.lock().expect("dispatcher mutex poisoned");
+        workers.retain(|_, h| !h.is_finished());
+        let mut started = 0;
+        for device in devices {
+            if workers.len() >= self.max_concurrency {
+                break;
+            }
+            if workers.contains_key(&device) {
+                continue;
+            }
+            debug!(%device, "starting device worker");
+            let handle = tokio::spawn(run_device(self.shared.clone(), device.clone()));
+            workers.insert(device, handle);
+            started += 1;
+        }
+        Ok(started)
+    }
+
+    /// Workers currently running.
+    pub fn active(&self) -> usize {
+        let mut workers = self.workers.lock().expect("dispatcher mutex poisoned");
+        workers.retain(|_, h| !h.is_finished());
+        workers.len()
+    }
+
+    /// Wait until a worker exits (a slot frees up) or `max` elapses.
+    pub async fn wait_for_worker(&self, max: Duration) {
+        let _ = tokio::time::timeout(max, self.shared.finished.notified()).await;
+    }
+
+    /// deep-review NH3/NH7: an executed-but-unacked command is NOT settled.
+    /// Drain outcomes that were persisted by an earlier dispatch but whose ack
+    /// failed (network blip / 5xx / crash before ack). Without this, the cloud
+    /// still considers the command outstanding and re-issues it — often under a
+    /// NEW command id that the local INSERT-OR-IGNORE dedup misses —
+    /// re-executing the charge/print. Returns false when an ack failed, so the
+    /// caller can back off instead of spinning against an unreachable cloud.
+    ///
+    /// Quarantined commands are reported on the same pass, once every ack is
+    /// through.
+    pub async fn retry_acks(&self) -> Result<bool> {
+        let shared = &self.shared;
+        for (cmd, outcome) in shared.queue.pending_acks(32).await? {
+            if shared
+                .settling
+                .lock()
+                .expect("dispatcher mutex poisoned")
+                .contains(&cmd.id)
+            {
+                continue;
+            }
+            let acked = shared.cloud.ack(&cmd, &outcome).await;
+            self.metrics.ack_retry(acked.is_ok());
+            match acked {
+                Ok(()) => shared.queue.mark_acked(&cmd.id).await?,
+                Err(e) => {
+                    // Leave the row in 'done' so it is retried on the next pass.
+                    warn!(cmd = %cmd.id, error = %e, "ack retry failed — outcome still not confirmed to cloud");
+                    return Ok(false);
+                }
+            }
+        }
+        for q in shared.queue.unreported_quarantine(32).await? {
+            match shared.cloud.report_quarantined(&q).await {
+                Ok(()) => shared.queue.mark_quarantine_reported(q.id).await?,
+                Err(e) => {
+                    warn!(cmd = %q.command_id, error = %e, "quarantine report failed — retried on the next pass");
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Stop handing out commands and give every worker up to `grace` to
+    /// finish the one it is running. A worker still busy at the deadline is
+    /// cut off and its command released under the crash-recovery rules
+    /// ([`CommandQueue::release_inflight`]): a print goes back to the queue, a
+    /// charge that may have happened parks in `needs_review`. Returns how many
+    /// workers were cut off.
+    pub async fn shutdown(&self, grace: Duration) -> Result<usize> {
+        self.shared.stopping.store(true, Ordering::SeqCst);
+        let handles: Vec<_> = self
+            .workers
+            .lock()
+            .expect("dispatcher mutex poisoned")
+            .drain()
+            .collect();
+        let deadline = tokio::time::Instant::now() + grace;
+        let mut cut_off = 0;
+        for (device, mut h) in handles {
+            if tokio::time::timeout_at(deadline, &mut h).await.is_err() {
+                warn!(%device, grace_secs = grace.as_secs(), "device worker still busy at the shutdown deadline — cutting it off");
+                h.abort();
+                let _ = h.await;
+                cut_off += 1;
+            }
+        }
+        if cut_off > 0 {
+            let drivers = &self.shared.drivers;
+            let (parked, requeued) = self
+                .shared
+                .queue
+                .release_inflight(|cmd| drivers.verifies_idempotency(cmd))
+                .await?;
+            warn!(
+                parked_needs_review = parked,
+                requeued, "released commands cut off by shutdown"
+            );
+        }
+        Ok(cut_off)
+    }
+}
+
+/// One device's worker: run its queued commands in order until none are left.
+async fn run_device(shared: Arc<Shared>, device: String) {
+    while !shared.stopping.load(Ordering::SeqCst) {
+        match shared.queue.pop_next_for_device(&device).await {
+            Ok(Some(cmd)) => {
+                if let Err(e) = execute(&shared, &cmd).await {
+                    // The row stays inflight; the lease reaper / boot recovery
+                    // picks it up under the usual kind-aware rules.
+                    warn!(cmd = %cmd.id, %device, error = %e, "settling command failed");
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                warn!(%device, error = %e, "leasing next command failed");
+                break;
+            }
+        }
+    }
+    shared.finished.notify_one();
+}
+
+/// Run one leased command and settle it. A workflow runs its steps through
+/// the same registry ([`workflow::run`]) and settles like any other command.
+async fn execute(shared: &Shared, cmd: &PendingCommand) -> Result<()> {
+    let queue = &shared.queue;
+    let cloud = &shared.cloud;
+    let dispatched = if cmd.kind == workflow::KIND {
+        workflow::run(queue, &shared.drivers, cmd).await
+    } else {
+        shared.drivers.dispatch(cmd).await
+    };
+    match dispatched {
+        Ok(outcome) => {
+            // Persist the outcome first (durable), THEN ack. On ack failure the
+            // row stays 'done' and `retry_acks` retries it — never silently
+            // lose the outcome.
+            shared
+                .settling
+                .lock()
+                .expect("dispatcher mutex poisoned")
+                .insert(cmd.id.clone());
+            let settled = async {
+                queue.mark_done(&cmd.id, &outcome).await?;
+                match cloud.ack(cmd, &outcome).await {
+                    Ok(()) => queue.mark_acked(&cmd.id).await?,
+                    Err(e) => {
+                        warn!(cmd = %cmd.id, error = %e, "ack failed — outcome persisted, will retry");
+                    }
+                }
+                Ok(())
+            }
+            .await;
+            shared
+                .settling
+                .lock()
+                .expect("dispatcher mutex poisoned")
+                .remove(&cmd.id);
+            settled
+        }
+        Err(e) => {
+            warn!(cmd = %cmd.id, device = %drivers::device_key(cmd), error = %e, "command failed");
+            // mark_failed is kind-aware: side-effecting (money/fiscal) kinds
+            // are parked in 'needs_review' here rather than requeued, so the
+            // ack_failed below does not race a retry.
+            queue.mark_failed(&cmd.id, &e.to_string()).await?;
+            // A refused replay may have run the first time: it stays parked
+            // with no ack, and the operator's `review resolve` acks the truth.
+            let refused = e.is::<drivers::ReplayRefused>()
+                || e.downcast_ref::<StepFailed>()
+                    .is_some_and(|failed| failed.replay_refused);
+            if refused {
+                warn!(cmd = %cmd.id, "replay refused — parked for reconciliation, not acked as failed");
+                return Ok(());
+            }
+            // A failed workflow step acks the steps that did run with it.
+            let acked = match e.downcast_ref::<StepFailed>() {
+                Some(failed) => cloud.ack(cmd, &failed.outcome()).await,
+                None => cloud.ack_failed(cmd, &e.to_string()).await,
+            };
+            if let Err(ack_err) = acked {
+                warn!(cmd = %cmd.id, error = %ack_err, "ack_failed not confirmed to cloud");
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloud_ws::{
+        auth::TokenRefreshResponse, BridgeIdentity, ClaimRequest, ClaimResponse, CloudTransport,
+        FetchResponse, HeartbeatResponse, OrderReplayResponse,
+    };
+    use crate::command_queue::{CommandOutcome, QuarantinedCommand};
+    use crate::drivers::LocalDriver;
+    use crate::offline_cache::JournaledOrder;
+    use async_trait::async_trait;
+    use serde_json::json;
+    use tempfile::TempDir;
+    use tokio::sync::Semaphore;
+
+    /// Printer whose "kitchen" device hangs until the test releases a permit;
+    /// every other printer answers at once. Records execution order.
+    struct Printers {
+        kitchen: Arc<Semaphore>,
+        ran: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl LocalDriver for Printers {
+        fn kind(&self) -> &str {
+            "escpos"
+        }
+        async fn execute(&self, cmd: &PendingCommand) -> Result<CommandOutcome> {
+            if cmd.payload["printerId"] == "kitchen" {
+                self.kitchen.acquire().await?.forget();
+            }
+            self.ran.lock().unwrap().push(cmd.id.clone());
+            Ok(CommandOutcome {
+                status: "done".to_string(),
+                result: json!({}),
+                error: None,
+            })
+        }
+    }
+
+    /// Card terminal that always approves.
+    struct Terminal;
+
+    #[async_trait]
+    impl LocalDriver for Terminal {
+        fn kind(&self) -> &str {
+            "gmp3"
+        }
+        async fn execute(&self, _cmd: &PendingCommand) -> Result<CommandOutcome> {
+            Ok(CommandOutcome {
+                status: "done".to_string(),
+                result: json!({ "approved": true }),
+                error: None,
+            })
+        }
+    }
+
+    /// Cloud that accepts every ack, recording the command ids.
+    struct AcceptAll {
+        acked: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl CloudTransport for AcceptAll {
+        async fn get_healthz(&self) -> Result<u16> {
+            Ok(200)
+        }
+        async fn get_next_commands(&self) -> Result<FetchResponse> {
+            Ok(FetchResponse::NoContent)
+        }
+        async fn post_ack(&self, cmd_id: &str, _outcome: &CommandOutcome) -> Result<()> {
+            self.acked.lock().unwrap().push(cmd_id.to_string());
+            Ok(())
+        }
+        async fn post_heartbeat(&self, _identity: &BridgeIdentity) -> Result<HeartbeatResponse> {
+            Ok(HeartbeatResponse::default())
+        }
+        async fn post_claim(&self, _req: &ClaimRequest) -> Result<ClaimResponse> {
+            anyhow::bail!("unused")
+        }
+        async fn post_offline_order(&self, _order: &JournaledOrder) -> Result<OrderReplayResponse> {
+            anyhow::bail!("unused")
+        }
+        async fn post_local_audit(
+            &self,
+            _cmd: &PendingCommand,
+            _outcome: &CommandOutcome,
+        ) -> Result<()> {
+            Ok(())
+        }
+        async fn post_token_refresh(&self) -> Result<TokenRefreshResponse> {
+            anyhow::bail!("unused")
+        }
+        async fn post_quarantine_report(&self, _q: &QuarantinedCommand) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Bench {
+        queue: Arc<CommandQueue>,
+        kitchen: Arc<Semaphore>,
+        ran: Arc<Mutex<Vec<String>>>,
+        acked: Arc<Mutex<Vec<String>>>,
+        dispatcher: Dispatcher,
+        _dir: TempDir,
+    }
+
+    fn bench(max_concurrency: usize) -> Bench {
+        let dir = TempDir::new().unwrap();
+        let queue = Arc::new(CommandQueue::open(dir.path().join("q.db")).unwrap());
+        let kitchen = Arc::new(Semaphore::new(0));
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let acked = Arc::new(Mutex::new(Vec::new()));
+        let drivers = Registry::from_drivers(vec![
+            Box::new(Printers {
+                kitchen: kitchen.clone(),
+                ran: ran.clone(),
+            }),
+            Box::new(Terminal),
+        ]);
+        let dispatcher = Dispatcher::new(
+            queue.clone(),
+            Arc::new(drivers),
+            CloudClient::with_transport(Arc::new(AcceptAll {
+                acked: acked.clone(),
+            })),
+            max_concurrency,
+        );
+        Bench {
+            queue,
+            kitchen,
+            ran,
+            acked,
+            dispatcher,
+            _dir: dir,
+        }
+    }
+
+    fn print(id: &str, printer: &str) -> PendingCommand {
+        PendingCommand {
+            id: id.to_string(),
+            kind: "print_receipt".to_string(),
+            payload: json!({ "target": "escpos", "printerId": printer }),
+            priority: 0,
+            attempts: 0,
+            idempotency_key: None,
+            origin: Default::default(),
+        }
+    }
+
+    fn charge(id: &str) -> PendingCommand {
+        PendingCommand {
+            id: id.to_string(),
+            kind: "charge_card".to_string(),
+            payload: json!({ "protocol": "GMP3", "fiscalSerial": "SER-1" }),
+            priority: 0,
+            attempts: 0,
+            idempotency_key: None,
+            origin: Default::default(),
+        }
+    }
+
+    async fn status(q: &CommandQueue, id: &str) -> String {
+        q.get(id).await.unwrap().unwrap().status
+    }
+
+    #[tokio::test]
+    async fn hung_printer_does_not_hold_up_the_card_terminal() {
+        let b = bench(4);
+        b.queue.push(&print("k-1", "kitchen")).await.unwrap();
+        b.queue.push(&charge("c-1")).await.unwrap();
+
+        assert_eq!(b.dispatcher.spawn_ready().await.unwrap(), 2);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while status(&b.queue, "c-1").await != "acked" {
+                b.dispatcher
+                    .wait_for_worker(Duration::from_millis(50))
+                    .await;
+            }
+        })
+        .await
+        .expect("charge settles while the kitchen printer hangs");
+        assert_eq!(status(&b.queue, "k-1").await, "inflight");
+
+        b.kitchen.add_permits(1);
+        b.dispatcher.shutdown(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(status(&b.queue, "k-1").await, "acked");
+    }
+
+    #[tokio::test]
+    async fn one_device_runs_its_commands_in_queue_order() {
+        let b = bench(4);
+        for id in ["p-1", "p-2", "p-3"] {
+            b.queue.push(&print(id, "bar")).await.unwrap();
+        }
+        assert_eq!(b.dispatcher.spawn_ready().await.unwrap(), 1, "one shard");
+        while b.dispatcher.active() > 0 {
+            b.dispatcher
+                .wait_for_worker(Duration::from_millis(50))
+                .await;
+        }
+        assert_eq!(*b.ran.lock().unwrap(), vec!["p-1", "p-2", "p-3"]);
+    }
+
+    #[tokio::test]
+    async fn concurrency_cap_limits_busy_devices() {
+        let b = bench(1);
+        b.queue.push(&print("k-1", "kitchen")).await.unwrap();
+        b.queue.push(&print("b-1", "bar")).await.unwrap();
+
+        assert_eq!(b.dispatcher.spawn_ready().await.unwrap(), 1);
+        assert_eq!(b.dispatcher.spawn_ready().await.unwrap(), 0, "cap reached");
+        tokio::task::yield_now().await;
+        assert_eq!(status(&b.queue, "b-1").await, "queued");
+
+        b.kitchen.add_permits(1);
+        b.dispatcher.wait_for_worker(Duration::from_secs(5)).await;
+        assert_eq!(b.dispatcher.spawn_ready().await.unwrap(), 1);
+        while b.dispatcher.active() > 0 {
+            b.dispatcher
+                .wait_for_worker(Duration::from_millis(50))
+                .await;
+        }
+        assert_eq!(status(&b.queue, "b-1").await, "acked");
+    }
+
+    #[tokio::test]
+    async fn shutdown_leaves_unstarted_commands_queued() {
+        let b = bench(4);
+        b.queue.push(&print("k-1", "kitchen")).await.unwrap();
+        b.queue.push(&print("k-2", "kitchen")).await.unwrap();
+        b.dispatcher.spawn_ready().await.unwrap();
+        tokio::task::yield_now().await;
+
+        b.kitchen.add_permits(1);
+        b.dispatcher.shutdown(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(
+            status(&b.queue, "k-1").await,
+            "acked",
+            "finished, not cut off"
+        );
+        assert_eq!(status(&b.queue, "k-2").await, "queued");
+        assert_eq!(b.dispatcher.spawn_ready().await.unwrap(), 0);
+    }
+
+    /// A worker that outlives the grace period is cut off: its side-effect-free
+    /// command goes back to the queue and the agent can still exit.
+    #[tokio::test]
+    async fn shutdown_deadline_releases_a_hung_command() {
+        let b = bench(4);
+        b.queue.push(&print("k-1", "kitchen")).await.unwrap();
+        b.dispatcher.spawn_ready().await.unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(status(&b.queue, "k-1").await, "inflight");
+
+        let cut_off = b
+            .dispatcher
+            .shutdown(Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(cut_off, 1);
+        let row = b.queue.get("k-1").await.unwrap().unwrap();
+        assert_eq!(row.status, "queued");
+        assert!(row.error.unwrap().contains("cut off by shutdown"));
+    }
+
+    /// A cut-off charge whose driver cannot verify its key parks at release
+    /// instead of going back to the queue.
+    #[tokio::test]
+    async fn shutdown_parks_a_cut_off_charge_the_driver_cannot_verify() {
+        let b = bench(4);
+        b.queue.push(&charge("c-1")).await.unwrap();
+        b.queue.pop_next().await.unwrap().unwrap();
+        assert_eq!(
+            b.queue
+                .release_inflight(|cmd| b.dispatcher.shared.drivers.verifies_idempotency(cmd))
+                .await
+                .unwrap(),
+            (1, 0)
+        );
+        assert_eq!(status(&b.queue, "c-1").await, "needs_review");
+    }
+
+    /// A charge that did reach the queue again (requeued by a caller that
+    /// thought it replayable) is refused by the registry — and that refusal
+    /// parks it with no `failed` ack, since the first attempt may have charged.
+    #[tokio::test]
+    async fn a_refused_replay_is_parked_not_acked_failed() {
+        let b = bench(4);
+        b.queue.push(&charge("c-1")).await.unwrap();
+        b.queue.pop_next().await.unwrap().unwrap();
+        b.queue.release_inflight(|_| true).await.unwrap();
+
+        assert_eq!(b.dispatcher.spawn_ready().await.unwrap(), 1);
+        while b.dispatcher.active() > 0 {
+            b.dispatcher
+                .wait_for_worker(Duration::from_millis(50))
+                .await;
+        }
+        let row = b.queue.get("c-1").await.unwrap().unwrap();
+        assert_eq!(row.status, "needs_review");
+        assert!(row.error.unwrap().contains("refusing to replay"));
+        assert!(b.acked.lock().unwrap().is_empty(), "no ack_failed sent");
+    }
+
+    #[tokio::test]
+    async fn pop_next_for_device_leases_only_that_shard() {
+        let b = bench(1);
+        b.queue.push(&print("k-1", "kitchen")).await.unwrap();
+        b.queue.push(&charge("c-1")).await.unwrap();
+        assert_eq!(
+            b.queue.devices_with_work().await.unwrap(),
+            vec!["escpos:kitchen", "gmp3:SER-1"]
+        );
+        let leased = b
+            .queue
+            .pop_next_for_device("gmp3:SER-1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(leased.id, "c-1");
+        assert!(b
+            .queue
+            .pop_next_for_device("gmp3:SER-1")
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(status(&b.queue, "k-1").await, "queued");
+    }
+
+    #[tokio::test]
+    async fn quarantined_commands_are_reported_once() {
+        let b = bench(1);
+        b.queue
+            .quarantine(
+                "x-1",
+                "open_drawer",
+                "unsigned",
+                "command is not signed",
+                &json!({}),
+            )
+            .await
+            .unwrap();
+        assert!(b.dispatcher.retry_acks().await.unwrap());
+        assert!(b.queue.unreported_quarantine(10).await.unwrap().is_empty());
+        let held = b.queue.quarantined_since(0).await.unwrap();
+        assert_eq!(held.len(), 1);
+        assert!(held[0].reported);
+    }
+}
@@ -0,0 +1,1037 @@
+//! LAN printer discovery: find raw-9100 printers and propose `printers.toml`
+//! entries for them.
+//!
+//! Two sources, merged by address:
+//!
+//! - **Scan.** Every host of the local subnet (or the `--subnet`s given) is
+//!   tried on the raw-print port. Each listener is asked who it is with the
+//!   ESC/POS `GS I` transmit-printer-ID command (maker, model, serial).
+//! - **mDNS.** A `_pdl-datastream._tcp.local` browse, answered by printers
+//!   that advertise raw printing. It also finds printers outside the scanned
+//!   range, and names the ones that do not answer `GS I`.
+//!
+//! The result renders as ready-to-paste `[[printer]]` entries. Printers that
+//! `printers.toml` already has are marked with their id instead.
+//!
+//! Runs from `hummytummy-local-bridge discover` and as the `discover_printers`
+//! command the cloud can send (see [`execute`]). Nothing here writes a config.
+//!
+//! `GS I` is harmless to an ESC/POS printer, but a listener on 9100 that is
+//! not one — an office laser — may print the three bytes as a stray page.
+//! `--no-identify` (`"identify": false`) only lists listeners.
+//!
+//! The mDNS side is a one-question DNS query sent from an ephemeral port, so
+//! responders answer by unicast (RFC 6762 §6.7) and no multicast membership
+//! or dependency is needed.
+
+use super::{load_printers, Printer, Transport, CONFIG_FILE, DEFAULT_TCP_PORT};
+use crate::{
+    command_queue::{CommandOutcome, PendingCommand},
+    config::BridgeConfig,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    sync::Semaphore,
+    task::JoinSet,
+    time::{timeout, Instant},
+};
+
+/// The command kind the cloud sends to run a discovery on the bridge.
+pub const KIND: &str = "discover_printers";
+
+/// Service type printers advertise raw (port 9100) printing under.
+const MDNS_SERVICE: &str = "_pdl-datastream._tcp.local";
+
+const MDNS_GROUP: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+
+/// How long a host gets to accept the connection. LAN printers answer in a
+/// few ms; a dead address must not hold a /24 scan up.
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(400);
+
+/// How long a listener gets to answer each `GS I` request.
+const IDENTIFY_TIMEOUT: Duration = Duration::from_millis(800);
+
+/// How long mDNS answers are collected.
+const MDNS_WAIT: Duration = Duration::from_millis(1500);
+
+/// Hosts probed at once.
+const SCAN_CONCURRENCY: usize = 64;
+
+/// Narrowest prefix accepted for a scan (1022 hosts). Discovery is for the
+/// shop's LAN, not a campus network.
+const MIN_PREFIX: u8 = 22;
+
+/// An IPv4 range to scan, written `192.168.1.0/24` (a bare address is a /32).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    net: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Subnet {
+    fn new(addr: Ipv4Addr, prefix: u8) -> Result<Self> {
+        if prefix > 32 {
+            bail!("subnet prefix /{prefix} is not an IPv4 prefix");
+        }
+        if prefix < MIN_PREFIX {
+            bail!("subnet {addr}/{prefix} is too large to scan — narrow it to /{MIN_PREFIX} or smaller");
+        }
+        let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+        Ok(Self {
+            net: Ipv4Addr::from(u32::from(addr) & mask),
+            prefix,
+        })
+    }
+
+    /// Every address worth probing: network and broadcast addresses are
+    /// skipped where the range has them.
+    fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let first = u32::from(self.net);
+        let size = 1u64 << (32 - u32::from(self.prefix));
+        let (lo, hi) = if size > 2 { (1, size - 1) } else { (0, size) };
+        (lo..hi).map(move |i| Ipv4Addr::from(first + i as u32))
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((a, p)) => (
+                a,
+                p.parse::<u8>()
+                    .map_err(|_| anyhow!("subnet '{s}': bad prefix length"))?,
+            ),
+            None => (s.trim(), 32),
+        };
+        let addr: Ipv4Addr = addr
+            .parse()
+            .map_err(|_| anyhow!("subnet '{s}': not an IPv4 address"))?;
+        Self::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.net, self.prefix)
+    }
+}
+
+/// What to look for.
+#[derive(Debug, Clone)]
+pub struct DiscoverOptions {
+    /// Ranges to scan. Empty: the subnet of the interface that routes out.
+    pub subnets: Vec<Subnet>,
+    pub port: u16,
+    pub mdns: bool,
+    /// Ask each listener for its ID with `GS I`.
+    pub identify: bool,
+    mdns_group: SocketAddr,
+}
+
+impl Default for DiscoverOptions {
+    fn default() -> Self {
+        Self {
+            subnets: Vec::new(),
+            port: DEFAULT_TCP_PORT,
+            mdns: true,
+            identify: true,
+            mdns_group: MDNS_GROUP,
+        }
+    }
+}
+
+/// `hummytummy-local-bridge discover`.
+#[derive(clap::Args, Debug, PartialEq)]
+pub struct DiscoverArgs {
+    /// Range to scan, e.g. 192.168.1.0/24 (repeatable). Defaults to the
+    /// subnet of the interface that routes out.
+    #[arg(long = "subnet")]
+    subnets: Vec<String>,
+    /// Raw-print port to scan.
+    #[arg(long, default_value_t = DEFAULT_TCP_PORT)]
+    port: u16,
+    /// Skip the mDNS browse.
+    #[arg(long)]
+    no_mdns: bool,
+    /// Do not send `GS I` to listeners; only list them.
+    #[arg(long)]
+    no_identify: bool,
+    /// Print the result as JSON instead of printers.toml entries.
+    #[arg(long)]
+    json: bool,
+}
+
+/// The payload of a `discover_printers` command. Every field is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DiscoverRequest {
+    #[serde(default)]
+    subnets: Vec<String>,
+    port: Option<u16>,
+    mdns: Option<bool>,
+    identify: Option<bool>,
+}
+
+/// One printer found on the LAN.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FoundPrinter {
+    pub host: Ipv4Addr,
+    pub port: u16,
+    /// "scan" and/or "mdns".
+    pub found_by: Vec<&'static str>,
+    /// mDNS instance name, e.g. "EPSON TM-T88V".
+    pub name: Option<String>,
+    pub maker: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    /// The `printers.toml` id already pointing at this address.
+    pub configured_as: Option<String>,
+    /// The id the proposed entry uses; `None` when already configured.
+    pub suggested_id: Option<String>,
+}
+
+impl FoundPrinter {
+    fn new(host: Ipv4Addr, port: u16) -> Self {
+        Self {
+            host,
+            port,
+            found_by: Vec::new(),
+            name: None,
+            maker: None,
+            model: None,
+            serial: None,
+            configured_as: None,
+            suggested_id: None,
+        }
+    }
+
+    /// "EPSON TM-T88V", falling back to the mDNS name.
+    fn label(&self) -> String {
+        match (&self.maker, &self.model, &self.name) {
+            (Some(maker), Some(model), _) => format!("{maker} {model}"),
+            (None, Some(model), _) => model.clone(),
+            (_, None, Some(name)) => name.clone(),
+            (Some(maker), None, None) => maker.clone(),
+            (None, None, None) => "unidentified printer".to_string(),
+        }
+    }
+}
+
+/// The outcome of one discovery.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Discovery {
+    pub subnets: Vec<String>,
+    pub printers: Vec<FoundPrinter>,
+    /// Things that limited the search (mDNS unavailable, config unreadable).
+    pub warnings: Vec<String>,
+}
+
+impl Discovery {
+    /// `printers.toml` entries for every printer not configured yet, and a
+    /// comment naming the ones that are.
+    pub fn to_toml(&self) -> String {
+        let mut out = format!(
+            "# {} printer(s) found on {}{}.\n",
+            self.printers.len(),
+            if self.subnets.is_empty() {
+                "mDNS only".to_string()
+            } else {
+                self.subnets.join(", ")
+            },
+            if self.subnets.is_empty() {
+                ""
+            } else {
+                " and via mDNS"
+            }
+        );
+        for w in &self.warnings {
+            out.push_str(&format!("# warning: {w}\n"));
+        }
+        out.push_str("# Paste the entries you want into printers.toml.\n");
+        for p in &self.printers {
+            out.push_str(&format!(
+                "\n# {} at {}:{} (found by {})\n",
+                p.label(),
+                p.host,
+                p.port,
+                p.found_by.join("+")
+            ));
+            if let Some(id) = &p.configured_as {
+                out.push_str(&format!("# already in printers.toml as id {}\n", quote(id)));
+                continue;
+            }
+            let id = p.suggested_id.as_deref().unwrap_or("printer");
+            out.push_str(&format!(
+                "[[printer]]\nid = {}\ntransport = \"tcp\"\nhost = \"{}\"\nport = {}\n",
+                quote(id),
+                p.host,
+                p.port
+            ));
+        }
+        out
+    }
+}
+
+fn quote(s: &str) -> String {
+    toml::Value::String(s.to_string()).to_string()
+}
+
+/// Run the `discover` subcommand.
+pub async fn run(cfg: &BridgeConfig, args: DiscoverArgs) -> Result<()> {
+    let subnets = args
+        .subnets
+        .iter()
+        .map(|s| s.parse())
+        .collect::<Result<Vec<Subnet>>>()?;
+    let opts = DiscoverOptions {
+        subnets,
+        port: args.port,
+        mdns: !args.no_mdns,
+        identify: !args.no_identify,
+        ..Default::default()
+    };
+    let path = cfg.data_dir.join(CONFIG_FILE);
+    let (configured, mut warnings) = match load_printers(&path) {
+        Ok(p) => (p, Vec::new()),
+        Err(_) if !path.exists() => (Vec::new(), Vec::new()),
+        Err(e) => (
+            Vec::new(),
+            vec![format!(
+                "{e:#} — printers already configured are not marked"
+            )],
+        ),
+    };
+    let mut found = discover(&opts, &configured).await?;
+    warnings.append(&mut found.warnings);
+    found.warnings = warnings;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&found)?);
+    } else {
+        print!("{}", found.to_toml());
+    }
+    Ok(())
+}
+
+/// Run a `discover_printers` command against the driver's current printers.
+pub(super) async fn execute(
+    configured: &[Printer],
+    cmd: &PendingCommand,
+) -> Result<CommandOutcome> {
+    let req: DiscoverRequest = serde_json::from_value(cmd.payload.clone()).with_context(|| {
+        format!(
+            "escpos: command {} has a malformed discovery payload",
+            cmd.id
+        )
+    })?;
+    let defaults = DiscoverOptions::default();
+    let opts = DiscoverOptions {
+        subnets: req
+            .subnets
+            .iter()
+            .map(|s| s.parse())
+            .collect::<Result<_>>()
+            .with_context(|| format!("escpos: command {}", cmd.id))?,
+        port: req.port.unwrap_or(defaults.port),
+        mdns: req.mdns.unwrap_or(defaults.mdns),
+        identify: req.identify.unwrap_or(defaults.identify),
+        ..defaults
+    };
+    let found = discover(&opts, configured).await?;
+    tracing::info!(
+        found = found.printers.len(),
+        subnets = %found.subnets.join(","),
+        "escpos: printer discovery finished"
+    );
+    Ok(CommandOutcome {
+        status: "done".to_string(),
+        result: json!({
+            "subnets": found.subnets,
+            "printers": found.printers,
+            "warnings": found.warnings,
+            "toml": found.to_toml(),
+        }),
+        error: None,
+    })
+}
+
+/// Scan and browse, then mark what `configured` already has.
+pub(super) async fn discover(opts: &DiscoverOptions, configured: &[Printer]) -> Result<Discovery> {
+    let mut warnings = Vec::new();
+    let subnets = if opts.subnets.is_empty() {
+        vec![local_subnet()?]
+    } else {
+        opts.subnets.clone()
+    };
+    let (scanned, browsed) = tokio::join!(scan(&subnets, opts.port), async {
+        if opts.mdns {
+            browse(opts.mdns_group).await
+        } else {
+            Ok(Vec::new())
+        }
+    });
+
+    let mut by_addr: BTreeMap<(Ipv4Addr, u16), FoundPrinter> = BTreeMap::new();
+    for (host, port) in scanned {
+        by_addr
+            .entry((host, port))
+            .or_insert_with(|| FoundPrinter::new(host, port))
+            .found_by
+            .push("scan");
+    }
+    let mut hostnames: HashMap<(Ipv4Addr, u16), String> = HashMap::new();
+    match browsed {
+        Ok(ads) => {
+            for ad in ads {
+                let p = by_addr
+                    .entry((ad.host, ad.port))
+                    .or_insert_with(|| FoundPrinter::new(ad.host, ad.port));
+                p.found_by.push("mdns");
+                p.name = Some(ad.instance);
+                p.model = ad.model;
+                hostnames.insert((ad.host, ad.port), ad.hostname);
+            }
+        }
+        Err(e) => warnings.push(format!("mDNS browse failed: {e:#}")),
+    }
+
+    let mut printers: Vec<FoundPrinter> = by_addr.into_values().collect();
+    if opts.identify {
+        let mut ids = JoinSet::new();
+        for (i, p) in printers.iter().enumerate() {
+            let addr = SocketAddr::from((p.host, p.port));
+            ids.spawn(async move { (i, identify(addr).await) });
+        }
+        while let Some(joined) = ids.join_next().await {
+            let (i, id) = joined.context("printer identification task failed")?;
+            let p = &mut printers[i];
+            p.maker = id.maker.or(p.maker.take());
+            p.model = id.model.or(p.model.take());
+            p.serial = id.serial;
+        }
+    }
+
+    let mut taken: Vec<String> = configured.iter().map(|c| c.id.clone()).collect();
+    for p in &mut printers {
+        let hostname = hostnames.get(&(p.host, p.port));
+        p.configured_as = configured
+            .iter()
+            .find(|c| match &c.transport {
+                Transport::Tcp { host, port } => {
+                    *port == p.port
+                        && (host.parse::<Ipv4Addr>().ok() == Some(p.host)
+                            || hostname.is_some_and(|h| {
+                                h.eq_ignore_ascii_case(host.trim_end_matches('.'))
+                            }))
+                }
+                Transport::Device { .. } => false,
+            })
+            .map(|c| c.id.clone());
+        if p.configured_as.is_none() {
+            let id = unique_id(&suggest_id(p), &taken);
+            taken.push(id.clone());
+            p.suggested_id = Some(id);
+        }
+    }
+
+    Ok(Discovery {
+        subnets: subnets.iter().map(Subnet::to_string).collect(),
+        printers,
+        warnings,
+    })
+}
+
+/// "tm-t88v-50": the model (or "printer") and the host's last octet.
+fn suggest_id(p: &FoundPrinter) -> String {
+    let base: String = p
+        .model
+        .as_deref()
+        .unwrap_or("printer")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let base = base
+        .split('-')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    let base = if base.is_empty() { "printer" } else { &base };
+    format!("{base}-{}", p.host.octets()[3])
+}
+
+fn unique_id(id: &str, taken: &[String]) -> String {
+    let mut candidate = id.to_string();
+    let mut n = 2;
+    while taken.contains(&candidate) {
+        candidate = format!("{id}-{n}");
+        n += 1;
+    }
+    candidate
+}
+
+/// The subnet of the interface the default route leaves through, as a /24
+/// (a wider LAN has to be named with `--subnet`). Connecting a UDP socket
+/// sends nothing; it only picks the source address.
+fn local_subnet() -> Result<Subnet> {
+    let probe = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    probe
+        .connect((Ipv4Addr::new(192, 0, 2, 1), 9))
+        .context("no IPv4 route out of this box — pass --subnet")?;
+    match probe.local_addr()?.ip() {
+        IpAddr::V4(ip) if !ip.is_loopback() && !ip.is_unspecified() => Subnet::new(ip, 24),
+        ip => bail!("cannot tell the local subnet (source address {ip}) — pass --subnet"),
+    }
+}
+
+/// Every host in `subnets` that accepts a connection on `port`.
+async fn scan(subnets: &[Subnet], port: u16) -> Vec<(Ipv4Addr, u16)> {
+    let permits = Arc::new(Semaphore::new(SCAN_CONCURRENCY));
+    let mut probes = JoinSet::new();
+    for host in subnets.iter().flat_map(Subnet::hosts) {
+        let permits = permits.clone();
+        probes.spawn(async move {
+            let _permit = permits.acquire_owned().await.ok()?;
+            let conn = timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port))).await;
+            matches!(conn, Ok(Ok(_))).then_some((host, port))
+        });
+    }
+    let mut found = Vec::new();
+    while let Some(joined) = probes.join_next().await {
+        if let Ok(Some(hit)) = joined {
+            found.push(hit);
+        }
+    }
+    found
+}
+
+/// What a printer said about itself.
+#[derive(Debug, Default, PartialEq)]
+struct PrinterId {
+    maker: Option<String>,
+    model: Option<String>,
+    serial: Option<String>,
+}
+
+/// `GS I` function numbers for the printer information blocks.
+const GS_I_MAKER: u8 = 0x42;
+const GS_I_MODEL: u8 = 0x43;
+const GS_I_SERIAL: u8 = 0x44;
+
+/// Ask a listener for its maker, model and serial. A listener that does not
+/// answer the first request is not asked the others; every failure just
+/// leaves the fields empty.
+async fn identify(addr: SocketAddr) -> PrinterId {
+    let mut id = PrinterId::default();
+    let Ok(Ok(mut stream)) = timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await else {
+        return id;
+    };
+    for (n, field) in [
+        (GS_I_MAKER, &mut id.maker),
+        (GS_I_MODEL, &mut id.model),
+        (GS_I_SERIAL, &mut id.serial),
+    ] {
+        match request_id(&mut stream, n).await {
+            Some(value) => *field = Some(value),
+            None if n == GS_I_MAKER => break,
+            None => {}
+        }
+    }
+    id
+}
+
+/// One `GS I n` exchange. The answer is a printer information block:
+/// `0x5F`, the text, `NUL`. Bytes before the header (automatic status
+/// back, say) are skipped.
+async fn request_id(stream: &mut TcpStream, n: u8) -> Option<String> {
+    stream.write_all(&[0x1D, b'I', n]).await.ok()?;
+    let deadline = Instant::now() + IDENTIFY_TIMEOUT;
+    let mut text: Option<Vec<u8>> = None;
+    let mut buf = [0u8; 64];
+    loop {
+        let read = tokio::time::timeout_at(deadline, stream.read(&mut buf)).await;
+        let len = match read {
+            Ok(Ok(len)) if len > 0 => len,
+            _ => return None,
+        };
+        for &b in &buf[..len] {
+            match (&mut text, b) {
+                (None, 0x5F) => text = Some(Vec::new()),
+                (None, _) => {}
+                (Some(t), 0x00) => {
+                    let s = String::from_utf8_lossy(t).trim().to_string();
+                    return (!s.is_empty()).then_some(s);
+                }
+                (Some(t), _) if t.len() < 80 => t.push(b),
+                (Some(_), _) => return None,
+            }
+        }
+    }
+}
+
+/// A printer advertising raw printing over mDNS.
+#[derive(Debug, PartialEq)]
+struct Advert {
+    instance: String,
+    hostname: String,
+    host: Ipv4Addr,
+    port: u16,
+    /// TXT `ty` (or `usb_MDL`).
+    model: Option<String>,
+}
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+
+/// Browse for [`MDNS_SERVICE`] and collect every answer within [`MDNS_WAIT`].
+async fn browse(group: SocketAddr) -> Result<Vec<Advert>> {
+    let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    sock.set_multicast_ttl_v4(255)?;
+    sock.send_to(&mdns_query(), group)
+        .await
+        .with_context(|| format!("sending the query to {group}"))?;
+
+    let mut records = Records::default();
+    let deadline = Instant::now() + MDNS_WAIT;
+    let mut buf = vec![0u8; 9000];
+    while let Ok(Ok((len, _))) = tokio::time::timeout_at(deadline, sock.recv_from(&mut buf)).await {
+        // A malformed answer from one responder does not spoil the others.
+        if let Err(e) = records.parse(&buf[..len]) {
+            tracing::debug!(error = %e, "escpos: ignoring a malformed mDNS answer");
+        }
+    }
+    Ok(records.adverts())
+}
+
+/// A PTR question for [`MDNS_SERVICE`].
+fn mdns_query() -> Vec<u8> {
+    let mut q = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    encode_name(&mut q, MDNS_SERVICE);
+    q.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    q.extend_from_slice(&1u16.to_be_bytes()); // IN
+    q
+}
+
+fn encode_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+/// The records of every answer, keyed by owner name (lower-cased).
+#[derive(Default)]
+struct Records {
+    ptr: Vec<String>,
+    srv: HashMap<String, (String, u16)>,
+    a: HashMap<String, Ipv4Addr>,
+    txt: HashMap<String, HashMap<String, String>>,
+}
+
+impl Records {
+    fn parse(&mut self, msg: &[u8]) -> Result<()> {
+        let count = |at: usize| -> Result<usize> {
+            Ok(usize::from(u16::from_be_bytes(
+                msg.get(at..at + 2)
+                    .ok_or_else(|| anyhow!("short header"))?
+                    .try_into()?,
+            )))
+        };
+        let questions = count(4)?;
+        let records = count(6)? + count(8)? + count(10)?;
+        let mut at = 12;
+        for _ in 0..questions {
+            at = read_name(msg, at)?.1 + 4;
+        }
+        for _ in 0..records {
+            let (owner, next) = read_name(msg, at)?;
+            let fixed = msg
+                .get(next..next + 10)
+                .ok_or_else(|| anyhow!("short record"))?;
+            let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+            let rdlen = usize::from(u16::from_be_bytes([fixed[8], fixed[9]]));
+            let rdata_at = next + 10;
+            let rdata = msg
+                .get(rdata_at..rdata_at + rdlen)
+                .ok_or_else(|| anyhow!("short rdata"))?;
+            let owner = owner.to_ascii_lowercase();
+            match rtype {
+                TYPE_PTR if owner == MDNS_SERVICE.to_ascii_lowercase() => {
+                    let (instance, _) = read_name(msg, rdata_at)?;
+                    self.ptr.push(instance);
+                }
+                TYPE_SRV if rdata.len() > 6 => {
+                    let port = u16::from_be_bytes([rdata[4], rdata[5]]);
+                    let (target, _) = read_name(msg, rdata_at + 6)?;
+                    self.srv.insert(owner, (target, port));
+                }
+                TYPE_A if rdata.len() == 4 => {
+                    let ip = Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]);
+                    self.a.insert(owner, ip);
+                }
+                TYPE_TXT => {
+                    let entry = self.txt.entry(owner).or_default();
+                    let mut i = 0;
+                    while let Some(&len) = rdata.get(i) {
+                        let s = rdata.get(i + 1..i + 1 + usize::from(len)).unwrap_or(&[]);
+                        let s = String::from_utf8_lossy(s);
+                        if let Some((k, v)) = s.split_once('=') {
+                            entry.insert(k.to_ascii_lowercase(), v.to_string());
+                        }
+                        i += 1 + usize::from(len);
+                    }
+                }
+                _ => {}
+            }
+            at = rdata_at + rdlen;
+        }
+        Ok(())
+    }
+
+    /// Instances with an address to print to. One without an A record in
+    /// the answers is left out rather than resolved separately.
+    fn adverts(&self) -> Vec<Advert> {
+        let mut out = Vec::new();
+        for instance in &self.ptr {
+            let key = instance.to_ascii_lowercase();
+            let Some((hostname, port)) = self.srv.get(&key) else {
+                continue;
+            };
+            let Some(host) = self.a.get(&hostname.to_ascii_lowercase()) else {
+                continue;
+            };
+            let txt = self.txt.get(&key);
+            let txt_model = |k: &str| {
+                txt.and_then(|t| t.get(k))
+                    .filter(|v| !v.is_empty())
+                    .cloned()
+            };
+            let name = instance
+                .strip_suffix(MDNS_SERVICE)
+                .map(|s| s.trim_end_matches('.'))
+                .unwrap_or(instance);
+            let advert = Advert {
+                instance: name.to_string(),
+                hostname: hostname.trim_end_matches('.').to_string(),
+                host: *host,
+                port: *port,
+                model: txt_model("ty").or_else(|| txt_model("usb_mdl")),
+            };
+            if !out.contains(&advert) {
+                out.push(advert);
+            }
+        }
+        out
+    }
+}
+
+/// A (possibly compressed) DNS name at `at`, and the offset just past it.
+fn read_name(msg: &[u8], mut at: usize) -> Result<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    for _ in 0..128 {
+        let len = *msg
+            .get(at)
+            .ok_or_else(|| anyhow!("name runs off the end"))?;
+        match len {
+            0 => {
+                return Ok((labels.join("."), end.unwrap_or(at + 1)));
+            }
+            l if l & 0xC0 == 0xC0 => {
+                let lo = *msg.get(at + 1).ok_or_else(|| anyhow!("cut-off pointer"))?;
+                end.get_or_insert(at + 2);
+                at = usize::from(u16::from_be_bytes([l & 0x3F, lo]));
+            }
+            l => {
+                let label = msg
+                    .get(at + 1..at + 1 + usize::from(l))
+                    .ok_or_else(|| anyhow!("label runs off the end"))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                at += 1 + usize::from(l);
+            }
+        }
+    }
+    bail!("name has too many labels or a pointer loop")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// A loopback ESC/POS printer that answers `GS I` like an Epson (status
+    /// bytes first, as with ASB on), or says nothing when `silent`.
+    fn fake_printer(silent: bool) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                let Ok(mut conn) = conn else { return };
+                std::thread::spawn(move || {
+                    let mut req = [0u8; 3];
+                    while conn.read_exact(&mut req).is_ok() {
+                        if silent {
+                            continue;
+                        }
+                        let reply: &[u8] = match req {
+                            [0x1D, b'I', GS_I_MAKER] => b"\x14\x00\x00\x0f_EPSON\x00",
+                            [0x1D, b'I', GS_I_MODEL] => b"_TM-T88V\x00",
+                            [0x1D, b'I', GS_I_SERIAL] => b"_K9XA012345\x00",
+                            _ => return,
+                        };
+                        conn.write_all(reply).unwrap();
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    fn tcp_printer(id: &str, host: &str, port: u16) -> Printer {
+        Printer {
+            id: id.to_string(),
+            transport: Transport::Tcp {
+                host: host.to_string(),
+                port,
+            },
+            poll_status: true,
+        }
+    }
+
+    fn loopback(port: u16) -> DiscoverOptions {
+        DiscoverOptions {
+            subnets: vec!["127.0.0.1".parse().unwrap()],
+            port,
+            mdns: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn subnets_parse_and_list_their_hosts() {
+        let s: Subnet = "192.168.1.77/24".parse().unwrap();
+        assert_eq!(s.to_string(), "192.168.1.0/24");
+        let hosts: Vec<_> = s.hosts().collect();
+        assert_eq!(hosts.len(), 254);
+        assert_eq!(hosts[0], Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(hosts[253], Ipv4Addr::new(192, 168, 1, 254));
+        let one: Subnet = "10.0.0.5".parse().unwrap();
+        assert_eq!(
+            one.hosts().collect::<Vec<_>>(),
+            vec![Ipv4Addr::new(10, 0, 0, 5)]
+        );
+        assert!("10.0.0.0/16"
+            .parse::<Subnet>()
+            .unwrap_err()
+            .to_string()
+            .contains("too large"));
+        assert!("printer.local/24".parse::<Subnet>().is_err());
+    }
+
+    #[tokio::test]
+    async fn scan_identifies_a_loopback_printer_and_proposes_an_entry() {
+        let port = fake_printer(false);
+        let found = discover(&loopback(port), &[]).await.unwrap();
+        assert_eq!(found.printers.len(), 1, "{found:?}");
+        let p = &found.printers[0];
+        assert_eq!(p.host, Ipv4Addr::LOCALHOST);
+        assert_eq!(p.found_by, vec!["scan"]);
+        assert_eq!(p.maker.as_deref(), Some("EPSON"));
+        assert_eq!(p.model.as_deref(), Some("TM-T88V"));
+        assert_eq!(p.serial.as_deref(), Some("K9XA012345"));
+        assert_eq!(p.suggested_id.as_deref(), Some("tm-t88v-1"));
+
+        // The proposal is a printers.toml the driver accepts as-is.
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join(CONFIG_FILE);
+        std::fs::write(&path, found.to_toml()).unwrap();
+        let loaded = load_printers(&path).unwrap();
+        assert_eq!(loaded[0].id, "tm-t88v-1");
+        assert_eq!(
+            loaded[0].transport,
+            Transport::Tcp {
+                host: "127.0.0.1".to_string(),
+                port
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn configured_printers_are_marked_not_proposed() {
+        let port = fake_printer(false);
+        let configured = [tcp_printer("front", "127.0.0.1", port)];
+        let found = discover(&loopback(port), &configured).await.unwrap();
+        let p = &found.printers[0];
+        assert_eq!(p.configured_as.as_deref(), Some("front"));
+        assert_eq!(p.suggested_id, None);
+        let toml = found.to_toml();
+        assert!(
+            toml.contains("already in printers.toml as id \"front\""),
+            "{toml}"
+        );
+        assert!(!toml.contains("[[printer]]"), "{toml}");
+    }
+
+    /// A listener that ignores `GS I` is still listed, just unidentified,
+    /// and its proposed id does not clash with a configured one.
+    #[tokio::test]
+    async fn silent_listeners_are_listed_unidentified() {
+        let port = fake_printer(true);
+        let configured = [tcp_printer("printer-1", "10.9.9.9", 9100)];
+        let found = discover(&loopback(port), &configured).await.unwrap();
+        let p = &found.printers[0];
+        assert_eq!((p.maker.as_ref(), p.model.as_ref()), (None, None));
+        assert_eq!(p.suggested_id.as_deref(), Some("printer-1-2"));
+        assert!(found
+            .to_toml()
+            .contains("# unidentified printer at 127.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn nothing_listening_finds_nothing() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let found = discover(&loopback(port), &[]).await.unwrap();
+        assert!(found.printers.is_empty());
+    }
+
+    fn rr(out: &mut Vec<u8>, owner: &str, rtype: u16, rdata: &[u8]) {
+        encode_name(out, owner);
+        out.extend_from_slice(&rtype.to_be_bytes());
+        out.extend_from_slice(&0x8001u16.to_be_bytes()); // IN, cache-flush
+        out.extend_from_slice(&120u32.to_be_bytes());
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(rdata);
+    }
+
+    /// What a Star printer on the LAN answers the browse with: PTR in the
+    /// answers, SRV/TXT/A in the additionals, names uncompressed.
+    fn mdns_answer(port: u16) -> Vec<u8> {
+        let instance = format!("Star TSP143IIIW.{MDNS_SERVICE}");
+        let mut msg = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 3];
+        let mut ptr = Vec::new();
+        encode_name(&mut ptr, &instance);
+        rr(&mut msg, MDNS_SERVICE, TYPE_PTR, &ptr);
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend_from_slice(&port.to_be_bytes());
+        encode_name(&mut srv, "star-a1b2.local");
+        rr(&mut msg, &instance, TYPE_SRV, &srv);
+        let ty = b"ty=Star TSP143IIIW";
+        let mut txt = vec![ty.len() as u8];
+        txt.extend_from_slice(ty);
+        rr(&mut msg, &instance, TYPE_TXT, &txt);
+        rr(&mut msg, "star-a1b2.local", TYPE_A, &[127, 0, 0, 1]);
+        msg
+    }
+
+    #[tokio::test]
+    async fn mdns_adverts_merge_with_the_scan() {
+        let port = fake_printer(true);
+        let responder = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let group = responder.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (len, from) = responder.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], mdns_query().as_slice());
+            responder.send_to(b"\x00garbage", from).unwrap();
+            responder.send_to(&mdns_answer(port), from).unwrap();
+        });
+
+        let opts = DiscoverOptions {
+            mdns: true,
+            mdns_group: group,
+            ..loopback(port)
+        };
+        let configured = [tcp_printer("bar", "star-a1b2.local", port)];
+        let found = discover(&opts, &configured).await.unwrap();
+        assert_eq!(found.printers.len(), 1, "{found:?}");
+        let p = &found.printers[0];
+        assert_eq!(p.found_by, vec!["scan", "mdns"]);
+        assert_eq!(p.name.as_deref(), Some("Star TSP143IIIW"));
+        assert_eq!(
+            p.model.as_deref(),
+            Some("Star TSP143IIIW"),
+            "TXT fills in for GS I"
+        );
+        assert_eq!(
+            p.configured_as.as_deref(),
+            Some("bar"),
+            "matched by mDNS hostname"
+        );
+        assert!(found.warnings.is_empty());
+    }
+
+    #[test]
+    fn compressed_names_are_followed() {
+        // "printer.local" at 12, then a pointer back to it.
+        let mut msg = vec![0u8; 12];
+        encode_name(&mut msg, "printer.local");
+        let ptr_at = msg.len();
+        msg.extend_from_slice(&[0xC0, 12]);
+        assert_eq!(
+            read_name(&msg, ptr_at).unwrap(),
+            ("printer.local".to_string(), ptr_at + 2)
+        );
+        // A pointer to itself is refused, not followed forever.
+        let looped = [0xC0, 0];
+        assert!(read_name(&looped, 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn discover_command_reports_entries_and_rejects_bad_subnets() {
+        let port = fake_printer(false);
+        let cmd = |payload| PendingCommand {
+            id: "d-1".to_string(),
+            kind: KIND.to_string(),
+            payload,
+            priority: 0,
+            attempts: 0,
+            idempotency_key: None,
+            origin: Default::default(),
+        };
+        let out = execute(
+            &[],
+            &cmd(json!({ "target": "escpos", "subnets": ["127.0.0.1/32"], "port": port, "mdns": false })),
+        )
+        .await
+        .unwrap();
+        assert_eq!(out.status, "done");
+        assert_eq!(out.result["printers"][0]["model"], "TM-T88V");
+        assert!(out.result["toml"]
+            .as_str()
+            .unwrap()
+            .contains("id = \"tm-t88v-1\""));
+
+        let err = execute(&[], &cmd(json!({ "subnets": ["10.0.0.0/8"] })))
+            .await
+            .expect_err("a /8 is not a shop LAN");
+        assert!(format!("{err:#}").contains("too large"), "{err:#}");
+    }
+}
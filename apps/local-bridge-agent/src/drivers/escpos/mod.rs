@@ -48,30 +48,53 @@
 //! id = "kitchen-01"
 //! transport = "device"   # alias: "serial"
 //! path = "/dev/usb/lp0"
+//! poll_status = false    # optional: this printer has no back-channel
 //! ```
 //!
+//! ## Printer status
+//!
+//! Before and after each job the driver asks the printer for its real-time
+//! status (`DLE EOT 1..4`, see [`status`]) on the same connection. A printer
+//! that reports its cover open, paper out or a cutter error before the job is
+//! not sent the job at all; one that reports it afterwards fails the job,
+//! because the ticket probably did not come out whole. Both readings go into
+//! the outcome `result`, and the latest one per printer is shipped with the
+//! heartbeat (see [`LocalDriver::device_status`]).
+//!
 //! ## Honest failure (no fake success)
 //!
-//! If the transport is not configured, the printer is unreachable, or the
-//! write fails, `execute` returns `Err` — which the agent's main loop turns
-//! into a `failed` ack to the cloud. The driver NEVER reports `done` unless
-//! the bytes were actually handed to the OS and flushed to the printer.
+//! If the transport is not configured, the printer is unreachable, the write
+//! fails or the printer reports a fault, `execute` returns `Err` — which the
+//! agent's main loop turns into a `failed` ack to the cloud. The driver NEVER
+//! reports `done` unless the bytes were actually handed to the OS and flushed
+//! to the printer. A printer that does not answer status requests still
+//! prints; its status is reported as unknown (`null`), never as healthy.
+
+pub mod discover;
+pub mod status;
 
 use crate::{
-    command_queue::{CommandOutcome, PendingCommand},
-    drivers::LocalDriver,
+    command_queue::{chrono_unix_now, CommandOutcome, PendingCommand},
+    drivers::{probe_tcp, DeviceReadiness, DeviceStatus, LocalDriver},
 };
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use serde::Deserialize;
 use serde_json::json;
+use status::PrinterStatus;
 use std::{
+    collections::HashMap,
+    fs::File,
     io::Write,
     net::TcpStream,
     path::{Path, PathBuf},
+    sync::Mutex,
     time::Duration,
 };
 
+/// The printer config file in the bridge data dir.
+pub const CONFIG_FILE: &str = "printers.toml";
+
 /// Default raw-print TCP port (HP JetDirect / "RAW 9100" — the de-facto
 /// standard every networked thermal printer listens on).
 const DEFAULT_TCP_PORT: u16 = 9100;
@@ -104,6 +127,14 @@ struct PrinterEntry {
     port: Option<u16>,
     // device / serial
     path: Option<String>,
+    /// Ask for `DLE EOT` status around each job. On by default; turn it off
+    /// for a printer whose interface cannot answer.
+    #[serde(default = "default_poll_status")]
+    poll_status: bool,
+}
+
+fn default_poll_status() -> bool {
+    true
 }
 
 #[derive(Debug, Clone, Deserialize, Default)]
@@ -117,6 +148,7 @@ struct PrintersConfig {
 struct Printer {
     id: String,
     transport: Transport,
+    poll_status: bool,
 }
 
 impl PrinterEntry {
@@ -150,19 +182,41 @@ impl PrinterEntry {
         Ok(Printer {
             id: self.id,
             transport,
+            poll_status: self.poll_status,
         })
     }
 }
 
 /// The ESC/POS driver. Holds the locally-configured printer transports
-/// (loaded once at boot from `printers.toml`); if the file is absent the
-/// driver still registers (so the agent boots) but every print honestly fails
-/// with a "no printer configured" error rather than faking success.
+/// (loaded from `printers.toml` at boot, and again on every hot reload — see
+/// [`crate::drivers::reload`]); if the file is absent the driver still
+/// registers (so the agent boots) but every print honestly fails with a
+/// "no printer configured" error rather than faking success.
 pub struct EscPosDriver {
     printers: Vec<Printer>,
     /// Where `printers.toml` was looked for — surfaced in error messages so an
     /// operator knows exactly which file to create/fix.
     config_path: PathBuf,
+    /// Latest status reading per printer id, for the heartbeat.
+    last_status: Mutex<HashMap<String, LastStatus>>,
+}
+
+/// The most recent status reading of one printer. `status: None` = it was
+/// asked and did not answer.
+#[derive(Debug, Clone, Copy)]
+struct LastStatus {
+    status: Option<PrinterStatus>,
+    checked_at: i64,
+}
+
+/// What happened on the wire for one job.
+#[derive(Debug, Clone, Copy)]
+struct JobReport {
+    /// Bytes written; 0 when the pre-job status stopped the job.
+    written: usize,
+    /// `None` = not asked (`poll_status = false`) or no answer.
+    before: Option<PrinterStatus>,
+    after: Option<PrinterStatus>,
 }
 
 impl EscPosDriver {
@@ -172,7 +226,7 @@ impl EscPosDriver {
     /// print time (and is visible to the cloud via the failed ack) instead of
     /// the command bouncing as "no driver installed".
     pub async fn try_init(data_dir: &Path) -> Result<Option<Self>> {
-        let config_path = data_dir.join("printers.toml");
+        let config_path = data_dir.join(CONFIG_FILE);
         let printers = match load_printers(&config_path) {
             Ok(p) => {
                 tracing::info!(
@@ -196,21 +250,49 @@ impl EscPosDriver {
         Ok(Some(EscPosDriver {
             printers,
             config_path,
+            last_status: Mutex::new(HashMap::new()),
         }))
     }
 
+    /// Hot-reload init: unlike [`EscPosDriver::try_init`], a `printers.toml`
+    /// that is missing or does not validate is an error, so the caller keeps
+    /// the driver it has instead of swapping in one with no printers.
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let config_path = data_dir.join(CONFIG_FILE);
+        let printers = load_printers(&config_path)?;
+        Ok(EscPosDriver {
+            printers,
+            config_path,
+            last_status: Mutex::new(HashMap::new()),
+        })
+    }
+
     /// Test/explicit constructor with a fixed printer set.
     #[cfg(test)]
     fn with_printers(printers: Vec<Printer>) -> Self {
         EscPosDriver {
             printers,
             config_path: PathBuf::from("<test>/printers.toml"),
+            last_status: Mutex::new(HashMap::new()),
         }
     }
 
     fn find(&self, id: &str) -> Option<&Printer> {
         self.printers.iter().find(|p| p.id == id)
     }
+
+    fn record_status(&self, printer_id: &str, status: Option<PrinterStatus>) {
+        self.last_status
+            .lock()
+            .expect("status mutex poisoned")
+            .insert(
+                printer_id.to_string(),
+                LastStatus {
+                    status,
+                    checked_at: chrono_unix_now(),
+                },
+            );
+    }
 }
 
 #[async_trait]
@@ -220,6 +302,9 @@ impl LocalDriver for EscPosDriver {
     }
 
     async fn execute(&self, cmd: &PendingCommand) -> Result<CommandOutcome> {
+        if cmd.kind == discover::KIND {
+            return discover::execute(&self.printers, cmd).await;
+        }
         let printer_id = cmd
             .payload
             .get("printerId")
@@ -285,15 +370,16 @@ impl LocalDriver for EscPosDriver {
             }
         })?;
 
-        // 4. Write the bytes to the real transport. Any connect/write/flush
-        //    error propagates as Err — we only fall through to "done" once the
-        //    OS has accepted and flushed every byte. The write is blocking I/O
-        //    (TcpStream / device file), so it runs on a blocking thread to keep
-        //    the (current-thread) async reactor — heartbeat, ack retries —
-        //    responsive while a slow thermal head finishes the ticket.
-        let transport = printer.transport.clone();
+        // 4. Write the bytes to the real transport, bracketed by status polls.
+        //    Any connect/write/flush error propagates as Err — we only fall
+        //    through to "done" once the OS has accepted and flushed every byte.
+        //    The job is blocking I/O (TcpStream / device file), so it runs on a
+        //    blocking thread to keep the (current-thread) async reactor —
+        //    heartbeat, ack retries — responsive while a slow thermal head
+        //    finishes the ticket.
+        let job_printer = printer.clone();
         let byte_len = bytes.len();
-        let written = tokio::task::spawn_blocking(move || write_to_transport(&transport, &bytes))
+        let report = tokio::task::spawn_blocking(move || run_job(&job_printer, &bytes))
             .await
             .context("escpos: print task panicked")?
             .with_context(|| {
@@ -302,11 +388,37 @@ impl LocalDriver for EscPosDriver {
                     byte_len, printer.id, printer.transport, cmd.id
                 )
             })?;
+        if printer.poll_status {
+            self.record_status(&printer.id, report.after.or(report.before));
+        }
 
+        // 5. A fault reported before the job means nothing was sent; one
+        //    reported after means the ticket is probably incomplete. Either
+        //    way the job did not happen as asked.
+        if let Some(fault) = report.before.and_then(|s| s.fault()) {
+            return Err(anyhow::Error::new(fault).context(format!(
+                "escpos: printer '{}' reports {} — command {} not printed",
+                printer.id,
+                fault.code(),
+                cmd.id
+            )));
+        }
+        if let Some(fault) = report.after.and_then(|s| s.fault()) {
+            return Err(anyhow::Error::new(fault).context(format!(
+                "escpos: printer '{}' reports {} after printing command {} — the ticket may be incomplete",
+                printer.id,
+                fault.code(),
+                cmd.id
+            )));
+        }
+        let written = report.written;
+
+        let state = report.after.as_ref().map(PrinterStatus::state);
         tracing::info!(
             printer_id = %printer.id,
             kind = %cmd.kind,
             bytes = written,
+            status = state.unwrap_or("unknown"),
             "escpos: receipt written to printer"
         );
 
@@ -315,26 +427,160 @@ impl LocalDriver for EscPosDriver {
             result: json!({
                 "printer_id": printer.id,
                 "bytes_written": written,
+                "status": {
+                    "before": report.before.map(status_json),
+                    "after": report.after.map(status_json),
+                },
             }),
             error: None,
         })
     }
+
+    fn device_status(&self) -> Vec<DeviceStatus> {
+        let last = self.last_status.lock().expect("status mutex poisoned");
+        let mut out: Vec<DeviceStatus> = last
+            .iter()
+            .map(|(id, last)| DeviceStatus {
+                driver: "escpos".to_string(),
+                device: id.clone(),
+                state: last
+                    .status
+                    .as_ref()
+                    .map_or("unknown", PrinterStatus::state)
+                    .to_string(),
+                detail: last
+                    .status
+                    .map(status_json)
+                    .unwrap_or(serde_json::Value::Null),
+                checked_at: last.checked_at,
+            })
+            .collect();
+        out.sort_by(|a, b| a.device.cmp(&b.device));
+        out
+    }
+
+    async fn readiness(&self) -> Vec<DeviceReadiness> {
+        if self.printers.is_empty() {
+            return vec![DeviceReadiness {
+                driver: "escpos".to_string(),
+                device: self.config_path.display().to_string(),
+                ready: false,
+                detail: "no printers configured — prints will fail".to_string(),
+            }];
+        }
+        let mut out = Vec::with_capacity(self.printers.len());
+        for printer in &self.printers {
+            let probe = match &printer.transport {
+                Transport::Tcp { host, port } => probe_tcp(host, *port)
+                    .await
+                    .map(|()| format!("tcp {host}:{port} reachable")),
+                Transport::Device { path } => probe_device(path),
+            };
+            let (ready, detail) = match probe {
+                Ok(detail) => (true, detail),
+                Err(e) => (false, e.to_string()),
+            };
+            out.push(DeviceReadiness {
+                driver: "escpos".to_string(),
+                device: printer.id.clone(),
+                ready,
+                detail,
+            });
+        }
+        out
+    }
 }
 
-/// Write the raw ESC/POS bytes to the configured transport. Returns the number
-/// of bytes written on success. Blocking I/O is offloaded so the async
-/// dispatch loop is never stalled by a slow/unreachable printer.
-fn write_to_transport(transport: &Transport, bytes: &[u8]) -> Result<usize> {
-    match transport {
-        Transport::Tcp { host, port } => write_tcp(host, *port, bytes),
-        Transport::Device { path } => write_device(path, bytes),
+/// Device-file readiness: the path exists and is not read-only. Opening it is
+/// deliberately avoided — on a serial line that can toggle DTR and wake the
+/// printer mid-shift.
+fn probe_device(path: &Path) -> Result<String> {
+    let meta = std::fs::metadata(path).with_context(|| format!("device {}", path.display()))?;
+    if meta.permissions().readonly() {
+        return Err(anyhow!("device {} is read-only", path.display()));
     }
+    Ok(format!("device {} present", path.display()))
+}
+
+/// A status reading as it appears in outcomes and heartbeats: the flags plus
+/// the one-word `state`.
+fn status_json(status: PrinterStatus) -> serde_json::Value {
+    let mut v = serde_json::to_value(status).unwrap_or_default();
+    v["state"] = json!(status.state());
+    v
 }
 
-/// Raw TCP (port 9100) write. Connects with a timeout, writes the whole
-/// stream, flushes. A powered-off or wrong-address printer surfaces as a
-/// connect error rather than a hang.
-fn write_tcp(host: &str, port: u16, bytes: &[u8]) -> Result<usize> {
+/// Run one job on the configured transport: status, write, status. Blocking
+/// I/O is offloaded so the async dispatch loop is never stalled by a
+/// slow/unreachable printer.
+fn run_job(printer: &Printer, bytes: &[u8]) -> Result<JobReport> {
+    match &printer.transport {
+        Transport::Tcp { host, port } => {
+            let (mut stream, addr) = connect_tcp(host, *port)?;
+            with_status(printer, &mut stream, bytes, |s, b| write_tcp(s, &addr, b))
+        }
+        Transport::Device { path } => {
+            // No `O_NONBLOCK` off unix, so a status read there could block
+            // forever: device printers are not polled (status stays unknown).
+            if !printer.poll_status || cfg!(not(unix)) {
+                let written = write_device(&mut open_device(path, false)?, path, bytes)?;
+                return Ok(JobReport {
+                    written,
+                    before: None,
+                    after: None,
+                });
+            }
+            let mut file = open_device(path, true)?;
+            with_status(printer, &mut file, bytes, |f, b| {
+                set_nonblocking(f, false)?;
+                let written = write_device(f, path, b)?;
+                set_nonblocking(f, true)?;
+                Ok(written)
+            })
+        }
+    }
+}
+
+/// Poll, write unless the printer reported a fault, poll again. A failed poll
+/// is logged and reads as unknown: the write that follows is the real test of
+/// the channel.
+fn with_status<T: std::io::Read + Write>(
+    printer: &Printer,
+    io: &mut T,
+    bytes: &[u8],
+    write: impl FnOnce(&mut T, &[u8]) -> Result<usize>,
+) -> Result<JobReport> {
+    let poll = |io: &mut T| -> Option<PrinterStatus> {
+        if !printer.poll_status {
+            return None;
+        }
+        status::poll(io).unwrap_or_else(|e| {
+            tracing::warn!(printer_id = %printer.id, error = %e, "escpos: status poll failed");
+            None
+        })
+    };
+    let before = poll(io);
+    if before.and_then(|s| s.fault()).is_some() {
+        return Ok(JobReport {
+            written: 0,
+            before,
+            after: None,
+        });
+    }
+    let written = write(io, bytes)?;
+    // Skip the second poll when the first went unanswered: this printer has
+    // no back-channel, and waiting out another timeout tells us nothing.
+    let after = if before.is_some() { poll(io) } else { None };
+    Ok(JobReport {
+        written,
+        before,
+        after,
+    })
+}
+
+/// Connect to a raw TCP (port 9100) printer with a timeout. A powered-off or
+/// wrong-address printer surfaces as a connect error rather than a hang.
+fn connect_tcp(host: &str, port: u16) -> Result<(TcpStream, String)> {
     use std::net::ToSocketAddrs;
 
     let addr_str = format!("{host}:{port}");
@@ -347,15 +593,23 @@ fn write_tcp(host: &str, port: u16, bytes: &[u8]) -> Result<usize> {
         .next()
         .ok_or_else(|| anyhow!("printer address {addr_str} resolved to no socket address"))?;
 
-    let mut stream = TcpStream::connect_timeout(&addr, TCP_TIMEOUT)
+    let stream = TcpStream::connect_timeout(&addr, TCP_TIMEOUT)
         .with_context(|| format!("connecting to printer {addr_str}"))?;
     stream
         .set_write_timeout(Some(TCP_TIMEOUT))
         .context("setting printer write timeout")?;
+    // Status replies are read in short slices (see `status::poll`).
+    stream
+        .set_read_timeout(Some(status::READ_SLICE))
+        .context("setting printer read timeout")?;
     // Send the bytes promptly rather than waiting for Nagle to coalesce — a
     // receipt is one short burst.
     let _ = stream.set_nodelay(true);
+    Ok((stream, addr_str))
+}
 
+/// Raw TCP write: the whole stream, then flush.
+fn write_tcp(stream: &mut TcpStream, addr_str: &str, bytes: &[u8]) -> Result<usize> {
     stream
         .write_all(bytes)
         .with_context(|| format!("writing receipt bytes to {addr_str}"))?;
@@ -365,17 +619,57 @@ fn write_tcp(host: &str, port: u16, bytes: &[u8]) -> Result<usize> {
     Ok(bytes.len())
 }
 
-/// Serial/USB device-file write. Opens the device for writing (append so we
-/// never truncate a character device) and writes the raw stream.
-fn write_device(path: &Path, bytes: &[u8]) -> Result<usize> {
+/// Open a serial/USB printer device. Append so we never truncate a character
+/// device; with `for_status` also readable (for status replies) and
+/// non-blocking, so a device that never answers cannot hang the read.
+fn open_device(path: &Path, for_status: bool) -> Result<File> {
     use std::fs::OpenOptions;
 
     // `append` implies write-mode; we append (never truncate) so a character
     // device / line-printer node is written to rather than clobbered.
-    let mut file = OpenOptions::new()
+    let file = OpenOptions::new()
         .append(true)
+        .read(for_status)
         .open(path)
         .with_context(|| format!("opening printer device {}", path.display()))?;
+    if for_status {
+        set_nonblocking(&file, true)?;
+    }
+    Ok(file)
+}
+
+/// Toggle `O_NONBLOCK`. Status reads need it (std has no read timeout for
+/// files); the job write must not have it, or a full printer buffer would
+/// fail the write with `WouldBlock` instead of waiting.
+#[cfg(unix)]
+fn set_nonblocking(file: &File, on: bool) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let fd = file.as_raw_fd();
+    // SAFETY: fcntl on a descriptor we own, with flag arguments only.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error()).context("reading printer device flags");
+    }
+    let flags = if on {
+        flags | libc::O_NONBLOCK
+    } else {
+        flags & !libc::O_NONBLOCK
+    };
+    // SAFETY: as above.
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        return Err(std::io::Error::last_os_error()).context("setting printer device flags");
+    }
+    Ok(())
+}
+
+/// Never called with a status handle off unix (see `run_job`).
+#[cfg(not(unix))]
+fn set_nonblocking(_file: &File, _on: bool) -> Result<()> {
+    Ok(())
+}
+
+/// Device-file write: the whole stream, then flush.
+fn write_device(file: &mut File, path: &Path, bytes: &[u8]) -> Result<usize> {
     file.write_all(bytes)
         .with_context(|| format!("writing receipt bytes to {}", path.display()))?;
     file.flush()
@@ -397,8 +691,15 @@ fn load_printers(path: &Path) -> Result<Vec<Printer>> {
             path.display()
         ));
     }
-    let mut out = Vec::with_capacity(cfg.printer.len());
+    let mut out: Vec<Printer> = Vec::with_capacity(cfg.printer.len());
     for entry in cfg.printer {
+        if out.iter().any(|p| p.id == entry.id) {
+            return Err(anyhow!(
+                "printer config {}: printer id '{}' is used twice",
+                path.display(),
+                entry.id
+            ));
+        }
         out.push(entry.resolve()?);
     }
     Ok(out)
@@ -531,6 +832,8 @@ mod tests {
             payload,
             priority: 0,
             attempts: 0,
+            idempotency_key: None,
+            origin: Default::default(),
         }
     }
 
@@ -617,6 +920,7 @@ mod tests {
     async fn unknown_printer_id_fails_honestly() {
         let driver = EscPosDriver::with_printers(vec![Printer {
             id: "default".to_string(),
+            poll_status: false,
             transport: Transport::Tcp {
                 host: "127.0.0.1".to_string(),
                 port: 9100,
@@ -631,6 +935,7 @@ mod tests {
     async fn missing_data_field_fails_honestly() {
         let driver = EscPosDriver::with_printers(vec![Printer {
             id: "default".to_string(),
+            poll_status: false,
             transport: Transport::Tcp {
                 host: "127.0.0.1".to_string(),
                 port: 9100,
@@ -642,6 +947,8 @@ mod tests {
             payload: json!({ "target": "escpos" }), // no data
             priority: 0,
             attempts: 0,
+            idempotency_key: None,
+            origin: Default::default(),
         };
         let err = driver.execute(&cmd).await.unwrap_err().to_string();
         assert!(err.contains("no base64 `data`"), "got: {err}");
@@ -651,6 +958,7 @@ mod tests {
     async fn empty_decoded_payload_is_not_reported_done() {
         let driver = EscPosDriver::with_printers(vec![Printer {
             id: "default".to_string(),
+            poll_status: false,
             transport: Transport::Tcp {
                 host: "127.0.0.1".to_string(),
                 port: 9100,
@@ -665,6 +973,7 @@ mod tests {
     async fn content_hash_mismatch_refuses_to_print() {
         let driver = EscPosDriver::with_printers(vec![Printer {
             id: "default".to_string(),
+            poll_status: false,
             transport: Transport::Tcp {
                 host: "127.0.0.1".to_string(),
                 port: 9100,
@@ -681,6 +990,7 @@ mod tests {
         // Port 1 on loopback: nothing listens → connect error → Err.
         let driver = EscPosDriver::with_printers(vec![Printer {
             id: "default".to_string(),
+            poll_status: false,
             transport: Transport::Tcp {
                 host: "127.0.0.1".to_string(),
                 port: 1,
@@ -721,6 +1031,7 @@ mod tests {
 
         let driver = EscPosDriver::with_printers(vec![Printer {
             id: "default".to_string(),
+            poll_status: false,
             transport: Transport::Tcp {
                 host: addr.ip().to_string(),
                 port: addr.port(),
@@ -762,6 +1073,7 @@ mod tests {
         let drawer: Vec<u8> = vec![0x1b, 0x70, 0x00, 25, 250];
         let driver = EscPosDriver::with_printers(vec![Printer {
             id: "default".to_string(),
+            poll_status: false,
             transport: Transport::Tcp {
                 host: addr.ip().to_string(),
                 port: addr.port(),
@@ -794,6 +1106,7 @@ mod tests {
         let hash = sha256_hex(&receipt);
         let driver = EscPosDriver::with_printers(vec![Printer {
             id: "default".to_string(),
+            poll_status: false,
             transport: Transport::Tcp {
                 host: addr.ip().to_string(),
                 port: addr.port(),
@@ -819,6 +1132,7 @@ mod tests {
 
         let driver = EscPosDriver::with_printers(vec![Printer {
             id: "default".to_string(),
+            poll_status: false,
             transport: Transport::Device { path: dev.clone() },
         }]);
         let receipt: Vec<u8> = vec![0x1b, 0x40, b'O', b'K', 0x0a, 0x1d, 0x56, 0x42, 0x00];
@@ -836,6 +1150,7 @@ mod tests {
         // A path that cannot be opened for writing → Err, never "done".
         let driver = EscPosDriver::with_printers(vec![Printer {
             id: "default".to_string(),
+            poll_status: false,
             transport: Transport::Device {
                 path: PathBuf::from("/nonexistent-dir-xyz/printer/lp0"),
             },
@@ -847,6 +1162,143 @@ mod tests {
         );
     }
 
+    // ── DLE EOT status around the job ─────────────────────────────────────
+
+    const OK: [u8; 4] = [0x12, 0x12, 0x12, 0x12];
+    const PAPER_OUT: [u8; 4] = [0x1a, 0x32, 0x12, 0x72];
+    const COVER_OPEN: [u8; 4] = [0x1a, 0x16, 0x12, 0x12];
+    const CUTTER_ERROR: [u8; 4] = [0x1a, 0x52, 0x1a, 0x12];
+
+    /// A loopback "printer" that answers `DLE EOT n` with `before[n-1]` until
+    /// job bytes arrive and with `after[n-1]` from then on. Returns the job
+    /// bytes it received, status requests stripped.
+    fn status_printer(
+        before: [u8; 4],
+        after: [u8; 4],
+    ) -> (Printer, std::thread::JoinHandle<Vec<u8>>) {
+        use std::io::Write as _;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut bytes = std::io::BufReader::new(sock.try_clone().unwrap())
+                .bytes()
+                .map(Result::unwrap);
+            let mut job = Vec::new();
+            while let Some(b) = bytes.next() {
+                if b != 0x10 {
+                    job.push(b);
+                    continue;
+                }
+                match bytes.next() {
+                    Some(0x04) => {
+                        let n = bytes.next().unwrap() as usize;
+                        let replies = if job.is_empty() { before } else { after };
+                        sock.write_all(&[replies[n - 1]]).unwrap();
+                    }
+                    Some(other) => job.extend([b, other]),
+                    None => job.push(b),
+                }
+            }
+            job
+        });
+        let printer = Printer {
+            id: "kitchen".to_string(),
+            transport: Transport::Tcp {
+                host: "127.0.0.1".to_string(),
+                port,
+            },
+            poll_status: true,
+        };
+        (printer, server)
+    }
+
+    #[tokio::test]
+    async fn healthy_printer_reports_status_in_outcome_and_heartbeat() {
+        let (printer, server) = status_printer(OK, [0x12, 0x12, 0x12, 0x1e]);
+        let driver = EscPosDriver::with_printers(vec![printer]);
+        let receipt = b"\x1b@status-checked\n".to_vec();
+        let cmd = print_cmd("c-status", Some("kitchen"), &b64(&receipt));
+
+        let outcome = driver.execute(&cmd).await.expect("prints");
+        assert_eq!(outcome.result["status"]["before"]["state"], "ok");
+        assert_eq!(outcome.result["status"]["after"]["state"], "paper_near_end");
+        assert_eq!(outcome.result["status"]["after"]["paper_near_end"], true);
+        assert_eq!(server.join().unwrap(), receipt, "job bytes unchanged");
+
+        let beat = driver.device_status();
+        assert_eq!(beat.len(), 1);
+        assert_eq!(beat[0].device, "kitchen");
+        assert_eq!(beat[0].state, "paper_near_end");
+        assert!(beat[0].checked_at > 0);
+    }
+
+    /// A fault reported before the job is its own failure, and nothing is sent.
+    #[tokio::test]
+    async fn paper_out_and_cover_open_refuse_the_job() {
+        for (replies, code, fault) in [
+            (PAPER_OUT, "paper_out", status::PrinterFault::PaperOut),
+            (COVER_OPEN, "cover_open", status::PrinterFault::CoverOpen),
+        ] {
+            let (printer, server) = status_printer(replies, replies);
+            let driver = EscPosDriver::with_printers(vec![printer]);
+            let cmd = print_cmd("c-fault", Some("kitchen"), &b64(b"\x1b@ticket"));
+
+            let err = driver.execute(&cmd).await.unwrap_err();
+            assert!(err.to_string().contains(code), "{err}");
+            assert!(err.to_string().contains("not printed"), "{err}");
+            assert_eq!(err.downcast_ref::<status::PrinterFault>(), Some(&fault));
+            drop(driver);
+            assert!(server.join().unwrap().is_empty(), "no job bytes sent");
+        }
+    }
+
+    #[tokio::test]
+    async fn cutter_error_after_the_job_fails_it() {
+        let (printer, server) = status_printer(OK, CUTTER_ERROR);
+        let driver = EscPosDriver::with_printers(vec![printer]);
+        let cmd = print_cmd("c-cut", Some("kitchen"), &b64(b"\x1b@ticket"));
+
+        let err = driver.execute(&cmd).await.unwrap_err().to_string();
+        assert!(err.contains("cutter_error"), "{err}");
+        assert!(err.contains("may be incomplete"), "{err}");
+        assert!(!server.join().unwrap().is_empty(), "the job was sent");
+        assert_eq!(driver.device_status()[0].state, "cutter_error");
+    }
+
+    /// A printer without a back-channel still prints; its status is unknown,
+    /// not reported as fine, and only one poll's timeout is spent on it.
+    #[tokio::test]
+    async fn silent_printer_prints_with_unknown_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            sock.read_to_end(&mut buf).unwrap();
+            buf
+        });
+        let driver = EscPosDriver::with_printers(vec![Printer {
+            id: "bar".to_string(),
+            transport: Transport::Tcp {
+                host: "127.0.0.1".to_string(),
+                port,
+            },
+            poll_status: true,
+        }]);
+        let cmd = print_cmd("c-silent", Some("bar"), &b64(b"\x1b@x"));
+
+        let outcome = driver.execute(&cmd).await.expect("prints anyway");
+        assert!(outcome.result["status"]["before"].is_null());
+        assert!(outcome.result["status"]["after"].is_null());
+        let received = server.join().unwrap();
+        assert_eq!(
+            received, b"\x10\x04\x01\x1b@x",
+            "one status request, then the job"
+        );
+        assert_eq!(driver.device_status()[0].state, "unknown");
+    }
+
     // ── printers.toml parsing ─────────────────────────────────────────────
 
     #[test]
@@ -871,6 +1323,7 @@ mod tests {
 
         let printers = load_printers(&path).unwrap();
         assert_eq!(printers.len(), 2);
+        assert!(printers[0].poll_status, "status polling is on by default");
         assert_eq!(
             printers[0].transport,
             Transport::Tcp {
@@ -918,4 +1371,51 @@ mod tests {
             "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
         );
     }
+
+    #[tokio::test]
+    async fn readiness_probes_each_printer_without_printing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let dir = tempfile::TempDir::new().unwrap();
+        let dev = dir.path().join("lp0");
+        std::fs::write(&dev, b"").unwrap();
+        let driver = EscPosDriver::with_printers(vec![
+            Printer {
+                id: "kitchen".to_string(),
+                poll_status: false,
+                transport: Transport::Tcp {
+                    host: "127.0.0.1".to_string(),
+                    port,
+                },
+            },
+            Printer {
+                id: "bar".to_string(),
+                poll_status: false,
+                transport: Transport::Device { path: dev.clone() },
+            },
+            Printer {
+                id: "gone".to_string(),
+                poll_status: false,
+                transport: Transport::Device {
+                    path: dir.path().join("missing"),
+                },
+            },
+        ]);
+
+        let r = driver.readiness().await;
+        assert_eq!(r.len(), 3);
+        assert!(r[0].ready, "{r:?}");
+        assert!(r[1].ready, "{r:?}");
+        assert!(!r[2].ready);
+        assert_eq!(r[2].device, "gone");
+        assert_eq!(std::fs::read(&dev).unwrap(), b"", "probe never writes");
+    }
+
+    #[tokio::test]
+    async fn readiness_without_config_names_the_file() {
+        let r = EscPosDriver::with_printers(vec![]).readiness().await;
+        assert_eq!(r.len(), 1);
+        assert!(!r[0].ready);
+        assert!(r[0].device.ends_with("printers.toml"));
+    }
 }
@@ -0,0 +1,613 @@
+//! A scriptable GMP-3 ÖKC emulator for integration testing.
+//!
+//! Listens on TCP and speaks the same frames, ACK/NAK link and handshake as a
+//! device ([`super::codec`]), so the driver's `mode = "emulator"` path — and
+//! the queue's crash recovery behind it — can be exercised end-to-end on a
+//! plain Linux box with no ÖKC on the desk. The `gmp3-emulator` binary wraps
+//! it; tests spawn it on a loopback port.
+//!
+//! Each transaction request (sale, void, refund, pre-auth and completion, tip
+//! adjust, split sale, receipt, cancel, report, batch close) takes the next
+//! [`Step`] from the script, or the fallback once the script is empty:
+//!   - `approve` — done, recorded, answered;
+//!   - `decline` — a card leg is declined by the "acquirer" (`05`), anything
+//!     else fails on the device (`96`);
+//!   - `hang` — the frame is ACKed and never answered (a cardholder who walked
+//!     away, a device that froze mid-transaction);
+//!   - `drop-after-approval` — done and recorded, then the connection is cut
+//!     before the reply: the case crash recovery exists for;
+//!   - `duplicate-seq` — done and recorded, but answered under the previous
+//!     sequence number.
+//!
+//! Like a device, it remembers every transaction it completed under its ECR
+//! reference and answers a status query for it; it refuses a stale sequence
+//! number (`SQ`) and a second transaction under a used reference (`DR`). That
+//! memory is a [`SimJournal`] — in memory, or a file to outlive the process.
+//! Approved card legs add up in the open batch, which a batch close reports
+//! and empties.
+//! The emulator's approval codes, RRNs and fiscal numbers are `EMU`-prefixed,
+//! so a value from it can never pass for a real one.
+
+use super::codec::{msg, tag, Field, Link, LinkConfig, Message};
+use super::journal::SimJournal;
+use super::sequence::MAX_SEQ;
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::net::{TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// How long a session may sit idle before the emulator hangs up.
+const IDLE: Duration = Duration::from_secs(600);
+
+/// What the emulator does with one transaction request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Approve,
+    Decline,
+    Hang,
+    DropAfterApproval,
+    DuplicateSeq,
+}
+
+impl Step {
+    pub fn name(self) -> &'static str {
+        match self {
+            Step::Approve => "approve",
+            Step::Decline => "decline",
+            Step::Hang => "hang",
+            Step::DropAfterApproval => "drop-after-approval",
+            Step::DuplicateSeq => "duplicate-seq",
+        }
+    }
+}
+
+impl FromStr for Step {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "approve" => Ok(Step::Approve),
+            "decline" => Ok(Step::Decline),
+            "hang" | "timeout" => Ok(Step::Hang),
+            "drop-after-approval" | "drop" => Ok(Step::DropAfterApproval),
+            "duplicate-seq" => Ok(Step::DuplicateSeq),
+            other => Err(anyhow!(
+                "unknown emulator step '{other}' (approve | decline | hang | drop-after-approval | duplicate-seq)"
+            )),
+        }
+    }
+}
+
+/// One emulated device.
+pub struct Emulator {
+    serial: String,
+    link_cfg: LinkConfig,
+    script: Mutex<VecDeque<Step>>,
+    then: Step,
+    journal: SimJournal,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// The last sequence number the device accepted.
+    last_seq: u32,
+    /// Fiscal receipts printed, for numbering.
+    fiscal_count: u32,
+    /// Every transaction request taken, in order.
+    transactions: Vec<Message>,
+    /// Approved card legs since the last batch close.
+    batch: Batch,
+}
+
+#[derive(Default)]
+struct Batch {
+    sale_count: u64,
+    sale_cents: u64,
+    refund_count: u64,
+    refund_cents: u64,
+}
+
+impl Emulator {
+    /// An emulator for `serial` that approves everything, with its memory in
+    /// this process.
+    pub fn new(serial: impl Into<String>) -> Result<Self> {
+        Ok(Self {
+            serial: serial.into(),
+            link_cfg: LinkConfig::default(),
+            script: Mutex::new(VecDeque::new()),
+            then: Step::Approve,
+            journal: SimJournal::in_memory()?,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Steps for the next transactions, in order.
+    pub fn with_script(self, steps: impl IntoIterator<Item = Step>) -> Self {
+        self.script
+            .lock()
+            .expect("emulator mutex poisoned")
+            .extend(steps);
+        self
+    }
+
+    /// What every transaction after the script gets (default: approve).
+    pub fn with_then(mut self, step: Step) -> Self {
+        self.then = step;
+        self
+    }
+
+    /// Device memory kept in a file, so it outlives the emulator process.
+    pub fn with_journal(mut self, journal: SimJournal) -> Self {
+        self.journal = journal;
+        self
+    }
+
+    /// The sequence number the device reports having seen at the handshake.
+    pub fn with_last_seq(self, seq: u32) -> Self {
+        self.state.lock().expect("emulator mutex poisoned").last_seq = seq;
+        self
+    }
+
+    pub fn with_link_config(mut self, cfg: LinkConfig) -> Self {
+        self.link_cfg = cfg;
+        self
+    }
+
+    /// Append a step to the script.
+    pub fn push(&self, step: Step) {
+        self.script
+            .lock()
+            .expect("emulator mutex poisoned")
+            .push_back(step);
+    }
+
+    /// Every transaction request taken so far (status queries excluded).
+    pub fn transactions(&self) -> Vec<Message> {
+        self.state
+            .lock()
+            .expect("emulator mutex poisoned")
+            .transactions
+            .clone()
+    }
+
+    /// Serve `listener` on a background thread until the process exits.
+    pub fn spawn(self: Arc<Self>, listener: TcpListener) -> JoinHandle<()> {
+        std::thread::spawn(move || self.serve(listener))
+    }
+
+    /// Accept connections one at a time, like a device with one ECR port.
+    pub fn serve(&self, listener: TcpListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let peer = stream
+                        .peer_addr()
+                        .map(|a| a.to_string())
+                        .unwrap_or_default();
+                    info!(serial = %self.serial, %peer, "emulator: session open");
+                    match self.session(stream) {
+                        Ok(()) => info!(serial = %self.serial, %peer, "emulator: session closed"),
+                        Err(e) => {
+                            warn!(serial = %self.serial, %peer, error = %e, "emulator: session ended")
+                        }
+                    }
+                }
+                Err(e) => warn!(error = %e, "emulator: accept failed"),
+            }
+        }
+    }
+
+    fn session(&self, stream: TcpStream) -> Result<()> {
+        let mut link = Link::new(stream, self.link_cfg);
+        loop {
+            // Peer gone or idle: the session is over either way.
+            let Ok(req) = link.recv(IDLE) else {
+                return Ok(());
+            };
+            if req.msg_type == msg::HANDSHAKE {
+                let last = self.state().last_seq;
+                link.send(
+                    &Message::reply(&req)
+                        .with_field(tag::SERIAL, self.serial.as_str())
+                        .with_field(tag::LAST_SEQ, last.to_string()),
+                )?;
+                continue;
+            }
+
+            let reference = req.field_str(tag::ECR_REF).map(str::to_string);
+            let refused = |code: &str, why: String| {
+                let mut m = Message::reply(&req)
+                    .with_field(tag::RESULT, code)
+                    .with_field(tag::MESSAGE, why);
+                if let Some(r) = &reference {
+                    m = m.with_field(tag::ECR_REF, r.as_str());
+                }
+                m
+            };
+
+            {
+                let mut state = self.state();
+                if !is_fresh(req.seq, state.last_seq) {
+                    let why = format!(
+                        "sequence {} already used (last {})",
+                        req.seq, state.last_seq
+                    );
+                    drop(state);
+                    link.send(&refused("SQ", why))?;
+                    continue;
+                }
+                state.last_seq = req.seq;
+            }
+
+            if req.msg_type == msg::STATUS {
+                let reply = match &reference {
+                    None => Message::reply(&req)
+                        .with_field(tag::RESULT, "00")
+                        .with_field(tag::SERIAL, self.serial.as_str()),
+                    Some(r) => match self.journal.lookup(&self.serial, r)? {
+                        Some(stored) => with_stored(Message::reply(&req), &stored.result),
+                        None => refused("NF", format!("no transaction with reference {r}")),
+                    },
+                };
+                link.send(&reply)?;
+                continue;
+            }
+            if !is_transaction(req.msg_type) {
+                link.send(&refused(
+                    "UT",
+                    format!("unknown message type {:#06x}", req.msg_type),
+                ))?;
+                continue;
+            }
+            let Some(reference) = reference.clone() else {
+                link.send(&refused(
+                    "NR",
+                    "transaction without a reference".to_string(),
+                ))?;
+                continue;
+            };
+            if self.journal.lookup(&self.serial, &reference)?.is_some() {
+                link.send(&refused(
+                    "DR",
+                    format!("reference {reference} already used"),
+                ))?;
+                continue;
+            }
+
+            let step = self.next_step();
+            self.state().transactions.push(req.clone());
+            info!(serial = %self.serial, seq = req.seq, %reference, step = step.name(), "emulator: transaction");
+            match step {
+                Step::Approve => {
+                    let reply = self.complete(&req, &reference, self.approval(&req))?;
+                    link.send(&reply)?;
+                }
+                Step::Decline if is_card(req.msg_type) => {
+                    // A declined card is still a transaction the device
+                    // journals: asking about it later must not invite a retry.
+                    let fields = vec![
+                        field(tag::RESULT, "05"),
+                        field(tag::MESSAGE, "declined by the issuer (emulator)"),
+                    ];
+                    let reply = self.complete(&req, &reference, fields)?;
+                    link.send(&reply)?;
+                }
+                Step::Decline => {
+                    link.send(&refused("96", "device error (emulator)".to_string()))?;
+                }
+                Step::Hang => {}
+                Step::DropAfterApproval => {
+                    self.complete(&req, &reference, self.approval(&req))?;
+                    return Ok(());
+                }
+                Step::DuplicateSeq => {
+                    let mut reply = self.complete(&req, &reference, self.approval(&req))?;
+                    reply.seq = req.seq.saturating_sub(1);
+                    link.send(&reply)?;
+                }
+            }
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("emulator mutex poisoned")
+    }
+
+    fn next_step(&self) -> Step {
+        self.script
+            .lock()
+            .expect("emulator mutex poisoned")
+            .pop_front()
+            .unwrap_or(self.then)
+    }
+
+    /// The result fields of a completed transaction.
+    fn approval(&self, req: &Message) -> Vec<Field> {
+        let seq = req.seq;
+        let mut out = vec![field(tag::RESULT, "00")];
+        let fiscal_no = || {
+            let mut state = self.state();
+            state.fiscal_count += 1;
+            format!("EMUFIS-{:06}", state.fiscal_count)
+        };
+        let cents = |t: u16| -> u64 { req.field_str(t).and_then(|v| v.parse().ok()).unwrap_or(0) };
+        let card = |out: &mut Vec<Field>, prefix: &str| {
+            out.push(field(tag::APPROVAL_CODE, &format!("{prefix}{seq:06}")));
+            out.push(field(tag::RRN, &format!("{prefix}{seq:09}")));
+            out.push(field(tag::CARD_BRAND, "EMULATOR"));
+            out.push(field(tag::MASKED_PAN, "**** **** **** 0000"));
+        };
+        match req.msg_type {
+            msg::SALE | msg::SPLIT_SALE | msg::PREAUTH_COMPLETE => {
+                card(&mut out, "EMU");
+                // Coupled: the fiş prints with the charge.
+                out.push(field(tag::FISCAL_NO, &fiscal_no()));
+                let charged = match req.msg_type {
+                    msg::SPLIT_SALE => cents(tag::CARD_AMOUNT),
+                    _ => cents(tag::AMOUNT),
+                };
+                let mut state = self.state();
+                state.batch.sale_count += 1;
+                state.batch.sale_cents += charged;
+            }
+            msg::PREAUTH => card(&mut out, "EMUP"),
+            msg::REFUND => {
+                out.push(field(tag::APPROVAL_CODE, &format!("EMUR{seq:06}")));
+                out.push(field(tag::RRN, &format!("EMUR{seq:09}")));
+                let mut state = self.state();
+                state.batch.refund_count += 1;
+                state.batch.refund_cents += cents(tag::AMOUNT);
+            }
+            msg::TIP_ADJUST => {
+                out.push(field(tag::APPROVAL_CODE, &format!("EMUT{seq:06}")));
+                self.state().batch.sale_cents += cents(tag::TIP_AMOUNT);
+            }
+            msg::BATCH_CLOSE => {
+                let batch = std::mem::take(&mut self.state().batch);
+                out.push(field(tag::BATCH_NO, &format!("EMUB-{seq}")));
+                out.push(field(tag::CLOSED_AT, "1970-01-01T00:00:00.000Z"));
+                out.push(field(tag::SALE_COUNT, &batch.sale_count.to_string()));
+                out.push(field(tag::SALE_TOTAL, &batch.sale_cents.to_string()));
+                out.push(field(tag::REFUND_COUNT, &batch.refund_count.to_string()));
+                out.push(field(tag::REFUND_TOTAL, &batch.refund_cents.to_string()));
+            }
+            msg::VOID => out.push(field(tag::APPROVAL_CODE, &format!("EMUV{:06}", seq))),
+            msg::RECEIPT => {
+                out.push(field(tag::FISCAL_NO, &fiscal_no()));
+                out.push(field(tag::Z_NO, "1"));
+            }
+            msg::REPORT => {
+                out.push(field(tag::Z_NO, &format!("EMUZ-{seq}")));
+                // Clearly-synthetic timestamps, as in the simulator.
+                out.push(field(tag::OPENED_AT, "1970-01-01T00:00:00.000Z"));
+                out.push(field(tag::CLOSED_AT, "1970-01-01T00:00:00.000Z"));
+            }
+            _ => {}
+        }
+        out
+    }
+
+    /// Journal a completed transaction and build its reply.
+    fn complete(&self, req: &Message, reference: &str, mut fields: Vec<Field>) -> Result<Message> {
+        fields.push(field(tag::ECR_REF, reference));
+        let stored = Value::Array(
+            fields
+                .iter()
+                .map(|f| json!([f.tag, String::from_utf8_lossy(&f.value)]))
+                .collect(),
+        );
+        self.journal
+            .record(&self.serial, reference, type_name(req.msg_type), &stored)?;
+        Ok(with_stored(Message::reply(req), &stored))
+    }
+}
+
+fn field(tag: u16, value: &str) -> Field {
+    Field {
+        tag,
+        value: value.as_bytes().to_vec(),
+    }
+}
+
+/// `reply` with the fields a journal entry holds.
+fn with_stored(mut reply: Message, stored: &Value) -> Message {
+    for pair in stored.as_array().into_iter().flatten() {
+        if let (Some(t), Some(v)) = (pair[0].as_u64(), pair[1].as_str()) {
+            reply = reply.with_field(t as u16, v);
+        }
+    }
+    reply
+}
+
+fn is_transaction(msg_type: u16) -> bool {
+    is_card(msg_type)
+        || matches!(
+            msg_type,
+            msg::VOID | msg::RECEIPT | msg::RECEIPT_CANCEL | msg::REPORT | msg::BATCH_CLOSE
+        )
+}
+
+/// The requests an acquirer answers, and so can decline.
+fn is_card(msg_type: u16) -> bool {
+    matches!(
+        msg_type,
+        msg::SALE
+            | msg::REFUND
+            | msg::PREAUTH
+            | msg::PREAUTH_COMPLETE
+            | msg::TIP_ADJUST
+            | msg::SPLIT_SALE
+    )
+}
+
+fn type_name(msg_type: u16) -> &'static str {
+    match msg_type {
+        msg::SALE => "sale",
+        msg::VOID => "void",
+        msg::REFUND => "refund",
+        msg::PREAUTH => "preauth",
+        msg::PREAUTH_COMPLETE => "preauth_complete",
+        msg::TIP_ADJUST => "tip_adjust",
+        msg::SPLIT_SALE => "split_sale",
+        msg::BATCH_CLOSE => "batch_close",
+        msg::RECEIPT => "receipt",
+        msg::RECEIPT_CANCEL => "receipt_cancel",
+        msg::REPORT => "report",
+        _ => "other",
+    }
+}
+
+/// Whether `seq` is ahead of `last`, allowing for the wrap after
+/// [`MAX_SEQ`] and for numbers the bridge skipped.
+fn is_fresh(seq: u32, last: u32) -> bool {
+    let ahead = (seq + MAX_SEQ - last) % MAX_SEQ;
+    (1..MAX_SEQ / 2).contains(&ahead)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::gmp3::codec::LinkConfig;
+
+    fn start(emu: Emulator) -> (Arc<Emulator>, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let emu = Arc::new(emu);
+        emu.clone().spawn(listener);
+        (emu, port)
+    }
+
+    /// A raw client link, handshake done.
+    fn client(port: u16) -> Link<TcpStream> {
+        let mut link = Link::new(
+            TcpStream::connect(("127.0.0.1", port)).unwrap(),
+            LinkConfig::default(),
+        );
+        link.send(&Message::new(msg::HANDSHAKE)).unwrap();
+        let hello = link.recv(Duration::from_secs(5)).unwrap();
+        assert_eq!(hello.field_str(tag::SERIAL), Some("EMU-1"));
+        link
+    }
+
+    fn sale(seq: u32, reference: &str) -> Message {
+        Message {
+            seq,
+            ..Message::new(msg::SALE)
+        }
+        .with_field(tag::AMOUNT, "100")
+        .with_field(tag::ECR_REF, reference)
+    }
+
+    fn status(seq: u32, reference: &str) -> Message {
+        Message {
+            seq,
+            ..Message::new(msg::STATUS)
+        }
+        .with_field(tag::ECR_REF, reference)
+    }
+
+    fn ask(link: &mut Link<TcpStream>, m: &Message) -> Message {
+        link.send(m).unwrap();
+        link.recv(Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn steps_parse_by_name() {
+        let parse = |s: &str| s.split(',').map(str::parse).collect::<Result<Vec<Step>>>();
+        assert_eq!(
+            parse("approve, decline,hang,drop-after-approval,duplicate-seq").unwrap(),
+            vec![
+                Step::Approve,
+                Step::Decline,
+                Step::Hang,
+                Step::DropAfterApproval,
+                Step::DuplicateSeq
+            ]
+        );
+        assert!(parse("approve,explode").is_err());
+    }
+
+    /// Approve, then decline, then remember both — and refuse a second
+    /// transaction under a used reference or a used sequence number.
+    #[test]
+    fn remembers_transactions_and_refuses_replays() {
+        let (emu, port) = start(
+            Emulator::new("EMU-1")
+                .unwrap()
+                .with_script([Step::Approve, Step::Decline])
+                .with_last_seq(10),
+        );
+        let mut link = client(port);
+
+        let ok = ask(&mut link, &sale(11, "a"));
+        assert_eq!(ok.field_str(tag::RESULT), Some("00"));
+        assert!(ok.field_str(tag::RRN).unwrap().starts_with("EMU"));
+        let no = ask(&mut link, &sale(12, "b"));
+        assert_eq!(no.field_str(tag::RESULT), Some("05"));
+
+        let found = ask(&mut link, &status(13, "a"));
+        assert_eq!(found.field_str(tag::RRN), ok.field_str(tag::RRN));
+        assert_eq!(
+            ask(&mut link, &status(14, "b")).field_str(tag::RESULT),
+            Some("05")
+        );
+        assert_eq!(
+            ask(&mut link, &status(15, "c")).field_str(tag::RESULT),
+            Some("NF")
+        );
+
+        assert_eq!(
+            ask(&mut link, &sale(16, "a")).field_str(tag::RESULT),
+            Some("DR")
+        );
+        assert_eq!(
+            ask(&mut link, &sale(9, "d")).field_str(tag::RESULT),
+            Some("SQ")
+        );
+        assert_eq!(emu.transactions().len(), 2, "refusals are not transactions");
+    }
+
+    #[test]
+    fn hangs_drops_and_misnumbers_on_cue() {
+        let (emu, port) = start(Emulator::new("EMU-1").unwrap().with_script([
+            Step::Hang,
+            Step::DuplicateSeq,
+            Step::DropAfterApproval,
+        ]));
+        let mut link = client(port);
+
+        link.send(&sale(1, "h")).unwrap();
+        assert!(
+            link.recv(Duration::from_millis(300)).is_err(),
+            "ACKed, never answered"
+        );
+
+        let dup = ask(&mut link, &sale(2, "d"));
+        assert_eq!(dup.seq, 1, "answered under the previous number");
+
+        link.send(&sale(3, "x")).unwrap();
+        let err = link.recv(Duration::from_secs(5)).unwrap_err();
+        assert!(err.to_string().contains("closed"), "{err}");
+
+        // The dropped sale happened: the device says so on the next session.
+        let mut link = client(port);
+        let found = ask(&mut link, &status(4, "x"));
+        assert_eq!(found.field_str(tag::RESULT), Some("00"));
+        assert_eq!(emu.transactions().len(), 3);
+    }
+
+    #[test]
+    fn sequence_freshness_allows_the_wrap() {
+        assert!(is_fresh(1, 0));
+        assert!(is_fresh(7, 3), "skipped numbers are fine");
+        assert!(!is_fresh(3, 3));
+        assert!(!is_fresh(2, 3));
+        assert!(is_fresh(1, MAX_SEQ));
+    }
+}
@@ -0,0 +1,125 @@
+//! Device-side transaction journal for simulator-mode GMP-3 devices.
+//!
+//! A real ÖKC remembers every transaction it completed, keyed by the ECR
+//! reference the bridge sent with it, and answers a status query for that
+//! reference. That is what lets the driver resolve a crash-interrupted charge
+//! on its own: before re-sending, ask the device whether transaction N already
+//! happened, and if so return what the device recorded instead of charging
+//! again.
+//!
+//! The simulator has no device memory, so this module provides one: a small
+//! SQLite table in the bridge data dir (`gmp3_sim_journal.db`). It lives in its
+//! own file, not in `command_queue.db`, because it stands in for state held on
+//! the DEVICE — it must survive a bridge restart exactly like the real thing,
+//! and must not be swept with the command rows.
+
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+use std::{path::Path, sync::Mutex};
+
+pub struct SimJournal {
+    conn: Mutex<Connection>,
+}
+
+/// A transaction the device completed: the command kind it ran as, and what
+/// it recorded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recorded {
+    pub kind: String,
+    pub result: Value,
+}
+
+impl SimJournal {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::init(Connection::open(path.as_ref())?)
+    }
+
+    /// Process-local journal (tests).
+    pub fn in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = FULL;
+             CREATE TABLE IF NOT EXISTS sim_transactions (
+                serial TEXT NOT NULL,
+                idempotency_key TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                result TEXT NOT NULL,
+                recorded_at INTEGER NOT NULL,
+                PRIMARY KEY (serial, idempotency_key)
+             );",
+        )?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// What the device recorded for `key`, if it ever completed it. The kind
+    /// comes back with it: a key reused by a different kind of command is the
+    /// caller's to refuse, never a result to hand back.
+    pub fn lookup(&self, serial: &str, key: &str) -> Result<Option<Recorded>> {
+        let conn = self.conn.lock().expect("sim journal mutex poisoned");
+        let raw: Option<(String, String)> = conn
+            .query_row(
+                "SELECT kind, result FROM sim_transactions WHERE serial = ?1 AND idempotency_key = ?2",
+                params![serial, key],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(match raw {
+            Some((kind, s)) => Some(Recorded {
+                kind,
+                result: serde_json::from_str(&s)?,
+            }),
+            None => None,
+        })
+    }
+
+    /// Record a completed transaction. First write wins: a device never
+    /// rewrites the outcome of a transaction it already finished.
+    pub fn record(&self, serial: &str, key: &str, kind: &str, result: &Value) -> Result<()> {
+        let conn = self.conn.lock().expect("sim journal mutex poisoned");
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0);
+        conn.execute(
+            "INSERT OR IGNORE INTO sim_transactions (serial, idempotency_key, kind, result, recorded_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![serial, key, kind, serde_json::to_string(result)?, now],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn records_survive_reopen_and_first_write_wins() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("j.db");
+        {
+            let j = SimJournal::open(&path).unwrap();
+            j.record("SER-1", "k1", "charge_card", &json!({ "rrn": "A" }))
+                .unwrap();
+            j.record("SER-1", "k1", "charge_card", &json!({ "rrn": "B" }))
+                .unwrap();
+        }
+        let j = SimJournal::open(&path).unwrap();
+        assert_eq!(
+            j.lookup("SER-1", "k1").unwrap(),
+            Some(Recorded {
+                kind: "charge_card".to_string(),
+                result: json!({ "rrn": "A" }),
+            })
+        );
+        assert_eq!(j.lookup("SER-2", "k1").unwrap(), None, "keyed per device");
+    }
+}
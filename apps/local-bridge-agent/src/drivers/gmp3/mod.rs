@@ -4,7 +4,10 @@
 //! per-vendor bits live in [`profiles`]. It handles BOTH command families a
 //! single physical device exposes:
 //!   - coupled card sale (`charge_card` / `void_card`) — the payment-terminal
-//!     rail, `paygo_ecr` provider;
+//!     rail, `paygo_ecr` provider — and the restaurant card operations around
+//!     it: linked `refund_card`, `preauth_card` / `preauth_complete` for tabs,
+//!     `tip_adjust`, `charge_split_tender` (card + cash on one fiş) and the
+//!     end-of-day `batch_close`;
 //!   - standalone fiş (`fiscal_receipt` / `fiscal_cancel` / `fiscal_report`) —
 //!     the fiscal-core rail, `fiscal_paygo` provider;
 //!   - plus `capability_probe`.
@@ -12,7 +15,10 @@
 //! ## Routing
 //! The cloud GMP-3 adapters emit `protocol: "GMP3"` + `vendorProfile` (no
 //! `target`), so the driver registry routes GMP-3-protocol commands here (see
-//! `drivers::Registry::dispatch`).
+//! `drivers::Registry::dispatch`). The vendor profile is resolved against the
+//! built-ins plus the signed `gmp3_profiles.toml` ([`profiles`]); the cloud
+//! pushes a new one as a [`profiles::UPDATE_KIND`] command with
+//! `target: "gmp3"`.
 //!
 //! ## Transport config (resolved LOCALLY, like `printers.toml`)
 //! The cloud never learns the device's LAN address (NAT); it lives on-prem in
@@ -22,63 +28,115 @@
 //! ```toml
 //! [[device]]
 //! serial = "5B0024050735"   # matches the command's fiscalSerial
-//! mode = "simulator"        # "simulator" (test) | "real" (Phase 1, cert'd)
+//! mode = "simulator"        # "simulator" | "emulator" (test) | "real" (Phase 1)
 //! sim_outcome = "approve"   # simulator only: approve | decline | error
-//! host = "192.168.1.60"     # required for real mode
+//! host = "192.168.1.60"     # required for emulator and real mode
 //! port = 59000              # optional; profile default otherwise
+//! timeout_secs = 20         # optional; how long to wait for a reply
 //! ```
 //!
+//! `mode = "emulator"` runs the framed path — [`protocol::request`] over a
+//! [`transport::Connection`], [`protocol::interpret`] on the reply — against
+//! the `gmp3-emulator` ([`emulator`]), without the vendor session crypto. It
+//! is the path real mode takes inside that session, so the codec, sequence
+//! numbers and crash recovery are tested end-to-end on a plain Linux box.
+//!
+//! ## Idempotent replay
+//! Money/fiscal commands carry `idempotency_key` (see `command_queue`). Before
+//! sending one, the driver asks the device whether that transaction already
+//! completed; if it did, the device's recorded result is returned (flagged
+//! `"replayed": true`) instead of charging or printing again. That is what
+//! lets crash recovery requeue an interrupted charge rather than park it. In
+//! simulator mode the device's memory is [`journal::SimJournal`]; in emulator
+//! mode it is a [`msg::STATUS`](codec::msg::STATUS) query by ECR reference.
+//!
 //! ## Honest failure (no fake success)
 //! `mode = "real"` fails closed until the vendor's certified handshake ships
 //! (`VendorProfile::real_impl_ready`) — we never fabricate an approval or a fiş.
 //! A device not present in `gmp3.toml`, an unknown vendor profile, or an
 //! unhandled kind all surface as `Err` (→ a `failed` ack), never a silent no-op.
 
+pub mod codec;
+pub mod emulator;
+pub mod journal;
 pub mod profiles;
 pub mod protocol;
+pub mod sequence;
 pub mod transport;
 
 use crate::{
     command_queue::{CommandOutcome, PendingCommand},
-    drivers::LocalDriver,
+    drivers::{probe_tcp, DeviceReadiness, LocalDriver},
 };
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
+use ed25519_dalek::VerifyingKey;
 use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use profiles::VendorProfile;
+use journal::SimJournal;
+use profiles::{ProfileSet, VendorProfile};
 use protocol::{CommandFamily, SimOutcome, SimResult};
+use sequence::SequenceStore;
+use transport::{Connection, TcpEndpoint};
+
+/// The device config file in the bridge data dir.
+pub const CONFIG_FILE: &str = "gmp3.toml";
 
 /// One device's on-prem transport config from `gmp3.toml`.
 #[derive(Debug, Clone, Deserialize)]
 struct Gmp3DeviceEntry {
     /// Device serial — matched against the command's `fiscalSerial`.
     serial: String,
-    /// "simulator" (opt-in test mode) | anything else → real (fail-closed in
-    /// Phase 0). Defaulting the UNSET/unknown value to real is deliberate: a
-    /// misconfigured device must fail closed, never silently simulate a sale.
+    /// "simulator" | "emulator" (opt-in test modes) | anything else → real
+    /// (fail-closed in Phase 0). Defaulting the UNSET/unknown value to real is
+    /// deliberate: a misconfigured device must fail closed, never silently
+    /// simulate a sale.
     #[serde(default)]
     mode: Option<String>,
     /// Simulator only: approve (default) | decline | error.
     #[serde(default)]
     sim_outcome: Option<String>,
-    /// LAN host (required for real mode; unused by the simulator).
+    /// LAN host (required for emulator and real mode; unused by the
+    /// simulator).
     #[serde(default)]
-    #[allow(dead_code)] // consumed by the real path (Phase 1); kept honest here.
     host: Option<String>,
     /// LAN port (optional; the vendor profile's default applies otherwise).
     #[serde(default)]
-    #[allow(dead_code)]
     port: Option<u16>,
+    /// The vendor profile this device is, for `--health` to probe its
+    /// `default_port` when no `port` is set. Commands still name theirs.
+    #[serde(default)]
+    vendor_profile: Option<String>,
+    /// How long to wait for the device's reply to a request (default 20 s —
+    /// a card sale waits on the cardholder).
+    #[serde(default)]
+    timeout_secs: Option<u64>,
 }
 
 impl Gmp3DeviceEntry {
+    fn mode(&self) -> String {
+        self.mode
+            .as_deref()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase()
+    }
+
     fn is_simulator(&self) -> bool {
-        matches!(
-            self.mode.as_deref().map(|m| m.trim().to_ascii_lowercase()),
-            Some(ref m) if m == "simulator" || m == "sim"
-        )
+        matches!(self.mode().as_str(), "simulator" | "sim")
+    }
+
+    fn is_emulator(&self) -> bool {
+        self.mode() == "emulator"
+    }
+
+    fn host(&self) -> Option<&str> {
+        self.host.as_deref().filter(|h| !h.trim().is_empty())
     }
 }
 
@@ -88,14 +146,29 @@ struct Gmp3Config {
     device: Vec<Gmp3DeviceEntry>,
 }
 
-/// The GMP-3 driver. Holds the locally-configured device transports (loaded once
-/// at boot from `gmp3.toml`). Like the ESC/POS driver, it registers even when
-/// the config is absent (so the agent boots) and fails honestly at command time.
+/// The GMP-3 driver. Holds the locally-configured device transports (loaded from
+/// `gmp3.toml` at boot and on every hot reload). Like the ESC/POS driver, it
+/// registers even when the config is absent (so the agent boots) and fails
+/// honestly at command time.
 pub struct Gmp3Driver {
     devices: Vec<Gmp3DeviceEntry>,
     /// Where `gmp3.toml` was looked for — named in errors so an operator knows
     /// exactly which file to create/fix.
     config_path: PathBuf,
+    /// Simulated device memory for idempotency lookups. `None` if the journal
+    /// file could not be opened — keyed replays then fail closed.
+    sim_journal: Option<SimJournal>,
+    /// İşlem Sıra No per device, for the framed path. `None` if it could not
+    /// be opened — framed commands then fail closed rather than risk reusing
+    /// a number.
+    sequence: Option<Arc<SequenceStore>>,
+    /// One persistent connection per device serial, opened on first use.
+    connections: Mutex<HashMap<String, Arc<Mutex<Connection>>>>,
+    /// Built-in vendor profiles plus `gmp3_profiles.toml`.
+    profiles: ProfileSet,
+    /// Keys a pushed profile file must be signed with (the pinned release
+    /// keys).
+    profile_keys: Vec<VerifyingKey>,
 }
 
 impl Gmp3Driver {
@@ -103,7 +176,7 @@ impl Gmp3Driver {
     /// registers the driver (returns `Some`) so a missing config doesn't drop
     /// the `gmp3` kind — the failure surfaces honestly at command time.
     pub async fn try_init(data_dir: &Path) -> Result<Option<Self>> {
-        let config_path = data_dir.join("gmp3.toml");
+        let config_path = data_dir.join(CONFIG_FILE);
         let devices = match load_config(&config_path) {
             Ok(d) => {
                 tracing::info!(
@@ -122,10 +195,90 @@ impl Gmp3Driver {
                 Vec::new()
             }
         };
-        Ok(Some(Gmp3Driver {
+        let keys = release_keys();
+        let profiles = match ProfileSet::load(data_dir, &keys) {
+            Ok(p) => p,
+            Err(e) => {
+                tracing::warn!(
+                    error = %format!("{e:#}"),
+                    "gmp3: profile file refused; using the built-in vendor profiles"
+                );
+                ProfileSet::builtin()
+            }
+        };
+        Ok(Some(Self::with_config(
+            data_dir,
+            devices,
+            config_path,
+            profiles,
+            keys,
+        )))
+    }
+
+    /// Hot-reload init: a `gmp3.toml` or `gmp3_profiles.toml` that does not
+    /// validate, or a `gmp3.toml` that is missing, is an error, so the caller
+    /// keeps the driver it has.
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let config_path = data_dir.join(CONFIG_FILE);
+        let devices = load_config(&config_path)?;
+        let keys = release_keys();
+        let profiles = ProfileSet::load(data_dir, &keys)?;
+        Ok(Self::with_config(
+            data_dir,
+            devices,
+            config_path,
+            profiles,
+            keys,
+        ))
+    }
+
+    fn with_config(
+        data_dir: &Path,
+        devices: Vec<Gmp3DeviceEntry>,
+        config_path: PathBuf,
+        profiles: ProfileSet,
+        profile_keys: Vec<VerifyingKey>,
+    ) -> Self {
+        for key in profiles.unknown_keys() {
+            tracing::warn!(
+                key = %key,
+                "gmp3: unknown key in {} ignored",
+                profiles::PROFILES_FILE
+            );
+        }
+        let journal_path = data_dir.join("gmp3_sim_journal.db");
+        let sim_journal = match SimJournal::open(&journal_path) {
+            Ok(j) => Some(j),
+            Err(e) => {
+                tracing::warn!(
+                    error = %e,
+                    path = %journal_path.display(),
+                    "gmp3: simulator journal unavailable; replayed simulator commands will FAIL closed"
+                );
+                None
+            }
+        };
+        let sequence_path = data_dir.join("command_queue.db");
+        let sequence = match SequenceStore::open(&sequence_path) {
+            Ok(s) => Some(Arc::new(s)),
+            Err(e) => {
+                tracing::warn!(
+                    error = %e,
+                    path = %sequence_path.display(),
+                    "gmp3: sequence store unavailable; emulator-mode commands will FAIL closed"
+                );
+                None
+            }
+        };
+        Gmp3Driver {
             devices,
             config_path,
-        }))
+            sim_journal,
+            sequence,
+            connections: Mutex::new(HashMap::new()),
+            profiles,
+            profile_keys,
+        }
     }
 
     #[cfg(test)]
@@ -133,6 +286,13 @@ impl Gmp3Driver {
         Gmp3Driver {
             devices,
             config_path: PathBuf::from("<test>/gmp3.toml"),
+            sim_journal: Some(SimJournal::in_memory().expect("in-memory journal")),
+            sequence: Some(Arc::new(
+                SequenceStore::in_memory().expect("in-memory sequence store"),
+            )),
+            connections: Mutex::new(HashMap::new()),
+            profiles: ProfileSet::builtin(),
+            profile_keys: Vec::new(),
         }
     }
 
@@ -143,6 +303,172 @@ impl Gmp3Driver {
     fn payload_str<'a>(&self, cmd: &'a PendingCommand, key: &str) -> Option<&'a str> {
         cmd.payload.get(key).and_then(|v| v.as_str())
     }
+
+    /// The port `--health` probes: the entry's `port`, else its
+    /// `vendor_profile`'s default — the fallback [`Self::connection`] takes
+    /// from the command's profile. With neither, the profiles must agree.
+    fn probe_port(&self, device: &Gmp3DeviceEntry) -> std::result::Result<u16, String> {
+        if let Some(port) = device.port {
+            return Ok(port);
+        }
+        if let Some(id) = &device.vendor_profile {
+            return self
+                .profiles
+                .resolve(id)
+                .map(|p| p.default_port)
+                .ok_or_else(|| {
+                    format!(
+                        "vendor_profile '{id}' is not a known profile (known: {})",
+                        self.profiles.known_ids().join(", ")
+                    )
+                });
+        }
+        match self.profiles.default_ports()[..] {
+            [port] => Ok(port),
+            ref ports => Err(format!(
+                "no `port` or `vendor_profile` set, and the vendor profiles' default ports differ ({ports:?})"
+            )),
+        }
+    }
+
+    /// The device's persistent connection, created on first use.
+    fn connection(
+        &self,
+        device: &Gmp3DeviceEntry,
+        profile: &VendorProfile,
+    ) -> Result<Arc<Mutex<Connection>>> {
+        let mut conns = self.connections.lock().expect("gmp3 connections poisoned");
+        if let Some(c) = conns.get(&device.serial) {
+            return Ok(c.clone());
+        }
+        let host = device.host().ok_or_else(|| {
+            anyhow!(
+                "gmp3: device '{}' in {} has mode = \"emulator\" but no `host`",
+                device.serial,
+                self.config_path.display()
+            )
+        })?;
+        let sequence = self.sequence.clone().ok_or_else(|| {
+            anyhow!(
+                "gmp3: sequence store unavailable — refusing to number a transaction for '{}'",
+                device.serial
+            )
+        })?;
+        let mut endpoint = TcpEndpoint::new(host, device.port.unwrap_or(profile.default_port));
+        endpoint.connect_timeout = profile.connect_timeout;
+        endpoint.io_timeout = device
+            .timeout_secs
+            .map_or(profile.reply_timeout, Duration::from_secs);
+        let conn = Arc::new(Mutex::new(
+            Connection::new(endpoint, device.serial.as_str(), sequence)
+                .with_link_config(profile.link)
+                .with_tags(profile.tags.clone()),
+        ));
+        conns.insert(device.serial.clone(), conn.clone());
+        Ok(conn)
+    }
+
+    /// The framed path: ask the device about the idempotency key, and only if
+    /// it never completed that transaction, send the request.
+    async fn execute_framed(
+        &self,
+        cmd: &PendingCommand,
+        device: &Gmp3DeviceEntry,
+        profile: &VendorProfile,
+        family: CommandFamily,
+    ) -> Result<CommandOutcome> {
+        let reference = cmd
+            .idempotency_key
+            .clone()
+            .unwrap_or_else(|| cmd.id.clone());
+        let request = protocol::request(family, &cmd.payload, &reference)
+            .with_context(|| format!("gmp3: command {} ({})", cmd.id, cmd.kind))?;
+        let conn = self.connection(device, profile)?;
+        let key = cmd.idempotency_key.clone();
+        tracing::info!(
+            serial = %device.serial,
+            vendor = %profile.id,
+            kind = %cmd.kind,
+            attempt = cmd.attempts,
+            "gmp3: EMULATOR — framed exchange, no vendor session"
+        );
+        let mut result = tokio::task::spawn_blocking(move || -> Result<Value> {
+            let mut conn = conn.lock().expect("gmp3 connection poisoned");
+            if let Some(key) = key {
+                let query = protocol::status_query(&key);
+                let reply = conn.exchange(query.clone())?;
+                if let Some(mut done) = protocol::recorded(family, &query, &reply)? {
+                    done["replayed"] = Value::Bool(true);
+                    return Ok(done);
+                }
+            }
+            let reply = conn.exchange(request.clone())?;
+            protocol::interpret(family, &request, &reply)
+        })
+        .await
+        .context("gmp3: exchange task")??;
+        if result.get("replayed").is_some() {
+            tracing::info!(
+                serial = %device.serial,
+                kind = %cmd.kind,
+                attempt = cmd.attempts,
+                "gmp3: transaction already on device, returning recorded result"
+            );
+        }
+        result["emulator"] = Value::Bool(true);
+        Ok(CommandOutcome {
+            status: "done".to_string(),
+            result,
+            error: None,
+        })
+    }
+
+    /// A profile file pushed by the cloud: verified, then written next to
+    /// `gmp3.toml`, where the reload watcher swaps in a driver built on it.
+    fn install_profiles(&self, cmd: &PendingCommand) -> Result<CommandOutcome> {
+        let field = |key: &str| {
+            self.payload_str(cmd, key).ok_or_else(|| {
+                anyhow!(
+                    "gmp3: {} command {} has no `{key}`",
+                    profiles::UPDATE_KIND,
+                    cmd.id
+                )
+            })
+        };
+        let data_dir = self
+            .config_path
+            .parent()
+            .ok_or_else(|| anyhow!("gmp3: no data dir to install profiles into"))?;
+        let set = ProfileSet::install(
+            data_dir,
+            field("profiles")?,
+            field("signature")?,
+            &self.profile_keys,
+        )
+        .context("gmp3: pushed profile file refused")?;
+        tracing::info!(
+            profiles = %set.known_ids().join(","),
+            unknown_keys = set.unknown_keys().len(),
+            "gmp3: profile file installed"
+        );
+        Ok(CommandOutcome {
+            status: "done".to_string(),
+            result: serde_json::json!({
+                "profiles": set.known_ids(),
+                "unknownKeys": set.unknown_keys(),
+            }),
+            error: None,
+        })
+    }
+}
+
+/// The release keys pinned in this build. A profile file cannot be verified
+/// without them; an unparseable list is reported by the updater too.
+fn release_keys() -> Vec<VerifyingKey> {
+    crate::updater::pinned_keys().unwrap_or_else(|e| {
+        tracing::warn!(error = %e, "gmp3: pinned release keys unusable; profile files cannot be verified");
+        Vec::new()
+    })
 }
 
 #[async_trait]
@@ -151,7 +477,15 @@ impl LocalDriver for Gmp3Driver {
         "gmp3"
     }
 
+    fn verifies_idempotency(&self) -> bool {
+        true
+    }
+
     async fn execute(&self, cmd: &PendingCommand) -> Result<CommandOutcome> {
+        if cmd.kind == profiles::UPDATE_KIND {
+            return self.install_profiles(cmd);
+        }
+
         // 1. Defensive protocol check (dispatch already routed us GMP-3 traffic).
         let protocol = self.payload_str(cmd, "protocol").unwrap_or("");
         if protocol != "GMP3" {
@@ -164,12 +498,12 @@ impl LocalDriver for Gmp3Driver {
 
         // 2. Resolve the vendor profile from the command.
         let vendor_profile = self.payload_str(cmd, "vendorProfile").unwrap_or("");
-        let profile: &VendorProfile = profiles::resolve(vendor_profile).ok_or_else(|| {
+        let profile: &VendorProfile = self.profiles.resolve(vendor_profile).ok_or_else(|| {
             anyhow!(
                 "gmp3: unknown vendorProfile '{}' for command {} (known: {})",
                 vendor_profile,
                 cmd.id,
-                profiles::known_ids().join(", ")
+                self.profiles.known_ids().join(", ")
             )
         })?;
 
@@ -181,6 +515,15 @@ impl LocalDriver for Gmp3Driver {
                 cmd.id
             )
         })?;
+        if !profile.supports(family) {
+            return Err(anyhow!(
+                "gmp3: {} ({}) does not take {} (command {})",
+                profile.display_name,
+                profile.id,
+                cmd.kind,
+                cmd.id
+            ));
+        }
 
         // 4. Resolve the on-prem device config by serial. No entry ⇒ fail closed
         //    (never fabricate a device), naming the config file to fix.
@@ -209,6 +552,54 @@ impl LocalDriver for Gmp3Driver {
 
         // 5. Simulator vs. real.
         if device.is_simulator() {
+            // A refund that names no sale is refused here as a device would.
+            protocol::preflight(family, &cmd.payload)
+                .with_context(|| format!("gmp3: command {} ({})", cmd.id, cmd.kind))?;
+
+            // 5a. Ask the (simulated) device whether this transaction already
+            //     happened before sending it again.
+            if let Some(key) = cmd.idempotency_key.as_deref() {
+                match &self.sim_journal {
+                    Some(j) => {
+                        if let Some(recorded) = j.lookup(fiscal_serial, key)? {
+                            // A key reused by another kind of command names a
+                            // different transaction; its result is not ours.
+                            if recorded.kind != cmd.kind {
+                                bail!(
+                                    "gmp3: idempotency key '{}' was already used for a {} on {}; refusing to run command {} ({}) under it",
+                                    key,
+                                    recorded.kind,
+                                    fiscal_serial,
+                                    cmd.id,
+                                    cmd.kind
+                                );
+                            }
+                            let mut result = recorded.result;
+                            tracing::info!(
+                                serial = %fiscal_serial,
+                                kind = %cmd.kind,
+                                attempt = cmd.attempts,
+                                "gmp3: SIMULATOR — transaction already on device, returning recorded result"
+                            );
+                            result["replayed"] = Value::Bool(true);
+                            return Ok(CommandOutcome {
+                                status: "done".to_string(),
+                                result,
+                                error: None,
+                            });
+                        }
+                    }
+                    None if cmd.attempts > 1 => {
+                        return Err(anyhow!(
+                            "gmp3: cannot verify idempotency key '{}' for replayed command {} — simulator journal unavailable",
+                            key,
+                            cmd.id
+                        ));
+                    }
+                    None => {}
+                }
+            }
+
             let outcome = SimOutcome::parse(device.sim_outcome.as_deref().unwrap_or("approve"));
             tracing::info!(
                 serial = %fiscal_serial,
@@ -218,11 +609,18 @@ impl LocalDriver for Gmp3Driver {
                 "gmp3: SIMULATOR — no hardware touched"
             );
             return match protocol::simulate(family, &cmd.id, outcome) {
-                SimResult::Done(result) => Ok(CommandOutcome {
-                    status: "done".to_string(),
-                    result,
-                    error: None,
-                }),
+                SimResult::Done(result) => {
+                    if let (Some(key), Some(j)) =
+                        (cmd.idempotency_key.as_deref(), &self.sim_journal)
+                    {
+                        j.record(fiscal_serial, key, &cmd.kind, &result)?;
+                    }
+                    Ok(CommandOutcome {
+                        status: "done".to_string(),
+                        result,
+                        error: None,
+                    })
+                }
                 // A simulated error/decline-as-error drives the SAME honest
                 // failure path a real device error/timeout would (kind-aware
                 // parking + failed ack), so the recovery rail is exercised too.
@@ -230,24 +628,80 @@ impl LocalDriver for Gmp3Driver {
             };
         }
 
+        if device.is_emulator() {
+            return self.execute_framed(cmd, device, profile, family).await;
+        }
+
         // Real mode: fail closed until the vendor's certified handshake ships.
         // We do NOT open a socket we cannot complete a transaction over —
         // partial contact with a fiscal/card device is worse than none.
         if !profile.real_impl_ready {
             return Err(anyhow!(protocol::real_mode_unavailable(
-                profile.display_name,
-                profile.id
+                &profile.display_name,
+                &profile.id
             )));
         }
 
-        // Phase 1: the certified handshake/TLV/crypto over transport::TcpEndpoint
-        // lands here (build request → request_reply → parse → outcome). Until a
-        // profile flips real_impl_ready true, this is unreachable.
+        // Phase 1: the certified session crypto around transport::Connection
+        // lands here (STATUS for `cmd.idempotency_key` → build the codec
+        // request → exchange → parse → outcome). The profile loader refuses
+        // `real_impl_ready = true` until then, so only a built-in profile
+        // flipped in code gets here — and it still fails closed.
         Err(anyhow!(
-            "gmp3: real transport for {} is marked ready but not wired — build error",
+            "gmp3: profile {} is marked real_impl_ready but no real transport is implemented",
             profile.id
         ))
     }
+
+    async fn readiness(&self) -> Vec<DeviceReadiness> {
+        if self.devices.is_empty() {
+            return vec![DeviceReadiness {
+                driver: "gmp3".to_string(),
+                device: self.config_path.display().to_string(),
+                ready: false,
+                detail: "no devices configured — GMP-3 commands will fail".to_string(),
+            }];
+        }
+        let mut out = Vec::with_capacity(self.devices.len());
+        for device in &self.devices {
+            let (ready, detail) = if device.is_simulator() {
+                (true, "simulator — no hardware touched".to_string())
+            } else if device.is_emulator() {
+                match (device.host(), self.probe_port(device)) {
+                    (None, _) => (false, "emulator mode requires a `host`".to_string()),
+                    (_, Err(e)) => (false, e),
+                    (Some(host), Ok(port)) => match probe_tcp(host, port).await {
+                        Ok(()) => (true, format!("emulator at tcp {host}:{port} reachable")),
+                        Err(e) => (false, e.to_string()),
+                    },
+                }
+            } else {
+                match (device.host(), self.probe_port(device)) {
+                    (None, _) => (false, "real mode requires a `host`".to_string()),
+                    (_, Err(e)) => (false, e),
+                    (Some(host), Ok(port)) => {
+                        match probe_tcp(host, port).await {
+                            // Reachable is not enough: until a vendor handshake
+                            // ships, every real-mode command fails closed.
+                            Ok(()) if !self.profiles.real_mode_available() => (
+                                false,
+                                format!("tcp {host}:{port} reachable, but real mode fails closed until a certified vendor handshake ships"),
+                            ),
+                            Ok(()) => (true, format!("tcp {host}:{port} reachable")),
+                            Err(e) => (false, e.to_string()),
+                        }
+                    }
+                }
+            };
+            out.push(DeviceReadiness {
+                driver: "gmp3".to_string(),
+                device: device.serial.clone(),
+                ready,
+                detail,
+            });
+        }
+        out
+    }
 }
 
 /// Load + validate `gmp3.toml`. Errors if the file is missing or unparseable, or
@@ -264,6 +718,21 @@ fn load_config(path: &Path) -> Result<Vec<Gmp3DeviceEntry>> {
             path.display()
         ));
     }
+    for (i, d) in cfg.device.iter().enumerate() {
+        if d.serial.trim().is_empty() {
+            return Err(anyhow!(
+                "gmp3 config {}: a device has no serial",
+                path.display()
+            ));
+        }
+        if cfg.device[..i].iter().any(|e| e.serial == d.serial) {
+            return Err(anyhow!(
+                "gmp3 config {}: serial '{}' is listed twice",
+                path.display(),
+                d.serial
+            ));
+        }
+    }
     Ok(cfg.device)
 }
 
@@ -277,6 +746,8 @@ fn cmd(id: &str, kind: &str, payload: serde_json::Value) -> PendingCommand {
         payload,
         priority: 10,
         attempts: 0,
+        idempotency_key: None,
+        origin: Default::default(),
     }
 }
 
@@ -292,6 +763,8 @@ mod tests {
             sim_outcome: Some(outcome.to_string()),
             host: None,
             port: None,
+            vendor_profile: None,
+            timeout_secs: None,
         }
     }
 
@@ -375,6 +848,8 @@ mod tests {
             sim_outcome: None,
             host: Some("192.168.1.60".to_string()),
             port: Some(59000),
+            vendor_profile: None,
+            timeout_secs: None,
         }]);
         let err = d
             .execute(&cmd("c-5", "charge_card", charge_payload("SER-1")))
@@ -419,6 +894,297 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn replayed_charge_returns_the_recorded_result_instead_of_charging_again() {
+        let d = Gmp3Driver::with_devices(vec![sim_device("SER-1", "approve")]);
+        let mut first = cmd("c-r", "charge_card", charge_payload("SER-1"));
+        first.attempts = 1;
+        first.idempotency_key = Some("order-9-charge".to_string());
+        let original = d.execute(&first).await.unwrap();
+        assert!(original.result.get("replayed").is_none());
+
+        // Same key, re-dispatched after a crash (attempts bumped by recovery),
+        // and the device would now DECLINE a fresh charge — proving the
+        // recorded approval is returned rather than a second transaction.
+        let d = Gmp3Driver {
+            devices: vec![sim_device("SER-1", "decline")],
+            ..d
+        };
+        let mut replay = first.clone();
+        replay.attempts = 2;
+        let out = d.execute(&replay).await.unwrap();
+        assert_eq!(out.result["replayed"], true);
+        assert_eq!(out.result["approved"], true);
+        assert_eq!(out.result["rrn"], original.result["rrn"]);
+    }
+
+    #[tokio::test]
+    async fn a_key_reused_by_another_kind_is_refused_not_replayed() {
+        // A refund carrying the key of an approved charge must not come back
+        // with the charge's approval as its own result.
+        let d = Gmp3Driver::with_devices(vec![sim_device("SER-1", "approve")]);
+        let mut charge = cmd("c-k", "charge_card", charge_payload("SER-1"));
+        charge.idempotency_key = Some("order-9".to_string());
+        d.execute(&charge).await.unwrap();
+
+        let mut payload = charge_payload("SER-1");
+        payload["rrn"] = json!("SIM-RRN-1");
+        let mut refund = cmd("r-k", "refund_card", payload);
+        refund.idempotency_key = Some("order-9".to_string());
+        let err = d.execute(&refund).await.unwrap_err().to_string();
+        assert!(err.contains("already used for a charge_card"), "got: {err}");
+    }
+
+    #[tokio::test]
+    async fn unknown_key_is_sent_fresh_on_replay() {
+        // The crash happened before the device saw the charge: lookup misses,
+        // so the charge goes through normally.
+        let d = Gmp3Driver::with_devices(vec![sim_device("SER-1", "approve")]);
+        let mut replay = cmd("c-n", "charge_card", charge_payload("SER-1"));
+        replay.attempts = 2;
+        replay.idempotency_key = Some("never-sent".to_string());
+        let out = d.execute(&replay).await.unwrap();
+        assert_eq!(out.result["approved"], true);
+        assert!(out.result.get("replayed").is_none());
+    }
+
+    fn start_emulator(emu: emulator::Emulator) -> (Arc<emulator::Emulator>, Gmp3DeviceEntry) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let emu = Arc::new(emu);
+        emu.clone().spawn(listener);
+        let device = Gmp3DeviceEntry {
+            serial: "EMU-1".to_string(),
+            mode: Some("emulator".to_string()),
+            sim_outcome: None,
+            host: Some("127.0.0.1".to_string()),
+            port: Some(port),
+            vendor_profile: None,
+            timeout_secs: Some(1),
+        };
+        (emu, device)
+    }
+
+    fn keyed_charge(id: &str, key: &str, attempts: i32) -> PendingCommand {
+        let mut c = cmd(id, "charge_card", charge_payload("EMU-1"));
+        c.idempotency_key = Some(key.to_string());
+        c.attempts = attempts;
+        c
+    }
+
+    #[tokio::test]
+    async fn emulator_charge_runs_the_framed_path() {
+        use emulator::{Emulator, Step};
+        let (emu, device) =
+            start_emulator(Emulator::new("EMU-1").unwrap().with_script([Step::Decline]));
+        let d = Gmp3Driver::with_devices(vec![device]);
+
+        let declined = d.execute(&keyed_charge("c-1", "k-1", 1)).await.unwrap();
+        assert_eq!(declined.result["approved"], false);
+        assert_eq!(declined.result["responseCode"], "05");
+
+        let approved = d.execute(&keyed_charge("c-2", "k-2", 1)).await.unwrap();
+        assert_eq!(approved.result["approved"], true);
+        assert_eq!(approved.result["emulator"], true);
+        assert!(approved.result["rrn"].as_str().unwrap().starts_with("EMU"));
+        assert!(approved.result["fiscalNo"].as_str().is_some());
+
+        let sent = emu.transactions();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1].field_str(codec::tag::AMOUNT), Some("12345"));
+        assert_eq!(sent[1].field_str(codec::tag::ECR_REF), Some("k-2"));
+    }
+
+    /// A device that goes quiet mid-sale fails the dispatch; the retry asks
+    /// first, learns the sale never completed, and sends it fresh.
+    #[tokio::test]
+    async fn emulator_hang_times_out_and_the_retry_is_sent_fresh() {
+        use emulator::{Emulator, Step};
+        let (emu, device) =
+            start_emulator(Emulator::new("EMU-1").unwrap().with_script([Step::Hang]));
+        let d = Gmp3Driver::with_devices(vec![device]);
+
+        let err = d.execute(&keyed_charge("c-1", "k-1", 1)).await.unwrap_err();
+        assert!(format!("{err:#}").contains("no GMP-3 frame"), "{err:#}");
+
+        let out = d.execute(&keyed_charge("c-1", "k-1", 2)).await.unwrap();
+        assert_eq!(out.result["approved"], true);
+        assert!(out.result.get("replayed").is_none());
+        assert_eq!(emu.transactions().len(), 2);
+    }
+
+    /// A reply under the wrong sequence number is not an answer — even an
+    /// approval. The sale did happen, so the replay returns it.
+    #[tokio::test]
+    async fn emulator_misnumbered_reply_is_refused_and_replay_recovers_it() {
+        use emulator::{Emulator, Step};
+        let (emu, device) = start_emulator(
+            Emulator::new("EMU-1")
+                .unwrap()
+                .with_script([Step::DuplicateSeq]),
+        );
+        let d = Gmp3Driver::with_devices(vec![device]);
+
+        let err = d.execute(&keyed_charge("c-1", "k-1", 1)).await.unwrap_err();
+        assert!(format!("{err:#}").contains("seq"), "{err:#}");
+
+        let out = d.execute(&keyed_charge("c-1", "k-1", 2)).await.unwrap();
+        assert_eq!(out.result["replayed"], true);
+        assert_eq!(out.result["approved"], true);
+        assert_eq!(emu.transactions().len(), 1, "charged once");
+    }
+
+    #[tokio::test]
+    async fn simulator_refund_must_name_the_sale() {
+        let d = Gmp3Driver::with_devices(vec![sim_device("SER-1", "approve")]);
+        let mut payload = charge_payload("SER-1");
+        let err = d
+            .execute(&cmd("r-1", "refund_card", payload.clone()))
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("must name the original transaction"),
+            "{err:#}"
+        );
+
+        payload["rrn"] = json!("SIM-c-1");
+        let out = d
+            .execute(&cmd("r-2", "refund_card", payload))
+            .await
+            .unwrap();
+        assert_eq!(out.result["approved"], true);
+        assert!(out.result["rrn"].as_str().unwrap().starts_with("SIM-REF-"));
+    }
+
+    /// A day on the emulator: a sale, a tip on it, a refund and a split
+    /// tender, then the batch close reports what the card took.
+    #[tokio::test]
+    async fn emulator_batch_close_reports_the_day() {
+        let (_emu, device) = start_emulator(emulator::Emulator::new("EMU-1").unwrap());
+        let d = Gmp3Driver::with_devices(vec![device]);
+        let run = |id: &str, kind: &str, extra: serde_json::Value| {
+            let mut payload = charge_payload("EMU-1");
+            for (k, v) in extra.as_object().unwrap() {
+                payload[k] = v.clone();
+            }
+            let mut c = cmd(id, kind, payload);
+            c.idempotency_key = Some(format!("key-{id}"));
+            c.attempts = 1;
+            c
+        };
+
+        let sale = d
+            .execute(&run("s", "charge_card", json!({})))
+            .await
+            .unwrap();
+        let rrn = sale.result["rrn"].clone();
+        let tip = d
+            .execute(&run(
+                "t",
+                "tip_adjust",
+                json!({ "rrn": rrn, "tipCents": 1000 }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(tip.result["approved"], true);
+        let refund = d
+            .execute(&run(
+                "r",
+                "refund_card",
+                json!({ "rrn": rrn, "amountCents": 345 }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(refund.result["approved"], true);
+        let split = d
+            .execute(&run(
+                "p",
+                "charge_split_tender",
+                json!({ "payments": [
+                    { "method": "card", "amountCents": 600 },
+                    { "method": "cash", "amountCents": 400 },
+                ] }),
+            ))
+            .await
+            .unwrap();
+        assert!(split.result["fiscalNo"].as_str().is_some());
+
+        let close = d
+            .execute(&run("b", "batch_close", json!({})))
+            .await
+            .unwrap();
+        assert_eq!(
+            close.result["totals"],
+            json!({ "saleCount": 2, "saleCents": 12345 + 1000 + 600, "refundCount": 1, "refundCents": 345 })
+        );
+        let again = d
+            .execute(&run("b2", "batch_close", json!({})))
+            .await
+            .unwrap();
+        assert_eq!(again.result["totals"]["saleCount"], 0, "the batch is empty");
+    }
+
+    const BEKO: &str = r#"
+        [[profile]]
+        id = "beko.300tr"
+        display_name = "Beko 300TR"
+        families = ["charge_card", "capability_probe"]
+    "#;
+
+    /// A pushed profile file lands next to `gmp3.toml`; a driver built on
+    /// it takes the new vendor's charges and refuses what it does not list.
+    #[tokio::test]
+    async fn pushed_profiles_are_installed_and_their_families_enforced() {
+        use ed25519_dalek::{Signer, SigningKey};
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let keys = vec![key.verifying_key()];
+        let signature = crate::updater::hex_encode(&key.sign(BEKO.as_bytes()).to_bytes());
+        let dir = tempfile::TempDir::new().unwrap();
+        let driver = |profiles| {
+            let mut d = Gmp3Driver::with_devices(vec![sim_device("SER-1", "approve")]);
+            d.config_path = dir.path().join(CONFIG_FILE);
+            d.profiles = profiles;
+            d.profile_keys = keys.clone();
+            d
+        };
+
+        let push = |sig: &str| {
+            cmd(
+                "p-1",
+                profiles::UPDATE_KIND,
+                json!({ "target": "gmp3", "profiles": BEKO, "signature": sig }),
+            )
+        };
+        let d = driver(ProfileSet::builtin());
+        let err = d.execute(&push(&"00".repeat(64))).await.unwrap_err();
+        assert!(format!("{err:#}").contains("refused"), "{err:#}");
+        assert!(!dir.path().join(profiles::PROFILES_FILE).exists());
+
+        let out = d.execute(&push(&signature)).await.unwrap();
+        assert_eq!(out.status, "done");
+        assert_eq!(out.result["profiles"], json!(["paygo.sp630", "beko.300tr"]));
+
+        let d = driver(ProfileSet::load(dir.path(), &keys).unwrap());
+        let mut charge = charge_payload("SER-1");
+        charge["vendorProfile"] = json!("beko.300tr");
+        let out = d.execute(&cmd("c-1", "charge_card", charge)).await.unwrap();
+        assert_eq!(out.result["approved"], true);
+
+        let receipt = json!({
+            "protocol": "GMP3",
+            "vendorProfile": "beko.300tr",
+            "fiscalSerial": "SER-1",
+        });
+        let err = d
+            .execute(&cmd("r-1", "fiscal_receipt", receipt))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("does not take fiscal_receipt"),
+            "{err}"
+        );
+    }
+
     #[test]
     fn loads_devices_from_toml() {
         let dir = tempfile::TempDir::new().unwrap();
@@ -457,7 +1223,78 @@ mod tests {
             sim_outcome: None,
             host: None,
             port: None,
+            vendor_profile: None,
+            timeout_secs: None,
         };
         assert!(!entry.is_simulator());
     }
+
+    #[tokio::test]
+    async fn readiness_reports_real_devices_as_failing_closed() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let real = |serial: &str, host: Option<&str>| Gmp3DeviceEntry {
+            serial: serial.to_string(),
+            mode: Some("real".to_string()),
+            sim_outcome: None,
+            host: host.map(str::to_string),
+            port: Some(port),
+            vendor_profile: None,
+            timeout_secs: None,
+        };
+        let d = Gmp3Driver::with_devices(vec![
+            sim_device("SIM-1", "approve"),
+            real("REAL-1", Some("127.0.0.1")),
+            real("REAL-2", None),
+        ]);
+
+        let r = d.readiness().await;
+        assert!(r[0].ready, "simulator is always ready");
+        assert!(!r[1].ready, "reachable but no certified handshake yet");
+        assert!(r[1].detail.contains("reachable"), "{}", r[1].detail);
+        assert!(!r[2].ready);
+        assert!(r[2].detail.contains("host"));
+    }
+
+    /// With no `port`, readiness probes the device's vendor profile's
+    /// `default_port` — not the generic GMP-3 one.
+    #[tokio::test]
+    async fn readiness_probes_the_vendor_profiles_default_port() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let emulator = |serial: &str, vendor_profile: Option<&str>| Gmp3DeviceEntry {
+            serial: serial.to_string(),
+            mode: Some("emulator".to_string()),
+            sim_outcome: None,
+            host: Some("127.0.0.1".to_string()),
+            port: None,
+            vendor_profile: vendor_profile.map(str::to_string),
+            timeout_secs: None,
+        };
+        let mut d = Gmp3Driver::with_devices(vec![
+            emulator("EMU-1", Some("beko.300tr")),
+            emulator("EMU-2", Some("no.such")),
+            emulator("EMU-3", None),
+        ]);
+        d.profiles = ProfileSet::parse_unverified(&format!(
+            "[[profile]]\nid = \"beko.300tr\"\ndefault_port = {port}\n"
+        ))
+        .unwrap();
+
+        let r = d.readiness().await;
+        assert!(r[0].ready, "{}", r[0].detail);
+        assert!(r[0].detail.contains(&format!(":{port}")), "{}", r[0].detail);
+        assert!(!r[1].ready);
+        assert!(
+            r[1].detail.contains("not a known profile"),
+            "{}",
+            r[1].detail
+        );
+        assert!(!r[2].ready, "profiles disagree on the port");
+        assert!(
+            r[2].detail.contains("default ports differ"),
+            "{}",
+            r[2].detail
+        );
+    }
 }
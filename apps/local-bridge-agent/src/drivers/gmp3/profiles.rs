@@ -3,69 +3,589 @@
 //! Every certified Turkish *Yeni Nesil ÖKC* speaks the same GİB GMP-3 message
 //! family, so the driver is protocol-first, not vendor-first: one `gmp3` driver
 //! plus one small profile per brand. A profile carries only the bits that
-//! actually differ between vendors: the default TCP port, and whether that
-//! brand's real (certified) handshake is implemented yet. Adding a new POS/ÖKC
-//! brand later is a single entry here (plus its cert/handshake specifics in
-//! `protocol.rs` when the real driver lands) — no new driver, no schema change.
+//! actually differ between vendors: the default TCP port, timeouts and link
+//! retries, the command families the device takes, field quirks (checksum,
+//! tag numbering) and whether that brand's real (certified) handshake is
+//! implemented yet.
+//!
+//! ## Where profiles come from
+//! The built-in list below is compiled in and always present. On top of it,
+//! `gmp3_profiles.toml` in the bridge data dir adds profiles and replaces
+//! built-ins of the same id, so onboarding a brand (Beko, Hugin, Profilo,
+//! Ingenico, Verifone, Pavo, …) is a file, not an agent release:
+//!
+//! ```toml
+//! [[profile]]
+//! id = "beko.300tr"                 # the cloud's vendorProfile
+//! display_name = "Beko 300TR"
+//! default_port = 59000
+//! families = ["charge_card", "void_card", "fiscal_receipt", "capability_probe"]
+//! real_impl_ready = false
+//!
+//! [profile.timeouts]
+//! connect_secs = 10
+//! reply_secs = 30
+//! ack_ms = 2000
+//! max_retries = 3
+//!
+//! [profile.quirks]
+//! checksum = "lrc"                  # crc16 (default) | lrc
+//! tags = { rrn = 0x0041 }           # fields this vendor numbers differently
+//! ```
+//!
+//! The file ships with the agent or is pushed by the cloud ([`UPDATE_KIND`]),
+//! and is only read with a valid detached signature, `gmp3_profiles.toml.sig`:
+//! hex ed25519 over the file's exact bytes, by one of the release keys pinned
+//! in this build for updates (see [`crate::updater`]). The data dir is
+//! writable by whoever could edit the file, so it cannot be trusted to say
+//! which devices take real money. A file that is unsigned, does not verify or
+//! does not validate is refused whole; keys the loader does not know are
+//! reported (most are typos) and otherwise ignored. `real_impl_ready = true`
+//! does not validate either while this build has no real transport to run it
+//! on: a file cannot turn on what the agent does not implement.
+
+use super::codec::{tag, Checksum, LinkConfig, TagMap};
+use super::protocol::CommandFamily;
+use anyhow::{anyhow, bail, Context, Result};
+use ed25519_dalek::VerifyingKey;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+/// The profile file in the bridge data dir.
+pub const PROFILES_FILE: &str = "gmp3_profiles.toml";
+/// Its detached signature, next to it.
+pub const SIGNATURE_FILE: &str = "gmp3_profiles.toml.sig";
+/// The command kind by which the cloud pushes a new profile file (payload
+/// `target: "gmp3"`, `profiles`: the file text, `signature`: its hex
+/// signature).
+pub const UPDATE_KIND: &str = "gmp3_profiles_update";
+
+/// The port GMP-3 devices conventionally expose their integration server on:
+/// the `default_port` of a profile that sets none.
+pub const DEFAULT_PORT: u16 = 59000;
 
 /// One vendor's GMP-3 profile.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct VendorProfile {
     /// The `vendorProfile` string the cloud sends in the command payload
     /// (e.g. "paygo.sp630"). Matched verbatim.
-    pub id: &'static str,
+    pub id: String,
     /// Human-readable name, for logs/errors.
-    pub display_name: &'static str,
+    pub display_name: String,
     /// TCP port the device's GMP-3 server listens on when the on-prem
     /// `gmp3.toml` entry omits an explicit `port`.
     pub default_port: u16,
+    pub connect_timeout: Duration,
+    /// How long a request may wait for its reply (a `gmp3.toml`
+    /// `timeout_secs` overrides it per device).
+    pub reply_timeout: Duration,
+    /// Checksum, ACK timeout and retransmissions.
+    pub link: LinkConfig,
+    /// The command families the device takes. Anything else fails before a
+    /// byte is sent.
+    pub families: Vec<CommandFamily>,
+    /// Fields this vendor numbers differently from the codec.
+    pub tags: TagMap,
     /// Whether this brand's REAL (certified) GMP-3 handshake is implemented.
     /// While false, a device configured `mode = "real"` fails closed regardless
     /// of config — the honest boundary until Phase-1 vendor onboarding lands.
     pub real_impl_ready: bool,
 }
 
-/// All known GMP-3 vendor profiles. Paygo is the first concrete binding; other
-/// Turkish ÖKC brands (Beko, Hugin, Profilo, Ingenico, Verifone, Pavo, …) each
-/// become one more entry here as they are onboarded.
-static PROFILES: &[VendorProfile] = &[VendorProfile {
-    id: "paygo.sp630",
-    display_name: "Paygo SP630PRO ECR",
-    // GMP-3 devices expose their integration server around :59000 on the LAN.
-    default_port: 59000,
-    // Phase 0: the real cert-handshake driver is not implemented yet. Flip to
-    // true only when the certified Paygo/Token GMP-3 handshake ships (Phase 1).
-    real_impl_ready: false,
-}];
-
-/// Resolve a vendor profile by its `vendorProfile` id, or `None` if unknown.
-pub fn resolve(vendor_profile: &str) -> Option<&'static VendorProfile> {
-    PROFILES.iter().find(|p| p.id == vendor_profile)
+impl VendorProfile {
+    /// A profile with the common defaults: every family, CRC-16, our tag
+    /// numbers, 10 s to connect and 20 s for a reply (a card sale waits on the
+    /// cardholder and an acquirer round trip).
+    fn new(id: &str, display_name: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            display_name: display_name.to_string(),
+            default_port: DEFAULT_PORT,
+            connect_timeout: Duration::from_secs(10),
+            reply_timeout: Duration::from_secs(20),
+            link: LinkConfig::default(),
+            families: CommandFamily::ALL.to_vec(),
+            tags: TagMap::default(),
+            real_impl_ready: false,
+        }
+    }
+
+    pub fn supports(&self, family: CommandFamily) -> bool {
+        self.families.contains(&family)
+    }
+}
+
+/// The compiled-in profiles: the fallback when no profile file is present or
+/// usable. Paygo is the first concrete binding.
+fn builtin() -> Vec<VendorProfile> {
+    vec![VendorProfile {
+        // Phase 0: the real cert-handshake driver is not implemented yet.
+        // Flip to true only when the certified Paygo/Token GMP-3 handshake
+        // ships (Phase 1).
+        real_impl_ready: false,
+        ..VendorProfile::new("paygo.sp630", "Paygo SP630PRO ECR")
+    }]
+}
+
+/// The profiles a driver resolves `vendorProfile` against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileSet {
+    profiles: Vec<VendorProfile>,
+    /// Keys in the profile file the loader did not recognise, as
+    /// `profile[<id>].<key>` paths.
+    unknown_keys: Vec<String>,
+}
+
+impl ProfileSet {
+    /// Only the compiled-in profiles.
+    pub fn builtin() -> Self {
+        Self {
+            profiles: builtin(),
+            unknown_keys: Vec::new(),
+        }
+    }
+
+    /// The built-ins overlaid with `data_dir`'s profile file, if there is one.
+    /// A file that is present but unsigned, badly signed or invalid is an
+    /// error: the caller decides between the built-ins and what it has.
+    pub fn load(data_dir: &Path, keys: &[VerifyingKey]) -> Result<Self> {
+        let path = data_dir.join(PROFILES_FILE);
+        let raw = match std::fs::read_to_string(&path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::builtin()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("reading gmp3 profiles {}", path.display()))
+            }
+        };
+        let sig_path = data_dir.join(SIGNATURE_FILE);
+        let signature = std::fs::read_to_string(&sig_path).with_context(|| {
+            format!(
+                "{} has no signature ({} missing)",
+                path.display(),
+                sig_path.display()
+            )
+        })?;
+        Self::verified(&raw, &signature, keys)
+            .with_context(|| format!("gmp3 profiles {}", path.display()))
+    }
+
+    /// Verify a pushed profile file and write it, with its signature, into
+    /// `data_dir` for the reload watcher to pick up. Nothing is written
+    /// unless it verifies and validates.
+    pub fn install(
+        data_dir: &Path,
+        raw: &str,
+        signature: &str,
+        keys: &[VerifyingKey],
+    ) -> Result<Self> {
+        let set = Self::verified(raw, signature, keys)?;
+        // The signature first: the reload watcher keys on the profile file,
+        // so it never sees the new file beside the old signature.
+        write_file(&data_dir.join(SIGNATURE_FILE), signature.trim().as_bytes())?;
+        write_file(&data_dir.join(PROFILES_FILE), raw.as_bytes())?;
+        Ok(set)
+    }
+
+    /// Verify `signature` over `raw`, then parse it over the built-ins.
+    pub fn verified(raw: &str, signature: &str, keys: &[VerifyingKey]) -> Result<Self> {
+        if keys.is_empty() {
+            bail!("no release signing key is pinned in this build, so the file cannot be verified");
+        }
+        crate::updater::verify_signature(keys, raw.as_bytes(), signature, "profile file")?;
+        Self::parse(raw)
+    }
+
+    /// Parse and validate a profile file over the built-ins. Unverified —
+    /// see [`ProfileSet::verified`].
+    fn parse(raw: &str) -> Result<Self> {
+        let file: RawFile = toml::from_str(raw)?;
+        if file.profile.is_empty() {
+            bail!("no [[profile]] entries");
+        }
+        let mut unknown_keys: Vec<String> = file.unknown.keys().cloned().collect();
+        let mut profiles = builtin();
+        let mut seen: Vec<&str> = Vec::new();
+        for raw in &file.profile {
+            let id = raw.id.trim();
+            if id.is_empty() {
+                bail!("a profile has no id");
+            }
+            if seen.contains(&id) {
+                bail!("profile '{id}' is listed twice");
+            }
+            seen.push(id);
+            raw.unknown_keys(&mut unknown_keys);
+            let profile = raw.build().with_context(|| format!("profile '{id}'"))?;
+            match profiles.iter_mut().find(|p| p.id == profile.id) {
+                Some(existing) => *existing = profile,
+                None => profiles.push(profile),
+            }
+        }
+        Ok(Self {
+            profiles,
+            unknown_keys,
+        })
+    }
+
+    /// Resolve a vendor profile by its `vendorProfile` id, or `None` if
+    /// unknown.
+    pub fn resolve(&self, vendor_profile: &str) -> Option<&VendorProfile> {
+        self.profiles.iter().find(|p| p.id == vendor_profile)
+    }
+
+    /// Every profile's `default_port`, sorted and deduplicated.
+    pub fn default_ports(&self) -> Vec<u16> {
+        let mut ports: Vec<u16> = self.profiles.iter().map(|p| p.default_port).collect();
+        ports.sort_unstable();
+        ports.dedup();
+        ports
+    }
+
+    /// [`ProfileSet::parse`] for tests elsewhere in the driver.
+    #[cfg(test)]
+    pub(super) fn parse_unverified(raw: &str) -> Result<Self> {
+        Self::parse(raw)
+    }
+
+    /// Whether ANY vendor's real (certified) handshake is implemented. While
+    /// false, every `mode = "real"` device fails closed at command time.
+    pub fn real_mode_available(&self) -> bool {
+        self.profiles.iter().any(|p| p.real_impl_ready)
+    }
+
+    /// The ids of every profile — surfaced in the "unknown profile" error so
+    /// an operator sees what IS supported.
+    pub fn known_ids(&self) -> Vec<&str> {
+        self.profiles.iter().map(|p| p.id.as_str()).collect()
+    }
+
+    pub fn unknown_keys(&self) -> &[String] {
+        &self.unknown_keys
+    }
+}
+
+/// Write-then-rename so a crash never leaves a torn file.
+fn write_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = std::path::PathBuf::from(tmp);
+    let mut f =
+        std::fs::File::create(&tmp).with_context(|| format!("creating {}", tmp.display()))?;
+    f.write_all(bytes)?;
+    f.sync_all()?;
+    std::fs::rename(&tmp, path).with_context(|| format!("installing {}", path.display()))
+}
+
+type Unknown = BTreeMap<String, toml::Value>;
+
+#[derive(Deserialize)]
+struct RawFile {
+    #[serde(default)]
+    profile: Vec<RawProfile>,
+    #[serde(flatten)]
+    unknown: Unknown,
 }
 
-/// The ids of every registered profile — surfaced in the "unknown profile"
-/// error so an operator sees what IS supported.
-pub fn known_ids() -> Vec<&'static str> {
-    PROFILES.iter().map(|p| p.id).collect()
+#[derive(Deserialize)]
+struct RawProfile {
+    id: String,
+    display_name: Option<String>,
+    default_port: Option<u16>,
+    families: Option<Vec<String>>,
+    #[serde(default)]
+    real_impl_ready: bool,
+    #[serde(default)]
+    timeouts: RawTimeouts,
+    #[serde(default)]
+    quirks: RawQuirks,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
+
+#[derive(Deserialize, Default)]
+struct RawTimeouts {
+    connect_secs: Option<u64>,
+    reply_secs: Option<u64>,
+    ack_ms: Option<u64>,
+    max_retries: Option<u32>,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
+
+#[derive(Deserialize, Default)]
+struct RawQuirks {
+    checksum: Option<String>,
+    #[serde(default)]
+    tags: BTreeMap<String, u16>,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
+
+impl RawProfile {
+    fn unknown_keys(&self, out: &mut Vec<String>) {
+        let id = self.id.trim();
+        for (section, keys) in [
+            ("", &self.unknown),
+            ("timeouts.", &self.timeouts.unknown),
+            ("quirks.", &self.quirks.unknown),
+        ] {
+            out.extend(keys.keys().map(|k| format!("profile[{id}].{section}{k}")));
+        }
+    }
+
+    fn build(&self) -> Result<VendorProfile> {
+        let id = self.id.trim();
+        if self.real_impl_ready {
+            bail!("real_impl_ready = true, but this build has no real GMP-3 transport — only simulator and emulator modes run");
+        }
+        let mut p = VendorProfile::new(id, self.display_name.as_deref().unwrap_or(id));
+        if let Some(port) = self.default_port {
+            if port == 0 {
+                bail!("default_port must not be 0");
+            }
+            p.default_port = port;
+        }
+        if let Some(families) = &self.families {
+            if families.is_empty() {
+                bail!("families is empty — the profile would take no command");
+            }
+            p.families = families
+                .iter()
+                .map(|f| {
+                    super::protocol::classify(f).ok_or_else(|| {
+                        anyhow!(
+                            "unknown family '{f}' (known: {})",
+                            CommandFamily::ALL.map(CommandFamily::kind).join(", ")
+                        )
+                    })
+                })
+                .collect::<Result<_>>()?;
+        }
+
+        let t = &self.timeouts;
+        let positive = |v: Option<u64>, name: &str| -> Result<Option<u64>> {
+            match v {
+                Some(0) => bail!("timeouts.{name} must be positive"),
+                v => Ok(v),
+            }
+        };
+        if let Some(s) = positive(t.connect_secs, "connect_secs")? {
+            p.connect_timeout = Duration::from_secs(s);
+        }
+        if let Some(s) = positive(t.reply_secs, "reply_secs")? {
+            p.reply_timeout = Duration::from_secs(s);
+        }
+        if let Some(ms) = positive(t.ack_ms, "ack_ms")? {
+            p.link.ack_timeout = Duration::from_millis(ms);
+        }
+        if let Some(n) = t.max_retries {
+            p.link.max_retries = n;
+        }
+
+        if let Some(c) = &self.quirks.checksum {
+            p.link.checksum = c.parse::<Checksum>()?;
+        }
+        let mut pairs = Vec::new();
+        for (name, wire) in &self.quirks.tags {
+            let ours = tag::by_name(name).ok_or_else(|| {
+                anyhow!(
+                    "quirks.tags: unknown field '{name}' (known: {})",
+                    tag::ALL
+                        .iter()
+                        .map(|(n, _)| *n)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?;
+            pairs.push((ours, *wire));
+        }
+        p.tags = TagMap::new(pairs).context("quirks.tags")?;
+        Ok(p)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::updater::hex_encode;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const BEKO: &str = r#"
+        [[profile]]
+        id = "beko.300tr"
+        display_name = "Beko 300TR"
+        default_port = 4500
+        families = ["charge_card", "capability_probe"]
+        reply_secs = 5
+
+        [profile.timeouts]
+        reply_secs = 45
+        max_retries = 5
+
+        [profile.quirks]
+        checksum = "lrc"
+        tags = { rrn = 0x0041 }
+        emoji = true
+    "#;
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn sign(raw: &str) -> String {
+        hex_encode(&key().sign(raw.as_bytes()).to_bytes())
+    }
 
     #[test]
     fn resolves_paygo_sp630() {
-        let p = resolve("paygo.sp630").expect("paygo profile registered");
+        let set = ProfileSet::builtin();
+        let p = set
+            .resolve("paygo.sp630")
+            .expect("paygo profile registered");
         assert_eq!(p.default_port, 59000);
         assert!(
             !p.real_impl_ready,
             "Phase 0 ships the Paygo real handshake as not-ready (fail-closed)"
         );
+        assert!(CommandFamily::ALL.into_iter().all(|f| p.supports(f)));
     }
 
     #[test]
     fn unknown_profile_resolves_to_none() {
-        assert!(resolve("nope.unknown").is_none());
-        assert!(known_ids().contains(&"paygo.sp630"));
+        let set = ProfileSet::builtin();
+        assert!(set.resolve("nope.unknown").is_none());
+        assert!(set.known_ids().contains(&"paygo.sp630"));
+    }
+
+    #[test]
+    fn file_profiles_add_to_the_builtins_and_report_unknown_keys() {
+        let set = ProfileSet::parse(BEKO).unwrap();
+        assert!(set.resolve("paygo.sp630").is_some(), "built-ins stay");
+        let beko = set.resolve("beko.300tr").unwrap();
+        assert_eq!(beko.default_port, 4500);
+        assert_eq!(beko.reply_timeout, Duration::from_secs(45));
+        assert_eq!(beko.connect_timeout, Duration::from_secs(10), "default");
+        assert_eq!(beko.link.max_retries, 5);
+        assert_eq!(beko.link.checksum, Checksum::Lrc);
+        assert!(beko.supports(CommandFamily::ChargeCard));
+        assert!(!beko.supports(CommandFamily::FiscalReport));
+        assert!(!beko.tags.is_empty());
+        assert!(!beko.real_impl_ready);
+        assert_eq!(
+            set.unknown_keys(),
+            [
+                "profile[beko.300tr].reply_secs",
+                "profile[beko.300tr].quirks.emoji"
+            ]
+        );
+    }
+
+    #[test]
+    fn a_file_profile_replaces_the_builtin_of_the_same_id() {
+        let set = ProfileSet::parse(
+            r#"
+            [[profile]]
+            id = "paygo.sp630"
+            default_port = 60000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(set.known_ids(), ["paygo.sp630"]);
+        let p = set.resolve("paygo.sp630").unwrap();
+        assert_eq!(p.default_port, 60000);
+        assert_eq!(p.display_name, "paygo.sp630");
+    }
+
+    #[test]
+    fn invalid_files_are_refused_whole() {
+        for (raw, why) in [
+            ("", "no [[profile]] entries"),
+            ("[[profile]]\nid = \" \"", "no id"),
+            (
+                "[[profile]]\nid = \"a\"\n[[profile]]\nid = \"a\"",
+                "listed twice",
+            ),
+            ("[[profile]]\nid = \"a\"\ndefault_port = 0", "default_port"),
+            (
+                "[[profile]]\nid = \"a\"\nfamilies = []",
+                "families is empty",
+            ),
+            (
+                "[[profile]]\nid = \"a\"\nfamilies = [\"teleport\"]",
+                "teleport",
+            ),
+            (
+                "[[profile]]\nid = \"a\"\ntimeouts = { reply_secs = 0 }",
+                "reply_secs",
+            ),
+            (
+                "[[profile]]\nid = \"a\"\nquirks = { checksum = \"md5\" }",
+                "md5",
+            ),
+            (
+                "[[profile]]\nid = \"a\"\nquirks = { tags = { pin = 9 } }",
+                "'pin'",
+            ),
+            (
+                "[[profile]]\nid = \"a\"\nquirks = { tags = { rrn = 0x20 } }",
+                "wire",
+            ),
+            (
+                "[[profile]]\nid = \"a\"\nreal_impl_ready = \"yes\"",
+                "real_impl_ready",
+            ),
+            (
+                "[[profile]]\nid = \"a\"\nreal_impl_ready = true",
+                "no real GMP-3 transport",
+            ),
+        ] {
+            let err = ProfileSet::parse(raw).expect_err(raw);
+            assert!(format!("{err:#}").contains(why), "{raw}: {err:#}");
+        }
+    }
+
+    #[test]
+    fn the_file_is_only_read_with_a_valid_signature() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let keys = [key().verifying_key()];
+        assert_eq!(
+            ProfileSet::load(dir.path(), &keys).unwrap(),
+            ProfileSet::builtin(),
+            "no file: built-ins"
+        );
+
+        std::fs::write(dir.path().join(PROFILES_FILE), BEKO).unwrap();
+        let err = ProfileSet::load(dir.path(), &keys).unwrap_err();
+        assert!(format!("{err:#}").contains("no signature"), "{err:#}");
+
+        std::fs::write(dir.path().join(SIGNATURE_FILE), sign("something else")).unwrap();
+        let err = ProfileSet::load(dir.path(), &keys).unwrap_err();
+        assert!(format!("{err:#}").contains("does not verify"), "{err:#}");
+
+        std::fs::write(dir.path().join(SIGNATURE_FILE), sign(BEKO)).unwrap();
+        let set = ProfileSet::load(dir.path(), &keys).unwrap();
+        assert!(set.resolve("beko.300tr").is_some());
+
+        let err = ProfileSet::load(dir.path(), &[]).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("no release signing key"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn a_pushed_file_is_written_only_once_it_verifies() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let keys = [key().verifying_key()];
+        assert!(ProfileSet::install(dir.path(), BEKO, &sign("other"), &keys).is_err());
+        assert!(!dir.path().join(PROFILES_FILE).exists());
+        assert!(!dir.path().join(SIGNATURE_FILE).exists());
+
+        let set = ProfileSet::install(dir.path(), BEKO, &sign(BEKO), &keys).unwrap();
+        assert!(set.resolve("beko.300tr").is_some());
+        assert_eq!(ProfileSet::load(dir.path(), &keys).unwrap(), set);
     }
 }
@@ -1,19 +1,26 @@
 //! GMP-3 protocol layer — vendor-neutral.
 //!
-//! Two responsibilities in Phase 0:
+//! Three responsibilities:
 //!   1. Classify the cloud command kind into a GMP-3 command family.
 //!   2. Produce deterministic SIMULATOR outcomes whose JSON shape EXACTLY
 //!      matches what the cloud reads back (payment-terminal `mapAck` and
 //!      fiscal-core `mapReceiptOutcome`/`runReport`), so the whole rail is
 //!      testable end-to-end without certified hardware.
+//!   3. Translate a command into its framed request ([`request`]) and the
+//!      device's reply back into that same JSON shape ([`interpret`]) — the
+//!      framed path `mode = "emulator"` runs today and real mode will run
+//!      inside the vendor session.
 //!
-//! The real (certified) path — the DH + PÖKC cert handshake, AES-CBC + HMAC
-//! framing, and the İşlem Sıra No (transaction sequence number) that Turkish GİB
-//! GMP-3 mandates — is Phase 1. It builds on `transport::TcpEndpoint`; nothing
-//! here fabricates a device reply. Until a vendor profile's `real_impl_ready`
+//! The wire layer — framing, checksums, ACK/NAK ([`super::codec`]) and the
+//! İşlem Sıra No that Turkish GİB GMP-3 mandates ([`super::sequence`]) — is in
+//! place under `transport::Connection`. The vendor-certified part, the DH +
+//! PÖKC cert handshake and AES-CBC + HMAC session, is Phase 1; nothing here
+//! fabricates a device reply. Until a vendor profile's `real_impl_ready`
 //! flips true, `mode = "real"` fails closed via [`real_mode_unavailable`].
 
-use serde_json::{json, Value};
+use super::codec::{msg, tag, Message};
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Map, Value};
 
 /// A GMP-3 command family, derived from the cloud command `kind`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -30,20 +37,73 @@ pub enum CommandFamily {
     FiscalReport,
     /// Read-only device status probe.
     CapabilityProbe,
+    /// Refund to the card against an earlier sale (linked by its `rrn`).
+    RefundCard,
+    /// Hold an amount on the card for a tab; nothing is charged or printed.
+    PreauthCard,
+    /// Charge a held pre-auth (linked by its `rrn`), with the fiş.
+    PreauthComplete,
+    /// Add a tip to an approved sale (linked by its `rrn`).
+    TipAdjust,
+    /// One fiş paid partly by card, partly in cash; the card part is charged.
+    SplitTender,
+    /// End-of-day settlement of the card batch, with its totals.
+    BatchClose,
+}
+
+impl CommandFamily {
+    pub const ALL: [CommandFamily; 12] = [
+        CommandFamily::ChargeCard,
+        CommandFamily::VoidCard,
+        CommandFamily::FiscalReceipt,
+        CommandFamily::FiscalCancel,
+        CommandFamily::FiscalReport,
+        CommandFamily::CapabilityProbe,
+        CommandFamily::RefundCard,
+        CommandFamily::PreauthCard,
+        CommandFamily::PreauthComplete,
+        CommandFamily::TipAdjust,
+        CommandFamily::SplitTender,
+        CommandFamily::BatchClose,
+    ];
+
+    /// The cloud command `kind` of this family.
+    pub fn kind(self) -> &'static str {
+        match self {
+            CommandFamily::ChargeCard => "charge_card",
+            CommandFamily::VoidCard => "void_card",
+            CommandFamily::FiscalReceipt => "fiscal_receipt",
+            CommandFamily::FiscalCancel => "fiscal_cancel",
+            CommandFamily::FiscalReport => "fiscal_report",
+            CommandFamily::CapabilityProbe => "capability_probe",
+            CommandFamily::RefundCard => "refund_card",
+            CommandFamily::PreauthCard => "preauth_card",
+            CommandFamily::PreauthComplete => "preauth_complete",
+            CommandFamily::TipAdjust => "tip_adjust",
+            CommandFamily::SplitTender => "charge_split_tender",
+            CommandFamily::BatchClose => "batch_close",
+        }
+    }
+
+    /// Whether the acquirer answers this family, so that a two-digit result
+    /// code is a decline (`approved: false`) rather than a device fault.
+    fn is_card(self) -> bool {
+        matches!(
+            self,
+            CommandFamily::ChargeCard
+                | CommandFamily::RefundCard
+                | CommandFamily::PreauthCard
+                | CommandFamily::PreauthComplete
+                | CommandFamily::TipAdjust
+                | CommandFamily::SplitTender
+        )
+    }
 }
 
 /// Map a cloud command `kind` to its GMP-3 family, or `None` if this driver
 /// doesn't handle it (the caller then errors — never a silent no-op).
 pub fn classify(kind: &str) -> Option<CommandFamily> {
-    match kind {
-        "charge_card" => Some(CommandFamily::ChargeCard),
-        "void_card" => Some(CommandFamily::VoidCard),
-        "fiscal_receipt" => Some(CommandFamily::FiscalReceipt),
-        "fiscal_cancel" => Some(CommandFamily::FiscalCancel),
-        "fiscal_report" => Some(CommandFamily::FiscalReport),
-        "capability_probe" => Some(CommandFamily::CapabilityProbe),
-        _ => None,
-    }
+    CommandFamily::ALL.into_iter().find(|f| f.kind() == kind)
 }
 
 /// The configured simulator outcome for a device (from `gmp3.toml`
@@ -84,10 +144,18 @@ fn short(cmd_id: &str) -> String {
 
 /// Build the deterministic simulator outcome for a command family. The JSON keys
 /// mirror the real device contract the cloud reads:
-///   - card: `{approved, approvalCode, rrn, cardBrand, maskedPan, fiscalNo}`
+///   - card sale, split tender, pre-auth completion:
+///     `{approved, approvalCode, rrn, cardBrand, maskedPan, fiscalNo}`
+///   - pre-auth: the same without `fiscalNo` (the fiş prints on completion)
+///   - refund: `{approved, approvalCode, rrn}`; tip adjust:
+///     `{approved, approvalCode}`
 ///   - fiscal receipt: `{fiscalNo, fiscalZNo}`
 ///   - fiscal report: `{zNo, openedAt, closedAt, totals}`
+///   - batch close: `{batchNo, closedAt, totals: {saleCount, saleCents,
+///     refundCount, refundCents}}`
 ///   - probe: `{deviceStatus}`
+///
+/// A card leg declines as a `done` ack with `approved: false`, like a sale.
 pub fn simulate(family: CommandFamily, cmd_id: &str, outcome: SimOutcome) -> SimResult {
     let s = short(cmd_id);
     match family {
@@ -147,9 +215,150 @@ pub fn simulate(family: CommandFamily, cmd_id: &str, outcome: SimOutcome) -> Sim
             }
             _ => SimResult::Done(json!({ "deviceStatus": "online", "simulator": true })),
         },
+        CommandFamily::RefundCard => card_sim(
+            outcome,
+            "refund",
+            json!({
+                "approved": true,
+                "approvalCode": format!("SIM-REF-{s}"),
+                "rrn": format!("SIM-REF-{s}"),
+                "simulator": true,
+            }),
+        ),
+        CommandFamily::PreauthCard => card_sim(
+            outcome,
+            "pre-auth",
+            json!({
+                "approved": true,
+                "approvalCode": format!("SIM-PA-{s}"),
+                "rrn": format!("SIM-PA-{s}"),
+                "cardBrand": "SIMULATOR",
+                "maskedPan": "**** **** **** 0000",
+                "simulator": true,
+            }),
+        ),
+        CommandFamily::PreauthComplete => card_sim(
+            outcome,
+            "pre-auth completion",
+            json!({
+                "approved": true,
+                "approvalCode": format!("SIM-PAC-{s}"),
+                "rrn": format!("SIM-PAC-{s}"),
+                "cardBrand": "SIMULATOR",
+                "maskedPan": "**** **** **** 0000",
+                "fiscalNo": format!("SIMFIS-{s}"),
+                "simulator": true,
+            }),
+        ),
+        CommandFamily::TipAdjust => card_sim(
+            outcome,
+            "tip adjust",
+            json!({
+                "approved": true,
+                "approvalCode": format!("SIM-TIP-{s}"),
+                "simulator": true,
+            }),
+        ),
+        CommandFamily::SplitTender => card_sim(
+            outcome,
+            "split tender",
+            json!({
+                "approved": true,
+                "approvalCode": format!("SIM-{s}"),
+                "rrn": format!("SIM-{s}"),
+                "cardBrand": "SIMULATOR",
+                "maskedPan": "**** **** **** 0000",
+                "fiscalNo": format!("SIMFIS-{s}"),
+                "simulator": true,
+            }),
+        ),
+        CommandFamily::BatchClose => match outcome {
+            SimOutcome::Approve => SimResult::Done(json!({
+                "batchNo": format!("SIMB-{s}"),
+                "closedAt": "1970-01-01T00:00:00.000Z",
+                "totals": {
+                    "saleCount": 0,
+                    "saleCents": 0,
+                    "refundCount": 0,
+                    "refundCents": 0,
+                },
+                "simulator": true,
+            })),
+            _ => SimResult::Failed("Simulated batch close failure".into()),
+        },
     }
 }
 
+/// A card leg's simulated outcome: `approved` as given, a `done` decline, or
+/// a failed ack.
+fn card_sim(outcome: SimOutcome, what: &str, approved: Value) -> SimResult {
+    match outcome {
+        SimOutcome::Approve => SimResult::Done(approved),
+        SimOutcome::Decline => SimResult::Done(json!({
+            "approved": false,
+            "error": format!("Simulated {what} decline"),
+            "simulator": true,
+        })),
+        SimOutcome::Error => SimResult::Failed(format!("Simulated {what} error")),
+    }
+}
+
+/// What the simulator checks before it answers: a command acting on an
+/// earlier transaction names it, and a split tender really splits. Anything
+/// more is the device's to refuse. The framed path checks the same while
+/// building the request ([`request`]).
+pub fn preflight(family: CommandFamily, payload: &Value) -> Result<()> {
+    original_rrn(family, payload)?;
+    if family == CommandFamily::SplitTender {
+        split_tender(payload)?;
+    }
+    Ok(())
+}
+
+/// The RRN of the earlier transaction a refund, pre-auth completion or tip
+/// adjust acts on (payload `rrn`). An unlinked refund is money out with
+/// nothing to reconcile it against, so a missing one is an error.
+fn original_rrn(family: CommandFamily, payload: &Value) -> Result<Option<&str>> {
+    match family {
+        CommandFamily::RefundCard | CommandFamily::PreauthComplete | CommandFamily::TipAdjust => {
+            required(payload, "rrn").map(Some).map_err(|e| {
+                e.context(format!(
+                    "{} must name the original transaction",
+                    family.kind()
+                ))
+            })
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The card and cash parts of a split tender's `payments`: exactly one card
+/// payment (one card is charged) and at least one cash payment.
+fn split_tender(payload: &Value) -> Result<(u64, u64)> {
+    let payments = payload
+        .get("payments")
+        .and_then(Value::as_array)
+        .ok_or_else(|| anyhow!("charge_split_tender payload has no payments"))?;
+    let (mut card, mut cards, mut cash, mut cashes) = (0, 0, 0, 0);
+    for p in payments {
+        let cents = amount(p.get("amountCents"), "payments[].amountCents")?;
+        match p.get("method").and_then(Value::as_str) {
+            Some("card") => (card, cards) = (card + cents, cards + 1),
+            Some("cash") => (cash, cashes) = (cash + cents, cashes + 1),
+            other => bail!(
+                "charge_split_tender payment method {:?}, expected card or cash",
+                other.unwrap_or("<none>")
+            ),
+        }
+    }
+    if cards != 1 || cashes == 0 {
+        bail!(
+            "charge_split_tender needs exactly one card payment and at least one cash payment (got {cards} card, {cashes} cash)"
+        );
+    }
+    Ok((card, cash))
+}
+
 /// The honest fail-closed error for a device configured `mode = "real"` on a
 /// vendor whose certified GMP-3 handshake is not implemented yet (Phase 0). We
 /// do NOT touch the hardware we cannot finish a transaction with.
@@ -162,6 +371,240 @@ pub fn real_mode_unavailable(display_name: &str, profile_id: &str) -> String {
     )
 }
 
+/// The framed request for a command of `family`. `reference` — the command's
+/// idempotency key, else its id — goes out as [`tag::ECR_REF`], so the device
+/// can later be asked whether the transaction happened ([`status_query`]).
+/// A payload missing what the device needs is an error, never a default.
+pub fn request(family: CommandFamily, payload: &Value, reference: &str) -> Result<Message> {
+    let m = match family {
+        CommandFamily::ChargeCard => Message::new(msg::SALE)
+            .with_field(
+                tag::AMOUNT,
+                amount(payload.get("amountCents"), "amountCents")?.to_string(),
+            )
+            .with_field(tag::CURRENCY, currency(payload)),
+        CommandFamily::VoidCard => {
+            let m = Message::new(msg::VOID).with_field(tag::RRN, required(payload, "rrn")?);
+            match payload.get("approvalCode").and_then(Value::as_str) {
+                Some(code) => m.with_field(tag::APPROVAL_CODE, code),
+                None => m,
+            }
+        }
+        CommandFamily::FiscalReceipt => {
+            let payments = payload
+                .get("payments")
+                .and_then(Value::as_array)
+                .filter(|p| !p.is_empty())
+                .ok_or_else(|| anyhow!("fiscal_receipt payload has no payments"))?;
+            let mut total: u64 = 0;
+            for p in payments {
+                total += amount(p.get("amountCents"), "payments[].amountCents")?;
+            }
+            Message::new(msg::RECEIPT).with_field(tag::AMOUNT, total.to_string())
+        }
+        CommandFamily::FiscalCancel => Message::new(msg::RECEIPT_CANCEL)
+            .with_field(tag::FISCAL_NO, required(payload, "receiptId")?),
+        CommandFamily::FiscalReport => match required(payload, "report")? {
+            r @ ("X" | "Z") => Message::new(msg::REPORT).with_field(tag::REPORT_TYPE, r),
+            other => bail!("fiscal_report payload has report '{other}', expected X or Z"),
+        },
+        // A probe is a status query about nothing in particular.
+        CommandFamily::CapabilityProbe => return Ok(Message::new(msg::STATUS)),
+        CommandFamily::RefundCard | CommandFamily::PreauthComplete => {
+            let msg_type = if family == CommandFamily::RefundCard {
+                msg::REFUND
+            } else {
+                msg::PREAUTH_COMPLETE
+            };
+            Message::new(msg_type)
+                .with_field(tag::RRN, original_rrn(family, payload)?.unwrap_or_default())
+                .with_field(
+                    tag::AMOUNT,
+                    amount(payload.get("amountCents"), "amountCents")?.to_string(),
+                )
+                .with_field(tag::CURRENCY, currency(payload))
+        }
+        CommandFamily::PreauthCard => Message::new(msg::PREAUTH)
+            .with_field(
+                tag::AMOUNT,
+                amount(payload.get("amountCents"), "amountCents")?.to_string(),
+            )
+            .with_field(tag::CURRENCY, currency(payload)),
+        CommandFamily::TipAdjust => Message::new(msg::TIP_ADJUST)
+            .with_field(tag::RRN, original_rrn(family, payload)?.unwrap_or_default())
+            .with_field(
+                tag::TIP_AMOUNT,
+                amount(payload.get("tipCents"), "tipCents")?.to_string(),
+            ),
+        CommandFamily::SplitTender => {
+            let (card, cash) = split_tender(payload)?;
+            Message::new(msg::SPLIT_SALE)
+                .with_field(tag::AMOUNT, (card + cash).to_string())
+                .with_field(tag::CARD_AMOUNT, card.to_string())
+                .with_field(tag::CURRENCY, currency(payload))
+        }
+        CommandFamily::BatchClose => Message::new(msg::BATCH_CLOSE),
+    };
+    Ok(m.with_field(tag::ECR_REF, reference))
+}
+
+/// Ask the device whether it completed a transaction under `reference`.
+pub fn status_query(reference: &str) -> Message {
+    Message::new(msg::STATUS).with_field(tag::ECR_REF, reference)
+}
+
+fn required<'a>(payload: &'a Value, key: &str) -> Result<&'a str> {
+    payload
+        .get(key)
+        .and_then(Value::as_str)
+        .filter(|s| !s.trim().is_empty())
+        .ok_or_else(|| anyhow!("payload has no '{key}'"))
+}
+
+fn currency(payload: &Value) -> &str {
+    payload
+        .get("currency")
+        .and_then(Value::as_str)
+        .unwrap_or("TRY")
+}
+
+/// A positive whole number of kuruş.
+fn amount(v: Option<&Value>, key: &str) -> Result<u64> {
+    match v.and_then(Value::as_u64) {
+        Some(n) if n > 0 => Ok(n),
+        _ => Err(anyhow!("payload '{key}' must be a positive integer")),
+    }
+}
+
+/// A two-digit result code is the acquirer's answer to a card — a decline,
+/// not a device fault.
+fn is_acquirer_code(code: &str) -> bool {
+    code.len() == 2 && code.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// The device's reply to `request`, in the JSON shape the cloud reads (the
+/// same keys [`simulate`] produces). A card decline is a result
+/// (`approved: false`); any other refusal is an error. A reply that names
+/// another transaction than the one asked about is refused.
+pub fn interpret(family: CommandFamily, request: &Message, reply: &Message) -> Result<Value> {
+    if let Some(ours) = request.field_str(tag::ECR_REF) {
+        let theirs = reply.field_str(tag::ECR_REF);
+        if theirs != Some(ours) {
+            bail!(
+                "device answered for reference {:?}, not '{ours}'",
+                theirs.unwrap_or("<none>")
+            );
+        }
+    }
+    let code = reply.field_str(tag::RESULT).unwrap_or("");
+    let reason = reply.field_str(tag::MESSAGE);
+    if code != "00" {
+        return match family {
+            _ if family.is_card() && is_acquirer_code(code) => Ok(json!({
+                "approved": false,
+                "error": reason.map(str::to_string).unwrap_or_else(|| format!("declined (response code {code})")),
+                "responseCode": code,
+            })),
+            CommandFamily::CapabilityProbe => Ok(json!({
+                "deviceStatus": "error",
+                "error": reason.unwrap_or(code),
+            })),
+            _ => Err(anyhow!(
+                "device refused the {family:?} request: {}{}",
+                if code.is_empty() {
+                    "no result code"
+                } else {
+                    code
+                },
+                reason.map(|r| format!(" ({r})")).unwrap_or_default()
+            )),
+        };
+    }
+
+    let mut out = Map::new();
+    let mut put = |key: &str, t: u16| {
+        if let Some(v) = reply.field_str(t) {
+            out.insert(key.to_string(), Value::from(v));
+        }
+    };
+    match family {
+        CommandFamily::ChargeCard
+        | CommandFamily::SplitTender
+        | CommandFamily::PreauthComplete
+        | CommandFamily::PreauthCard => {
+            put("approvalCode", tag::APPROVAL_CODE);
+            put("rrn", tag::RRN);
+            put("cardBrand", tag::CARD_BRAND);
+            put("maskedPan", tag::MASKED_PAN);
+            put("fiscalNo", tag::FISCAL_NO);
+            out.insert("approved".into(), Value::Bool(true));
+        }
+        CommandFamily::RefundCard => {
+            put("approvalCode", tag::APPROVAL_CODE);
+            put("rrn", tag::RRN);
+            put("fiscalNo", tag::FISCAL_NO);
+            out.insert("approved".into(), Value::Bool(true));
+        }
+        CommandFamily::TipAdjust => {
+            put("approvalCode", tag::APPROVAL_CODE);
+            out.insert("approved".into(), Value::Bool(true));
+        }
+        CommandFamily::BatchClose => {
+            // Settlement the cloud cannot reconcile is not a settlement.
+            if reply.field_str(tag::BATCH_NO).is_none() {
+                bail!("device reported the batch closed but sent no batch number");
+            }
+            put("batchNo", tag::BATCH_NO);
+            put("closedAt", tag::CLOSED_AT);
+            let total = |t: u16, name: &str| -> Result<u64> {
+                reply
+                    .field_str(t)
+                    .and_then(|v| v.parse().ok())
+                    .ok_or_else(|| anyhow!("device closed the batch but sent no valid {name}"))
+            };
+            let totals = json!({
+                "saleCount": total(tag::SALE_COUNT, "sale count")?,
+                "saleCents": total(tag::SALE_TOTAL, "sale total")?,
+                "refundCount": total(tag::REFUND_COUNT, "refund count")?,
+                "refundCents": total(tag::REFUND_TOTAL, "refund total")?,
+            });
+            out.insert("totals".into(), totals);
+        }
+        CommandFamily::VoidCard => {
+            put("approvalCode", tag::APPROVAL_CODE);
+            out.insert("approved".into(), Value::Bool(true));
+        }
+        CommandFamily::FiscalReceipt => {
+            // A fiş the device cannot number is not a fiş the cloud can record.
+            if reply.field_str(tag::FISCAL_NO).is_none() {
+                bail!("device reported the receipt done but sent no fiscal number");
+            }
+            put("fiscalNo", tag::FISCAL_NO);
+            put("fiscalZNo", tag::Z_NO);
+        }
+        CommandFamily::FiscalCancel => {}
+        CommandFamily::FiscalReport => {
+            put("zNo", tag::Z_NO);
+            put("openedAt", tag::OPENED_AT);
+            put("closedAt", tag::CLOSED_AT);
+            out.insert("totals".into(), json!({}));
+        }
+        CommandFamily::CapabilityProbe => {
+            out.insert("deviceStatus".into(), Value::from("online"));
+        }
+    }
+    Ok(Value::Object(out))
+}
+
+/// The device's answer to [`status_query`]: the recorded result of the
+/// transaction, or `None` if it never completed one under that reference.
+pub fn recorded(family: CommandFamily, query: &Message, reply: &Message) -> Result<Option<Value>> {
+    if reply.field_str(tag::RESULT) == Some("NF") {
+        return Ok(None);
+    }
+    interpret(family, query, reply).map(Some)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -180,9 +623,140 @@ mod tests {
             classify("capability_probe"),
             Some(CommandFamily::CapabilityProbe)
         );
+        assert_eq!(classify("refund_card"), Some(CommandFamily::RefundCard));
+        assert_eq!(
+            classify("charge_split_tender"),
+            Some(CommandFamily::SplitTender)
+        );
+        assert_eq!(classify("batch_close"), Some(CommandFamily::BatchClose));
+        for family in CommandFamily::ALL {
+            assert_eq!(classify(family.kind()), Some(family));
+        }
         assert_eq!(classify("print_receipt"), None);
     }
 
+    #[test]
+    fn simulated_restaurant_operations_keep_the_ack_shapes() {
+        let done = |family| match simulate(family, "cmd-1", SimOutcome::Approve) {
+            SimResult::Done(v) => v,
+            other => panic!("expected Done, got {other:?}"),
+        };
+        // No fiş until the tab is completed.
+        assert!(done(CommandFamily::PreauthCard).get("fiscalNo").is_none());
+        for family in [CommandFamily::PreauthComplete, CommandFamily::SplitTender] {
+            let v = done(family);
+            assert_eq!(v["approved"], true);
+            assert!(v["fiscalNo"].as_str().unwrap().starts_with("SIMFIS-"));
+        }
+        assert!(done(CommandFamily::RefundCard)["rrn"]
+            .as_str()
+            .unwrap()
+            .starts_with("SIM-REF-"));
+        assert_eq!(done(CommandFamily::BatchClose)["totals"]["saleCount"], 0);
+
+        match simulate(CommandFamily::TipAdjust, "c1", SimOutcome::Decline) {
+            SimResult::Done(v) => assert_eq!(v["approved"], false),
+            other => panic!("a declined tip is a done ack, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn linked_and_split_requests_are_checked() {
+        let refund = request(
+            CommandFamily::RefundCard,
+            &json!({ "rrn": "R1", "amountCents": 500 }),
+            "k",
+        )
+        .unwrap();
+        assert_eq!(refund.msg_type, msg::REFUND);
+        assert_eq!(refund.field_str(tag::RRN), Some("R1"));
+        assert_eq!(refund.field_str(tag::AMOUNT), Some("500"));
+
+        let tip = request(
+            CommandFamily::TipAdjust,
+            &json!({ "rrn": "R1", "tipCents": 150 }),
+            "k",
+        )
+        .unwrap();
+        assert_eq!(tip.field_str(tag::TIP_AMOUNT), Some("150"));
+
+        let split = request(
+            CommandFamily::SplitTender,
+            &json!({ "payments": [
+                { "method": "card", "amountCents": 700 },
+                { "method": "cash", "amountCents": 300 },
+            ] }),
+            "k",
+        )
+        .unwrap();
+        assert_eq!(split.field_str(tag::AMOUNT), Some("1000"));
+        assert_eq!(split.field_str(tag::CARD_AMOUNT), Some("700"));
+
+        let card = json!({ "method": "card", "amountCents": 1 });
+        let cash = json!({ "method": "cash", "amountCents": 1 });
+        for (family, payload) in [
+            (CommandFamily::RefundCard, json!({ "amountCents": 500 })),
+            (
+                CommandFamily::PreauthComplete,
+                json!({ "amountCents": 500 }),
+            ),
+            (CommandFamily::TipAdjust, json!({ "tipCents": 150 })),
+            (CommandFamily::SplitTender, json!({ "payments": [card] })),
+            (
+                CommandFamily::SplitTender,
+                json!({ "payments": [card, card, cash] }),
+            ),
+            (
+                CommandFamily::SplitTender,
+                json!({ "payments": [card, { "method": "voucher", "amountCents": 1 }] }),
+            ),
+        ] {
+            assert!(
+                request(family, &payload, "k").is_err(),
+                "{family:?} {payload}"
+            );
+            assert!(preflight(family, &payload).is_err(), "{family:?} {payload}");
+        }
+        let err = preflight(CommandFamily::RefundCard, &json!({})).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("refund_card must name the original transaction"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn batch_close_replies_carry_totals() {
+        let req = request(CommandFamily::BatchClose, &json!({}), "eod-1").unwrap();
+        let reply = |fields: &[(u16, &str)]| {
+            fields.iter().fold(
+                Message::reply(&req)
+                    .with_field(tag::RESULT, "00")
+                    .with_field(tag::ECR_REF, "eod-1"),
+                |m, (t, v)| m.with_field(*t, *v),
+            )
+        };
+        let totals = [
+            (tag::SALE_COUNT, "2"),
+            (tag::SALE_TOTAL, "1500"),
+            (tag::REFUND_COUNT, "1"),
+            (tag::REFUND_TOTAL, "200"),
+        ];
+        let mut full = vec![(tag::BATCH_NO, "B7")];
+        full.extend(totals);
+        let v = interpret(CommandFamily::BatchClose, &req, &reply(&full)).unwrap();
+        assert_eq!(
+            v,
+            json!({
+                "batchNo": "B7",
+                "totals": { "saleCount": 2, "saleCents": 1500, "refundCount": 1, "refundCents": 200 },
+            })
+        );
+
+        assert!(interpret(CommandFamily::BatchClose, &req, &reply(&totals)).is_err());
+        let garbled = [(tag::BATCH_NO, "B7"), (tag::SALE_COUNT, "two")];
+        assert!(interpret(CommandFamily::BatchClose, &req, &reply(&garbled)).is_err());
+    }
+
     #[test]
     fn sim_outcome_parses_forgivingly() {
         assert_eq!(SimOutcome::parse("APPROVE"), SimOutcome::Approve);
@@ -238,6 +812,90 @@ mod tests {
         }
     }
 
+    #[test]
+    fn requests_carry_what_the_device_needs() {
+        let sale = request(
+            CommandFamily::ChargeCard,
+            &json!({ "amountCents": 12345 }),
+            "order-1",
+        )
+        .unwrap();
+        assert_eq!(sale.msg_type, msg::SALE);
+        assert_eq!(sale.field_str(tag::AMOUNT), Some("12345"));
+        assert_eq!(sale.field_str(tag::CURRENCY), Some("TRY"));
+        assert_eq!(sale.field_str(tag::ECR_REF), Some("order-1"));
+
+        let fis = request(
+            CommandFamily::FiscalReceipt,
+            &json!({ "payments": [{ "amountCents": 500 }, { "amountCents": 250 }] }),
+            "r",
+        )
+        .unwrap();
+        assert_eq!(fis.field_str(tag::AMOUNT), Some("750"));
+
+        let probe = request(CommandFamily::CapabilityProbe, &json!({}), "p").unwrap();
+        assert_eq!(probe.msg_type, msg::STATUS);
+        assert!(
+            probe.field(tag::ECR_REF).is_none(),
+            "a probe asks about nothing"
+        );
+
+        for (family, payload) in [
+            (CommandFamily::ChargeCard, json!({ "amountCents": 0 })),
+            (CommandFamily::ChargeCard, json!({ "amountCents": "100" })),
+            (CommandFamily::VoidCard, json!({ "approvalCode": "A" })),
+            (CommandFamily::FiscalReport, json!({ "report": "Y" })),
+            (CommandFamily::FiscalReceipt, json!({ "payments": [] })),
+        ] {
+            assert!(
+                request(family, &payload, "k").is_err(),
+                "{family:?} {payload}"
+            );
+        }
+    }
+
+    #[test]
+    fn replies_map_to_the_ack_contract() {
+        let req = request(CommandFamily::ChargeCard, &json!({ "amountCents": 1 }), "k").unwrap();
+        let approved = Message::reply(&req)
+            .with_field(tag::RESULT, "00")
+            .with_field(tag::ECR_REF, "k")
+            .with_field(tag::RRN, "R1")
+            .with_field(tag::FISCAL_NO, "F1");
+        let v = interpret(CommandFamily::ChargeCard, &req, &approved).unwrap();
+        assert_eq!(
+            v,
+            json!({ "approved": true, "rrn": "R1", "fiscalNo": "F1" })
+        );
+
+        let declined = Message::reply(&req)
+            .with_field(tag::RESULT, "05")
+            .with_field(tag::ECR_REF, "k");
+        let v = interpret(CommandFamily::ChargeCard, &req, &declined).unwrap();
+        assert_eq!(v["approved"], false);
+        assert_eq!(v["responseCode"], "05");
+
+        // A device refusal is not a decline, and a reply about another
+        // transaction is not an answer.
+        let refused = Message::reply(&req)
+            .with_field(tag::RESULT, "DR")
+            .with_field(tag::ECR_REF, "k");
+        assert!(interpret(CommandFamily::ChargeCard, &req, &refused).is_err());
+        let other = Message::reply(&req)
+            .with_field(tag::RESULT, "00")
+            .with_field(tag::ECR_REF, "someone-else");
+        assert!(interpret(CommandFamily::ChargeCard, &req, &other).is_err());
+
+        let query = status_query("k");
+        let unknown = Message::reply(&query)
+            .with_field(tag::RESULT, "NF")
+            .with_field(tag::ECR_REF, "k");
+        assert_eq!(
+            recorded(CommandFamily::ChargeCard, &query, &unknown).unwrap(),
+            None
+        );
+    }
+
     #[test]
     fn deterministic_reference_from_command_id() {
         let a = simulate(
@@ -0,0 +1,156 @@
+//! İşlem Sıra No — the per-device transaction sequence counter.
+//!
+//! Every GMP-3 request carries a sequence number the device has not seen
+//! before; the device uses it to spot retransmissions and to refuse replays.
+//! The counter is kept per device serial in the `gmp3_sequence` table of
+//! `command_queue.db` and moved forward BEFORE a request goes out, so a crash
+//! between the two can skip a number but never reuse one.
+//!
+//! On connect the device reports the last number it saw (handshake); if that is
+//! ahead of ours — a restored or replaced data dir — the counter jumps to it
+//! ([`SequenceStore::advance_to`]).
+
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{path::Path, sync::Mutex};
+
+/// Highest sequence number; the next one after it is 1. Six digits, as
+/// printed on the fiş.
+pub const MAX_SEQ: u32 = 999_999;
+
+pub struct SequenceStore {
+    conn: Mutex<Connection>,
+}
+
+impl SequenceStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::init(Connection::open(path.as_ref())?)
+    }
+
+    /// Process-local store (tests).
+    pub fn in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        // Shares command_queue.db with CommandQueue's connection, like the
+        // offline cache.
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = FULL;
+             PRAGMA busy_timeout = 5000;
+             CREATE TABLE IF NOT EXISTS gmp3_sequence (
+                serial TEXT PRIMARY KEY,
+                last_seq INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+             );",
+        )?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// The last number used with `serial` (0: none yet).
+    pub fn current(&self, serial: &str) -> Result<u32> {
+        let conn = self.conn.lock().expect("sequence mutex poisoned");
+        Self::read(&conn, serial)
+    }
+
+    fn read(conn: &Connection, serial: &str) -> Result<u32> {
+        let last: Option<u32> = conn
+            .query_row(
+                "SELECT last_seq FROM gmp3_sequence WHERE serial = ?1",
+                params![serial],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(last.unwrap_or(0))
+    }
+
+    fn write(conn: &Connection, serial: &str, seq: u32) -> Result<()> {
+        conn.execute(
+            "INSERT INTO gmp3_sequence (serial, last_seq, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(serial) DO UPDATE SET last_seq = excluded.last_seq,
+                                               updated_at = excluded.updated_at",
+            params![serial, seq, now_ms()],
+        )?;
+        Ok(())
+    }
+
+    /// Take the next number for `serial`. It is durable when this returns.
+    pub fn next(&self, serial: &str) -> Result<u32> {
+        let mut conn = self.conn.lock().expect("sequence mutex poisoned");
+        let tx = conn.transaction()?;
+        let last = Self::read(&tx, serial)?;
+        let next = if last >= MAX_SEQ { 1 } else { last + 1 };
+        Self::write(&tx, serial, next)?;
+        tx.commit()?;
+        Ok(next)
+    }
+
+    /// Move the counter up to `seen` (the device's last number) if it is
+    /// behind. Returns the counter afterwards.
+    pub fn advance_to(&self, serial: &str, seen: u32) -> Result<u32> {
+        let mut conn = self.conn.lock().expect("sequence mutex poisoned");
+        let tx = conn.transaction()?;
+        let last = Self::read(&tx, serial)?;
+        if seen <= last {
+            return Ok(last);
+        }
+        let seen = seen.min(MAX_SEQ);
+        Self::write(&tx, serial, seen)?;
+        tx.commit()?;
+        Ok(seen)
+    }
+}
+
+fn now_ms() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_survive_a_restart_and_are_per_device() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("command_queue.db");
+        {
+            let s = SequenceStore::open(&path).unwrap();
+            assert_eq!(s.next("SER-1").unwrap(), 1);
+            assert_eq!(s.next("SER-1").unwrap(), 2);
+            assert_eq!(s.next("SER-2").unwrap(), 1);
+        }
+        let s = SequenceStore::open(&path).unwrap();
+        assert_eq!(s.current("SER-1").unwrap(), 2);
+        assert_eq!(s.next("SER-1").unwrap(), 3);
+        assert_eq!(s.current("NEVER").unwrap(), 0);
+    }
+
+    #[test]
+    fn wraps_after_the_last_number() {
+        let s = SequenceStore::in_memory().unwrap();
+        s.advance_to("SER-1", MAX_SEQ - 1).unwrap();
+        assert_eq!(s.next("SER-1").unwrap(), MAX_SEQ);
+        assert_eq!(s.next("SER-1").unwrap(), 1);
+    }
+
+    #[test]
+    fn only_ever_moves_forward_to_the_device() {
+        let s = SequenceStore::in_memory().unwrap();
+        for _ in 0..5 {
+            s.next("SER-1").unwrap();
+        }
+        assert_eq!(
+            s.advance_to("SER-1", 3).unwrap(),
+            5,
+            "device behind: keep ours"
+        );
+        assert_eq!(s.advance_to("SER-1", 40).unwrap(), 40, "device ahead: jump");
+        assert_eq!(s.next("SER-1").unwrap(), 41);
+    }
+}
@@ -6,17 +6,25 @@
 //! half as a small, dependency-free, real primitive — the on-prem bridge is the
 //! TCP CLIENT and the device (Paygo SP630) is the server on the LAN.
 //!
-//! Phase 0 exposes a half-close request/response exchange (`request_reply`):
-//! connect → write the whole request → `shutdown(Write)` to signal end-of-request
-//! → read the reply to EOF. It is real and unit-tested against a loopback server.
-//! The certified GMP-3 wire framing (length-prefixed / STX-ETX over a persistent,
-//! encrypted socket with the İşlem Sıra No handshake) lands in Phase 1 and builds
-//! on this primitive; nothing here fakes a device.
-
-use anyhow::{anyhow, Context, Result};
+//! Two exchanges:
+//!   - [`TcpEndpoint::request_reply`], a half-close byte exchange: connect →
+//!     write the whole request → `shutdown(Write)` → read the reply to EOF.
+//!   - [`Connection`], the framed one: a persistent socket carrying
+//!     [`codec`] frames with ACK/NAK, opened with the İşlem Sıra No handshake
+//!     and numbered from the durable [`SequenceStore`].
+//!
+//! Both are real and tested against loopback peers. Per-vendor session
+//! encryption (the certified DH + PÖKC handshake) wraps [`Connection`] when a
+//! vendor's real handshake ships; nothing here fakes a device.
+
+use super::codec::{msg, tag, Link, LinkConfig, Message, TagMap};
+use super::sequence::SequenceStore;
+use anyhow::{anyhow, bail, Context, Result};
 use std::io::{Read, Write};
 use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
 use std::time::Duration;
+use tracing::{info, warn};
 
 /// A GMP-3 device endpoint on the LAN.
 #[derive(Debug, Clone)]
@@ -43,13 +51,9 @@ impl TcpEndpoint {
         }
     }
 
-    /// Half-close request/response. Connects (bounded), writes the whole
-    /// request, half-closes the write side to signal "request complete", then
-    /// reads the device's reply to EOF. Any connect/write/read error surfaces as
-    /// `Err` — the caller turns that into a `failed` ack; we NEVER synthesise a
-    /// reply.
-    pub fn request_reply(&self, request: &[u8]) -> Result<Vec<u8>> {
-        let addr_str = format!("{}:{}", self.host, self.port);
+    /// Connect (bounded) with both I/O timeouts set and Nagle off.
+    fn connect(&self) -> Result<TcpStream> {
+        let addr_str = self.addr();
         let addr = addr_str
             .to_socket_addrs()
             .with_context(|| format!("resolving GMP-3 device address {addr_str}"))?
@@ -58,7 +62,7 @@ impl TcpEndpoint {
                 anyhow!("GMP-3 device address {addr_str} resolved to no socket address")
             })?;
 
-        let mut stream = TcpStream::connect_timeout(&addr, self.connect_timeout)
+        let stream = TcpStream::connect_timeout(&addr, self.connect_timeout)
             .with_context(|| format!("connecting to GMP-3 device {addr_str}"))?;
         stream
             .set_read_timeout(Some(self.io_timeout))
@@ -69,7 +73,21 @@ impl TcpEndpoint {
         // A GMP-3 frame is one short burst; send promptly rather than waiting
         // for Nagle to coalesce.
         let _ = stream.set_nodelay(true);
+        Ok(stream)
+    }
+
+    fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
 
+    /// Half-close request/response. Connects (bounded), writes the whole
+    /// request, half-closes the write side to signal "request complete", then
+    /// reads the device's reply to EOF. Any connect/write/read error surfaces as
+    /// `Err` — the caller turns that into a `failed` ack; we NEVER synthesise a
+    /// reply.
+    pub fn request_reply(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let addr_str = self.addr();
+        let mut stream = self.connect()?;
         stream
             .write_all(request)
             .with_context(|| format!("writing GMP-3 request to {addr_str}"))?;
@@ -87,6 +105,140 @@ impl TcpEndpoint {
     }
 }
 
+/// A persistent, framed connection to one GMP-3 device.
+///
+/// Opened on first use: connect, then a handshake in which each side names
+/// itself and its last sequence number. A device that answers with another
+/// serial is refused — the address in `gmp3.toml` points at the wrong till.
+/// Any error closes the socket; the next [`Connection::exchange`] reopens it.
+///
+/// A request is retransmitted only by the link layer, under its original
+/// sequence number, which the device deduplicates. Once a request has been
+/// acknowledged but its reply is lost, it is NOT sent again here: whether the
+/// transaction happened is for the caller to ask the device
+/// ([`msg::STATUS`] by ECR reference).
+pub struct Connection {
+    endpoint: TcpEndpoint,
+    serial: String,
+    sequence: Arc<SequenceStore>,
+    link_cfg: LinkConfig,
+    tags: TagMap,
+    link: Option<Link<TcpStream>>,
+}
+
+impl Connection {
+    pub fn new(
+        endpoint: TcpEndpoint,
+        serial: impl Into<String>,
+        sequence: Arc<SequenceStore>,
+    ) -> Self {
+        Self {
+            endpoint,
+            serial: serial.into(),
+            sequence,
+            link_cfg: LinkConfig::default(),
+            tags: TagMap::default(),
+            link: None,
+        }
+    }
+
+    /// Checksum, ACK timeout and retry budget (from the vendor profile).
+    pub fn with_link_config(mut self, cfg: LinkConfig) -> Self {
+        self.link_cfg = cfg;
+        self
+    }
+
+    /// The vendor's field numbering (from the vendor profile). Requests and
+    /// replies are in ours on this side of the connection.
+    pub fn with_tags(mut self, tags: TagMap) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.link.is_some()
+    }
+
+    /// Send `request` under the next sequence number and return the device's
+    /// reply to it.
+    pub fn exchange(&mut self, mut request: Message) -> Result<Message> {
+        let res = self.try_exchange(&mut request);
+        if let Err(e) = &res {
+            if self.link.take().is_some() {
+                warn!(serial = %self.serial, error = %e, "GMP-3 connection closed after an error");
+            }
+        }
+        res.with_context(|| {
+            format!(
+                "GMP-3 {:#06x} (seq {}) with {} at {}",
+                request.msg_type,
+                request.seq,
+                self.serial,
+                self.endpoint.addr()
+            )
+        })
+    }
+
+    fn try_exchange(&mut self, request: &mut Message) -> Result<Message> {
+        if self.link.is_none() {
+            self.link = Some(self.open()?);
+        }
+        request.seq = self.sequence.next(&self.serial)?;
+        let link = self.link.as_mut().expect("opened above");
+        link.send(&self.tags.to_wire(request.clone()))?;
+        let reply = self.tags.from_wire(link.recv(self.endpoint.io_timeout)?);
+        if reply.seq != request.seq || reply.msg_type != msg::reply_to(request.msg_type) {
+            bail!(
+                "device answered {:#06x} seq {} to {:#06x} seq {}",
+                reply.msg_type,
+                reply.seq,
+                request.msg_type,
+                request.seq
+            );
+        }
+        Ok(reply)
+    }
+
+    fn open(&self) -> Result<Link<TcpStream>> {
+        let mut link = Link::new(self.endpoint.connect()?, self.link_cfg);
+        let ours = self.sequence.current(&self.serial)?;
+        let hello = Message {
+            seq: ours,
+            ..Message::new(msg::HANDSHAKE)
+        }
+        .with_field(tag::SERIAL, self.serial.as_str())
+        .with_field(tag::LAST_SEQ, ours.to_string());
+        link.send(&self.tags.to_wire(hello))
+            .context("GMP-3 handshake")?;
+        let reply = self.tags.from_wire(
+            link.recv(self.endpoint.io_timeout)
+                .context("GMP-3 handshake")?,
+        );
+        if reply.msg_type != msg::reply_to(msg::HANDSHAKE) {
+            bail!("device answered the handshake with {:#06x}", reply.msg_type);
+        }
+        match reply.field_str(tag::SERIAL) {
+            Some(s) if s == self.serial => {}
+            other => bail!(
+                "device at {} is serial {:?}, not {} — check gmp3.toml",
+                self.endpoint.addr(),
+                other.unwrap_or("<none>"),
+                self.serial
+            ),
+        }
+        let theirs: u32 = reply
+            .field_str(tag::LAST_SEQ)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| anyhow!("device handshake carries no last sequence number"))?;
+        let now = self.sequence.advance_to(&self.serial, theirs)?;
+        if now != ours {
+            warn!(serial = %self.serial, ours, device = theirs, "GMP-3 sequence was behind the device — moved forward");
+        }
+        info!(serial = %self.serial, addr = %self.endpoint.addr(), seq = now, "GMP-3 connection open");
+        Ok(link)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -136,4 +288,100 @@ mod tests {
         };
         assert!(ep.request_reply(b"x").is_err());
     }
+
+    /// One device session: answer the handshake as `serial` with `last_seq`,
+    /// then reply to each request with its ECR reference echoed, until the
+    /// bridge hangs up or `requests` have been answered.
+    fn serve_session(
+        listener: &TcpListener,
+        serial: &str,
+        last_seq: u32,
+        requests: usize,
+    ) -> Vec<Message> {
+        let (sock, _) = listener.accept().unwrap();
+        let mut link = Link::new(sock, LinkConfig::default());
+        let hello = link.recv(Duration::from_secs(5)).unwrap();
+        assert_eq!(hello.msg_type, msg::HANDSHAKE);
+        link.send(
+            &Message::reply(&hello)
+                .with_field(tag::SERIAL, serial)
+                .with_field(tag::LAST_SEQ, last_seq.to_string()),
+        )
+        .unwrap();
+        let mut seen = vec![hello];
+        for _ in 0..requests {
+            let Ok(req) = link.recv(Duration::from_secs(5)) else {
+                break;
+            };
+            let reply = Message::reply(&req)
+                .with_field(tag::RESULT, "00")
+                .with_field(tag::ECR_REF, req.field(tag::ECR_REF).unwrap_or_default());
+            link.send(&reply).unwrap();
+            seen.push(req);
+        }
+        seen
+    }
+
+    fn connection(port: u16, seq: Arc<SequenceStore>) -> Connection {
+        let mut ep = TcpEndpoint::new("127.0.0.1", port);
+        ep.io_timeout = Duration::from_secs(5);
+        Connection::new(ep, "SER-1", seq)
+    }
+
+    fn sale(reference: &str) -> Message {
+        Message::new(msg::SALE)
+            .with_field(tag::AMOUNT, "12345")
+            .with_field(tag::ECR_REF, reference)
+    }
+
+    #[test]
+    fn connection_handshakes_numbers_requests_and_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let device = std::thread::spawn(move || {
+            // The device has seen up to 41 (our store is empty: restored box).
+            let first = serve_session(&listener, "SER-1", 41, 2);
+            // Session dropped; the bridge reconnects.
+            let second = serve_session(&listener, "SER-1", 43, 1);
+            (first, second)
+        });
+
+        let seq = Arc::new(SequenceStore::in_memory().unwrap());
+        let mut conn = connection(port, seq.clone());
+        let a = conn.exchange(sale("order-1")).unwrap();
+        assert_eq!(a.seq, 42, "continued from the device's last number");
+        assert_eq!(a.field_str(tag::ECR_REF), Some("order-1"));
+        let b = conn.exchange(sale("order-2")).unwrap();
+        assert_eq!(b.seq, 43);
+
+        // The device closed the first session after two requests.
+        assert!(conn.exchange(sale("order-3")).is_err());
+        assert!(!conn.is_open());
+        let c = conn.exchange(sale("order-3")).unwrap();
+        // 44 was taken by the failed attempt and is never reused.
+        assert_eq!(c.seq, 45);
+        assert_eq!(seq.current("SER-1").unwrap(), 45);
+
+        let (first, second) = device.join().unwrap();
+        assert_eq!(first[0].field_str(tag::LAST_SEQ), Some("0"));
+        assert_eq!(second[0].field_str(tag::LAST_SEQ), Some("44"));
+        assert_eq!(second[1].field_str(tag::ECR_REF), Some("order-3"));
+    }
+
+    /// Fail closed: a device that is not the configured serial never gets a
+    /// request.
+    #[test]
+    fn connection_refuses_the_wrong_device() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let device = std::thread::spawn(move || serve_session(&listener, "OTHER-9", 0, 1));
+
+        let seq = Arc::new(SequenceStore::in_memory().unwrap());
+        let err = connection(port, seq.clone())
+            .exchange(sale("order-1"))
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("OTHER-9"), "{err:#}");
+        assert_eq!(seq.current("SER-1").unwrap(), 0, "no number spent");
+        assert_eq!(device.join().unwrap().len(), 1, "only the handshake");
+    }
 }
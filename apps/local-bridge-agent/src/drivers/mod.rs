@@ -4,16 +4,27 @@
 //! looks the driver up by the command's `target` (kind + identifier) and
 //! invokes it. Failures bubble up as `anyhow::Error` so the main loop can
 //! retry / fail-out uniformly.
+//!
+//! The driver set is swappable: [`reload`] re-reads `printers.toml` and
+//! `gmp3.toml` while the agent runs and replaces just the drivers whose config
+//! changed. A dispatch holds the driver it started on, so a command running
+//! during a reload finishes on the old set.
 
-use crate::command_queue::{CommandOutcome, PendingCommand};
+use crate::command_queue::{is_side_effecting, CommandOutcome, PendingCommand};
+use crate::metrics::Metrics;
 use anyhow::Result;
 use async_trait::async_trait;
+use serde::Serialize;
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 pub mod escpos;
 pub mod gmp3;
 pub mod ingenico_iwl;
+pub mod reload;
+#[cfg(feature = "yazarkasa-hugin")]
 pub mod yazarkasa_hugin;
 
 #[async_trait]
@@ -24,11 +35,93 @@ pub trait LocalDriver: Send + Sync {
     /// Execute one command. The driver is responsible for parsing the
     /// command payload — keeping it untyped here means new command kinds
     /// don't require touching the driver registry.
+    ///
+    /// `cmd.idempotency_key` is set for money/fiscal kinds. A driver that
+    /// returns true from [`LocalDriver::verifies_idempotency`] must look that
+    /// key up on the device before sending, and return the recorded outcome
+    /// instead of re-sending when the device already has it.
     async fn execute(&self, cmd: &PendingCommand) -> Result<CommandOutcome>;
+
+    /// Whether this driver checks `cmd.idempotency_key` against the device
+    /// before sending. Only such drivers are handed a replayed (attempts > 1)
+    /// money/fiscal command; everyone else gets it refused by
+    /// [`Registry::dispatch`] so it parks in `needs_review`.
+    fn verifies_idempotency(&self) -> bool {
+        false
+    }
+
+    /// Probe every configured device without side effects (a TCP connect, a
+    /// device-file stat — never a print or a sale). Used by `--health`. The
+    /// default reports nothing, for drivers with nothing to probe.
+    async fn readiness(&self) -> Vec<DeviceReadiness> {
+        Vec::new()
+    }
+
+    /// The last state each device reported while executing commands, shipped
+    /// with the heartbeat. Cached, never probed here: asking a device for its
+    /// state would compete with the worker printing on it. The default reports
+    /// nothing, for drivers whose devices have no status channel.
+    fn device_status(&self) -> Vec<DeviceStatus> {
+        Vec::new()
+    }
+}
+
+/// One device's last reported state, as returned by
+/// [`LocalDriver::device_status`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceStatus {
+    pub driver: String,
+    pub device: String,
+    /// Short machine-readable state ("ok", "paper_out", "unknown", …).
+    pub state: String,
+    /// Driver-specific detail (e.g. the ESC/POS status flags); null if unknown.
+    pub detail: serde_json::Value,
+    /// Unix ms of the reading.
+    pub checked_at: i64,
+}
+
+/// One device's readiness, as reported by [`LocalDriver::readiness`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceReadiness {
+    /// Driver kind ("escpos", "gmp3", …).
+    pub driver: String,
+    /// Printer id / device serial, or the config file when nothing is configured.
+    pub device: String,
+    pub ready: bool,
+    pub detail: String,
+}
+
+/// Connect timeout for readiness probes. Shorter than the drivers' own
+/// dispatch timeouts — a health check must answer fast.
+pub(crate) const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// TCP reachability probe: connect, then drop the socket without writing.
+pub(crate) async fn probe_tcp(host: &str, port: u16) -> Result<()> {
+    match tokio::time::timeout(PROBE_TIMEOUT, tokio::net::TcpStream::connect((host, port))).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(anyhow::anyhow!("connect {host}:{port}: {e}")),
+        Err(_) => Err(anyhow::anyhow!(
+            "connect {host}:{port}: timed out after {}s",
+            PROBE_TIMEOUT.as_secs()
+        )),
+    }
 }
 
+type DriverSet = HashMap<String, Arc<dyn LocalDriver>>;
+
 pub struct Registry {
-    drivers: HashMap<String, Box<dyn LocalDriver>>,
+    /// Replaced wholesale on reload; readers clone the `Arc` and let go of the
+    /// lock before calling into a driver.
+    drivers: RwLock<Arc<DriverSet>>,
+    /// Where the reloadable configs live; `None` for [`Registry::from_drivers`].
+    data_dir: Option<PathBuf>,
+    /// Fingerprint of each reloadable config as last read (`None`: absent).
+    seen: Mutex<HashMap<&'static str, Option<reload::Fingerprint>>>,
+    /// Where dispatch latency is recorded; see [`Registry::with_metrics`].
+    metrics: Arc<Metrics>,
+    /// One lock per [`device_key`], held for each dispatch to that device.
+    leases: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
 }
 
 impl Registry {
@@ -36,66 +129,240 @@ impl Registry {
     /// directory (`cfg.data_dir`); drivers read their LAN/transport config
     /// (e.g. the ESC/POS `printers.toml`) from there.
     pub async fn init(data_dir: &Path) -> Result<Self> {
-        let mut drivers: HashMap<String, Box<dyn LocalDriver>> = HashMap::new();
+        // Fingerprint before reading, so an edit racing the boot is picked up
+        // by the first reload rather than lost.
+        let seen = reload::fingerprints(data_dir);
+        let mut drivers: DriverSet = HashMap::new();
         // Drivers self-discover their availability — a printer driver that
         // can't find any printer simply does not register, and the agent
         // surfaces that fact to the cloud at heartbeat time.
         if let Some(d) = escpos::EscPosDriver::try_init(data_dir).await? {
-            drivers.insert(d.kind().to_string(), Box::new(d));
+            drivers.insert(d.kind().to_string(), Arc::new(d));
         }
         // Vendor-neutral GMP-3 ÖKC driver (Paygo SP630 + future Turkish ÖKC
         // brands). Registers even without gmp3.toml (fails honestly at command
         // time), mirroring the ESC/POS driver.
         if let Some(d) = gmp3::Gmp3Driver::try_init(data_dir).await? {
-            drivers.insert(d.kind().to_string(), Box::new(d));
+            drivers.insert(d.kind().to_string(), Arc::new(d));
         }
-        if let Some(d) = yazarkasa_hugin::HuginDriver::try_init().await? {
-            drivers.insert(d.kind().to_string(), Box::new(d));
+        // Hugin yazarkasa: only with hugin.toml naming its port and serial.
+        #[cfg(feature = "yazarkasa-hugin")]
+        if let Some(d) = yazarkasa_hugin::HuginDriver::try_init(data_dir).await? {
+            drivers.insert(d.kind().to_string(), Arc::new(d));
         }
         if let Some(d) = ingenico_iwl::IngenicoIwlDriver::try_init().await? {
-            drivers.insert(d.kind().to_string(), Box::new(d));
+            drivers.insert(d.kind().to_string(), Arc::new(d));
         }
-        Ok(Self { drivers })
+        Ok(Self {
+            drivers: RwLock::new(Arc::new(drivers)),
+            data_dir: Some(data_dir.to_path_buf()),
+            seen: Mutex::new(seen),
+            metrics: Arc::default(),
+            leases: Mutex::default(),
+        })
+    }
+
+    /// A registry over an explicit driver set, skipping device discovery.
+    /// For embedding and tests; the agent itself uses [`Registry::init`].
+    /// Such a registry has no config to reload.
+    pub fn from_drivers(drivers: Vec<Box<dyn LocalDriver>>) -> Self {
+        Self {
+            drivers: RwLock::new(Arc::new(
+                drivers
+                    .into_iter()
+                    .map(|d| (d.kind().to_string(), Arc::from(d)))
+                    .collect(),
+            )),
+            data_dir: None,
+            seen: Mutex::new(HashMap::new()),
+            metrics: Arc::default(),
+            leases: Mutex::default(),
+        }
+    }
+
+    /// Record every dispatch in `metrics` (the agent's shared instance).
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// The current driver set. Later reloads do not change what it holds.
+    fn snapshot(&self) -> Arc<DriverSet> {
+        self.drivers
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Put `driver` in place of the one of the same kind. Commands already
+    /// running keep the driver they were dispatched to.
+    fn install(&self, driver: Arc<dyn LocalDriver>) {
+        let mut current = self.drivers.write().unwrap_or_else(|e| e.into_inner());
+        let mut next = DriverSet::clone(&current);
+        next.insert(driver.kind().to_string(), driver);
+        *current = Arc::new(next);
     }
 
     pub fn installed_kinds(&self) -> Vec<String> {
-        self.drivers.keys().cloned().collect()
+        self.snapshot().keys().cloned().collect()
+    }
+
+    /// Readiness of every device of every installed driver, ordered by driver
+    /// kind so the `--health` output is stable.
+    pub async fn readiness(&self) -> Vec<DeviceReadiness> {
+        let drivers = self.snapshot();
+        let mut kinds: Vec<&String> = drivers.keys().collect();
+        kinds.sort();
+        let mut out = Vec::new();
+        for kind in kinds {
+            out.extend(drivers[kind].readiness().await);
+        }
+        out
+    }
+
+    /// Last reported state of every device, ordered by driver kind.
+    pub fn device_status(&self) -> Vec<DeviceStatus> {
+        let drivers = self.snapshot();
+        let mut kinds: Vec<&String> = drivers.keys().collect();
+        kinds.sort();
+        kinds
+            .into_iter()
+            .flat_map(|kind| drivers[kind].device_status())
+            .collect()
+    }
+
+    /// Whether `cmd` may run again when it may already have happened: it has
+    /// an idempotency key and routes to an installed driver that checks that
+    /// key against the device. Crash recovery requeues an orphaned money row
+    /// only on this; [`Registry::dispatch`] refuses the replay otherwise.
+    pub fn verifies_idempotency(&self, cmd: &PendingCommand) -> bool {
+        cmd.idempotency_key.is_some()
+            && self
+                .snapshot()
+                .get(driver_kind(cmd))
+                .is_some_and(|driver| driver.verifies_idempotency())
+    }
+
+    /// The lock a dispatch to `device` holds.
+    fn lease(&self, device: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.leases
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(device.to_string())
+            .or_default()
+            .clone()
     }
 
     pub async fn dispatch(&self, cmd: &PendingCommand) -> Result<CommandOutcome> {
-        // Routing precedence:
-        //   1. An explicit `target` in the payload root wins — ESC/POS (and any
-        //      command the mesh tags) identify their driver class this way
-        //      ("escpos", "hugin", "ingenico-iwl").
-        //   2. Otherwise a GMP-3 command (`protocol == "GMP3"`) routes to the
-        //      vendor-neutral `gmp3` driver. The payment-terminal / fiscal-core
-        //      GMP-3 adapters emit `protocol`+`vendorProfile` and NO `target`;
-        //      the `gmp3` driver then selects the vendor by `vendorProfile`.
-        let target = cmd
-            .payload
-            .get("target")
-            .and_then(|v| v.as_str())
-            .unwrap_or("");
-        let protocol = cmd.payload.get("protocol").and_then(|v| v.as_str());
-        let driver_kind: &str = if !target.is_empty() {
-            target
-        } else if protocol == Some("GMP3") {
-            "gmp3"
-        } else {
-            ""
-        };
-        match self.drivers.get(driver_kind) {
-            Some(driver) => driver.execute(cmd).await,
+        // Routing precedence: see [`driver_kind`]. The driver is cloned out
+        // of the snapshot so a reload mid-command cannot pull it away.
+        let driver = self.snapshot().get(driver_kind(cmd)).cloned();
+        match driver {
+            Some(driver) => {
+                // A money/fiscal command being re-dispatched (crash recovery
+                // requeued it) may already have happened on the device. Only a
+                // driver that can ask the device is allowed to run it again.
+                if cmd.attempts > 1
+                    && is_side_effecting(&cmd.kind)
+                    && !(driver.verifies_idempotency() && cmd.idempotency_key.is_some())
+                {
+                    return Err(ReplayRefused {
+                        kind: cmd.kind.clone(),
+                        id: cmd.id.clone(),
+                        attempts: cmd.attempts,
+                        driver: driver.kind().to_string(),
+                        key: cmd.idempotency_key.clone(),
+                    }
+                    .into());
+                }
+                // One dispatch per device at a time. A device's own worker
+                // never waits here; a workflow step addressing a device
+                // outside the workflow's shard does, until that device's
+                // current command is done.
+                let lease = self.lease(&device_key(cmd));
+                let _held = lease.lock().await;
+                let started = Instant::now();
+                let outcome = driver.execute(cmd).await;
+                self.metrics.observe_dispatch(
+                    driver.kind(),
+                    outcome.as_ref().map_or("error", |o| o.status.as_str()),
+                    started.elapsed(),
+                );
+                outcome
+            }
             None => anyhow::bail!(
                 "no driver installed for target='{}' protocol='{}' (kind={})",
-                target,
-                protocol.unwrap_or(""),
+                payload_str(cmd, "target").unwrap_or(""),
+                payload_str(cmd, "protocol").unwrap_or(""),
                 cmd.kind
             ),
         }
     }
 }
 
+/// A money/fiscal command [`Registry::dispatch`] would not run again: it may
+/// already have happened and its driver cannot ask the device. Nobody knows
+/// whether it ran, so it is parked for reconciliation, never acked `failed`.
+#[derive(Debug, thiserror::Error)]
+#[error("refusing to replay {kind} (command {id}, attempt {attempts}): driver '{driver}' cannot verify idempotency key {key:?} against the device — needs reconciliation")]
+pub struct ReplayRefused {
+    pub kind: String,
+    pub id: String,
+    pub attempts: i32,
+    pub driver: String,
+    pub key: Option<String>,
+}
+
+fn payload_str<'a>(cmd: &'a PendingCommand, key: &str) -> Option<&'a str> {
+    cmd.payload.get(key).and_then(|v| v.as_str())
+}
+
+/// The driver a command routes to. Precedence:
+///   1. An explicit `target` in the payload root wins — ESC/POS (and any
+///      command the mesh tags) identify their driver class this way
+///      ("escpos", "hugin", "ingenico-iwl").
+///   2. Otherwise a GMP-3 command (`protocol == "GMP3"`) routes to the
+///      vendor-neutral `gmp3` driver. The payment-terminal / fiscal-core
+///      GMP-3 adapters emit `protocol`+`vendorProfile` and NO `target`;
+///      the `gmp3` driver then selects the vendor by `vendorProfile`.
+///   3. A `discover_printers` command goes to `escpos`, which runs it.
+///
+/// Empty when none of these apply (dispatch fails honestly).
+pub fn driver_kind(cmd: &PendingCommand) -> &str {
+    match payload_str(cmd, "target") {
+        Some(t) if !t.is_empty() => t,
+        _ if payload_str(cmd, "protocol") == Some("GMP3") => "gmp3",
+        _ if cmd.kind == escpos::discover::KIND => "escpos",
+        _ => "",
+    }
+}
+
+/// Shard key for concurrent dispatch: commands with the same key run one at a
+/// time in queue order, different keys run in parallel. It is the driver kind
+/// refined by the physical device the payload addresses — the ESC/POS
+/// `printerId` (defaulting like the driver does) or the GMP-3 `fiscalSerial` —
+/// so a wedged kitchen printer only ever holds up its own tickets. A workflow
+/// takes its first step's key ([`crate::workflow::device_key`]), and each of
+/// its steps the lease of the device it addresses ([`Registry::dispatch`]); a
+/// printer discovery addresses no one printer and gets a shard of its own.
+pub fn device_key(cmd: &PendingCommand) -> String {
+    if cmd.kind == crate::workflow::KIND {
+        return crate::workflow::device_key(cmd);
+    }
+    if cmd.kind == escpos::discover::KIND {
+        return "escpos:discover".to_string();
+    }
+    match driver_kind(cmd) {
+        "escpos" => format!(
+            "escpos:{}",
+            payload_str(cmd, "printerId").unwrap_or("default")
+        ),
+        "gmp3" => format!("gmp3:{}", payload_str(cmd, "fiscalSerial").unwrap_or("")),
+        "" => "unrouted".to_string(),
+        other => other.to_string(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -126,11 +393,7 @@ mod tests {
     }
 
     fn registry_with(drivers: Vec<Box<dyn LocalDriver>>) -> Registry {
-        let mut map: HashMap<String, Box<dyn LocalDriver>> = HashMap::new();
-        for d in drivers {
-            map.insert(d.kind().to_string(), d);
-        }
-        Registry { drivers: map }
+        Registry::from_drivers(drivers)
     }
 
     fn cmd_with_target(id: &str, target: Option<&str>) -> PendingCommand {
@@ -144,9 +407,38 @@ mod tests {
             payload,
             priority: 0,
             attempts: 0,
+            idempotency_key: None,
+            origin: Default::default(),
         }
     }
 
+    #[test]
+    fn device_key_shards_by_physical_device() {
+        let key = |payload: serde_json::Value| {
+            device_key(&PendingCommand {
+                payload,
+                ..cmd_with_target("k", None)
+            })
+        };
+        assert_eq!(key(json!({ "target": "escpos" })), "escpos:default");
+        assert_eq!(
+            key(json!({ "target": "escpos", "printerId": "kitchen" })),
+            "escpos:kitchen"
+        );
+        assert_eq!(
+            key(json!({ "protocol": "GMP3", "fiscalSerial": "SER-1" })),
+            "gmp3:SER-1"
+        );
+        assert_eq!(key(json!({ "target": "ingenico-iwl" })), "ingenico-iwl");
+        assert_eq!(key(json!({})), "unrouted");
+        let discover = PendingCommand {
+            kind: escpos::discover::KIND.to_string(),
+            ..cmd_with_target("d", None)
+        };
+        assert_eq!(driver_kind(&discover), "escpos");
+        assert_eq!(device_key(&discover), "escpos:discover");
+    }
+
     #[tokio::test]
     async fn dispatch_routes_to_matching_driver() {
         let calls = StdArc::new(AtomicUsize::new(0));
@@ -230,6 +522,8 @@ mod tests {
             payload,
             priority: 0,
             attempts: 0,
+            idempotency_key: None,
+            origin: Default::default(),
         }
     }
 
@@ -278,6 +572,66 @@ mod tests {
         assert_eq!(gmp3_calls.load(Ordering::SeqCst), 0);
     }
 
+    /// A driver that can look the idempotency key up on the device.
+    struct VerifyingDriver {
+        calls: StdArc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl LocalDriver for VerifyingDriver {
+        fn kind(&self) -> &str {
+            "gmp3"
+        }
+        async fn execute(&self, _cmd: &PendingCommand) -> Result<CommandOutcome> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(CommandOutcome {
+                status: "done".to_string(),
+                result: json!({}),
+                error: None,
+            })
+        }
+        fn verifies_idempotency(&self) -> bool {
+            true
+        }
+    }
+
+    fn replayed_charge(target: &str) -> PendingCommand {
+        PendingCommand {
+            id: "cc-replay".to_string(),
+            kind: "charge_card".to_string(),
+            payload: json!({ "target": target }),
+            priority: 0,
+            attempts: 2,
+            idempotency_key: Some("cc-replay".to_string()),
+            origin: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn replayed_money_command_is_refused_by_non_verifying_driver() {
+        let calls = StdArc::new(AtomicUsize::new(0));
+        let reg = registry_with(vec![Box::new(FakeDriver {
+            kind: "hugin",
+            calls: calls.clone(),
+        })]);
+        let err = reg
+            .dispatch(&replayed_charge("hugin"))
+            .await
+            .expect_err("no device lookup → no replay");
+        assert!(err.to_string().contains("cannot verify"), "got: {err}");
+        assert_eq!(calls.load(Ordering::SeqCst), 0, "device never touched");
+    }
+
+    #[tokio::test]
+    async fn replayed_money_command_reaches_verifying_driver() {
+        let calls = StdArc::new(AtomicUsize::new(0));
+        let reg = registry_with(vec![Box::new(VerifyingDriver {
+            calls: calls.clone(),
+        })]);
+        reg.dispatch(&replayed_charge("gmp3")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
     #[test]
     fn installed_kinds_lists_registered_drivers() {
         let reg = registry_with(vec![
@@ -294,4 +648,28 @@ mod tests {
         kinds.sort();
         assert_eq!(kinds, vec!["escpos".to_string(), "hugin".to_string()]);
     }
+
+    #[tokio::test]
+    async fn probe_tcp_distinguishes_listening_from_closed_ports() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        probe_tcp("127.0.0.1", port)
+            .await
+            .expect("listener accepts");
+
+        drop(listener);
+        let err = probe_tcp("127.0.0.1", port)
+            .await
+            .expect_err("nothing listening");
+        assert!(err.to_string().contains(&format!("127.0.0.1:{port}")));
+    }
+
+    #[tokio::test]
+    async fn drivers_without_probes_report_no_devices() {
+        let reg = registry_with(vec![Box::new(FakeDriver {
+            kind: "hugin",
+            calls: StdArc::new(AtomicUsize::new(0)),
+        })]);
+        assert!(reg.readiness().await.is_empty());
+    }
 }
@@ -0,0 +1,374 @@
+//! Hot reload of the device configs (`printers.toml`, `gmp3.toml`,
+//! `gmp3_profiles.toml`).
+//!
+//! [`spawn_watch`] looks at the data dir every [`POLL_INTERVAL`] and on
+//! SIGHUP. A config whose content changed is parsed and validated in full
+//! before anything is swapped; only then does its driver replace the running
+//! one ([`Registry::reload`]). Commands already dispatched finish on the
+//! driver they started on.
+//!
+//! A config that fails validation is kept out: the running driver stays, the
+//! error is logged once per bad version of the file, and `--health` reports it
+//! until it is fixed. A config that disappears is treated the same way — an
+//! editor's save-by-rename leaves the file briefly absent, and dropping every
+//! printer over that is worse than keeping the last good set. Remove a device
+//! by editing it out, not by deleting the file.
+
+use super::{escpos, gmp3, LocalDriver, Registry};
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// How often the data dir is checked for edited configs.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// SHA-256 of a config file's bytes.
+pub type Fingerprint = [u8; 32];
+
+/// A config file and the strict constructor for the driver it feeds.
+struct Reloadable {
+    file: &'static str,
+    load: fn(&Path) -> Result<Arc<dyn LocalDriver>>,
+    /// An optional file's absence is not reported: the driver runs without it.
+    optional: bool,
+}
+
+const RELOADABLE: &[Reloadable] = &[
+    Reloadable {
+        file: escpos::CONFIG_FILE,
+        load: load_escpos,
+        optional: false,
+    },
+    Reloadable {
+        file: gmp3::CONFIG_FILE,
+        load: load_gmp3,
+        optional: false,
+    },
+    // The signature is written before the file it signs, so watching the
+    // file alone never loads a half-installed pair.
+    Reloadable {
+        file: gmp3::profiles::PROFILES_FILE,
+        load: load_gmp3,
+        optional: true,
+    },
+];
+
+fn load_escpos(data_dir: &Path) -> Result<Arc<dyn LocalDriver>> {
+    Ok(Arc::new(escpos::EscPosDriver::load(data_dir)?))
+}
+
+fn load_gmp3(data_dir: &Path) -> Result<Arc<dyn LocalDriver>> {
+    Ok(Arc::new(gmp3::Gmp3Driver::load(data_dir)?))
+}
+
+/// What one [`Registry::reload`] did.
+#[derive(Debug, Default, PartialEq)]
+pub struct ReloadReport {
+    /// Config files whose new driver is now live.
+    pub applied: Vec<&'static str>,
+    /// Config files that changed but were kept out, with the reason.
+    pub rejected: Vec<(&'static str, String)>,
+}
+
+/// `None` when the file cannot be read (most often: it does not exist).
+fn fingerprint(path: &Path) -> Option<Fingerprint> {
+    std::fs::read(path).ok().map(|b| Sha256::digest(b).into())
+}
+
+/// Fingerprint of every reloadable config in `data_dir`.
+pub(super) fn fingerprints(data_dir: &Path) -> HashMap<&'static str, Option<Fingerprint>> {
+    RELOADABLE
+        .iter()
+        .map(|r| (r.file, fingerprint(&data_dir.join(r.file))))
+        .collect()
+}
+
+/// Every config present in `data_dir` that would be rejected on reload, with
+/// the reason. Absent configs are not listed: the driver runs unconfigured and
+/// says so at command time. For `--health`.
+pub fn validate(data_dir: &Path) -> Vec<(&'static str, String)> {
+    RELOADABLE
+        .iter()
+        .filter(|r| data_dir.join(r.file).exists())
+        .filter_map(|r| (r.load)(data_dir).err().map(|e| (r.file, format!("{e:#}"))))
+        .collect()
+}
+
+impl Registry {
+    /// Re-read the device configs and swap in a driver for each one that
+    /// changed since it was last read (every one, if `force`). A config that
+    /// is missing or invalid leaves its current driver in place and is
+    /// reported in [`ReloadReport::rejected`]. A registry built with
+    /// [`Registry::from_drivers`] has nothing to reload.
+    pub fn reload(&self, force: bool) -> ReloadReport {
+        let mut report = ReloadReport::default();
+        let Some(data_dir) = &self.data_dir else {
+            return report;
+        };
+        for r in RELOADABLE {
+            let path = data_dir.join(r.file);
+            let now = fingerprint(&path);
+            {
+                let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+                if !force && seen.get(r.file) == Some(&now) {
+                    continue;
+                }
+                // Recorded even when rejected: the same bad file is reported
+                // once, not on every poll.
+                seen.insert(r.file, now);
+            }
+            if now.is_none() && r.optional {
+                continue;
+            }
+            if now.is_none() {
+                report.rejected.push((
+                    r.file,
+                    format!(
+                        "{} is missing or unreadable — keeping the running config",
+                        path.display()
+                    ),
+                ));
+                continue;
+            }
+            match (r.load)(data_dir) {
+                Ok(driver) => {
+                    self.install(driver);
+                    report.applied.push(r.file);
+                }
+                Err(e) => report.rejected.push((r.file, format!("{e:#}"))),
+            }
+        }
+        report
+    }
+}
+
+/// Watch the device configs for the life of the agent: poll every
+/// [`POLL_INTERVAL`], and reload everything at once on SIGHUP.
+pub fn spawn_watch(registry: Arc<Registry>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut hangup = HangUp::install();
+        let mut tick = tokio::time::interval(POLL_INTERVAL);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The first tick is immediate; init has only just read the configs.
+        tick.tick().await;
+        loop {
+            let force = tokio::select! {
+                _ = tick.tick() => false,
+                _ = hangup.recv() => {
+                    info!("SIGHUP — reloading device configs");
+                    true
+                }
+            };
+            let report = registry.reload(force);
+            for file in &report.applied {
+                info!(
+                    file,
+                    installed = registry.installed_kinds().join(","),
+                    "device config reloaded"
+                );
+            }
+            for (file, error) in &report.rejected {
+                warn!(
+                    file,
+                    error = %error,
+                    "device config rejected — the running config stays in place"
+                );
+            }
+        }
+    })
+}
+
+/// SIGHUP, where there is one. If the handler cannot be installed the watcher
+/// still polls.
+struct HangUp {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl HangUp {
+    fn install() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let signal = signal(SignalKind::hangup())
+                .map_err(|e| warn!(error = %e, "cannot listen for SIGHUP — configs reload on the poll only"))
+                .ok();
+            Self { signal }
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(s) = &mut self.signal {
+            if s.recv().await.is_some() {
+                return;
+            }
+            self.signal = None;
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_queue::{CommandOutcome, PendingCommand};
+    use async_trait::async_trait;
+    use serde_json::json;
+    use tempfile::TempDir;
+    use tokio::sync::{Notify, Semaphore};
+
+    /// A `printers.toml` with one device-file printer per id, each writing to
+    /// a file of the same name in `dir`.
+    fn write_printers(dir: &Path, ids: &[&str]) {
+        let mut toml = String::new();
+        for id in ids {
+            let path = dir.join(format!("{id}.out"));
+            std::fs::write(&path, b"").unwrap();
+            toml.push_str(&format!(
+                "[[printer]]\nid = \"{id}\"\ntransport = \"device\"\npath = \"{}\"\npoll_status = false\n\n",
+                path.display()
+            ));
+        }
+        std::fs::write(dir.join(escpos::CONFIG_FILE), toml).unwrap();
+    }
+
+    fn print_on(printer: &str) -> PendingCommand {
+        PendingCommand {
+            id: format!("p-{printer}"),
+            kind: "print_receipt".to_string(),
+            // "QUJD" = "ABC"
+            payload: json!({ "target": "escpos", "printerId": printer, "data": "QUJD" }),
+            priority: 0,
+            attempts: 0,
+            idempotency_key: None,
+            origin: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn an_edited_config_is_swapped_in() {
+        let dir = TempDir::new().unwrap();
+        write_printers(dir.path(), &["front"]);
+        let reg = Registry::init(dir.path()).await.unwrap();
+        assert!(reg.dispatch(&print_on("kitchen")).await.is_err());
+
+        write_printers(dir.path(), &["front", "kitchen"]);
+        let report = reg.reload(false);
+        assert_eq!(report.applied, vec![escpos::CONFIG_FILE]);
+        assert!(report.rejected.is_empty(), "{report:?}");
+
+        let out = reg.dispatch(&print_on("kitchen")).await.unwrap();
+        assert_eq!(out.status, "done");
+        assert_eq!(
+            std::fs::read(dir.path().join("kitchen.out")).unwrap(),
+            b"ABC"
+        );
+    }
+
+    #[tokio::test]
+    async fn unchanged_configs_are_not_reloaded() {
+        let dir = TempDir::new().unwrap();
+        write_printers(dir.path(), &["front"]);
+        let reg = Registry::init(dir.path()).await.unwrap();
+        assert_eq!(reg.reload(false), ReloadReport::default());
+        // A forced reload (SIGHUP) re-reads what is there; the absent
+        // gmp3.toml is reported, not fatal.
+        let forced = reg.reload(true);
+        assert_eq!(forced.applied, vec![escpos::CONFIG_FILE]);
+        assert_eq!(forced.rejected.len(), 1);
+        assert_eq!(forced.rejected[0].0, gmp3::CONFIG_FILE);
+    }
+
+    /// Fail closed: a broken or vanished config never replaces a working one.
+    #[tokio::test]
+    async fn an_invalid_config_is_kept_out_and_reported_once() {
+        let dir = TempDir::new().unwrap();
+        write_printers(dir.path(), &["front"]);
+        let reg = Registry::init(dir.path()).await.unwrap();
+
+        let config = dir.path().join(escpos::CONFIG_FILE);
+        let good = std::fs::read_to_string(&config).unwrap();
+        std::fs::write(&config, format!("{good}{good}")).unwrap(); // "front" twice
+        let report = reg.reload(false);
+        assert!(report.applied.is_empty());
+        assert_eq!(report.rejected.len(), 1);
+        assert!(report.rejected[0].1.contains("used twice"), "{report:?}");
+        assert_eq!(validate(dir.path()).len(), 1, "health sees it too");
+        assert_eq!(reg.reload(false), ReloadReport::default(), "reported once");
+        assert_eq!(
+            reg.dispatch(&print_on("front")).await.unwrap().status,
+            "done",
+            "the running config still prints"
+        );
+
+        std::fs::remove_file(&config).unwrap();
+        let report = reg.reload(false);
+        assert!(report.rejected[0].1.contains("missing"), "{report:?}");
+        assert_eq!(
+            reg.dispatch(&print_on("front")).await.unwrap().status,
+            "done"
+        );
+
+        write_printers(dir.path(), &["front"]);
+        assert_eq!(reg.reload(false).applied, vec![escpos::CONFIG_FILE]);
+        assert!(validate(dir.path()).is_empty());
+    }
+
+    /// Holds each command until the test releases it.
+    struct GatedDriver {
+        name: &'static str,
+        entered: Arc<Notify>,
+        gate: Arc<Semaphore>,
+    }
+
+    #[async_trait]
+    impl LocalDriver for GatedDriver {
+        fn kind(&self) -> &str {
+            "escpos"
+        }
+        async fn execute(&self, _cmd: &PendingCommand) -> Result<CommandOutcome> {
+            self.entered.notify_one();
+            let _permit = self.gate.acquire().await?;
+            Ok(CommandOutcome {
+                status: "done".to_string(),
+                result: json!({ "driver": self.name }),
+                error: None,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn in_flight_commands_finish_on_the_old_driver() {
+        let entered = Arc::new(Notify::new());
+        let gate = Arc::new(Semaphore::new(0));
+        let reg = Arc::new(Registry::from_drivers(vec![Box::new(GatedDriver {
+            name: "old",
+            entered: entered.clone(),
+            gate: gate.clone(),
+        })]));
+        let running = {
+            let reg = reg.clone();
+            tokio::spawn(async move { reg.dispatch(&print_on("front")).await })
+        };
+        entered.notified().await;
+
+        reg.install(Arc::new(GatedDriver {
+            name: "new",
+            entered: entered.clone(),
+            gate: gate.clone(),
+        }));
+        gate.add_permits(2);
+        let old = running.await.unwrap().unwrap();
+        assert_eq!(old.result["driver"], "old");
+        let new = reg.dispatch(&print_on("front")).await.unwrap();
+        assert_eq!(new.result["driver"], "new");
+    }
+}
@@ -1,39 +1,205 @@
-//! Hugin yazarkasa driver. Scaffold only.
+//! Hugin yazarkasa driver. Config scaffolding only.
 //!
-//! Real implementation talks to the Hugin serial protocol (typically RS-232
-//! over a USB-Serial adapter). The protocol is documented under NDA; this
-//! crate stubs the interface so the rest of the agent can be built and
-//! tested without the proprietary spec.
+//! Built only with the `yazarkasa-hugin` feature, and registered only when
+//! `hugin.toml` in the data dir names the register's port and serial:
+//!
+//! ```toml
+//! port = "/dev/ttyUSB0"      # the only port this driver will ever open
+//! serial = "HUG-12345678"    # as on the register; commands carry it as fiscalSerial
+//! ```
+//!
+//! The real implementation talks Hugin's serial protocol (RS-232, typically
+//! over a USB-serial adapter). That protocol is documented under NDA and this
+//! tree does not have it, so there is no transport here: no command codes, no
+//! framing, and no probing of `/dev/ttyUSB*` for a status reply — each of
+//! those would be bytes invented without the spec, sent to a fiscal device.
+//! Until the spec lands the driver fails closed like a GMP-3 profile that is
+//! not `real_impl_ready`: it registers from `hugin.toml` so commands addressed
+//! to it fail here with a clear reason, `--health` shows it not ready, and
+//! the port is never opened.
 
 use crate::{
     command_queue::{CommandOutcome, PendingCommand},
-    drivers::LocalDriver,
+    drivers::{DeviceReadiness, LocalDriver},
 };
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
-use serde_json::json;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// The driver's config file in the bridge data dir.
+pub const CONFIG_FILE: &str = "hugin.toml";
 
-pub struct HuginDriver;
+/// `hugin.toml`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HuginConfig {
+    port: PathBuf,
+    serial: String,
+}
+
+pub struct HuginDriver {
+    /// The configured port.
+    path: PathBuf,
+    /// The configured serial.
+    serial: String,
+}
 
 impl HuginDriver {
-    pub async fn try_init() -> Result<Option<Self>> {
-        // Real version would probe /dev/ttyUSB* and look for the Hugin reply
-        // to a status query. Scaffold: not installed in this build.
-        Ok(None)
+    /// `None` without `hugin.toml`, or with one that does not validate (logged).
+    pub async fn try_init(data_dir: &Path) -> Result<Option<Self>> {
+        let config_path = data_dir.join(CONFIG_FILE);
+        if !config_path.exists() {
+            return Ok(None);
+        }
+        let cfg = match load_config(&config_path) {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                tracing::warn!(
+                    error = %format!("{e:#}"),
+                    "hugin: config refused; Hugin commands will fail at the registry"
+                );
+                return Ok(None);
+            }
+        };
+        tracing::warn!(
+            port = %cfg.port.display(),
+            serial = %cfg.serial,
+            "hugin: no serial transport in this build; Hugin commands will FAIL closed"
+        );
+        Ok(Some(Self {
+            path: cfg.port,
+            serial: cfg.serial,
+        }))
     }
 }
 
+fn load_config(path: &Path) -> Result<HuginConfig> {
+    let raw = std::fs::read_to_string(path)
+        .with_context(|| format!("reading hugin config {}", path.display()))?;
+    let cfg: HuginConfig =
+        toml::from_str(&raw).with_context(|| format!("parsing hugin config {}", path.display()))?;
+    if cfg.serial.trim().is_empty() {
+        bail!("hugin config {}: serial is empty", path.display());
+    }
+    if cfg.port.as_os_str().is_empty() {
+        bail!("hugin config {}: port is empty", path.display());
+    }
+    Ok(cfg)
+}
+
+/// Why a command was refused without touching the device.
+fn not_implemented(path: &Path) -> String {
+    format!(
+        "hugin: no serial transport until Hugin's protocol spec is implemented — nothing was sent to {}",
+        path.display()
+    )
+}
+
 #[async_trait]
 impl LocalDriver for HuginDriver {
     fn kind(&self) -> &str {
         "hugin"
     }
 
-    async fn execute(&self, _cmd: &PendingCommand) -> Result<CommandOutcome> {
-        Ok(CommandOutcome {
-            status: "failed".to_string(),
-            result: json!({}),
-            error: Some("Hugin driver not implemented in this scaffold".to_string()),
-        })
+    async fn execute(&self, cmd: &PendingCommand) -> Result<CommandOutcome> {
+        bail!(
+            "{} (command {}, {} for {})",
+            not_implemented(&self.path),
+            cmd.id,
+            cmd.kind,
+            self.serial
+        )
+    }
+
+    /// Never ready; the detail also says whether the port is there, which is
+    /// a stat, not a byte on the line.
+    async fn readiness(&self) -> Vec<DeviceReadiness> {
+        let port = if self.path.exists() {
+            "present"
+        } else {
+            "missing (adapter unplugged?)"
+        };
+        vec![DeviceReadiness {
+            driver: "hugin".to_string(),
+            device: self.serial.clone(),
+            ready: false,
+            detail: format!("{}; serial port {port}", not_implemented(&self.path)),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    async fn init(config: Option<&str>) -> Option<HuginDriver> {
+        let dir = TempDir::new().unwrap();
+        if let Some(config) = config {
+            std::fs::write(dir.path().join(CONFIG_FILE), config).unwrap();
+        }
+        HuginDriver::try_init(dir.path()).await.unwrap()
+    }
+
+    /// No file, or one that does not validate: no driver, so commands fail
+    /// at the registry rather than reach a guessed port.
+    #[tokio::test]
+    async fn registers_only_from_a_valid_config() {
+        assert!(init(None).await.is_none());
+        assert!(init(Some("port = \"/dev/ttyUSB0\"\n")).await.is_none());
+        assert!(init(Some("port = \"/dev/ttyUSB0\"\nserial = \" \"\n"))
+            .await
+            .is_none());
+        assert!(
+            init(Some(
+                "port = \"/dev/ttyUSB0\"\nserial = \"HUG-1\"\nbaud = 9600\n"
+            ))
+            .await
+            .is_none(),
+            "unknown keys are refused"
+        );
+        let d = init(Some("port = \"/dev/ttyUSB0\"\nserial = \"HUG-1\"\n"))
+            .await
+            .expect("valid config registers");
+        assert_eq!(d.path, PathBuf::from("/dev/ttyUSB0"));
+        assert_eq!(d.serial, "HUG-1");
+    }
+
+    /// Every command fails closed and the device is never ready, however the
+    /// port looks.
+    #[tokio::test]
+    async fn fails_closed_without_opening_the_port() {
+        let dir = TempDir::new().unwrap();
+        let port = dir.path().join("ttyUSB0");
+        std::fs::write(&port, b"").unwrap();
+        let d = HuginDriver {
+            path: port.clone(),
+            serial: "HUG-1".to_string(),
+        };
+        let err = d
+            .execute(&PendingCommand {
+                id: "r-1".to_string(),
+                kind: "fiscal_receipt".to_string(),
+                payload: json!({ "target": "hugin", "fiscalSerial": "HUG-1" }),
+                priority: 0,
+                attempts: 1,
+                idempotency_key: Some("r-1".to_string()),
+                origin: Default::default(),
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("nothing was sent"), "{err}");
+        assert!(!d.verifies_idempotency());
+        assert_eq!(std::fs::read(&port).unwrap(), b"", "not written to");
+
+        let r = d.readiness().await;
+        assert!(!r[0].ready);
+        assert!(
+            r[0].detail.contains("serial port present"),
+            "{}",
+            r[0].detail
+        );
     }
 }
@@ -1,11 +1,799 @@
 //! One-shot health check used by `--health` and by the systemd `ExecStartPre`
-//! hook on the HummyBox install. Reports cloud connectivity and driver
-//! readiness then exits.
+//! hook on the HummyBox install. Reports config, credentials, the local queue,
+//! the network path and cloud connectivity (with a plain-words reading of TLS
+//! and proxy failures) and driver readiness, then exits.
+//!
+//! Every check lands in one of three buckets, and the worst one sets the exit
+//! code:
+//!
+//! | status     | exit | meaning                                                        |
+//! |------------|------|----------------------------------------------------------------|
+//! | `ok`       | 0    | everything checked out                                         |
+//! | `degraded` | 1    | the agent runs, but something needs attention (cloud down, a  |
+//! |            |      | printer offline, commands parked in `needs_review`)            |
+//! | `broken`   | 2    | the agent cannot do its job (config unreadable, queue will not |
+//! |            |      | open, no credential and no way to get one)                     |
+//!
+//! The agent is built to run offline, so a unit that must still start on a
+//! degraded box should only treat exit 2 as fatal:
+//! `ExecStartPre=/bin/sh -c 'hummytummy-local-bridge --health; test $? -ne 2'`.
+//!
+//! Runs next to a live agent: the queue is opened with
+//! [`CommandQueue::open_shared`] and the driver probes never write to a device.
 
-use crate::config::BridgeConfig;
-use anyhow::Result;
+use crate::{
+    cloud_ws::{signing::CommandVerifier, CloudClient},
+    command_queue::{chrono_unix_now, CommandQueue},
+    config::{self, BridgeConfig},
+    drivers::{reload, Registry},
+    network::{self, Network},
+    offline_cache::{Menu, OfflineCache},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, time::Duration};
 
-pub async fn run(_cfg: &BridgeConfig) -> Result<()> {
-    println!("ok");
-    Ok(())
+/// Upper bound on the cloud warm-up probe. `ExecStartPre` blocks the unit
+/// start, so a black-holed uplink must not hold it for the full HTTP timeout.
+const CLOUD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a quarantined command keeps `command_signing` degraded.
+const QUARANTINE_WINDOW_MS: i64 = 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Degraded,
+    Broken,
+}
+
+impl Status {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Status::Ok => 0,
+            Status::Degraded => 1,
+            Status::Broken => 2,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Degraded => "degraded",
+            Status::Broken => "broken",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    /// Worst status across `checks`.
+    pub status: Status,
+    pub checks: Vec<Check>,
+    /// Queue rows per status; empty if the queue could not be opened.
+    pub queue: BTreeMap<String, i64>,
+    /// Failed commands waiting out their backoff, soonest first.
+    pub retries: Vec<ScheduledRetry>,
+}
+
+/// One entry of the retry schedule.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduledRetry {
+    pub id: String,
+    pub kind: String,
+    /// Attempts made so far.
+    pub attempts: i32,
+    /// Unix ms; at or before now means it is due and waiting for a worker.
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+}
+
+impl HealthReport {
+    fn from_checks(checks: Vec<Check>, queue: BTreeMap<String, i64>) -> Self {
+        let status = checks.iter().map(|c| c.status).max().unwrap_or(Status::Ok);
+        Self {
+            status,
+            checks,
+            queue,
+            retries: Vec::new(),
+        }
+    }
+
+    fn render_text(&self) -> String {
+        let mut out = format!("bridge health: {}\n", self.status.label().to_uppercase());
+        for c in &self.checks {
+            out.push_str(&format!(
+                "  {:<9} {:<24} {}\n",
+                c.status.label(),
+                c.name,
+                c.detail
+            ));
+        }
+        out
+    }
+}
+
+/// Entry point for `--health`. Loads the config itself (an unreadable config is
+/// a reportable `broken`, not a crash), prints the report and returns the exit
+/// code.
+pub async fn run(config_dir: Option<&str>, json: bool) -> i32 {
+    let report = match config::load(config_dir) {
+        Ok(cfg) => match CloudClient::new(cfg.clone()) {
+            Ok(cloud) => {
+                crate::credentials::load_into_process(&cfg);
+                check(&cfg, &cloud, config::resolve_bearer_token().is_some()).await
+            }
+            Err(e) => HealthReport::from_checks(
+                vec![Check::new(
+                    "config",
+                    Status::Broken,
+                    format!("cloud client: {e:#}"),
+                )],
+                BTreeMap::new(),
+            ),
+        },
+        Err(e) => HealthReport::from_checks(
+            vec![Check::new("config", Status::Broken, format!("{e:#}"))],
+            BTreeMap::new(),
+        ),
+    };
+    if json {
+        match serde_json::to_string_pretty(&report) {
+            Ok(s) => println!("{s}"),
+            Err(e) => eprintln!("health: serialising report: {e}"),
+        }
+    } else {
+        print!("{}", report.render_text());
+    }
+    report.status.exit_code()
+}
+
+/// Run every check against an already-loaded config. `has_bearer` is passed in
+/// (rather than read from the environment here) so tests stay off process-global
+/// state.
+pub async fn check(cfg: &BridgeConfig, cloud: &CloudClient, has_bearer: bool) -> HealthReport {
+    let mut checks = vec![Check::new(
+        "config",
+        Status::Ok,
+        format!(
+            "bridge {} → {} (data dir {})",
+            cfg.bridge_id,
+            cfg.cloud_url,
+            cfg.data_dir.display()
+        ),
+    )];
+
+    let has_provisioning = cfg
+        .provisioning_token
+        .as_deref()
+        .is_some_and(|t| !t.is_empty());
+    let unauthenticated = crate::cloud_ws::auth::read_marker(&cfg.data_dir);
+    checks.push(match (has_bearer, has_provisioning, unauthenticated) {
+        // Degraded, not broken: the agent keeps executing locally, and exit 2
+        // would stop `ExecStartPre` from starting it at all.
+        (true, _, Some(marker)) => Check::new(
+                "bearer_token",
+                Status::Degraded,
+                format!(
+                    "unauthenticated since {} (unix ms) — the cloud rejected the token and {}; re-provision the bridge or set HUMMY_BRIDGE_TOKEN",
+                marker.since, marker.reason
+            ),
+        ),
+        (true, _, None) => Check::new("bearer_token", Status::Ok, "resolved"),
+        (false, true, _) => Check::new(
+            "bearer_token",
+            Status::Degraded,
+            "none yet — the agent will claim with provisioning_token on boot",
+        ),
+        (false, false, _) => Check::new(
+            "bearer_token",
+            Status::Broken,
+            "no bearer token and no provisioning_token — set HUMMY_BRIDGE_TOKEN or provisioning_token in bridge.toml",
+        ),
+    });
+
+    let mut queue_counts = BTreeMap::new();
+    let mut retries = Vec::new();
+    let db_path = cfg.data_dir.join("command_queue.db");
+    match CommandQueue::open_shared(&db_path) {
+        Ok(queue) => match queue.status_counts().await {
+            Ok(counts) => {
+                let n = |s: &str| counts.get(s).copied().unwrap_or(0);
+                retries = match queue.list_retrying().await {
+                    Ok(rows) => rows
+                        .into_iter()
+                        .map(|r| ScheduledRetry {
+                            id: r.id,
+                            kind: r.kind,
+                            attempts: r.attempts,
+                            next_attempt_at: r.next_attempt_at.unwrap_or_default(),
+                            last_error: r.error,
+                        })
+                        .collect(),
+                    Err(e) => {
+                        tracing::warn!(error = %e, "health: reading retry schedule");
+                        Vec::new()
+                    }
+                };
+                let mut detail = format!(
+                    "queued={} inflight={} needs_review={} ack_pending={} retrying={} expired={}",
+                    n("queued"),
+                    n("inflight"),
+                    n("needs_review"),
+                    n("done"),
+                    retries.len(),
+                    n("expired")
+                );
+                if let Some(next) = retries.first() {
+                    let secs = (next.next_attempt_at - chrono_unix_now()).max(0) / 1000;
+                    detail.push_str(&format!(" (next retry in {secs}s: {})", next.kind));
+                }
+                let status = if n("needs_review") > 0 {
+                    Status::Degraded
+                } else {
+                    Status::Ok
+                };
+                checks.push(Check::new("queue", status, detail));
+                queue_counts = counts;
+            }
+            Err(e) => checks.push(Check::new(
+                "queue",
+                Status::Broken,
+                format!("{}: {e:#}", db_path.display()),
+            )),
+        },
+        Err(e) => checks.push(Check::new(
+            "queue",
+            Status::Broken,
+            format!("{}: {e:#}", db_path.display()),
+        )),
+    }
+
+    checks.push(offline_orders_check(&db_path));
+    checks.push(command_signing_check(cfg, &db_path).await);
+
+    checks.push(match Network::from_config(&cfg.network, &cfg.cloud_url) {
+        Ok(net) => Check::new("network", Status::Ok, net.describe()),
+        Err(e) => Check::new("network", Status::Broken, format!("{e:#}")),
+    });
+    checks.push(
+        match tokio::time::timeout(CLOUD_TIMEOUT, cloud.warm_up()).await {
+            Ok(Ok(())) => Check::new("cloud", Status::Ok, "reachable"),
+            Ok(Err(e)) => Check::new(
+                "cloud",
+                Status::Degraded,
+                match network::explain(&e) {
+                    Some(why) => {
+                        format!("warm-up failed: {why} ({e:#}) — the agent runs offline")
+                    }
+                    None => format!("warm-up failed: {e:#} — the agent runs offline"),
+                },
+            ),
+            Err(_) => Check::new(
+                "cloud",
+                Status::Degraded,
+                format!(
+                    "warm-up timed out after {}s — the agent runs offline",
+                    CLOUD_TIMEOUT.as_secs()
+                ),
+            ),
+        },
+    );
+
+    // A device config the agent would refuse to reload. It keeps running on
+    // the last good one (or none, if it booted with this file).
+    for (file, error) in reload::validate(&cfg.data_dir) {
+        checks.push(Check::new(
+            format!("config:{file}"),
+            Status::Degraded,
+            error,
+        ));
+    }
+
+    match Registry::init(&cfg.data_dir).await {
+        Ok(registry) => {
+            for d in registry.readiness().await {
+                let status = if d.ready {
+                    Status::Ok
+                } else {
+                    Status::Degraded
+                };
+                checks.push(Check::new(
+                    format!("{}:{}", d.driver, d.device),
+                    status,
+                    d.detail,
+                ));
+            }
+        }
+        Err(e) => checks.push(Check::new("drivers", Status::Broken, format!("{e:#}"))),
+    }
+
+    let mut report = HealthReport::from_checks(checks, queue_counts);
+    report.retries = retries;
+    report
+}
+
+/// Command signing: off (no key pinned) and recent quarantines both need a
+/// person; a bad key is a config error the agent will not start with.
+async fn command_signing_check(cfg: &BridgeConfig, db_path: &std::path::Path) -> Check {
+    const NAME: &str = "command_signing";
+    match CommandVerifier::from_config(&cfg.command_signing) {
+        Err(e) => return Check::new(NAME, Status::Broken, format!("{e:#}")),
+        Ok(v) if !v.is_enforcing() => {
+            return Check::new(
+                NAME,
+                Status::Degraded,
+                "no key pinned in [command_signing] — cloud commands are queued unverified",
+            )
+        }
+        Ok(_) => {}
+    }
+    let keys = cfg.command_signing.public_keys.len();
+    let since = chrono_unix_now() - QUARANTINE_WINDOW_MS;
+    let recent = match CommandQueue::open_shared(db_path) {
+        Ok(queue) => queue.quarantined_since(since).await,
+        Err(e) => Err(e),
+    };
+    match recent {
+        Ok(rows) => match rows.first() {
+            None => Check::new(
+                NAME,
+                Status::Ok,
+                format!("{keys} key(s) pinned; nothing quarantined in the last 24h"),
+            ),
+            Some(latest) => Check::new(
+                NAME,
+                Status::Degraded,
+                format!(
+                    "{} command(s) quarantined in the last 24h — latest {} ({}): {}",
+                    rows.len(),
+                    latest.command_id,
+                    latest.kind,
+                    latest.detail
+                ),
+            ),
+        },
+        Err(e) => Check::new(
+            NAME,
+            Status::Degraded,
+            format!("{keys} key(s) pinned; quarantine unreadable: {e:#}"),
+        ),
+    }
+}
+
+/// Offline-order journal: pending orders are normal (replay drains them), but
+/// conflicts and rejections wait on a human.
+fn offline_orders_check(db_path: &std::path::Path) -> Check {
+    let summary = OfflineCache::open(db_path).and_then(|cache| {
+        let menu = cache.get::<Menu>()?.map(|m| m.version);
+        Ok((cache.order_counts()?, menu))
+    });
+    match summary {
+        Ok((counts, menu)) => {
+            let n = |s: &str| counts.get(s).copied().unwrap_or(0);
+            let menu = match menu {
+                Some(v) => format!("menu v{v}"),
+                None => "no menu snapshot".to_string(),
+            };
+            let detail = format!(
+                "pending={} replayed={} conflict={} rejected={} ({menu})",
+                n("pending"),
+                n("replayed"),
+                n("conflict"),
+                n("rejected")
+            );
+            let status = if n("conflict") + n("rejected") > 0 {
+                Status::Degraded
+            } else {
+                Status::Ok
+            };
+            Check::new("offline_orders", status, detail)
+        }
+        Err(e) => Check::new(
+            "offline_orders",
+            Status::Degraded,
+            format!("{}: {e:#}", db_path.display()),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloud_ws::signing::tests as signing_tests;
+    use crate::cloud_ws::{
+        auth::TokenRefreshResponse, BridgeIdentity, ClaimRequest, ClaimResponse, CloudTransport,
+        FetchResponse, HeartbeatResponse, OrderReplayResponse,
+    };
+    use crate::command_queue::{CommandOutcome, PendingCommand, QuarantinedCommand};
+    use crate::offline_cache::JournaledOrder;
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use serde_json::json;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    /// Transport whose only interesting call is `/healthz`.
+    struct Healthz(u16);
+
+    #[async_trait]
+    impl CloudTransport for Healthz {
+        async fn get_healthz(&self) -> Result<u16> {
+            Ok(self.0)
+        }
+        async fn get_next_commands(&self) -> Result<FetchResponse> {
+            Ok(FetchResponse::NoContent)
+        }
+        async fn post_ack(&self, _cmd_id: &str, _outcome: &CommandOutcome) -> Result<()> {
+            Ok(())
+        }
+        async fn post_heartbeat(&self, _identity: &BridgeIdentity) -> Result<HeartbeatResponse> {
+            Ok(HeartbeatResponse::default())
+        }
+        async fn post_claim(&self, _req: &ClaimRequest) -> Result<ClaimResponse> {
+            anyhow::bail!("unused")
+        }
+        async fn post_offline_order(&self, _order: &JournaledOrder) -> Result<OrderReplayResponse> {
+            anyhow::bail!("unused")
+        }
+        async fn post_local_audit(
+            &self,
+            _cmd: &PendingCommand,
+            _outcome: &CommandOutcome,
+        ) -> Result<()> {
+            anyhow::bail!("unused")
+        }
+        async fn post_token_refresh(&self) -> Result<TokenRefreshResponse> {
+            anyhow::bail!("unused")
+        }
+        async fn post_quarantine_report(&self, _q: &QuarantinedCommand) -> Result<()> {
+            anyhow::bail!("unused")
+        }
+    }
+
+    fn cfg(dir: &TempDir) -> BridgeConfig {
+        BridgeConfig {
+            cloud_url: "https://api.example.com".to_string(),
+            bridge_id: "b1".to_string(),
+            provisioning_token: None,
+            data_dir: dir.path().to_path_buf(),
+            update: Default::default(),
+            local_api: None,
+            dispatch: Default::default(),
+            retry: Default::default(),
+            credentials: Default::default(),
+            command_signing: crate::config::CommandSigningConfig {
+                public_keys: vec![signing_tests::key_hex(signing_tests::SEED)],
+                ..Default::default()
+            },
+            network: Default::default(),
+            command_ttl: Default::default(),
+            metrics: None,
+        }
+    }
+
+    /// A box with a simulator-mode GMP-3 device and a printer listening on
+    /// loopback — every check passes.
+    fn healthy_data_dir(dir: &TempDir) -> std::net::TcpListener {
+        let printer = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        std::fs::write(
+            dir.path().join("printers.toml"),
+            format!(
+                "[[printer]]\nid = \"default\"\ntransport = \"tcp\"\nhost = \"127.0.0.1\"\nport = {}\n",
+                printer.local_addr().unwrap().port()
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("gmp3.toml"),
+            "[[device]]\nserial = \"SIM-1\"\nmode = \"simulator\"\n",
+        )
+        .unwrap();
+        printer
+    }
+
+    fn status_of<'a>(r: &'a HealthReport, name: &str) -> &'a Check {
+        r.checks
+            .iter()
+            .find(|c| c.name == name)
+            .unwrap_or_else(|| panic!("no check {name} in {r:?}"))
+    }
+
+    #[tokio::test]
+    async fn healthy_box_reports_ok() {
+        let dir = TempDir::new().unwrap();
+        let _printer = healthy_data_dir(&dir);
+        let cloud = CloudClient::with_transport(Arc::new(Healthz(200)));
+
+        let r = check(&cfg(&dir), &cloud, true).await;
+        assert_eq!(r.status, Status::Ok, "{}", r.render_text());
+        assert_eq!(status_of(&r, "escpos:default").status, Status::Ok);
+        assert_eq!(status_of(&r, "gmp3:SIM-1").status, Status::Ok);
+        assert_eq!(r.status.exit_code(), 0);
+    }
+
+    #[tokio::test]
+    async fn cloud_down_and_parked_commands_degrade() {
+        let dir = TempDir::new().unwrap();
+        let _printer = healthy_data_dir(&dir);
+        {
+            let q = CommandQueue::open(dir.path().join("command_queue.db")).unwrap();
+            q.push(&PendingCommand {
+                id: "cc".to_string(),
+                kind: "charge_card".to_string(),
+                payload: json!({}),
+                priority: 0,
+                attempts: 0,
+                idempotency_key: None,
+                origin: Default::default(),
+            })
+            .await
+            .unwrap();
+            q.pop_next().await.unwrap();
+            q.mark_failed("cc", "terminal timeout").await.unwrap();
+        }
+        let cloud = CloudClient::with_transport(Arc::new(Healthz(503)));
+
+        let r = check(&cfg(&dir), &cloud, true).await;
+        assert_eq!(r.status, Status::Degraded);
+        assert_eq!(r.status.exit_code(), 1);
+        assert_eq!(status_of(&r, "cloud").status, Status::Degraded);
+        let queue = status_of(&r, "queue");
+        assert_eq!(queue.status, Status::Degraded);
+        assert!(queue.detail.contains("needs_review=1"), "{}", queue.detail);
+        assert_eq!(r.queue.get("needs_review"), Some(&1));
+    }
+
+    /// A print job backing off after a failure is normal operation: the queue
+    /// stays `ok`, but the schedule shows up in the detail and the JSON.
+    #[tokio::test]
+    async fn retry_schedule_is_reported_without_degrading() {
+        let dir = TempDir::new().unwrap();
+        let _printer = healthy_data_dir(&dir);
+        {
+            let q = CommandQueue::open(dir.path().join("command_queue.db")).unwrap();
+            q.push(&PendingCommand {
+                id: "p1".to_string(),
+                kind: "print_receipt".to_string(),
+                payload: json!({ "target": "escpos" }),
+                priority: 0,
+                attempts: 0,
+                idempotency_key: None,
+                origin: Default::default(),
+            })
+            .await
+            .unwrap();
+            q.pop_next().await.unwrap();
+            q.mark_failed("p1", "paper out").await.unwrap();
+        }
+        let cloud = CloudClient::with_transport(Arc::new(Healthz(200)));
+
+        let r = check(&cfg(&dir), &cloud, true).await;
+        let queue = status_of(&r, "queue");
+        assert_eq!(queue.status, Status::Ok);
+        assert!(queue.detail.contains("retrying=1"), "{}", queue.detail);
+        assert!(queue.detail.contains("print_receipt"), "{}", queue.detail);
+        assert_eq!(r.retries.len(), 1);
+        assert_eq!(r.retries[0].attempts, 1);
+        assert_eq!(r.retries[0].last_error.as_deref(), Some("paper out"));
+        let v = serde_json::to_value(&r).unwrap();
+        assert!(v["retries"][0]["nextAttemptAt"].as_i64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn conflicted_offline_orders_degrade() {
+        use crate::offline_cache::{MenuItem, OfflineOrderRequest, OrderLineRequest};
+        let dir = TempDir::new().unwrap();
+        let _printer = healthy_data_dir(&dir);
+        let cache = OfflineCache::open(dir.path().join("command_queue.db")).unwrap();
+        cache
+            .put(
+                &Menu {
+                    currency: "TRY".to_string(),
+                    items: vec![MenuItem {
+                        id: "cay".to_string(),
+                        name: "Çay".to_string(),
+                        price_cents: 1_500,
+                        available: true,
+                    }],
+                },
+                9,
+            )
+            .unwrap();
+        let order = cache
+            .journal_order(&OfflineOrderRequest {
+                base_order_id: None,
+                base_version: None,
+                table: None,
+                lines: vec![OrderLineRequest {
+                    item_id: "cay".to_string(),
+                    quantity: 2,
+                    note: None,
+                }],
+            })
+            .unwrap();
+        let cloud = CloudClient::with_transport(Arc::new(Healthz(200)));
+
+        let r = check(&cfg(&dir), &cloud, true).await;
+        assert_eq!(status_of(&r, "offline_orders").status, Status::Ok);
+
+        cache
+            .mark_order_conflict(&order.local_id, "table closed")
+            .unwrap();
+        let r = check(&cfg(&dir), &cloud, true).await;
+        let c = status_of(&r, "offline_orders");
+        assert_eq!(c.status, Status::Degraded);
+        assert!(c.detail.contains("conflict=1 "), "{}", c.detail);
+        assert!(c.detail.contains("menu v9"), "{}", c.detail);
+    }
+
+    #[tokio::test]
+    async fn missing_device_config_degrades_per_driver() {
+        let dir = TempDir::new().unwrap();
+        let cloud = CloudClient::with_transport(Arc::new(Healthz(200)));
+
+        let r = check(&cfg(&dir), &cloud, true).await;
+        assert_eq!(r.status, Status::Degraded);
+        assert!(r
+            .checks
+            .iter()
+            .any(|c| c.name.starts_with("escpos:") && c.name.ends_with("printers.toml")));
+        assert!(r
+            .checks
+            .iter()
+            .any(|c| c.name.starts_with("gmp3:") && c.name.ends_with("gmp3.toml")));
+    }
+
+    /// The agent would refuse to reload this file, so health says so by name.
+    #[tokio::test]
+    async fn invalid_device_config_degrades() {
+        let dir = TempDir::new().unwrap();
+        let _printer = healthy_data_dir(&dir);
+        std::fs::write(
+            dir.path().join("gmp3.toml"),
+            "[[device]]\nserial = \"SIM-1\"\n[[device]]\nserial = \"SIM-1\"\n",
+        )
+        .unwrap();
+        let cloud = CloudClient::with_transport(Arc::new(Healthz(200)));
+
+        let r = check(&cfg(&dir), &cloud, true).await;
+        assert_eq!(r.status, Status::Degraded);
+        let c = status_of(&r, "config:gmp3.toml");
+        assert!(c.detail.contains("listed twice"), "{c:?}");
+        assert!(r.checks.iter().all(|c| c.name != "config:printers.toml"));
+    }
+
+    #[tokio::test]
+    async fn no_credential_at_all_is_broken() {
+        let dir = TempDir::new().unwrap();
+        let _printer = healthy_data_dir(&dir);
+        let cloud = CloudClient::with_transport(Arc::new(Healthz(200)));
+
+        let r = check(&cfg(&dir), &cloud, false).await;
+        assert_eq!(status_of(&r, "bearer_token").status, Status::Broken);
+        assert_eq!(r.status.exit_code(), 2);
+
+        let mut with_prov = cfg(&dir);
+        with_prov.provisioning_token = Some("one-shot".to_string());
+        let r = check(&with_prov, &cloud, false).await;
+        assert_eq!(status_of(&r, "bearer_token").status, Status::Degraded);
+    }
+
+    /// The agent gave up on its token (refresh refused): reported, but only
+    /// degraded — local execution is still running.
+    #[tokio::test]
+    async fn unauthenticated_bridge_degrades() {
+        let dir = TempDir::new().unwrap();
+        let _printer = healthy_data_dir(&dir);
+        let cloud = CloudClient::with_transport(Arc::new(Healthz(200)));
+        crate::cloud_ws::auth::AuthState::new(None, Some(dir.path()))
+            .enter_unauthenticated("token refresh failed: HTTP 401");
+
+        let r = check(&cfg(&dir), &cloud, true).await;
+        let c = status_of(&r, "bearer_token");
+        assert_eq!(c.status, Status::Degraded);
+        assert!(c.detail.starts_with("unauthenticated"), "{}", c.detail);
+        assert!(c.detail.contains("HTTP 401"), "{}", c.detail);
+        assert_eq!(r.status.exit_code(), 1);
+    }
+
+    /// A TLS-inspecting proxy in the path: the cloud check says which
+    /// setting to look at, not just the rustls error. A malformed `[network]`
+    /// is broken — the agent would refuse to start with it.
+    #[tokio::test]
+    async fn tls_failures_are_explained_and_bad_network_settings_break() {
+        use crate::cloud_ws::ReqwestTransport;
+        let dir = TempDir::new().unwrap();
+        let _printer = healthy_data_dir(&dir);
+        let port = crate::network::tests::https_cloud().await;
+        let mut c = cfg(&dir);
+        c.cloud_url = format!("https://localhost:{port}");
+        let transport = ReqwestTransport::new(c.clone(), &Network::default()).unwrap();
+        let cloud = CloudClient::with_transport(Arc::new(transport));
+
+        let r = check(&c, &cloud, true).await;
+        assert_eq!(status_of(&r, "network").detail, "direct");
+        let cloud_check = status_of(&r, "cloud");
+        assert_eq!(cloud_check.status, Status::Degraded);
+        assert!(
+            cloud_check.detail.contains("ca_bundles"),
+            "{}",
+            cloud_check.detail
+        );
+
+        c.network.spki_pins = vec!["sha256/AAAA".into()];
+        let r = check(&c, &cloud, true).await;
+        assert_eq!(status_of(&r, "network").status, Status::Broken);
+        assert_eq!(r.status.exit_code(), 2);
+    }
+
+    #[tokio::test]
+    async fn unsigned_commands_and_quarantines_degrade() {
+        let dir = TempDir::new().unwrap();
+        let _printer = healthy_data_dir(&dir);
+        let cloud = CloudClient::with_transport(Arc::new(Healthz(200)));
+
+        let mut unpinned = cfg(&dir);
+        unpinned.command_signing = Default::default();
+        let r = check(&unpinned, &cloud, true).await;
+        let c = status_of(&r, "command_signing");
+        assert_eq!(c.status, Status::Degraded);
+        assert!(c.detail.contains("unverified"), "{}", c.detail);
+
+        let queue = CommandQueue::open(dir.path().join("command_queue.db")).unwrap();
+        queue
+            .quarantine(
+                "x-1",
+                "open_drawer",
+                "bad_signature",
+                "signature does not verify against any pinned key",
+                &json!({}),
+            )
+            .await
+            .unwrap();
+        let r = check(&cfg(&dir), &cloud, true).await;
+        let c = status_of(&r, "command_signing");
+        assert_eq!(c.status, Status::Degraded);
+        assert!(c.detail.contains("x-1 (open_drawer)"), "{}", c.detail);
+    }
+
+    #[tokio::test]
+    async fn unreadable_config_is_broken() {
+        let dir = TempDir::new().unwrap();
+        let code = run(Some(dir.path().to_str().unwrap()), true).await;
+        assert_eq!(code, 2, "no bridge.toml in the config dir");
+    }
+
+    #[test]
+    fn json_shape_is_stable() {
+        let r = HealthReport::from_checks(
+            vec![
+                Check::new("config", Status::Ok, "loaded"),
+                Check::new("cloud", Status::Degraded, "down"),
+            ],
+            BTreeMap::from([("queued".to_string(), 3)]),
+        );
+        let v = serde_json::to_value(&r).unwrap();
+        assert_eq!(v["status"], "degraded");
+        assert_eq!(v["checks"][1]["status"], "degraded");
+        assert_eq!(v["queue"]["queued"], 3);
+    }
 }
@@ -9,8 +9,16 @@
 pub mod cloud_ws;
 pub mod command_queue;
 pub mod config;
+pub mod credentials;
+pub mod dispatcher;
 pub mod drivers;
 pub mod health;
+pub mod local_api;
+pub mod metrics;
+pub mod network;
 pub mod offline_cache;
+pub mod review;
+pub mod service;
 pub mod telemetry;
 pub mod updater;
+pub mod workflow;
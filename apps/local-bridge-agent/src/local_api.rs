@@ -0,0 +1,713 @@
+//! LAN API for POS terminals.
+//!
+//! Lets terminals keep working when the restaurant's uplink is down: they read
+//! the cached menu, open orders and device state, and submit new orders that
+//! the bridge journals and replays to the cloud later (see
+//! [`crate::offline_cache`]).
+//!
+//! | method | path                          | answer                                   |
+//! |--------|-------------------------------|------------------------------------------|
+//! | GET    | `/v1/local/menu`              | cached menu, 503 before the first one    |
+//! | GET    | `/v1/local/orders/open`       | cached open orders, 503 before the first |
+//! | GET    | `/v1/local/device-state`      | last-known device state, 503 likewise    |
+//! | POST   | `/v1/local/orders`            | 202 + journaled order; 422 invalid, 409 stale base |
+//! | GET    | `/v1/local/orders/:local_id`  | journal entry incl. replay status        |
+//! | POST   | `/v1/local/commands[?wait=s]` | queue a command; 200 + outcome if it settles within `wait`, else 202 |
+//! | GET    | `/v1/local/commands/:id`      | status + outcome of a command queued here |
+//!
+//! Commands use the cloud's `PendingCommand` JSON shape (`kind`, `payload`,
+//! `priority`, `idempotency_key`); any `id`/`attempts` sent are ignored — the
+//! bridge assigns a UUIDv7. For a money/fiscal kind the key (top-level, or the
+//! payload's `idempotencyKey`) is stored as `local:<key>`, so a terminal can
+//! never name a cloud command's key and be handed its recorded outcome. The
+//! same key again with the same `kind` and `payload` answers with the command
+//! already queued under it — a retried charge is not charged twice — and with
+//! anything else is refused with 409. Commands run through the same
+//! [`CommandQueue`] and drivers as cloud commands, and their outcomes are
+//! forwarded to the cloud for audit once it is reachable (see
+//! [`crate::cloud_ws::CloudClient::ack`]).
+//! Only commands queued through this API are visible here.
+//!
+//! Every route requires `Authorization: Bearer <token>` with the token from
+//! [`config::resolve_local_api_token`]. With no token configured the API is
+//! not served at all — the LAN is not a trust boundary we get to assume.
+//! Errors are JSON: `{ "error": <code>, "message": <text> }`.
+
+use crate::{
+    command_queue::{
+        is_side_effecting, CommandOrigin, CommandOutcome, CommandQueue, CommandRecord,
+        PendingCommand,
+    },
+    config::{self, LocalApiConfig},
+    offline_cache::{
+        DeviceState, Menu, OfflineCache, OfflineOrderRequest, OpenOrders, OrderRejection, Snapshot,
+    },
+};
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{sync::Arc, time::Duration};
+use tokio::{net::TcpListener, task::JoinHandle};
+use tracing::{info, warn};
+
+/// Longest a `POST /v1/local/commands?wait=` may hold the request open.
+const MAX_WAIT: Duration = Duration::from_secs(30);
+
+/// How often a waiting request re-reads the command row.
+const WAIT_POLL: Duration = Duration::from_millis(100);
+
+/// Namespace of the idempotency keys terminals supply for money/fiscal kinds.
+const LOCAL_KEY_PREFIX: &str = "local:";
+
+#[derive(Clone)]
+struct ApiState {
+    cache: Arc<OfflineCache>,
+    queue: Arc<CommandQueue>,
+    token: Arc<str>,
+    /// Held from the key-reuse check to the push, so two requests racing
+    /// with one key cannot both queue a charge.
+    submitting: Arc<tokio::sync::Mutex<()>>,
+}
+
+/// Build the router. Split from [`start`] so tests can serve it on an
+/// ephemeral loopback port.
+pub fn router(cache: Arc<OfflineCache>, queue: Arc<CommandQueue>, token: String) -> Router {
+    let state = ApiState {
+        cache,
+        queue,
+        token: token.into(),
+        submitting: Arc::default(),
+    };
+    Router::new()
+        .route("/v1/local/menu", get(snapshot::<Menu>))
+        .route("/v1/local/orders/open", get(snapshot::<OpenOrders>))
+        .route("/v1/local/device-state", get(snapshot::<DeviceState>))
+        .route("/v1/local/orders", axum::routing::post(create_order))
+        .route("/v1/local/orders/:local_id", get(get_order))
+        .route("/v1/local/commands", axum::routing::post(create_command))
+        .route("/v1/local/commands/:id", get(get_command))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state)
+}
+
+/// Resolve the token, bind `cfg.listen` and serve in the background.
+/// `Ok(None)` when no token is configured: the API stays off (fail closed).
+pub async fn start(
+    cfg: &LocalApiConfig,
+    cache: Arc<OfflineCache>,
+    queue: Arc<CommandQueue>,
+) -> Result<Option<JoinHandle<()>>> {
+    let Some(token) = config::resolve_local_api_token(cfg)? else {
+        warn!("[local_api] configured but no token (HUMMY_LOCAL_API_TOKEN / token_file) — LAN API not started");
+        return Ok(None);
+    };
+    let listener = TcpListener::bind(cfg.listen)
+        .await
+        .with_context(|| format!("bind local API on {}", cfg.listen))?;
+    info!(listen = %cfg.listen, "local API listening");
+    Ok(Some(serve(listener, router(cache, queue, token))))
+}
+
+/// Serve `app` on an already-bound listener.
+pub fn serve(listener: TcpListener, app: Router) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            warn!(error = %e, "local API stopped");
+        }
+    })
+}
+
+fn error(status: StatusCode, code: &str, message: impl Into<String>) -> Response {
+    (
+        status,
+        Json(json!({ "error": code, "message": message.into() })),
+    )
+        .into_response()
+}
+
+fn internal(e: anyhow::Error) -> Response {
+    warn!(error = %e, "local API request failed");
+    error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal",
+        format!("{e:#}"),
+    )
+}
+
+async fn require_token(State(state): State<ApiState>, req: Request, next: Next) -> Response {
+    let presented = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match presented {
+        Some(t) if constant_time_eq(t.as_bytes(), state.token.as_bytes()) => next.run(req).await,
+        _ => error(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "missing or wrong bearer token",
+        ),
+    }
+}
+
+/// Compare without an early exit on the first differing byte, so response
+/// timing does not leak how much of a guessed token was right.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn snapshot<S: Snapshot + Send + 'static>(State(state): State<ApiState>) -> Response {
+    match state.cache.get::<S>() {
+        Ok(Some(snap)) => Json(snap).into_response(),
+        Ok(None) => error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "no_snapshot",
+            format!("no '{}' snapshot received from the cloud yet", S::KEY),
+        ),
+        Err(e) => internal(e),
+    }
+}
+
+async fn create_order(
+    State(state): State<ApiState>,
+    Json(req): Json<OfflineOrderRequest>,
+) -> Response {
+    match state.cache.journal_order(&req) {
+        Ok(order) => {
+            info!(local_id = %order.local_id, total_cents = order.total_cents, "offline order journaled");
+            (StatusCode::ACCEPTED, Json(order)).into_response()
+        }
+        Err(e) => match e.downcast::<OrderRejection>() {
+            Ok(r @ OrderRejection::NoMenu) => error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "no_snapshot",
+                r.to_string(),
+            ),
+            Ok(r @ OrderRejection::StaleBase { .. }) => {
+                error(StatusCode::CONFLICT, "stale_base", r.to_string())
+            }
+            Ok(r) => error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_order",
+                r.to_string(),
+            ),
+            Err(e) => internal(e),
+        },
+    }
+}
+
+async fn get_order(State(state): State<ApiState>, Path(local_id): Path<String>) -> Response {
+    match state.cache.get_order(&local_id) {
+        Ok(Some(order)) => Json(order).into_response(),
+        Ok(None) => error(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("no offline order '{local_id}'"),
+        ),
+        Err(e) => internal(e),
+    }
+}
+
+/// A command as a terminal submits it: the `PendingCommand` shape minus the
+/// fields the bridge owns (`id`, `attempts`), which serde ignores if sent.
+#[derive(Debug, Deserialize)]
+struct LocalCommandRequest {
+    kind: String,
+    #[serde(default)]
+    payload: serde_json::Value,
+    #[serde(default)]
+    priority: i32,
+    #[serde(default)]
+    idempotency_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WaitQuery {
+    /// Seconds to wait for the command to settle; 0 answers immediately.
+    #[serde(default)]
+    wait: u64,
+}
+
+/// What a terminal sees of a command it queued.
+#[derive(Debug, Serialize)]
+struct LocalCommandStatus {
+    id: String,
+    kind: String,
+    status: String,
+    /// Set once the command has run (`done`/`acked`) or given up (`failed`).
+    outcome: Option<CommandOutcome>,
+    /// Last error, including while it is being retried or parked for review.
+    error: Option<String>,
+}
+
+impl LocalCommandStatus {
+    fn from_record(r: CommandRecord) -> Self {
+        let outcome = match r.status.as_str() {
+            "done" | "acked" => Some(CommandOutcome {
+                status: "done".to_string(),
+                result: r.result.unwrap_or(serde_json::Value::Null),
+                error: None,
+            }),
+            "failed" => Some(CommandOutcome {
+                status: "failed".to_string(),
+                result: serde_json::Value::Null,
+                error: r.error.clone(),
+            }),
+            "expired" => Some(CommandOutcome {
+                status: "expired".to_string(),
+                result: r.result.unwrap_or(serde_json::Value::Null),
+                error: r.error.clone(),
+            }),
+            _ => None,
+        };
+        Self {
+            id: r.id,
+            kind: r.kind,
+            status: r.status,
+            outcome,
+            error: r.error,
+        }
+    }
+
+    /// Nothing more will happen without a human (`needs_review`) or at all.
+    fn is_settled(&self) -> bool {
+        self.outcome.is_some() || self.status == "needs_review"
+    }
+
+    fn into_response(self) -> Response {
+        let code = if self.is_settled() {
+            StatusCode::OK
+        } else {
+            StatusCode::ACCEPTED
+        };
+        (code, Json(self)).into_response()
+    }
+}
+
+async fn local_command(queue: &CommandQueue, id: &str) -> Result<Option<LocalCommandStatus>> {
+    Ok(queue
+        .get(id)
+        .await?
+        .filter(|r| r.origin == CommandOrigin::Local)
+        .map(LocalCommandStatus::from_record))
+}
+
+async fn create_command(
+    State(state): State<ApiState>,
+    Query(q): Query<WaitQuery>,
+    Json(req): Json<LocalCommandRequest>,
+) -> Response {
+    if req.kind.trim().is_empty() {
+        return error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_command",
+            "kind is required",
+        );
+    }
+    let mut payload = req.payload;
+    let idempotency_key = if is_side_effecting(&req.kind) {
+        let in_payload = payload
+            .as_object_mut()
+            .and_then(|p| p.remove("idempotencyKey"))
+            .and_then(|v| v.as_str().map(str::to_string));
+        match (req.idempotency_key, in_payload) {
+            (Some(top), Some(inner)) if top != inner => {
+                return error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "invalid_command",
+                    "idempotency_key and payload idempotencyKey differ",
+                )
+            }
+            (top, inner) => top
+                .or(inner)
+                .filter(|k| !k.is_empty())
+                .map(|k| format!("{LOCAL_KEY_PREFIX}{k}")),
+        }
+    } else {
+        req.idempotency_key
+    };
+    let cmd = PendingCommand {
+        id: uuid::Uuid::now_v7().to_string(),
+        kind: req.kind,
+        payload,
+        priority: req.priority,
+        attempts: 0,
+        idempotency_key,
+        origin: CommandOrigin::Local,
+    };
+    let id = {
+        let _submitting = state.submitting.lock().await;
+        let earlier = match &cmd.idempotency_key {
+            Some(key) if is_side_effecting(&cmd.kind) => {
+                match state.queue.find_local_by_key(key).await {
+                    Ok(earlier) => earlier,
+                    Err(e) => return internal(e),
+                }
+            }
+            _ => None,
+        };
+        match earlier {
+            Some(r) if r.kind == cmd.kind && r.payload == cmd.payload => {
+                info!(cmd = %r.id, kind = %r.kind, "local command retried under its idempotency key");
+                r.id
+            }
+            Some(r) => {
+                return error(
+                    StatusCode::CONFLICT,
+                    "idempotency_key_reused",
+                    format!(
+                    "idempotency key already used by command {} with a different kind or payload",
+                    r.id
+                ),
+                )
+            }
+            None => {
+                if let Err(e) = state.queue.push(&cmd).await {
+                    return internal(e);
+                }
+                info!(cmd = %cmd.id, kind = %cmd.kind, "local command queued");
+                cmd.id
+            }
+        }
+    };
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(q.wait).min(MAX_WAIT);
+    loop {
+        match local_command(&state.queue, &id).await {
+            Ok(Some(status)) => {
+                if status.is_settled() || tokio::time::Instant::now() >= deadline {
+                    return status.into_response();
+                }
+            }
+            Ok(None) => return internal(anyhow::anyhow!("command {id} vanished")),
+            Err(e) => return internal(e),
+        }
+        tokio::time::sleep(WAIT_POLL).await;
+    }
+}
+
+async fn get_command(State(state): State<ApiState>, Path(id): Path<String>) -> Response {
+    match local_command(&state.queue, &id).await {
+        Ok(Some(status)) => status.into_response(),
+        Ok(None) => error(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("no local command '{id}'"),
+        ),
+        Err(e) => internal(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::offline_cache::{MenuItem, OpenOrder};
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    const TOKEN: &str = "terminal-token-0123456789";
+
+    struct Api {
+        base: String,
+        http: reqwest::Client,
+        cache: Arc<OfflineCache>,
+        queue: Arc<CommandQueue>,
+        _dir: TempDir,
+    }
+
+    impl Api {
+        async fn start() -> Self {
+            let dir = TempDir::new().unwrap();
+            let db = dir.path().join("command_queue.db");
+            let queue = Arc::new(CommandQueue::open(&db).unwrap());
+            let cache = Arc::new(OfflineCache::open(&db).unwrap());
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let base = format!("http://{}", listener.local_addr().unwrap());
+            serve(
+                listener,
+                router(cache.clone(), queue.clone(), TOKEN.to_string()),
+            );
+            Self {
+                base,
+                http: reqwest::Client::new(),
+                cache,
+                queue,
+                _dir: dir,
+            }
+        }
+
+        async fn get(&self, path: &str) -> (u16, Value) {
+            let resp = self
+                .http
+                .get(format!("{}{path}", self.base))
+                .bearer_auth(TOKEN)
+                .send()
+                .await
+                .unwrap();
+            (resp.status().as_u16(), resp.json().await.unwrap())
+        }
+
+        async fn post(&self, path: &str, body: Value) -> (u16, Value) {
+            let resp = self
+                .http
+                .post(format!("{}{path}", self.base))
+                .bearer_auth(TOKEN)
+                .json(&body)
+                .send()
+                .await
+                .unwrap();
+            (resp.status().as_u16(), resp.json().await.unwrap())
+        }
+
+        fn seed(&self) {
+            self.cache
+                .put(
+                    &Menu {
+                        currency: "TRY".to_string(),
+                        items: vec![MenuItem {
+                            id: "lahmacun".to_string(),
+                            name: "Lahmacun".to_string(),
+                            price_cents: 9_000,
+                            available: true,
+                        }],
+                    },
+                    2,
+                )
+                .unwrap();
+            self.cache
+                .put(
+                    &OpenOrders {
+                        orders: vec![OpenOrder {
+                            id: "o-7".to_string(),
+                            version: 3,
+                            table: Some("T2".to_string()),
+                            lines: vec![],
+                            total_cents: 0,
+                        }],
+                    },
+                    2,
+                )
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn every_route_requires_the_bearer_token() {
+        let api = Api::start().await;
+        for token in [None, Some("wrong-token-0123456789")] {
+            let mut req = api.http.get(format!("{}/v1/local/menu", api.base));
+            if let Some(t) = token {
+                req = req.bearer_auth(t);
+            }
+            let resp = req.send().await.unwrap();
+            assert_eq!(resp.status().as_u16(), 401);
+            let body: Value = resp.json().await.unwrap();
+            assert_eq!(body["error"], "unauthorized");
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_served_once_the_cloud_has_sent_them() {
+        let api = Api::start().await;
+        let (status, body) = api.get("/v1/local/menu").await;
+        assert_eq!(status, 503);
+        assert_eq!(body["error"], "no_snapshot");
+
+        api.seed();
+        let (status, body) = api.get("/v1/local/menu").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["version"], 2);
+        assert_eq!(body["data"]["items"][0]["priceCents"], 9_000);
+        let (status, body) = api.get("/v1/local/orders/open").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["data"]["orders"][0]["id"], "o-7");
+    }
+
+    #[tokio::test]
+    async fn orders_are_journaled_and_readable_by_local_id() {
+        let api = Api::start().await;
+        api.seed();
+        let (status, body) = api
+            .post(
+                "/v1/local/orders",
+                json!({ "table": "T5", "lines": [{ "itemId": "lahmacun", "quantity": 2, "priceCents": 1 }] }),
+            )
+            .await;
+        assert_eq!(status, 202, "{body}");
+        assert_eq!(body["totalCents"], 18_000, "price comes from the menu");
+        assert_eq!(body["status"], "pending");
+
+        let id = body["localId"].as_str().unwrap();
+        let (status, got) = api.get(&format!("/v1/local/orders/{id}")).await;
+        assert_eq!(status, 200);
+        assert_eq!(got["localId"], id);
+
+        let (status, _) = api.get("/v1/local/orders/nope").await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn invalid_and_stale_orders_are_refused() {
+        let api = Api::start().await;
+        api.seed();
+        let (status, body) = api
+            .post(
+                "/v1/local/orders",
+                json!({ "lines": [{ "itemId": "pide", "quantity": 1 }] }),
+            )
+            .await;
+        assert_eq!(status, 422);
+        assert_eq!(body["error"], "invalid_order");
+
+        let (status, body) = api
+            .post(
+                "/v1/local/orders",
+                json!({
+                    "baseOrderId": "o-7",
+                    "baseVersion": 2,
+                    "lines": [{ "itemId": "lahmacun", "quantity": 1 }]
+                }),
+            )
+            .await;
+        assert_eq!(status, 409);
+        assert_eq!(body["error"], "stale_base");
+    }
+
+    #[tokio::test]
+    async fn local_command_is_queued_with_a_fresh_id_and_reports_its_outcome() {
+        let api = Api::start().await;
+        // Stand-in for the main loop: run whatever is queued and record it.
+        let queue = api.queue.clone();
+        tokio::spawn(async move {
+            loop {
+                queue.wait_for_push(Duration::from_secs(1)).await;
+                while let Some(cmd) = queue.pop_next().await.unwrap() {
+                    assert_eq!(cmd.origin, CommandOrigin::Local);
+                    let outcome = CommandOutcome {
+                        status: "done".to_string(),
+                        result: json!({ "printed": cmd.payload["copies"] }),
+                        error: None,
+                    };
+                    queue.mark_done(&cmd.id, &outcome).await.unwrap();
+                }
+            }
+        });
+
+        let (status, body) = api
+            .post(
+                "/v1/local/commands?wait=5",
+                json!({
+                    "id": "terminal-picked-id",
+                    "kind": "print_receipt",
+                    "payload": { "copies": 2 },
+                    "priority": 0,
+                    "attempts": 9
+                }),
+            )
+            .await;
+        assert_eq!(status, 200, "{body}");
+        let id = body["id"].as_str().unwrap();
+        assert_ne!(id, "terminal-picked-id");
+        assert_eq!(uuid::Uuid::parse_str(id).unwrap().get_version_num(), 7);
+        assert_eq!(body["outcome"]["status"], "done");
+        assert_eq!(body["outcome"]["result"]["printed"], 2);
+
+        let (status, again) = api.get(&format!("/v1/local/commands/{id}")).await;
+        assert_eq!(status, 200);
+        assert_eq!(again["status"], "done");
+    }
+
+    #[tokio::test]
+    async fn unsettled_commands_answer_202_and_cloud_commands_stay_hidden() {
+        let api = Api::start().await;
+        let (status, body) = api
+            .post("/v1/local/commands", json!({ "kind": "open_drawer" }))
+            .await;
+        assert_eq!(status, 202);
+        assert_eq!(body["status"], "queued");
+        assert!(body["outcome"].is_null());
+
+        api.queue
+            .push(&PendingCommand {
+                id: "cloud-1".to_string(),
+                kind: "print_receipt".to_string(),
+                payload: json!({}),
+                priority: 0,
+                attempts: 0,
+                idempotency_key: None,
+                origin: CommandOrigin::Cloud,
+            })
+            .await
+            .unwrap();
+        let (status, _) = api.get("/v1/local/commands/cloud-1").await;
+        assert_eq!(status, 404);
+
+        let (status, body) = api.post("/v1/local/commands", json!({ "kind": " " })).await;
+        assert_eq!(status, 422);
+        assert_eq!(body["error"], "invalid_command");
+    }
+
+    /// A terminal's key for a charge is namespaced, so it cannot reach a cloud
+    /// charge's key. Sent again with the same request it answers with the
+    /// command already queued; with a different payload it is refused.
+    #[tokio::test]
+    async fn charge_keys_are_namespaced_and_reuse_is_checked() {
+        let api = Api::start().await;
+        let charge = json!({
+            "kind": "charge_card",
+            "payload": { "amountCents": 4250, "idempotencyKey": "order-7" },
+        });
+        let (status, first) = api.post("/v1/local/commands", charge.clone()).await;
+        assert_eq!(status, 202, "{first}");
+        let id = first["id"].as_str().unwrap();
+        let row = api.queue.get(id).await.unwrap().unwrap();
+        assert_eq!(row.idempotency_key.as_deref(), Some("local:order-7"));
+        assert_eq!(row.payload, json!({ "amountCents": 4250 }));
+
+        let (status, again) = api.post("/v1/local/commands", charge).await;
+        assert_eq!(status, 202);
+        assert_eq!(again["id"], id, "the retry is the same command");
+        let (status, same_key) = api
+            .post(
+                "/v1/local/commands",
+                json!({
+                    "kind": "charge_card",
+                    "payload": { "amountCents": 9999 },
+                    "idempotency_key": "order-7",
+                }),
+            )
+            .await;
+        assert_eq!(status, 409);
+        assert_eq!(same_key["error"], "idempotency_key_reused");
+
+        let (status, body) = api
+            .post(
+                "/v1/local/commands",
+                json!({
+                    "kind": "charge_card",
+                    "payload": { "idempotencyKey": "a" },
+                    "idempotency_key": "b",
+                }),
+            )
+            .await;
+        assert_eq!(status, 422, "{body}");
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+    }
+}
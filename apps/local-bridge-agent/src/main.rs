@@ -4,20 +4,39 @@
 //!   - [`cloud_ws`]: persistent WSS to the cloud + REST fallback.
 //!   - [`command_queue`]: SQLite-backed durable command FIFO with backoff.
 //!   - [`drivers`]: per-device-class executors (escpos / yazarkasa / …).
+//!   - [`dispatcher`]: per-device workers, so slow devices don't block others.
 //!   - [`offline_cache`]: menu + open orders snapshot for offline ops.
+//!   - [`local_api`]: LAN API POS terminals use while the uplink is down.
 //!   - [`telemetry`]: heartbeat + structured logs to the cloud.
+//!   - [`metrics`]: Prometheus `/metrics` for the site's monitoring.
 //!   - [`updater`]: signed-manifest auto-update channel.
+//!   - [`service`]: stop signals and systemd `sd_notify`.
 //!
 //! Order of operations on startup:
-//!   1. Load config (cloud URL, bearer token from OS keyring).
+//!   1. Load config (cloud URL) and the bearer token from its credential store.
 //!   2. Initialise the local SQLite (`command_queue.db`).
 //!   3. Spawn driver tasks and bring them to "idle".
 //!   4. Open the WSS to the cloud. On failure, fall back to REST polling.
-//!   5. Run the main event loop: drain queue → execute → ack → repeat.
+//!   5. Run the main event loop: drain queue → execute (per device) → ack → repeat.
+//!   6. On SIGTERM/SIGINT (or a staged update): stop taking work, let running
+//!      commands finish within `[dispatch] shutdown_grace_secs`, flush acks,
+//!      join the background tasks and exit.
 
 use anyhow::Result;
 use clap::Parser;
-use hummytummy_local_bridge::{cloud_ws, command_queue, config, drivers, health, telemetry};
+use clap::Subcommand;
+use hummytummy_local_bridge::{
+    cloud_ws, command_queue, config, credentials, dispatcher, drivers, health, local_api, metrics,
+    offline_cache, review, service, telemetry, updater,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::task::JoinHandle;
 use tracing::{info, warn};
 use tracing_subscriber::EnvFilter;
 
@@ -28,9 +47,64 @@ struct Cli {
     #[arg(long)]
     config_dir: Option<String>,
 
-    /// Run a one-shot health-check and exit.
+    /// Run a one-shot health-check and exit (0 ok, 1 degraded, 2 broken).
     #[arg(long)]
     health: bool,
+
+    /// With --health: print the report as JSON.
+    #[arg(long, requires = "health")]
+    json: bool,
+
+    /// Operator subcommand. Without one, the agent runs.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+enum Command {
+    /// Inspect and settle commands parked in needs_review.
+    Review {
+        #[command(subcommand)]
+        action: review::ReviewAction,
+    },
+    /// Find printers on the LAN and print printers.toml entries for them.
+    Discover(drivers::escpos::discover::DiscoverArgs),
+}
+
+/// How long the shutdown waits for outstanding acks to go through before
+/// leaving them to the next start.
+const ACK_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wait for a reason to run the loop again: the cloud pushed, a LAN API
+/// command landed in the queue, a worker finished and freed a slot, a
+/// failed command's backoff ran out, or a stop was requested. The timeout
+/// keeps the ack drain (and the watchdog ping) ticking.
+async fn idle(
+    cloud: &cloud_ws::CloudClient,
+    queue: &command_queue::CommandQueue,
+    dispatcher: &dispatcher::Dispatcher,
+    stop: &service::StopFlag,
+    max: Duration,
+) {
+    let max = match queue.next_retry_in().await {
+        Ok(Some(wait)) => max.min(wait),
+        _ => max,
+    };
+    tokio::select! {
+        _ = cloud.wait_for_push(max) => {}
+        _ = queue.wait_for_push(max) => {}
+        _ = dispatcher.wait_for_worker(max) => {}
+        _ = stop.wait() => {}
+    }
+}
+
+/// Abort background tasks and wait for each to wind down, so none is dropped
+/// mid-write when the process exits.
+async fn join_all(tasks: impl IntoIterator<Item = JoinHandle<()>>) {
+    for task in tasks {
+        task.abort();
+        let _ = task.await;
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -47,27 +121,73 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
     info!(version = env!("CARGO_PKG_VERSION"), "bridge starting");
 
-    let cfg = config::load(cli.config_dir.as_deref())?;
+    // Before config::load: an unreadable config is something --health reports
+    // (exit 2), not a startup error.
     if cli.health {
-        return health::run(&cfg).await;
+        let code = health::run(cli.config_dir.as_deref(), cli.json).await;
+        std::process::exit(code);
     }
 
+    let cfg = config::load(cli.config_dir.as_deref())?;
+    if let Some(store) = credentials::load_into_process(&cfg) {
+        info!(store, "bearer token loaded");
+    }
+    match cli.command {
+        Some(Command::Review { action }) => return review::run(&cfg, action).await,
+        Some(Command::Discover(args)) => return drivers::escpos::discover::run(&cfg, args).await,
+        None => {}
+    }
+
+    // A freshly swapped binary counts its boots before doing anything else, so
+    // one that keeps crashing on startup is rolled back (see updater).
+    match updater::note_boot(&cfg.data_dir, env!("CARGO_PKG_VERSION")) {
+        Ok(updater::BootVerdict::RolledBack { version, reason }) => {
+            warn!(%version, %reason, "update rolled back — restarting into the previous binary");
+            std::process::exit(updater::EXIT_RESTART);
+        }
+        Ok(_) => {}
+        Err(e) => warn!(error = %e, "update state unreadable — skipping boot accounting"),
+    }
+
+    // One set of counters for the whole agent, read by `/metrics`.
+    let metrics = Arc::new(metrics::Metrics::default());
+
+    // The drivers registry resolves device kinds → executors at runtime.
+    // A driver that fails to initialise (e.g. printer not yet wired) is
+    // logged but does NOT block the agent boot.
+    let drivers = Arc::new(
+        drivers::Registry::init(&cfg.data_dir)
+            .await?
+            .with_metrics(metrics.clone()),
+    );
+    info!(
+        installed = drivers.installed_kinds().join(","),
+        "drivers initialised"
+    );
+
     // The command queue is the single source of truth for "what does this
     // bridge owe?". It outlives the cloud connection, so the agent keeps
     // working through transient internet outages.
     // Arc-wrapped so a low-frequency retention sweep can run alongside the main
-    // loop without moving the queue. open() also runs crash recovery (NH1/NH4):
-    // inflight rows orphaned by a previous crash are requeued (safe kinds) or
-    // parked in needs_review (money/fiscal kinds).
-    let queue = std::sync::Arc::new(command_queue::CommandQueue::open(
-        cfg.data_dir.join("command_queue.db"),
-    )?);
+    // loop without moving the queue. Opening also runs crash recovery (NH1/NH4):
+    // inflight rows orphaned by a previous crash are requeued (safe kinds, and
+    // keyed money/fiscal kinds whose driver verifies the key on the device) or
+    // parked in needs_review (every other money/fiscal row) — hence the
+    // registry comes first.
+    let queue = Arc::new(
+        command_queue::CommandQueue::open_replaying(
+            cfg.data_dir.join("command_queue.db"),
+            |cmd| drivers.verifies_idempotency(cmd),
+        )?
+        .with_retry(cfg.retry.clone())
+        .with_ttl(cfg.command_ttl.clone()),
+    );
 
     // deep-review NM2: bounded retention sweep on a low-frequency cadence so the
     // SQLite file does not grow without bound (eventually disk-full → all new
     // charges/prints fail). Drops only fully-settled rows (acked/failed) older
     // than 48h and reclaims pages via incremental_vacuum.
-    let _sweep_handle = {
+    let sweep_handle = {
         let q = queue.clone();
         tokio::spawn(async move {
             let mut tick = tokio::time::interval(std::time::Duration::from_secs(3600));
@@ -92,17 +212,15 @@ async fn main() -> Result<()> {
         _ => {}
     }
 
-    // The drivers registry resolves device kinds → executors at runtime.
-    // A driver that fails to initialise (e.g. printer not yet wired) is
-    // logged but does NOT block the agent boot.
-    let drivers = drivers::Registry::init(&cfg.data_dir).await?;
-    info!(
-        installed = drivers.installed_kinds().join(","),
-        "drivers initialised"
-    );
+    // printers.toml / gmp3.toml edits (and SIGHUP) take effect without a
+    // restart; a config that does not validate is logged and kept out.
+    let reload_handle = drivers::reload::spawn_watch(drivers.clone());
 
     // Cloud transport. WSS is the primary channel; REST polling is fallback.
-    let cloud = cloud_ws::CloudClient::new(cfg.clone())?;
+    let cloud = cloud_ws::CloudClient::new(cfg.clone())?.with_metrics(metrics.clone());
+    if cfg.command_signing.public_keys.is_empty() {
+        warn!("no [command_signing] key pinned — cloud commands are queued WITHOUT signature verification");
+    }
 
     // M9 — first-boot claim. If we have no bearer token yet but DO have a
     // one-shot provisioning token, exchange it for a bearer via
@@ -119,11 +237,18 @@ async fn main() -> Result<()> {
                 info!("no bearer token found — attempting first-boot claim");
                 match cloud.claim(prov).await {
                     Ok(resp) => {
-                        if let Err(e) = config::persist_bearer_token(&resp.token) {
-                            warn!(error = %e, "claim succeeded but persisting bearer token failed");
-                        } else {
-                            // Never log the token itself; the bridge id is safe.
-                            info!(bridge_id = %resp.bridge_id, "bridge claimed — bearer token persisted");
+                        // Usable for this run whatever happens below.
+                        config::set_bearer_token(&resp.token);
+                        // Never log the token itself; the bridge id is safe.
+                        match credentials::Credentials::from_config(&cfg).save(&resp.token) {
+                            Ok(store) => {
+                                info!(bridge_id = %resp.bridge_id, store, "bridge claimed — bearer token persisted")
+                            }
+                            Err(e) => warn!(
+                                bridge_id = %resp.bridge_id,
+                                error = %e,
+                                "bridge claimed but the bearer token could NOT be persisted — it is lost on restart and the provisioning token is spent; set HUMMY_BRIDGE_TOKEN from the cloud console"
+                            ),
                         }
                     }
                     Err(e) => {
@@ -143,75 +268,196 @@ async fn main() -> Result<()> {
         warn!(error = %e, "cloud warm-up failed — agent continues in offline mode");
     }
 
-    // Spawn telemetry heartbeat in the background. Handle is intentionally
-    // detached — the task runs for the lifetime of the agent and is torn
-    // down on process exit. Prefixed `_` so clippy doesn't flag it under
-    // -D warnings.
-    let _heartbeat_handle = telemetry::spawn_heartbeat(cloud.clone());
-
-    // Main loop: retry outstanding acks, then pull next queued command,
-    // dispatch, ack.
-    loop {
-        // deep-review NH3/NH7: an executed-but-unacked command is NOT settled.
-        // Before fetching new work, drain any outcomes that were persisted by a
-        // previous dispatch but whose ack failed (network blip / 5xx / crash
-        // before ack). Without this, the cloud still considers the command
-        // outstanding and re-issues it — often under a NEW command id that the
-        // local INSERT-OR-IGNORE dedup misses — re-executing the charge/print.
-        for (acked_cmd, outcome) in queue.pending_acks(32).await? {
-            match cloud.ack(&acked_cmd, &outcome).await {
-                Ok(()) => queue.mark_acked(&acked_cmd.id).await?,
-                Err(e) => {
-                    warn!(cmd = %acked_cmd.id, error = %e, "ack retry failed — outcome still not confirmed to cloud");
-                    // Leave the row in 'done' so it is retried on the next pass.
-                    // Back off so we don't spin while the cloud is unreachable.
-                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-                    break;
-                }
+    // Post-update health gate: keep the new binary unless it broke something
+    // the old one had working.
+    if updater::has_pending(&cfg.data_dir) {
+        let post = health::check(&cfg, &cloud, config::resolve_bearer_token().is_some())
+            .await
+            .status;
+        match updater::confirm_or_rollback(&cfg.data_dir, post) {
+            Ok(updater::BootVerdict::RolledBack { version, reason }) => {
+                warn!(%version, %reason, "update rolled back — restarting into the previous binary");
+                std::process::exit(updater::EXIT_RESTART);
             }
+            Ok(_) => {}
+            Err(e) => warn!(error = %e, "update confirmation failed"),
         }
+    }
 
-        if let Some(cmd) = queue.pop_next().await? {
-            match drivers.dispatch(&cmd).await {
-                Ok(outcome) => {
-                    // Persist the outcome first (durable), THEN ack. On ack
-                    // failure the row stays 'done' and the pending-acks drain
-                    // above retries it — never silently lose the outcome.
-                    queue.mark_done(&cmd.id, &outcome).await?;
-                    match cloud.ack(&cmd, &outcome).await {
-                        Ok(()) => queue.mark_acked(&cmd.id).await?,
-                        Err(e) => {
-                            warn!(cmd = %cmd.id, error = %e, "ack failed — outcome persisted, will retry");
-                        }
-                    }
-                }
+    // Telemetry heartbeat in the background, for the lifetime of the agent;
+    // joined on shutdown with the other background tasks.
+    let cache = Arc::new(offline_cache::OfflineCache::open(
+        cfg.data_dir.join("command_queue.db"),
+    )?);
+    let heartbeat_handle = telemetry::spawn_heartbeat(
+        cloud.clone(),
+        cache.clone(),
+        drivers.clone(),
+        metrics.clone(),
+    );
+
+    // Offline orders: the LAN API journals them, the replay task uploads them
+    // once the cloud answers again.
+    let local_api_handle = match &cfg.local_api {
+        Some(api) => match local_api::start(api, cache.clone(), queue.clone()).await {
+            Ok(handle) => handle,
+            Err(e) => {
+                warn!(error = %e, "local API unavailable");
+                None
+            }
+        },
+        None => None,
+    };
+    let replay_handle = offline_cache::spawn_replay(cache.clone(), cloud.clone());
+
+    // WSS push channel. Runs alongside the main loop, reconnecting with backoff;
+    // while it is down the loop below keeps polling REST.
+    let push_handle = cloud.spawn_push(queue.clone());
+
+    // Signed-manifest updates. The checker only stages and swaps; the restart
+    // happens at the top of the main loop, never mid-dispatch.
+    let (update_handle, restart_requested) = match updater::Updater::from_config(&cfg) {
+        Ok(Some(u)) => {
+            let (handle, flag) = updater::spawn(u, cfg.clone(), cloud.clone());
+            (Some(handle), flag)
+        }
+        Ok(None) => (None, Arc::new(AtomicBool::new(false))),
+        Err(e) => {
+            warn!(error = %e, "auto-update unavailable");
+            (None, Arc::new(AtomicBool::new(false)))
+        }
+    };
+
+    // Commands run on per-device workers (see dispatcher): a hung kitchen
+    // printer holds up its own tickets, never the card terminal.
+    let dispatcher = dispatcher::Dispatcher::new(
+        queue.clone(),
+        drivers.clone(),
+        cloud.clone(),
+        cfg.dispatch.max_concurrency,
+    )
+    .with_metrics(metrics.clone());
+
+    // Scrape endpoint. Stays up through the shutdown drain, so the last
+    // scrape shows what was left behind.
+    let metrics_handle = match &cfg.metrics {
+        Some(m) => match metrics::start(m, metrics, queue.clone(), drivers).await {
+            Ok(handle) => handle,
+            Err(e) => {
+                warn!(error = %e, "metrics endpoint unavailable");
+                None
+            }
+        },
+        None => None,
+    };
+
+    // From here on a stop signal is an orderly shutdown, not a kill.
+    let stop = service::StopFlag::default();
+    let signal_handle = service::spawn_signal_handler(stop.clone());
+    let notifier = service::Notifier::from_env();
+    notifier.ready(&format!("taking commands (v{})", env!("CARGO_PKG_VERSION")));
+    // Never sleep through a watchdog ping.
+    let tick = |max: Duration| notifier.watchdog_interval().map_or(max, |w| max.min(w));
+
+    // Main loop: retry outstanding acks, hand queued work to device workers,
+    // then wait for more (push) or pull it (REST).
+    let exit_code = loop {
+        notifier.watchdog();
+        if stop.is_raised() {
+            break 0;
+        }
+        if restart_requested.load(Ordering::SeqCst) {
+            info!(
+                "updated binary installed — finishing running commands, then exiting for restart"
+            );
+            break updater::EXIT_RESTART;
+        }
+
+        // Errors here are logged, never `?`: leaving the loop that way would
+        // skip the orderly stop below. Only the stop flag (or a restart) ends it.
+        match notifier.keep_alive(dispatcher.retry_acks()).await {
+            Ok(true) => {}
+            // Back off so we don't spin while the cloud is unreachable.
+            Ok(false) => tokio::time::sleep(tick(Duration::from_secs(5))).await,
+            Err(e) => {
+                warn!(error = %e, "retrying acks failed");
+                tokio::time::sleep(tick(Duration::from_secs(5))).await;
+            }
+        }
+
+        if let Err(e) = dispatcher.spawn_ready().await {
+            warn!(error = %e, "starting device workers failed");
+            tokio::time::sleep(tick(Duration::from_secs(5))).await;
+        }
+
+        let idle = |max| idle(&cloud, &queue, &dispatcher, &stop, tick(max));
+        if cloud.push_is_live() {
+            // The cloud pushes new work over the socket; wait for it instead of
+            // polling.
+            idle(Duration::from_secs(5)).await;
+        } else {
+            // Pull more from the cloud even while workers are busy — a dead
+            // printer's backlog must not keep the next charge server-side.
+            match notifier.keep_alive(cloud.fetch_more(&queue)).await {
+                Ok(()) if dispatcher.active() > 0 => idle(Duration::from_secs(1)).await,
+                Ok(()) => {}
                 Err(e) => {
-                    warn!(cmd = %cmd.id, error = %e, "command failed");
-                    // mark_failed is kind-aware: side-effecting (money/fiscal)
-                    // kinds are parked in 'needs_review' here rather than
-                    // requeued, so the ack_failed below does not race a retry.
-                    queue.mark_failed(&cmd.id, &e.to_string()).await?;
-                    if let Err(ack_err) = cloud.ack_failed(&cmd, &e.to_string()).await {
-                        warn!(cmd = %cmd.id, error = %ack_err, "ack_failed not confirmed to cloud");
-                    }
+                    // Back off briefly so we don't hammer the API. A LAN API
+                    // push ends the back-off early: offline is exactly when
+                    // local commands matter.
+                    warn!(error = %e, "cloud fetch failed");
+                    idle(Duration::from_secs(5)).await;
                 }
             }
-        } else if let Err(e) = cloud.fetch_more(&queue).await {
-            // No work locally → pull more from the cloud. On error, back off
-            // briefly so we don't hammer the API.
-            warn!(error = %e, "cloud fetch failed");
-            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
         }
         tokio::task::yield_now().await;
-    }
+    };
 
-    // (background task handles drop with the process; the OS reclaims them.)
-    #[allow(unreachable_code)]
-    {
-        drop(_heartbeat_handle);
-        drop(_sweep_handle);
-        Ok(())
+    // Orderly stop. Intake first — the push channel, the LAN API, order
+    // replay and the update checker — so nothing new arrives while the
+    // running commands finish.
+    notifier.stopping(if exit_code == 0 {
+        "stopping: finishing running commands"
+    } else {
+        "restarting into the updated binary"
+    });
+    join_all(
+        [push_handle, local_api_handle, update_handle]
+            .into_iter()
+            .flatten()
+            .chain([replay_handle]),
+    )
+    .await;
+    let grace = Duration::from_secs(cfg.dispatch.shutdown_grace_secs);
+    match dispatcher.shutdown(grace).await {
+        Ok(0) => info!("running commands finished"),
+        Ok(n) => warn!(
+            cut_off = n,
+            "some commands did not finish in time and were released"
+        ),
+        Err(e) => {
+            warn!(error = %e, "releasing cut-off commands failed — boot recovery will settle them")
+        }
+    }
+    // Outcomes are durable either way; flushing just spares the cloud the wait.
+    match tokio::time::timeout(ACK_FLUSH_TIMEOUT, dispatcher.retry_acks()).await {
+        Ok(Ok(true)) => info!("acks flushed"),
+        Ok(Ok(false)) | Err(_) => {
+            warn!("cloud did not take every ack before shutdown — the rest go out after restart")
+        }
+        Ok(Err(e)) => warn!(error = %e, "flushing acks failed — they go out after restart"),
     }
+    join_all(
+        [heartbeat_handle, sweep_handle, reload_handle, signal_handle]
+            .into_iter()
+            .chain(metrics_handle),
+    )
+    .await;
+    info!(exit_code, "bridge stopped");
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -245,6 +491,13 @@ mod tests {
         assert!(cli.config_dir.is_none());
     }
 
+    #[test]
+    fn json_flag_only_applies_to_health() {
+        let cli = Cli::parse_from(["bridge", "--health", "--json"]);
+        assert!(cli.health && cli.json);
+        assert!(Cli::try_parse_from(["bridge", "--json"]).is_err());
+    }
+
     #[test]
     fn combined_flags_parse_together() {
         let cli = Cli::parse_from(["bridge", "--config-dir", "/srv/cfg", "--health"]);
@@ -252,6 +505,93 @@ mod tests {
         assert!(cli.health);
     }
 
+    #[test]
+    fn review_subcommands_parse() {
+        use super::Command;
+        use hummytummy_local_bridge::review::{ResolveAs, ReviewAction};
+
+        let cli = Cli::parse_from(["bridge", "review", "list"]);
+        assert_eq!(
+            cli.command,
+            Some(Command::Review {
+                action: ReviewAction::List
+            })
+        );
+
+        let cli = Cli::parse_from(["bridge", "review", "retries"]);
+        assert_eq!(
+            cli.command,
+            Some(Command::Review {
+                action: ReviewAction::Retries
+            })
+        );
+
+        let cli = Cli::parse_from(["bridge", "review", "show", "cmd-1"]);
+        assert_eq!(
+            cli.command,
+            Some(Command::Review {
+                action: ReviewAction::Show {
+                    id: "cmd-1".to_string()
+                }
+            })
+        );
+
+        let cli = Cli::parse_from([
+            "bridge",
+            "--config-dir",
+            "/etc/hummy",
+            "review",
+            "resolve",
+            "cmd-2",
+            "--as",
+            "requeue",
+            "--note",
+            "not on the Z report",
+        ]);
+        assert_eq!(cli.config_dir.as_deref(), Some("/etc/hummy"));
+        match cli.command {
+            Some(Command::Review {
+                action:
+                    ReviewAction::Resolve {
+                        id,
+                        resolution,
+                        note,
+                        ..
+                    },
+            }) => {
+                assert_eq!(id, "cmd-2");
+                assert_eq!(resolution, ResolveAs::Requeue);
+                assert_eq!(note.as_deref(), Some("not on the Z report"));
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn review_resolve_requires_a_valid_resolution() {
+        assert!(Cli::try_parse_from(["bridge", "review", "resolve", "c"]).is_err());
+        assert!(
+            Cli::try_parse_from(["bridge", "review", "resolve", "c", "--as", "maybe"]).is_err()
+        );
+    }
+
+    #[test]
+    fn discover_subcommand_parses() {
+        use super::Command;
+        let cli = Cli::parse_from([
+            "bridge",
+            "discover",
+            "--subnet",
+            "192.168.1.0/24",
+            "--subnet",
+            "10.0.5.0/24",
+            "--no-mdns",
+            "--json",
+        ]);
+        assert!(matches!(cli.command, Some(Command::Discover(_))));
+        assert!(Cli::try_parse_from(["bridge", "discover", "--port", "printer"]).is_err());
+    }
+
     #[test]
     fn unknown_flag_is_rejected() {
         // try_parse_from returns Err on an unrecognised flag — the binary
@@ -0,0 +1,566 @@
+//! Prometheus `/metrics` endpoint.
+//!
+//! One [`Metrics`] is shared by the pieces that do the work: the driver
+//! [`Registry`] times every dispatch, the [`crate::cloud_ws::CloudClient`]
+//! counts every cloud call, the [`crate::dispatcher::Dispatcher`] counts ack
+//! retries and the heartbeat task stamps each accepted heartbeat. Queue depth
+//! and device readiness are read from the [`CommandQueue`] and the registry
+//! when scraped, so they are never stale.
+//!
+//! | metric                                         | type      | labels            |
+//! |------------------------------------------------|-----------|-------------------|
+//! | `hummy_bridge_build_info`                      | gauge     | `version`         |
+//! | `hummy_bridge_queue_commands`                  | gauge     | `status`          |
+//! | `hummy_bridge_needs_review_commands`           | gauge     |                   |
+//! | `hummy_bridge_acks_pending`                    | gauge     |                   |
+//! | `hummy_bridge_ack_retries_total`               | counter   | `result`          |
+//! | `hummy_bridge_dispatch_duration_seconds`       | histogram | `driver`, `outcome` |
+//! | `hummy_bridge_cloud_requests_total`            | counter   | `call`, `result`  |
+//! | `hummy_bridge_heartbeat_age_seconds`           | gauge     |                   |
+//! | `hummy_bridge_device_ready`                    | gauge     | `driver`, `device` |
+//!
+//! `outcome` is the command's status when the driver returned one (`done`,
+//! `failed`), or `error` when it did not. `result` is `ok` or `error`. The
+//! heartbeat age counts from agent start until the first heartbeat goes
+//! through, so a bridge that never reached the cloud still alerts.
+//!
+//! Served on its own listener (`[metrics]`, loopback by default) in the
+//! Prometheus text format. A scrape token is required on any other address:
+//! the output names every device on the site.
+
+use crate::{
+    command_queue::CommandQueue,
+    config::{self, MetricsConfig},
+    drivers::Registry,
+};
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+use tracing::{info, warn};
+
+/// Upper bounds of the dispatch latency buckets, in seconds. A print takes
+/// well under a second; a card sale waits on the customer.
+const DISPATCH_BUCKETS: [f64; 11] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Statuses always reported, at 0 when the queue has none.
+const QUEUE_STATUSES: [&str; 7] = [
+    "queued",
+    "inflight",
+    "done",
+    "acked",
+    "failed",
+    "needs_review",
+    "expired",
+];
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    buckets: [u64; DISPATCH_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        for (bound, n) in DISPATCH_BUCKETS.iter().zip(&mut self.buckets) {
+            if secs <= *bound {
+                *n += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+/// Counters and histograms the agent records as it works.
+#[derive(Debug)]
+pub struct Metrics {
+    started: Instant,
+    dispatch: Mutex<BTreeMap<(String, String), Histogram>>,
+    cloud_requests: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    ack_retries: Mutex<BTreeMap<&'static str, u64>>,
+    last_heartbeat: Mutex<Option<Instant>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            dispatch: Mutex::default(),
+            cloud_requests: Mutex::default(),
+            ack_retries: Mutex::default(),
+            last_heartbeat: Mutex::default(),
+        }
+    }
+}
+
+fn result(ok: bool) -> &'static str {
+    if ok {
+        "ok"
+    } else {
+        "error"
+    }
+}
+
+impl Metrics {
+    /// One command through a driver. `outcome` is the returned status, or
+    /// `"error"` when the driver returned an error.
+    pub fn observe_dispatch(&self, driver: &str, outcome: &str, took: Duration) {
+        self.dispatch
+            .lock()
+            .expect("metrics mutex poisoned")
+            .entry((driver.to_string(), outcome.to_string()))
+            .or_default()
+            .observe(took.as_secs_f64());
+    }
+
+    /// One cloud call, after any token refresh and retry it needed.
+    pub fn cloud_request(&self, call: &'static str, ok: bool) {
+        *self
+            .cloud_requests
+            .lock()
+            .expect("metrics mutex poisoned")
+            .entry((call, result(ok)))
+            .or_default() += 1;
+    }
+
+    /// One attempt to deliver an outcome whose first ack did not go through.
+    pub fn ack_retry(&self, ok: bool) {
+        *self
+            .ack_retries
+            .lock()
+            .expect("metrics mutex poisoned")
+            .entry(result(ok))
+            .or_default() += 1;
+    }
+
+    /// The cloud accepted a heartbeat.
+    pub fn heartbeat_ok(&self) {
+        *self.last_heartbeat.lock().expect("metrics mutex poisoned") = Some(Instant::now());
+    }
+
+    /// The exposition: what was recorded, plus the queue and the devices as
+    /// they are now. A queue that cannot be read leaves its metrics out
+    /// rather than reporting zeros.
+    pub async fn render(&self, queue: &CommandQueue, drivers: &Registry) -> String {
+        let mut out = String::new();
+        family(&mut out, "build_info", "gauge", "Agent version.");
+        sample(
+            &mut out,
+            "build_info",
+            &[("version", env!("CARGO_PKG_VERSION"))],
+            1,
+        );
+
+        match queue.status_counts().await {
+            Ok(counts) => {
+                family(
+                    &mut out,
+                    "queue_commands",
+                    "gauge",
+                    "Commands in the local queue by status.",
+                );
+                let mut all: BTreeMap<&str, i64> = QUEUE_STATUSES.iter().map(|s| (*s, 0)).collect();
+                all.extend(counts.iter().map(|(s, n)| (s.as_str(), *n)));
+                for (status, n) in &all {
+                    sample(&mut out, "queue_commands", &[("status", status)], n);
+                }
+                family(
+                    &mut out,
+                    "needs_review_commands",
+                    "gauge",
+                    "Commands parked for an operator to reconcile.",
+                );
+                sample(&mut out, "needs_review_commands", &[], all["needs_review"]);
+            }
+            Err(e) => warn!(error = %e, "metrics: queue counts unavailable"),
+        }
+        match queue.pending_ack_count().await {
+            Ok(n) => {
+                family(
+                    &mut out,
+                    "acks_pending",
+                    "gauge",
+                    "Settled commands whose outcome the cloud has not confirmed.",
+                );
+                sample(&mut out, "acks_pending", &[], n);
+            }
+            Err(e) => warn!(error = %e, "metrics: pending ack count unavailable"),
+        }
+
+        family(
+            &mut out,
+            "ack_retries_total",
+            "counter",
+            "Re-sent acks, by result.",
+        );
+        for (res, n) in self
+            .ack_retries
+            .lock()
+            .expect("metrics mutex poisoned")
+            .iter()
+        {
+            sample(&mut out, "ack_retries_total", &[("result", res)], n);
+        }
+
+        family(
+            &mut out,
+            "dispatch_duration_seconds",
+            "histogram",
+            "Time a driver spent on a command, by driver and outcome.",
+        );
+        for ((driver, outcome), h) in self.dispatch.lock().expect("metrics mutex poisoned").iter() {
+            let labels = [("driver", driver.as_str()), ("outcome", outcome.as_str())];
+            for (bound, n) in DISPATCH_BUCKETS.iter().zip(h.buckets) {
+                let le = bound.to_string();
+                let mut with_le = labels.to_vec();
+                with_le.push(("le", &le));
+                sample(&mut out, "dispatch_duration_seconds_bucket", &with_le, n);
+            }
+            let mut inf = labels.to_vec();
+            inf.push(("le", "+Inf"));
+            sample(&mut out, "dispatch_duration_seconds_bucket", &inf, h.count);
+            sample(&mut out, "dispatch_duration_seconds_sum", &labels, h.sum);
+            sample(
+                &mut out,
+                "dispatch_duration_seconds_count",
+                &labels,
+                h.count,
+            );
+        }
+
+        family(
+            &mut out,
+            "cloud_requests_total",
+            "counter",
+            "Calls to the cloud, by call and result.",
+        );
+        for ((call, res), n) in self
+            .cloud_requests
+            .lock()
+            .expect("metrics mutex poisoned")
+            .iter()
+        {
+            sample(
+                &mut out,
+                "cloud_requests_total",
+                &[("call", call), ("result", res)],
+                n,
+            );
+        }
+
+        let since = self
+            .last_heartbeat
+            .lock()
+            .expect("metrics mutex poisoned")
+            .unwrap_or(self.started);
+        family(
+            &mut out,
+            "heartbeat_age_seconds",
+            "gauge",
+            "Seconds since the cloud last accepted a heartbeat (since start, if never).",
+        );
+        sample(
+            &mut out,
+            "heartbeat_age_seconds",
+            &[],
+            since.elapsed().as_secs_f64(),
+        );
+
+        family(
+            &mut out,
+            "device_ready",
+            "gauge",
+            "1 if the device answered its readiness probe.",
+        );
+        for d in drivers.readiness().await {
+            sample(
+                &mut out,
+                "device_ready",
+                &[("driver", &d.driver), ("device", &d.device)],
+                u8::from(d.ready),
+            );
+        }
+        out
+    }
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP hummy_bridge_{name} {help}");
+    let _ = writeln!(out, "# TYPE hummy_bridge_{name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    let _ = write!(out, "hummy_bridge_{name}");
+    if !labels.is_empty() {
+        let body: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| format!("{k}=\"{}\"", escape(v)))
+            .collect();
+        let _ = write!(out, "{{{}}}", body.join(","));
+    }
+    let _ = writeln!(out, " {value}");
+}
+
+/// Label values escape backslash, double quote and newline.
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[derive(Clone)]
+struct MetricsState {
+    metrics: Arc<Metrics>,
+    queue: Arc<CommandQueue>,
+    drivers: Arc<Registry>,
+    token: Option<Arc<str>>,
+}
+
+/// Build the router. Split from [`start`] so tests can serve it on an
+/// ephemeral loopback port.
+pub fn router(
+    metrics: Arc<Metrics>,
+    queue: Arc<CommandQueue>,
+    drivers: Arc<Registry>,
+    token: Option<String>,
+) -> Router {
+    let state = MetricsState {
+        metrics,
+        queue,
+        drivers,
+        token: token.map(Into::into),
+    };
+    Router::new()
+        .route("/metrics", get(scrape))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state)
+}
+
+/// Bind `cfg.listen` and serve in the background. `Ok(None)` when the
+/// address is not loopback and no token is configured: the endpoint stays
+/// off (fail closed).
+pub async fn start(
+    cfg: &MetricsConfig,
+    metrics: Arc<Metrics>,
+    queue: Arc<CommandQueue>,
+    drivers: Arc<Registry>,
+) -> Result<Option<JoinHandle<()>>> {
+    let token = config::resolve_metrics_token(cfg)?;
+    if token.is_none() && !cfg.listen.ip().is_loopback() {
+        warn!(listen = %cfg.listen, "[metrics] listens beyond loopback but has no token (HUMMY_METRICS_TOKEN / token_file) — /metrics not served");
+        return Ok(None);
+    }
+    let listener = TcpListener::bind(cfg.listen)
+        .await
+        .with_context(|| format!("bind metrics on {}", cfg.listen))?;
+    info!(listen = %cfg.listen, "metrics listening");
+    Ok(Some(crate::local_api::serve(
+        listener,
+        router(metrics, queue, drivers, token),
+    )))
+}
+
+async fn require_token(State(state): State<MetricsState>, req: Request, next: Next) -> Response {
+    let Some(token) = &state.token else {
+        return next.run(req).await;
+    };
+    let presented = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match presented {
+        Some(t) if crate::local_api::constant_time_eq(t.as_bytes(), token.as_bytes()) => {
+            next.run(req).await
+        }
+        _ => (StatusCode::UNAUTHORIZED, "missing or wrong bearer token\n").into_response(),
+    }
+}
+
+async fn scrape(State(state): State<MetricsState>) -> Response {
+    let body = state.metrics.render(&state.queue, &state.drivers).await;
+    (
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        body,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_queue::{CommandOutcome, PendingCommand};
+    use crate::drivers::LocalDriver;
+    use async_trait::async_trait;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    struct Printer;
+
+    #[async_trait]
+    impl LocalDriver for Printer {
+        fn kind(&self) -> &str {
+            "escpos"
+        }
+        async fn execute(&self, cmd: &PendingCommand) -> Result<CommandOutcome> {
+            if cmd.payload["fail"] == true {
+                anyhow::bail!("paper out");
+            }
+            Ok(CommandOutcome {
+                status: "done".to_string(),
+                result: json!({}),
+                error: None,
+            })
+        }
+        async fn readiness(&self) -> Vec<crate::drivers::DeviceReadiness> {
+            vec![crate::drivers::DeviceReadiness {
+                driver: "escpos".to_string(),
+                device: "bar \"2\"".to_string(),
+                ready: false,
+                detail: "connect refused".to_string(),
+            }]
+        }
+    }
+
+    fn print(id: &str, fail: bool) -> PendingCommand {
+        PendingCommand {
+            id: id.to_string(),
+            kind: "print_receipt".to_string(),
+            payload: json!({ "target": "escpos", "fail": fail }),
+            priority: 0,
+            attempts: 0,
+            idempotency_key: None,
+            origin: Default::default(),
+        }
+    }
+
+    fn line<'a>(body: &'a str, prefix: &str) -> &'a str {
+        body.lines()
+            .find(|l| l.starts_with(prefix))
+            .unwrap_or_else(|| panic!("no line starting {prefix} in\n{body}"))
+    }
+
+    /// What a scrape shows after two prints (one failed), a cloud error,
+    /// a retried ack and a heartbeat.
+    #[tokio::test]
+    async fn scrape_reports_what_the_agent_did() {
+        let dir = TempDir::new().unwrap();
+        let queue = Arc::new(CommandQueue::open(dir.path().join("q.db")).unwrap());
+        queue.push(&print("waiting", false)).await.unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let drivers =
+            Arc::new(Registry::from_drivers(vec![Box::new(Printer)]).with_metrics(metrics.clone()));
+        drivers.dispatch(&print("p-1", false)).await.unwrap();
+        drivers.dispatch(&print("p-2", true)).await.unwrap_err();
+        metrics.cloud_request("ack", true);
+        metrics.cloud_request("fetch", false);
+        metrics.ack_retry(true);
+        metrics.heartbeat_ok();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = crate::local_api::serve(
+            listener,
+            router(
+                metrics,
+                queue,
+                drivers,
+                Some("scrape-token-0123456789".into()),
+            ),
+        );
+        let client = reqwest::Client::new();
+        let url = format!("http://{addr}/metrics");
+
+        let denied = client.get(&url).send().await.unwrap();
+        assert_eq!(denied.status(), 401);
+
+        let resp = client
+            .get(&url)
+            .bearer_auth("scrape-token-0123456789")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert!(resp.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain; version=0.0.4"));
+        let body = resp.text().await.unwrap();
+        server.abort();
+
+        assert!(body.contains("hummy_bridge_queue_commands{status=\"queued\"} 1\n"));
+        assert!(body.contains("hummy_bridge_queue_commands{status=\"needs_review\"} 0\n"));
+        assert!(body.contains("hummy_bridge_needs_review_commands 0\n"));
+        assert!(body.contains("hummy_bridge_acks_pending 0\n"));
+        assert!(body.contains(
+            "hummy_bridge_dispatch_duration_seconds_count{driver=\"escpos\",outcome=\"done\"} 1\n"
+        ));
+        assert!(body.contains(
+            "hummy_bridge_dispatch_duration_seconds_bucket{driver=\"escpos\",outcome=\"error\",le=\"+Inf\"} 1\n"
+        ));
+        assert!(
+            body.contains("hummy_bridge_cloud_requests_total{call=\"fetch\",result=\"error\"} 1\n")
+        );
+        assert!(body.contains("hummy_bridge_ack_retries_total{result=\"ok\"} 1\n"));
+        assert!(body
+            .contains("hummy_bridge_device_ready{driver=\"escpos\",device=\"bar \\\"2\\\"\"} 0\n"));
+        let age: f64 = line(&body, "hummy_bridge_heartbeat_age_seconds ")
+            .rsplit(' ')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(age < 5.0, "{age}");
+        assert!(body.contains("# TYPE hummy_bridge_dispatch_duration_seconds histogram\n"));
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut h = Histogram::default();
+        h.observe(0.07);
+        h.observe(3.0);
+        h.observe(500.0);
+        assert_eq!(h.buckets[0], 0, "le=0.05");
+        assert_eq!(h.buckets[1], 1, "le=0.1");
+        assert_eq!(h.buckets[6], 2, "le=5");
+        assert_eq!(*h.buckets.last().unwrap(), 2, "500s only in +Inf");
+        assert_eq!(h.count, 3);
+    }
+
+    /// Fail closed: off loopback, no token means no endpoint.
+    #[tokio::test]
+    async fn non_loopback_without_a_token_is_not_served() {
+        let dir = TempDir::new().unwrap();
+        let queue = Arc::new(CommandQueue::open(dir.path().join("q.db")).unwrap());
+        let cfg = MetricsConfig {
+            listen: "0.0.0.0:0".parse().unwrap(),
+            token_file: None,
+        };
+        let served = start(
+            &cfg,
+            Arc::default(),
+            queue,
+            Arc::new(Registry::from_drivers(vec![])),
+        )
+        .await
+        .unwrap();
+        assert!(served.is_none());
+    }
+}
@@ -1,35 +1,853 @@
-//! Offline cache for menu, open orders, and last-known device state.
+//! Offline cache for menu, open orders, and last-known device state, plus the
+//! journal of orders taken on the LAN while the cloud was unreachable.
 //!
 //! Stored in the same SQLite file as the command queue but in separate
-//! tables. The cloud snapshots these at intervals and pushes the latest
-//! payload at bridge claim/heartbeat.
+//! tables. The cloud ships the latest snapshots with the heartbeat response
+//! ([`crate::cloud_ws::HeartbeatResponse`]); [`OfflineCache::ingest`] keeps
+//! the newest version of each and ignores stale or reordered ones.
+//!
+//! ## Offline orders
+//!
+//! POS terminals keep taking orders through the local API while the internet
+//! is down. Each order is validated against the cached menu (prices always
+//! come from the menu, never from the terminal) and journaled in
+//! `offline_orders` with a locally generated UUIDv7. [`replay_pending`] uploads
+//! them oldest-first once the cloud is reachable again.
+//!
+//! Conflict detection: an order that amends an existing open order records the
+//! order version the terminal saw (`base_version`). If a newer open-orders
+//! snapshot shows that order has moved on (or closed) by replay time, the entry
+//! is marked `conflict` on the bridge without uploading; the cloud applies the
+//! same check and answers 409 for changes the bridge has not seen yet. A
+//! conflicted order is never merged automatically — a human decides.
+
+use crate::{
+    cloud_ws::{CloudClient, OrderReplayResponse},
+    command_queue::chrono_unix_now,
+};
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::BTreeMap, path::Path, sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// A typed snapshot stored under a fixed key in `snapshots`.
+pub trait Snapshot: Serialize + DeserializeOwned {
+    /// Row key, and the `key` the cloud uses in a [`SnapshotEnvelope`].
+    const KEY: &'static str;
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Menu {
+    pub currency: String,
+    pub items: Vec<MenuItem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MenuItem {
+    pub id: String,
+    pub name: String,
+    pub price_cents: i64,
+    #[serde(default = "available_by_default")]
+    pub available: bool,
+}
+
+fn available_by_default() -> bool {
+    true
+}
+
+impl Snapshot for Menu {
+    const KEY: &'static str = "menu";
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenOrders {
+    pub orders: Vec<OpenOrder>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenOrder {
+    pub id: String,
+    /// Cloud-side optimistic-concurrency version of the order.
+    pub version: i64,
+    #[serde(default)]
+    pub table: Option<String>,
+    pub lines: Vec<OrderLine>,
+    pub total_cents: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderLine {
+    pub item_id: String,
+    pub quantity: i64,
+    pub price_cents: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+impl Snapshot for OpenOrders {
+    const KEY: &'static str = "open_orders";
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceState {
+    pub devices: Vec<DeviceStateEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceStateEntry {
+    pub id: String,
+    pub kind: String,
+    pub status: String,
+    #[serde(default)]
+    pub updated_at: Option<i64>,
+}
+
+impl Snapshot for DeviceState {
+    const KEY: &'static str = "device_state";
+}
+
+/// A snapshot as read back, with the cloud version it was shipped at.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Versioned<T> {
+    pub version: i64,
+    /// When the bridge stored it (unix ms) — how stale the offline view is.
+    pub updated_at: i64,
+    pub data: T,
+}
+
+/// A snapshot as the cloud ships it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotEnvelope {
+    pub key: String,
+    /// Monotonic per key; older versions never overwrite newer ones.
+    pub version: i64,
+    pub payload: serde_json::Value,
+}
+
+/// An order as a POS terminal submits it to the local API.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OfflineOrderRequest {
+    /// Set when adding to an existing open order.
+    #[serde(default)]
+    pub base_order_id: Option<String>,
+    /// The version of `base_order_id` the terminal was looking at.
+    #[serde(default)]
+    pub base_version: Option<i64>,
+    #[serde(default)]
+    pub table: Option<String>,
+    pub lines: Vec<OrderLineRequest>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderLineRequest {
+    pub item_id: String,
+    pub quantity: i64,
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+/// Why an offline order was refused at the counter.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum OrderRejection {
+    #[error("no menu snapshot yet — cannot take orders offline")]
+    NoMenu,
+    #[error("order has no lines")]
+    Empty,
+    #[error("unknown menu item '{0}'")]
+    UnknownItem(String),
+    #[error("menu item '{0}' is not available")]
+    Unavailable(String),
+    #[error("quantity for '{0}' must be positive")]
+    BadQuantity(String),
+    #[error("order '{0}' is not open")]
+    UnknownBaseOrder(String),
+    #[error("order '{order}' is at version {current}, the terminal sent version {sent} — refresh and retry")]
+    StaleBase {
+        order: String,
+        sent: i64,
+        current: i64,
+    },
+}
+
+/// An `offline_orders` row.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JournaledOrder {
+    pub local_id: String,
+    pub base_order_id: Option<String>,
+    pub base_version: Option<i64>,
+    pub table: Option<String>,
+    pub lines: Vec<OrderLine>,
+    pub total_cents: i64,
+    /// pending | replayed | conflict | rejected
+    pub status: String,
+    pub cloud_order_id: Option<String>,
+    pub error: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
 
-use anyhow::Result;
-use rusqlite::Connection;
-use std::path::Path;
+/// Result of one [`replay_pending`] pass.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub replayed: usize,
+    pub conflicts: usize,
+    pub rejected: usize,
+    /// Left pending because the cloud was unreachable mid-pass.
+    pub deferred: usize,
+}
 
 pub struct OfflineCache {
-    // Held but not yet read — the bridge currently only creates the schema
-    // at startup (in `open` below). Readers + writers land alongside the
-    // first feature that needs the cache (Phase 6 — offline order replay).
-    // Until then we keep the connection alive so the file handle outlives
-    // this struct's caller and SQLite WAL flushes happen on Drop.
-    #[allow(dead_code)]
     conn: std::sync::Mutex<Connection>,
 }
 
 impl OfflineCache {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
         let conn = Connection::open(path.as_ref())?;
+        // Shares command_queue.db with CommandQueue's connection: same WAL and
+        // busy_timeout hygiene so the two never trip over each other.
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = FULL;
+             PRAGMA busy_timeout = 5000;",
+        )?;
         conn.execute_batch(
             "CREATE TABLE IF NOT EXISTS snapshots (
                 key TEXT PRIMARY KEY,
                 payload TEXT NOT NULL,
                 updated_at INTEGER NOT NULL
-            );",
+            );
+            CREATE TABLE IF NOT EXISTS offline_orders (
+                local_id TEXT PRIMARY KEY,
+                base_order_id TEXT,
+                base_version INTEGER,
+                table_label TEXT,
+                lines TEXT NOT NULL,
+                total_cents INTEGER NOT NULL,
+                status TEXT NOT NULL DEFAULT 'pending',
+                cloud_order_id TEXT,
+                error TEXT,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_offline_orders_status
+              ON offline_orders (status, created_at);",
         )?;
+        // The original schema had no version column; every pre-existing row
+        // reads as version 0 and is replaced by the first real snapshot.
+        let _ = conn.execute(
+            "ALTER TABLE snapshots ADD COLUMN version INTEGER NOT NULL DEFAULT 0",
+            [],
+        );
         Ok(Self {
             conn: std::sync::Mutex::new(conn),
         })
     }
+
+    /// Store `snap` at `version`. Returns false (and stores nothing) when an
+    /// equal or newer version is already cached.
+    pub fn put<S: Snapshot>(&self, snap: &S, version: i64) -> Result<bool> {
+        let payload = serde_json::to_string(snap)?;
+        let conn = self.conn.lock().expect("offline cache mutex poisoned");
+        let n = conn.execute(
+            "INSERT INTO snapshots (key, payload, version, updated_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(key) DO UPDATE
+                SET payload = excluded.payload,
+                    version = excluded.version,
+                    updated_at = excluded.updated_at
+              WHERE excluded.version > snapshots.version",
+            params![S::KEY, payload, version, chrono_unix_now()],
+        )?;
+        Ok(n > 0)
+    }
+
+    pub fn get<S: Snapshot>(&self) -> Result<Option<Versioned<S>>> {
+        let conn = self.conn.lock().expect("offline cache mutex poisoned");
+        let row: Option<(String, i64, i64)> = conn
+            .query_row(
+                "SELECT payload, version, updated_at FROM snapshots WHERE key = ?1",
+                params![S::KEY],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        match row {
+            Some((payload, version, updated_at)) => Ok(Some(Versioned {
+                version,
+                updated_at,
+                data: serde_json::from_str(&payload)
+                    .with_context(|| format!("decoding cached '{}' snapshot", S::KEY))?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Store a cloud-shipped snapshot after checking it decodes into its typed
+    /// shape — a malformed menu must not replace a good one.
+    pub fn ingest(&self, env: &SnapshotEnvelope) -> Result<bool> {
+        fn typed<S: Snapshot>(cache: &OfflineCache, env: &SnapshotEnvelope) -> Result<bool> {
+            let snap: S = serde_json::from_value(env.payload.clone())
+                .with_context(|| format!("snapshot '{}' v{} is malformed", env.key, env.version))?;
+            cache.put(&snap, env.version)
+        }
+        match env.key.as_str() {
+            Menu::KEY => typed::<Menu>(self, env),
+            OpenOrders::KEY => typed::<OpenOrders>(self, env),
+            DeviceState::KEY => typed::<DeviceState>(self, env),
+            other => anyhow::bail!("unknown snapshot key '{other}'"),
+        }
+    }
+
+    /// Validate an order against the cached snapshots and journal it.
+    /// Validation failures are [`OrderRejection`]s (downcast from the error).
+    pub fn journal_order(&self, req: &OfflineOrderRequest) -> Result<JournaledOrder> {
+        let menu = self.get::<Menu>()?.ok_or(OrderRejection::NoMenu)?.data;
+        if req.lines.is_empty() {
+            return Err(OrderRejection::Empty.into());
+        }
+        let mut lines = Vec::with_capacity(req.lines.len());
+        for l in &req.lines {
+            let item = menu
+                .items
+                .iter()
+                .find(|i| i.id == l.item_id)
+                .ok_or_else(|| OrderRejection::UnknownItem(l.item_id.clone()))?;
+            if !item.available {
+                return Err(OrderRejection::Unavailable(item.id.clone()).into());
+            }
+            if l.quantity <= 0 {
+                return Err(OrderRejection::BadQuantity(item.id.clone()).into());
+            }
+            lines.push(OrderLine {
+                item_id: item.id.clone(),
+                quantity: l.quantity,
+                price_cents: item.price_cents,
+                note: l.note.clone(),
+            });
+        }
+        let total_cents = lines.iter().map(|l| l.price_cents * l.quantity).sum();
+
+        let base_version = match &req.base_order_id {
+            None => None,
+            Some(base) => {
+                let current = self
+                    .current_order_version(base)?
+                    .ok_or_else(|| OrderRejection::UnknownBaseOrder(base.clone()))?;
+                if let Some(sent) = req.base_version {
+                    if sent != current {
+                        return Err(OrderRejection::StaleBase {
+                            order: base.clone(),
+                            sent,
+                            current,
+                        }
+                        .into());
+                    }
+                }
+                Some(current)
+            }
+        };
+
+        let now = chrono_unix_now();
+        let order = JournaledOrder {
+            local_id: uuid::Uuid::now_v7().to_string(),
+            base_order_id: req.base_order_id.clone(),
+            base_version,
+            table: req.table.clone(),
+            lines,
+            total_cents,
+            status: "pending".to_string(),
+            cloud_order_id: None,
+            error: None,
+            created_at: now,
+            updated_at: now,
+        };
+        let conn = self.conn.lock().expect("offline cache mutex poisoned");
+        conn.execute(
+            "INSERT INTO offline_orders
+                (local_id, base_order_id, base_version, table_label, lines, total_cents,
+                 status, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, 'pending', ?7, ?7)",
+            params![
+                order.local_id,
+                order.base_order_id,
+                order.base_version,
+                order.table,
+                serde_json::to_string(&order.lines)?,
+                order.total_cents,
+                now
+            ],
+        )?;
+        Ok(order)
+    }
+
+    pub fn get_order(&self, local_id: &str) -> Result<Option<JournaledOrder>> {
+        let conn = self.conn.lock().expect("offline cache mutex poisoned");
+        Ok(conn
+            .query_row(
+                &format!("SELECT {ORDER_COLUMNS} FROM offline_orders WHERE local_id = ?1"),
+                params![local_id],
+                order_from_row,
+            )
+            .optional()?)
+    }
+
+    /// Journal entries in `status`, oldest first.
+    pub fn orders_with_status(&self, status: &str, limit: i64) -> Result<Vec<JournaledOrder>> {
+        let conn = self.conn.lock().expect("offline cache mutex poisoned");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {ORDER_COLUMNS} FROM offline_orders
+              WHERE status = ?1 ORDER BY created_at, local_id LIMIT ?2"
+        ))?;
+        let rows = stmt.query_map(params![status, limit], order_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Journal rows per status, for `--health`.
+    pub fn order_counts(&self) -> Result<BTreeMap<String, i64>> {
+        let conn = self.conn.lock().expect("offline cache mutex poisoned");
+        let mut stmt =
+            conn.prepare("SELECT status, COUNT(*) FROM offline_orders GROUP BY status")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<BTreeMap<_, _>>>()?)
+    }
+
+    pub fn mark_order_replayed(&self, local_id: &str, cloud_order_id: &str) -> Result<()> {
+        self.settle_order(local_id, "replayed", Some(cloud_order_id), None)
+    }
+
+    pub fn mark_order_conflict(&self, local_id: &str, reason: &str) -> Result<()> {
+        self.settle_order(local_id, "conflict", None, Some(reason))
+    }
+
+    pub fn mark_order_rejected(&self, local_id: &str, reason: &str) -> Result<()> {
+        self.settle_order(local_id, "rejected", None, Some(reason))
+    }
+
+    fn settle_order(
+        &self,
+        local_id: &str,
+        status: &str,
+        cloud_order_id: Option<&str>,
+        error: Option<&str>,
+    ) -> Result<()> {
+        let conn = self.conn.lock().expect("offline cache mutex poisoned");
+        conn.execute(
+            "UPDATE offline_orders
+                SET status = ?2, cloud_order_id = ?3, error = ?4, updated_at = ?5
+              WHERE local_id = ?1 AND status = 'pending'",
+            params![local_id, status, cloud_order_id, error, chrono_unix_now()],
+        )?;
+        Ok(())
+    }
+
+    /// The version of an open order per the cached snapshot, `None` if it is
+    /// not (or no longer) open.
+    fn current_order_version(&self, order_id: &str) -> Result<Option<i64>> {
+        Ok(self.get::<OpenOrders>()?.and_then(|s| {
+            s.data
+                .orders
+                .iter()
+                .find(|o| o.id == order_id)
+                .map(|o| o.version)
+        }))
+    }
+
+    /// Bridge-side conflict check for a journaled order, against the newest
+    /// open-orders snapshot. `Some(reason)` means do not upload.
+    fn local_conflict(&self, order: &JournaledOrder) -> Result<Option<String>> {
+        let (Some(base), Some(base_version)) = (&order.base_order_id, order.base_version) else {
+            return Ok(None);
+        };
+        Ok(match self.current_order_version(base)? {
+            None => Some(format!(
+                "order '{base}' was closed while this was taken offline"
+            )),
+            Some(v) if v != base_version => Some(format!(
+                "order '{base}' moved to version {v} since the offline edit (base {base_version})"
+            )),
+            Some(_) => None,
+        })
+    }
+}
+
+const ORDER_COLUMNS: &str = "local_id, base_order_id, base_version, table_label, lines, total_cents, status, cloud_order_id, error, created_at, updated_at";
+
+fn order_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<JournaledOrder> {
+    let lines: String = row.get(4)?;
+    Ok(JournaledOrder {
+        local_id: row.get(0)?,
+        base_order_id: row.get(1)?,
+        base_version: row.get(2)?,
+        table: row.get(3)?,
+        lines: serde_json::from_str(&lines).unwrap_or_default(),
+        total_cents: row.get(5)?,
+        status: row.get(6)?,
+        cloud_order_id: row.get(7)?,
+        error: row.get(8)?,
+        created_at: row.get(9)?,
+        updated_at: row.get(10)?,
+    })
+}
+
+/// Upload pending offline orders, oldest first. Stops at the first transport
+/// error so order is preserved — the rest wait for the next pass.
+pub async fn replay_pending(cache: &OfflineCache, cloud: &CloudClient) -> Result<ReplayReport> {
+    let mut report = ReplayReport::default();
+    let pending = cache.orders_with_status("pending", 100)?;
+    let total = pending.len();
+    for (i, order) in pending.into_iter().enumerate() {
+        if let Some(reason) = cache.local_conflict(&order)? {
+            warn!(local_id = %order.local_id, %reason, "offline order conflicts with a newer snapshot");
+            cache.mark_order_conflict(&order.local_id, &reason)?;
+            report.conflicts += 1;
+            continue;
+        }
+        match cloud.replay_order(&order).await {
+            Ok(OrderReplayResponse::Accepted { order_id }) => {
+                cache.mark_order_replayed(&order.local_id, &order_id)?;
+                report.replayed += 1;
+            }
+            Ok(OrderReplayResponse::Conflict { reason }) => {
+                warn!(local_id = %order.local_id, %reason, "cloud reports offline order conflict");
+                cache.mark_order_conflict(&order.local_id, &reason)?;
+                report.conflicts += 1;
+            }
+            Ok(OrderReplayResponse::Rejected { status, reason }) => {
+                warn!(local_id = %order.local_id, status, %reason, "cloud rejected offline order");
+                cache.mark_order_rejected(&order.local_id, &format!("HTTP {status}: {reason}"))?;
+                report.rejected += 1;
+            }
+            Err(e) => {
+                report.deferred = total - i;
+                warn!(error = %e, deferred = report.deferred, "offline order replay interrupted");
+                break;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Background replay loop. Cheap when there is nothing pending.
+pub fn spawn_replay(cache: Arc<OfflineCache>, cloud: CloudClient) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(30));
+        loop {
+            tick.tick().await;
+            match replay_pending(&cache, &cloud).await {
+                Ok(r) if r.replayed + r.conflicts + r.rejected > 0 => info!(
+                    replayed = r.replayed,
+                    conflicts = r.conflicts,
+                    rejected = r.rejected,
+                    deferred = r.deferred,
+                    "offline order replay pass"
+                ),
+                Ok(_) => {}
+                Err(e) => warn!(error = %e, "offline order replay failed"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloud_ws::{
+        auth::TokenRefreshResponse, BridgeIdentity, ClaimRequest, ClaimResponse, CloudTransport,
+        FetchResponse, HeartbeatResponse,
+    };
+    use crate::command_queue::{CommandOutcome, PendingCommand, QuarantinedCommand};
+    use async_trait::async_trait;
+    use serde_json::json;
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+
+    fn cache(dir: &TempDir) -> OfflineCache {
+        OfflineCache::open(dir.path().join("command_queue.db")).unwrap()
+    }
+
+    fn menu() -> Menu {
+        Menu {
+            currency: "TRY".to_string(),
+            items: vec![
+                MenuItem {
+                    id: "ayran".to_string(),
+                    name: "Ayran".to_string(),
+                    price_cents: 4_000,
+                    available: true,
+                },
+                MenuItem {
+                    id: "kunefe".to_string(),
+                    name: "Künefe".to_string(),
+                    price_cents: 18_000,
+                    available: false,
+                },
+            ],
+        }
+    }
+
+    fn open_order(id: &str, version: i64) -> OpenOrders {
+        OpenOrders {
+            orders: vec![OpenOrder {
+                id: id.to_string(),
+                version,
+                table: Some("T4".to_string()),
+                lines: vec![],
+                total_cents: 0,
+            }],
+        }
+    }
+
+    fn line(item: &str, quantity: i64) -> OrderLineRequest {
+        OrderLineRequest {
+            item_id: item.to_string(),
+            quantity,
+            note: None,
+        }
+    }
+
+    fn new_order(lines: Vec<OrderLineRequest>) -> OfflineOrderRequest {
+        OfflineOrderRequest {
+            base_order_id: None,
+            base_version: None,
+            table: Some("T1".to_string()),
+            lines,
+        }
+    }
+
+    fn rejection(err: anyhow::Error) -> OrderRejection {
+        err.downcast::<OrderRejection>()
+            .expect("a validation failure")
+    }
+
+    #[test]
+    fn snapshots_round_trip_and_never_go_backwards() {
+        let dir = TempDir::new().unwrap();
+        let c = cache(&dir);
+        assert!(c.get::<Menu>().unwrap().is_none());
+
+        assert!(c.put(&menu(), 5).unwrap());
+        let mut older = menu();
+        older.currency = "EUR".to_string();
+        assert!(!c.put(&older, 4).unwrap(), "stale version is ignored");
+
+        let got = c.get::<Menu>().unwrap().unwrap();
+        assert_eq!(got.version, 5);
+        assert_eq!(got.data, menu());
+    }
+
+    #[test]
+    fn ingest_validates_shape_and_key() {
+        let dir = TempDir::new().unwrap();
+        let c = cache(&dir);
+        c.ingest(&SnapshotEnvelope {
+            key: "device_state".to_string(),
+            version: 1,
+            payload: json!({ "devices": [{ "id": "p1", "kind": "escpos", "status": "online" }] }),
+        })
+        .unwrap();
+        assert_eq!(
+            c.get::<DeviceState>().unwrap().unwrap().data.devices.len(),
+            1
+        );
+
+        assert!(c
+            .ingest(&SnapshotEnvelope {
+                key: "menu".to_string(),
+                version: 2,
+                payload: json!({ "items": "not a list" }),
+            })
+            .is_err());
+        assert!(c
+            .ingest(&SnapshotEnvelope {
+                key: "weather".to_string(),
+                version: 1,
+                payload: json!({}),
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn orders_are_priced_from_the_menu_and_validated() {
+        let dir = TempDir::new().unwrap();
+        let c = cache(&dir);
+        assert_eq!(
+            rejection(
+                c.journal_order(&new_order(vec![line("ayran", 1)]))
+                    .unwrap_err()
+            ),
+            OrderRejection::NoMenu
+        );
+        c.put(&menu(), 1).unwrap();
+
+        let o = c.journal_order(&new_order(vec![line("ayran", 3)])).unwrap();
+        assert_eq!(o.total_cents, 12_000);
+        assert_eq!(o.status, "pending");
+        assert_eq!(c.get_order(&o.local_id).unwrap().unwrap(), o);
+
+        assert_eq!(
+            rejection(
+                c.journal_order(&new_order(vec![line("kunefe", 1)]))
+                    .unwrap_err()
+            ),
+            OrderRejection::Unavailable("kunefe".to_string())
+        );
+        assert_eq!(
+            rejection(
+                c.journal_order(&new_order(vec![line("raki", 1)]))
+                    .unwrap_err()
+            ),
+            OrderRejection::UnknownItem("raki".to_string())
+        );
+        assert_eq!(
+            rejection(
+                c.journal_order(&new_order(vec![line("ayran", 0)]))
+                    .unwrap_err()
+            ),
+            OrderRejection::BadQuantity("ayran".to_string())
+        );
+    }
+
+    #[test]
+    fn amending_with_a_stale_version_is_refused_at_the_counter() {
+        let dir = TempDir::new().unwrap();
+        let c = cache(&dir);
+        c.put(&menu(), 1).unwrap();
+        c.put(&open_order("o-1", 7), 1).unwrap();
+
+        let mut req = new_order(vec![line("ayran", 1)]);
+        req.base_order_id = Some("o-1".to_string());
+        req.base_version = Some(6);
+        assert!(matches!(
+            rejection(c.journal_order(&req).unwrap_err()),
+            OrderRejection::StaleBase { current: 7, .. }
+        ));
+
+        req.base_version = None;
+        assert_eq!(c.journal_order(&req).unwrap().base_version, Some(7));
+    }
+
+    /// Cloud stand-in for replay: answers each upload from a script and
+    /// records the order ids it saw.
+    #[derive(Default)]
+    struct ReplayCloud {
+        script: Mutex<Vec<Result<OrderReplayResponse, String>>>,
+        seen: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl CloudTransport for ReplayCloud {
+        async fn get_healthz(&self) -> Result<u16> {
+            Ok(200)
+        }
+        async fn get_next_commands(&self) -> Result<FetchResponse> {
+            Ok(FetchResponse::NoContent)
+        }
+        async fn post_ack(&self, _cmd_id: &str, _outcome: &CommandOutcome) -> Result<()> {
+            Ok(())
+        }
+        async fn post_heartbeat(&self, _identity: &BridgeIdentity) -> Result<HeartbeatResponse> {
+            Ok(HeartbeatResponse::default())
+        }
+        async fn post_claim(&self, _req: &ClaimRequest) -> Result<ClaimResponse> {
+            anyhow::bail!("unused")
+        }
+        async fn post_offline_order(&self, order: &JournaledOrder) -> Result<OrderReplayResponse> {
+            self.seen.lock().unwrap().push(order.local_id.clone());
+            self.script
+                .lock()
+                .unwrap()
+                .remove(0)
+                .map_err(|e| anyhow::anyhow!(e))
+        }
+        async fn post_local_audit(
+            &self,
+            _cmd: &PendingCommand,
+            _outcome: &CommandOutcome,
+        ) -> Result<()> {
+            anyhow::bail!("unused")
+        }
+        async fn post_token_refresh(&self) -> Result<TokenRefreshResponse> {
+            anyhow::bail!("unused")
+        }
+        async fn post_quarantine_report(&self, _q: &QuarantinedCommand) -> Result<()> {
+            anyhow::bail!("unused")
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_settles_in_order_and_defers_on_outage() {
+        let dir = TempDir::new().unwrap();
+        let c = cache(&dir);
+        c.put(&menu(), 1).unwrap();
+        let ids: Vec<String> = (0..4)
+            .map(|_| {
+                c.journal_order(&new_order(vec![line("ayran", 1)]))
+                    .unwrap()
+                    .local_id
+            })
+            .collect();
+        let t = Arc::new(ReplayCloud {
+            script: Mutex::new(vec![
+                Ok(OrderReplayResponse::Accepted {
+                    order_id: "cloud-1".to_string(),
+                }),
+                Ok(OrderReplayResponse::Conflict {
+                    reason: "table closed".to_string(),
+                }),
+                Err("connection refused".to_string()),
+            ]),
+            ..Default::default()
+        });
+        let cloud = CloudClient::with_transport(t.clone());
+
+        let r = replay_pending(&c, &cloud).await.unwrap();
+        assert_eq!(
+            r,
+            ReplayReport {
+                replayed: 1,
+                conflicts: 1,
+                rejected: 0,
+                deferred: 2
+            }
+        );
+        assert_eq!(*t.seen.lock().unwrap(), ids[..3].to_vec(), "oldest first");
+        let first = c.get_order(&ids[0]).unwrap().unwrap();
+        assert_eq!(first.cloud_order_id.as_deref(), Some("cloud-1"));
+        assert_eq!(c.get_order(&ids[1]).unwrap().unwrap().status, "conflict");
+        assert_eq!(c.orders_with_status("pending", 10).unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn newer_snapshot_marks_amendment_as_conflict_without_upload() {
+        let dir = TempDir::new().unwrap();
+        let c = cache(&dir);
+        c.put(&menu(), 1).unwrap();
+        c.put(&open_order("o-1", 3), 1).unwrap();
+        let mut req = new_order(vec![line("ayran", 2)]);
+        req.base_order_id = Some("o-1".to_string());
+        let o = c.journal_order(&req).unwrap();
+
+        // The cloud came back with the order edited elsewhere meanwhile.
+        c.put(&open_order("o-1", 4), 2).unwrap();
+        let t = Arc::new(ReplayCloud::default());
+        let r = replay_pending(&c, &CloudClient::with_transport(t.clone()))
+            .await
+            .unwrap();
+        assert_eq!(r.conflicts, 1);
+        assert!(t.seen.lock().unwrap().is_empty(), "never uploaded");
+        let got = c.get_order(&o.local_id).unwrap().unwrap();
+        assert!(got.error.unwrap().contains("version 4"));
+        assert_eq!(c.order_counts().unwrap().get("conflict"), Some(&1));
+    }
 }
@@ -0,0 +1,397 @@
+//! `review` subcommands — operator reconciliation of commands parked in
+//! `needs_review`.
+//!
+//! A money/fiscal command that failed or was interrupted mid-dispatch is never
+//! retried automatically (see `command_queue::is_side_effecting`); it waits here
+//! for a human who can check the terminal slip or the acquirer portal and say
+//! whether it happened. Runs as a separate process next to the live agent, so
+//! the queue is opened with [`CommandQueue::open_shared`] (no crash recovery).
+//!
+//! Every decision is written to the `review_audit` table, and `done`/`failed`
+//! decisions are acked to the cloud straight away. A `done` whose ack fails is
+//! left ack-pending and the agent's drain loop delivers it later.
+//!
+//! `review retries` shows the other side of the queue: side-effect-free
+//! commands that failed and are waiting out their backoff before the agent
+//! tries them again. Nothing to decide there — it is for "why hasn't the
+//! kitchen ticket printed yet?".
+//!
+//! `review expired` lists commands that outlived their TTL before they could
+//! run. They were never executed and the cloud is acked `expired`; the list is
+//! for "the ticket never printed and nobody saw an error".
+
+use crate::{
+    cloud_ws::CloudClient,
+    command_queue::{CommandQueue, Resolution, ReviewDecision},
+    config::BridgeConfig,
+};
+use anyhow::{Context, Result};
+use clap::{Subcommand, ValueEnum};
+
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum ReviewAction {
+    /// List every command parked for reconciliation.
+    List,
+    /// List failed commands waiting to be retried, soonest first.
+    Retries,
+    /// List commands that expired before they could run, newest first.
+    Expired,
+    /// Show one command (payload, last error, audit trail, workflow steps) as JSON.
+    Show { id: String },
+    /// Settle a parked command and ack the decision to the cloud.
+    Resolve {
+        id: String,
+        /// done = it happened; failed = it did not, do not retry;
+        /// requeue = it did not, run it again.
+        #[arg(long = "as", value_enum)]
+        resolution: ResolveAs,
+        /// Free-text reason, kept in the audit trail.
+        #[arg(long)]
+        note: Option<String>,
+        /// `--as done` only: result JSON to ack (e.g. '{"approvalCode":"123456"}').
+        #[arg(long)]
+        result: Option<String>,
+        /// Who is deciding. Defaults to $USER.
+        #[arg(long)]
+        operator: Option<String>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResolveAs {
+    Done,
+    Failed,
+    Requeue,
+}
+
+impl From<ResolveAs> for Resolution {
+    fn from(r: ResolveAs) -> Self {
+        match r {
+            ResolveAs::Done => Resolution::Done,
+            ResolveAs::Failed => Resolution::Failed,
+            ResolveAs::Requeue => Resolution::Requeue,
+        }
+    }
+}
+
+pub async fn run(cfg: &BridgeConfig, action: ReviewAction) -> Result<()> {
+    let queue = CommandQueue::open_shared(cfg.data_dir.join("command_queue.db"))?;
+    match action {
+        ReviewAction::List => list(&queue).await,
+        ReviewAction::Retries => retries(&queue).await,
+        ReviewAction::Expired => expired(&queue).await,
+        ReviewAction::Show { id } => show(&queue, &id).await,
+        ReviewAction::Resolve {
+            id,
+            resolution,
+            note,
+            result,
+            operator,
+        } => {
+            let result = result
+                .map(|r| serde_json::from_str(&r).context("--result is not valid JSON"))
+                .transpose()?;
+            if result.is_some() && resolution != ResolveAs::Done {
+                anyhow::bail!("--result only applies to --as done");
+            }
+            let decision = ReviewDecision {
+                resolution: resolution.into(),
+                operator: operator
+                    .or_else(|| std::env::var("USER").ok())
+                    .unwrap_or_else(|| "unknown".to_string()),
+                note,
+                result,
+            };
+            let cloud = CloudClient::new(cfg.clone())?;
+            resolve(&queue, &cloud, &id, &decision).await
+        }
+    }
+}
+
+async fn list(queue: &CommandQueue) -> Result<()> {
+    let rows = queue.list_needs_review().await?;
+    if rows.is_empty() {
+        println!("no commands need review");
+        return Ok(());
+    }
+    let now = crate::command_queue::chrono_unix_now();
+    println!(
+        "{:<38} {:<16} {:>8} {:>9}  ERROR",
+        "ID", "KIND", "ATTEMPTS", "AGE"
+    );
+    for r in rows {
+        println!(
+            "{:<38} {:<16} {:>8} {:>9}  {}",
+            r.id,
+            r.kind,
+            r.attempts,
+            format_age(now - r.updated_at),
+            r.error.as_deref().unwrap_or("-")
+        );
+    }
+    Ok(())
+}
+
+async fn retries(queue: &CommandQueue) -> Result<()> {
+    let rows = queue.list_retrying().await?;
+    if rows.is_empty() {
+        println!("no commands waiting to retry");
+        return Ok(());
+    }
+    let now = crate::command_queue::chrono_unix_now();
+    println!(
+        "{:<38} {:<16} {:>8} {:>9}  ERROR",
+        "ID", "KIND", "ATTEMPTS", "NEXT IN"
+    );
+    for r in rows {
+        let next = r.next_attempt_at.unwrap_or(now);
+        println!(
+            "{:<38} {:<16} {:>8} {:>9}  {}",
+            r.id,
+            r.kind,
+            r.attempts,
+            if next <= now {
+                "due".to_string()
+            } else {
+                format_age(next - now)
+            },
+            r.error.as_deref().unwrap_or("-")
+        );
+    }
+    Ok(())
+}
+
+async fn expired(queue: &CommandQueue) -> Result<()> {
+    let rows = queue.list_expired().await?;
+    if rows.is_empty() {
+        println!("no expired commands");
+        return Ok(());
+    }
+    let now = crate::command_queue::chrono_unix_now();
+    println!(
+        "{:<38} {:<16} {:>8} {:>9} {:>7}  ERROR",
+        "ID", "KIND", "ATTEMPTS", "EXPIRED", "ACKED"
+    );
+    for r in rows {
+        println!(
+            "{:<38} {:<16} {:>8} {:>9} {:>7}  {}",
+            r.id,
+            r.kind,
+            r.attempts,
+            format!(
+                "{} ago",
+                format_age(now - r.expires_at.unwrap_or(r.updated_at))
+            ),
+            if r.acked_at.is_some() {
+                "yes"
+            } else {
+                "pending"
+            },
+            r.error.as_deref().unwrap_or("-")
+        );
+    }
+    Ok(())
+}
+
+async fn show(queue: &CommandQueue, id: &str) -> Result<()> {
+    let record = queue
+        .get(id)
+        .await?
+        .with_context(|| format!("no command with id '{id}'"))?;
+    let audit = queue.review_audit(id).await?;
+    let mut out = serde_json::json!({ "command": record, "audit": audit });
+    if record.kind == crate::workflow::KIND {
+        out["steps"] = serde_json::to_value(queue.workflow_steps(id).await?)?;
+    }
+    println!("{}", serde_json::to_string_pretty(&out)?);
+    Ok(())
+}
+
+/// Settle one row and ack it. Split out from [`run`] so tests can drive it with
+/// a fake-transport [`CloudClient`].
+pub async fn resolve(
+    queue: &CommandQueue,
+    cloud: &CloudClient,
+    id: &str,
+    decision: &ReviewDecision,
+) -> Result<()> {
+    let (audit_id, cmd, outcome) = queue.resolve_review(id, decision).await?;
+    let Some(outcome) = outcome else {
+        println!("{id}: requeued — the agent will run it again");
+        return Ok(());
+    };
+    match cloud.ack(&cmd, &outcome).await {
+        Ok(()) => {
+            if decision.resolution == Resolution::Done {
+                queue.mark_acked(id).await?;
+            }
+            queue.mark_review_acked(audit_id).await?;
+            println!("{id}: resolved as {} and acked to the cloud", decision.resolution.as_str());
+            Ok(())
+        }
+        // A `done` row stays ack-pending; the agent's drain loop retries it.
+        Err(e) if decision.resolution == Resolution::Done => {
+            eprintln!("{id}: resolved as done; cloud ack failed ({e}) — the agent will retry it");
+            Ok(())
+        }
+        Err(e) => Err(e.context(format!(
+            "{id}: resolved as failed locally, but the cloud was NOT told — fail it cloud-side by hand"
+        ))),
+    }
+}
+
+fn format_age(ms: i64) -> String {
+    let secs = ms.max(0) / 1000;
+    match secs {
+        s if s < 120 => format!("{s}s"),
+        s if s < 7200 => format!("{}m", s / 60),
+        s if s < 172_800 => format!("{}h", s / 3600),
+        s => format!("{}d", s / 86_400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloud_ws::{
+        auth::TokenRefreshResponse, BridgeIdentity, ClaimRequest, ClaimResponse, CloudTransport,
+        FetchResponse, HeartbeatResponse, OrderReplayResponse,
+    };
+    use crate::command_queue::{CommandOutcome, PendingCommand, QuarantinedCommand};
+    use crate::offline_cache::JournaledOrder;
+    use async_trait::async_trait;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+
+    #[derive(Default)]
+    struct AckRecorder {
+        acks: Mutex<Vec<(String, CommandOutcome)>>,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl CloudTransport for AckRecorder {
+        async fn get_healthz(&self) -> Result<u16> {
+            Ok(200)
+        }
+        async fn get_next_commands(&self) -> Result<FetchResponse> {
+            Ok(FetchResponse::NoContent)
+        }
+        async fn post_ack(&self, cmd_id: &str, outcome: &CommandOutcome) -> Result<()> {
+            if self.fail {
+                anyhow::bail!("cloud unreachable");
+            }
+            self.acks
+                .lock()
+                .unwrap()
+                .push((cmd_id.to_string(), outcome.clone()));
+            Ok(())
+        }
+        async fn post_heartbeat(&self, _identity: &BridgeIdentity) -> Result<HeartbeatResponse> {
+            Ok(HeartbeatResponse::default())
+        }
+        async fn post_claim(&self, _req: &ClaimRequest) -> Result<ClaimResponse> {
+            anyhow::bail!("unused")
+        }
+        async fn post_offline_order(&self, _order: &JournaledOrder) -> Result<OrderReplayResponse> {
+            anyhow::bail!("unused")
+        }
+        async fn post_local_audit(
+            &self,
+            _cmd: &PendingCommand,
+            _outcome: &CommandOutcome,
+        ) -> Result<()> {
+            anyhow::bail!("unused")
+        }
+        async fn post_token_refresh(&self) -> Result<TokenRefreshResponse> {
+            anyhow::bail!("unused")
+        }
+        async fn post_quarantine_report(&self, _q: &QuarantinedCommand) -> Result<()> {
+            anyhow::bail!("unused")
+        }
+    }
+
+    async fn parked_queue(dir: &TempDir) -> CommandQueue {
+        let q = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        q.push(&PendingCommand {
+            id: "cc".to_string(),
+            kind: "charge_card".to_string(),
+            payload: json!({}),
+            priority: 0,
+            attempts: 0,
+            idempotency_key: None,
+            origin: Default::default(),
+        })
+        .await
+        .unwrap();
+        q.pop_next().await.unwrap();
+        q.mark_failed("cc", "terminal timeout").await.unwrap();
+        q
+    }
+
+    fn decision(resolution: Resolution) -> ReviewDecision {
+        ReviewDecision {
+            resolution,
+            operator: "ops".to_string(),
+            note: None,
+            result: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_done_acks_and_settles() {
+        let dir = TempDir::new().unwrap();
+        let q = parked_queue(&dir).await;
+        let t = Arc::new(AckRecorder::default());
+        let cloud = CloudClient::with_transport(t.clone());
+
+        resolve(&q, &cloud, "cc", &decision(Resolution::Done))
+            .await
+            .unwrap();
+        let acks = t.acks.lock().unwrap().clone();
+        assert_eq!(acks.len(), 1);
+        assert_eq!(acks[0].1.status, "done");
+        assert_eq!(q.get("cc").await.unwrap().unwrap().status, "acked");
+        assert!(q.review_audit("cc").await.unwrap()[0].cloud_acked);
+    }
+
+    #[tokio::test]
+    async fn resolve_done_with_cloud_down_leaves_ack_pending() {
+        let dir = TempDir::new().unwrap();
+        let q = parked_queue(&dir).await;
+        let cloud = CloudClient::with_transport(Arc::new(AckRecorder {
+            fail: true,
+            ..Default::default()
+        }));
+
+        resolve(&q, &cloud, "cc", &decision(Resolution::Done))
+            .await
+            .expect("the agent retries done acks, so this is not an error");
+        assert_eq!(q.pending_acks(10).await.unwrap().len(), 1);
+        assert!(!q.review_audit("cc").await.unwrap()[0].cloud_acked);
+    }
+
+    #[tokio::test]
+    async fn resolve_failed_with_cloud_down_is_reported() {
+        let dir = TempDir::new().unwrap();
+        let q = parked_queue(&dir).await;
+        let cloud = CloudClient::with_transport(Arc::new(AckRecorder {
+            fail: true,
+            ..Default::default()
+        }));
+
+        let err = resolve(&q, &cloud, "cc", &decision(Resolution::Failed))
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("NOT told"));
+    }
+
+    #[test]
+    fn age_is_humanised() {
+        assert_eq!(format_age(5_000), "5s");
+        assert_eq!(format_age(600_000), "10m");
+        assert_eq!(format_age(3 * 3_600_000), "3h");
+        assert_eq!(format_age(3 * 86_400_000), "3d");
+    }
+}
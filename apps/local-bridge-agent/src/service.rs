@@ -0,0 +1,321 @@
+//! Running under a service manager: stop signals and systemd `sd_notify`.
+//!
+//! SIGTERM (systemd stop, `docker stop`) or SIGINT (Ctrl-C) does not kill the
+//! agent where it stands. [`StopFlag`] is raised; the main loop stops leasing
+//! and fetching, lets the device workers finish what they are running (see
+//! [`crate::dispatcher::Dispatcher::shutdown`]), flushes outstanding acks and
+//! exits 0.
+//!
+//! With `Type=notify` the agent tells systemd where it is: `READY=1` once it
+//! is taking commands, `WATCHDOG=1` from every main-loop pass when the unit sets
+//! `WatchdogSec=`, and `STOPPING=1` when a stop begins. A main loop that
+//! wedges stops pinging, and systemd restarts the agent. The loop's cloud
+//! calls (bounded by their own timeouts) ping while they wait
+//! ([`Notifier::keep_alive`]), so a slow cloud is not mistaken for a wedge. Without
+//! `$NOTIFY_SOCKET` (not under systemd, or not `Type=notify`) every
+//! notification is a no-op.
+//!
+//! Implemented on a plain Unix datagram socket — the protocol is one
+//! newline-separated `KEY=value` datagram — so it costs no dependency.
+
+use anyhow::{Context, Result};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+/// Raised once when the agent should stop. Cheap to clone; every clone sees
+/// the same flag.
+#[derive(Clone, Default)]
+pub struct StopFlag {
+    inner: Arc<(AtomicBool, Notify)>,
+}
+
+impl StopFlag {
+    pub fn raise(&self) {
+        self.inner.0.store(true, Ordering::SeqCst);
+        self.inner.1.notify_waiters();
+    }
+
+    pub fn is_raised(&self) -> bool {
+        self.inner.0.load(Ordering::SeqCst)
+    }
+
+    /// Resolve once the flag is raised (at once if it already is).
+    pub async fn wait(&self) {
+        let notified = self.inner.1.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if self.is_raised() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Raise `stop` on the first SIGTERM or SIGINT. A second one exits at once,
+/// so an operator can still kill a shutdown that hangs.
+pub fn spawn_signal_handler(stop: StopFlag) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut signals = match StopSignals::install() {
+            Ok(s) => s,
+            Err(e) => {
+                warn!(error = %e, "cannot listen for stop signals — the agent stops only when killed");
+                return;
+            }
+        };
+        let signal = signals.recv().await;
+        info!(signal, "stop requested — finishing running commands");
+        stop.raise();
+        let signal = signals.recv().await;
+        warn!(
+            signal,
+            "second stop signal — exiting without waiting for running commands"
+        );
+        std::process::exit(1);
+    })
+}
+
+#[cfg(unix)]
+struct StopSignals {
+    term: tokio::signal::unix::Signal,
+    int: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl StopSignals {
+    fn install() -> Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Self {
+            term: signal(SignalKind::terminate()).context("install SIGTERM handler")?,
+            int: signal(SignalKind::interrupt()).context("install SIGINT handler")?,
+        })
+    }
+
+    async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.term.recv() => "SIGTERM",
+            _ = self.int.recv() => "SIGINT",
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct StopSignals;
+
+#[cfg(not(unix))]
+impl StopSignals {
+    fn install() -> Result<Self> {
+        Ok(Self)
+    }
+
+    async fn recv(&mut self) -> &'static str {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+        "Ctrl-C"
+    }
+}
+
+/// `sd_notify` client. Never fails the caller: a notification that cannot be
+/// delivered is logged at debug level, since systemd will act on its absence.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    /// `$NOTIFY_SOCKET`; a leading `@` is a Linux abstract socket.
+    socket: Option<PathBuf>,
+    /// Half of `$WATCHDOG_USEC`: how often systemd wants a ping.
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// From `$NOTIFY_SOCKET` and `$WATCHDOG_USEC` (honoured only when
+    /// `$WATCHDOG_PID` is unset or is this process).
+    pub fn from_env() -> Self {
+        let socket = std::env::var_os("NOTIFY_SOCKET")
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from);
+        let for_us = std::env::var("WATCHDOG_PID")
+            .map(|pid| pid.trim() == std::process::id().to_string())
+            .unwrap_or(true);
+        let watchdog = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .filter(|_| for_us)
+            .and_then(|usec| usec.trim().parse::<u64>().ok())
+            .filter(|usec| *usec > 0)
+            .map(|usec| Duration::from_micros(usec / 2));
+        Self::new(socket, watchdog)
+    }
+
+    pub fn new(socket: Option<PathBuf>, watchdog: Option<Duration>) -> Self {
+        Self {
+            watchdog: watchdog.filter(|_| socket.is_some()),
+            socket,
+        }
+    }
+
+    /// How often to call [`Notifier::watchdog`], if systemd is watching.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    pub fn ready(&self, status: &str) {
+        self.send(&format!("READY=1\nSTATUS={status}"));
+    }
+
+    pub fn watchdog(&self) {
+        if self.watchdog.is_some() {
+            self.send("WATCHDOG=1");
+        }
+    }
+
+    /// Await `fut`, pinging the watchdog every interval until it resolves.
+    /// Only for futures with their own bound: one that never resolves would
+    /// keep a wedged agent alive.
+    pub async fn keep_alive<F: std::future::Future>(&self, fut: F) -> F::Output {
+        let Some(every) = self.watchdog else {
+            return fut.await;
+        };
+        tokio::pin!(fut);
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + every, every);
+        loop {
+            tokio::select! {
+                out = &mut fut => return out,
+                _ = ticks.tick() => self.watchdog(),
+            }
+        }
+    }
+
+    pub fn stopping(&self, status: &str) {
+        self.send(&format!("STOPPING=1\nSTATUS={status}"));
+    }
+
+    fn send(&self, state: &str) {
+        let Some(socket) = &self.socket else { return };
+        if let Err(e) = send_datagram(socket, state) {
+            debug!(error = %e, socket = %socket.display(), "sd_notify failed");
+        }
+    }
+}
+
+#[cfg(unix)]
+fn send_datagram(socket: &std::path::Path, state: &str) -> Result<()> {
+    use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+    let sock = UnixDatagram::unbound()?;
+    let raw = socket.as_os_str().as_bytes();
+    match raw.strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            sock.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => anyhow::bail!("abstract notify sockets are Linux-only"),
+        None => {
+            sock.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_datagram(_socket: &std::path::Path, _state: &str) -> Result<()> {
+    anyhow::bail!("sd_notify needs Unix sockets")
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+    use tempfile::TempDir;
+
+    fn recv(sock: &UnixDatagram) -> String {
+        let mut buf = [0u8; 256];
+        let n = sock.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..n]).into_owned()
+    }
+
+    /// What systemd would read off the socket, in order.
+    #[test]
+    fn notifications_reach_the_socket() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let n = Notifier::new(Some(path), Some(Duration::from_secs(10)));
+
+        n.ready("taking commands");
+        n.watchdog();
+        n.stopping("stopping (SIGTERM)");
+        assert_eq!(recv(&systemd), "READY=1\nSTATUS=taking commands");
+        assert_eq!(recv(&systemd), "WATCHDOG=1");
+        assert_eq!(recv(&systemd), "STOPPING=1\nSTATUS=stopping (SIGTERM)");
+        assert_eq!(n.watchdog_interval(), Some(Duration::from_secs(10)));
+    }
+
+    /// A cloud call slower than the watchdog interval still gets pings out.
+    #[tokio::test]
+    async fn keep_alive_pings_while_a_slow_call_runs() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd.set_nonblocking(true).unwrap();
+        let n = Notifier::new(Some(path), Some(Duration::from_millis(20)));
+
+        let out = n
+            .keep_alive(async {
+                tokio::time::sleep(Duration::from_millis(110)).await;
+                7
+            })
+            .await;
+        assert_eq!(out, 7);
+        let mut pings = 0;
+        let mut buf = [0u8; 64];
+        while let Ok(len) = systemd.recv(&mut buf) {
+            assert_eq!(&buf[..len], b"WATCHDOG=1");
+            pings += 1;
+        }
+        assert!(pings >= 3, "only {pings} pings");
+    }
+
+    #[test]
+    fn without_a_socket_nothing_is_sent_and_nothing_fails() {
+        let n = Notifier::new(None, Some(Duration::from_secs(10)));
+        assert_eq!(n.watchdog_interval(), None);
+        n.ready("x");
+        n.watchdog();
+
+        // A socket nobody listens on is not an error for the caller either.
+        let dir = TempDir::new().unwrap();
+        Notifier::new(Some(dir.path().join("gone")), None).ready("x");
+    }
+
+    #[tokio::test]
+    async fn stop_flag_wakes_every_waiter() {
+        let stop = StopFlag::default();
+        let waiters: Vec<_> = (0..2)
+            .map(|_| {
+                let s = stop.clone();
+                tokio::spawn(async move { s.wait().await })
+            })
+            .collect();
+        tokio::task::yield_now().await;
+        stop.raise();
+        for w in waiters {
+            tokio::time::timeout(Duration::from_secs(2), w)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert!(stop.is_raised());
+        stop.wait().await; // already raised: returns at once
+    }
+}
@@ -8,12 +8,30 @@
 //! flipped every running bridge to `offline` after provisioning and it never
 //! recovered. The loop now posts a real heartbeat, which is the only call that
 //! updates `lastSeenAt` server-side.
+//!
+//! The heartbeat reply doubles as the offline-cache feed: any snapshots the
+//! cloud attaches are stored via [`OfflineCache::ingest`].
+//!
+//! Each heartbeat also carries the last reported state of every local device
+//! ([`Registry::device_status`] — e.g. a printer that ran out of paper), so the
+//! cloud sees it without waiting for the next failed ticket's ack.
 
-use crate::cloud_ws::{BridgeIdentity, CloudClient};
+use crate::{
+    cloud_ws::{BridgeIdentity, CloudClient, HeartbeatResponse},
+    drivers::Registry,
+    metrics::Metrics,
+    offline_cache::OfflineCache,
+};
+use std::sync::Arc;
 use tokio::task::JoinHandle;
 use tracing::{debug, warn};
 
-pub fn spawn_heartbeat(cloud: CloudClient) -> JoinHandle<()> {
+pub fn spawn_heartbeat(
+    cloud: CloudClient,
+    cache: Arc<OfflineCache>,
+    drivers: Arc<Registry>,
+    metrics: Arc<Metrics>,
+) -> JoinHandle<()> {
     tokio::spawn(async move {
         // Detect identity once; it does not change for the life of the process.
         let identity = BridgeIdentity::detect();
@@ -21,11 +39,61 @@ pub fn spawn_heartbeat(cloud: CloudClient) -> JoinHandle<()> {
             // Best-effort. Failures here MUST NOT take down the agent — the
             // sweep on the cloud side already flips us offline. We log so a
             // sustained auth/network failure is at least visible.
-            match cloud.post_heartbeat(&identity).await {
-                Ok(()) => debug!("heartbeat posted"),
+            let beat = BridgeIdentity {
+                devices: drivers.device_status(),
+                ..identity.clone()
+            };
+            match cloud.post_heartbeat(&beat).await {
+                Ok(resp) => {
+                    metrics.heartbeat_ok();
+                    debug!(snapshots = resp.snapshots.len(), "heartbeat posted");
+                    ingest_snapshots(&cache, &resp);
+                }
                 Err(e) => warn!(error = %e, "heartbeat post failed (best-effort)"),
             }
             tokio::time::sleep(std::time::Duration::from_secs(20)).await;
         }
     })
 }
+
+/// Store each shipped snapshot. One bad snapshot must not block the others.
+fn ingest_snapshots(cache: &OfflineCache, resp: &HeartbeatResponse) {
+    for snap in &resp.snapshots {
+        match cache.ingest(snap) {
+            Ok(true) => debug!(key = %snap.key, version = snap.version, "snapshot stored"),
+            Ok(false) => {}
+            Err(e) => warn!(key = %snap.key, error = %e, "snapshot ignored"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::offline_cache::{DeviceState, Menu};
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    #[test]
+    fn heartbeat_snapshots_are_ingested_past_a_bad_one() {
+        let dir = TempDir::new().unwrap();
+        let cache = OfflineCache::open(dir.path().join("q.db")).unwrap();
+        let resp: HeartbeatResponse = serde_json::from_value(json!({
+            "ok": true,
+            "snapshots": [
+                { "key": "menu", "version": 3, "payload": { "bogus": true } },
+                { "key": "device_state", "version": 3, "payload": { "devices": [] } }
+            ]
+        }))
+        .unwrap();
+        ingest_snapshots(&cache, &resp);
+        assert!(cache.get::<Menu>().unwrap().is_none());
+        assert_eq!(cache.get::<DeviceState>().unwrap().unwrap().version, 3);
+    }
+
+    #[test]
+    fn legacy_heartbeat_reply_carries_no_snapshots() {
+        let resp: HeartbeatResponse = serde_json::from_value(json!({ "ok": true })).unwrap();
+        assert!(resp.snapshots.is_empty());
+    }
+}
@@ -1,14 +1,1010 @@
 //! Signed-manifest auto-update channel.
 //!
-//! On each heartbeat, the bridge POSTs its current version. The cloud
-//! replies with the latest signed manifest URL if an update is available.
-//! The agent downloads the binary, verifies the embedded public-key
-//! signature, swaps the binary via the OS-appropriate atomic replace, then
+//! Every `check_interval_minutes` the bridge asks the cloud for the current
+//! manifest of its channel (`GET {cloud_url}/v1/bridges/updates/manifest`).
+//! The agent verifies the manifest's ed25519 signature against the keys
+//! compiled into this binary, downloads the binary into `data_dir/updates/`,
+//! checks its size and sha256, swaps it in with an atomic rename, then
 //! restarts.
 //!
-//! Stubbed for the scaffold — the deployment side of the pipeline is the
-//! gating piece, not the agent code.
+//! ## Manifest
+//!
+//! ```json
+//! { "manifest": "{\"version\":\"0.2.0\",\"channel\":\"stable\",\"target\":\"x86_64-linux\",\"url\":\"https://…\",\"sha256\":\"…\",\"size\":7340032}",
+//!   "signature": "<hex ed25519 signature over the exact bytes of `manifest`>" }
+//! ```
+//!
+//! The signature covers the manifest STRING, not a re-serialisation of it, so
+//! there is no JSON canonicalisation to get wrong. Nothing in the inner
+//! manifest is trusted until the signature checks out.
+//!
+//! ## Trust
+//!
+//! The verifying keys are pinned at compile time from `HUMMY_UPDATE_PUBKEYS`
+//! (comma-separated hex ed25519 public keys). A build without it never
+//! updates — there is no config knob that adds a key, because bridge.toml
+//! lives on the same disk an attacker would be writing the binary to.
+//!
+//! ## Swap and rollback
+//!
+//! The running binary is copied to `<exe>.previous`, the staged binary is
+//! copied next to the executable and renamed over it (same directory, so the
+//! rename is atomic), and `updates/state.json` records the pending swap with
+//! the health status measured just before it. The agent then exits with
+//! [`EXIT_RESTART`] between commands and the service manager starts the new
+//! binary. On boot the new binary:
+//!
+//!   1. [`note_boot`] — counts unconfirmed boots; a binary that keeps dying
+//!      before it can confirm is rolled back after [`MAX_UNCONFIRMED_BOOTS`].
+//!   2. [`confirm_or_rollback`] — runs once the agent is up. If the post-swap
+//!      health is `broken` where the pre-swap baseline was not, `<exe>.previous`
+//!      is renamed back and the version is remembered as failed so it is never
+//!      offered again on this box.
+//!
+//! The swap relies on Unix rename-over-a-running-executable semantics; other
+//! platforms report the update as unsupported rather than half-installing.
+
+use crate::{
+    cloud_ws::CloudClient,
+    config::{self, BridgeConfig, UpdateConfig},
+    health::{self, Status},
+};
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Ordering as CmpOrdering,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Exit code the agent uses to ask the service manager for a restart into a
+/// freshly swapped (or rolled back) binary. EX_TEMPFAIL from sysexits.h; the
+/// systemd unit runs with `Restart=always`.
+pub const EXIT_RESTART: i32 = 75;
+
+/// Boots a new binary gets to confirm itself before it is rolled back.
+pub const MAX_UNCONFIRMED_BOOTS: u32 = 3;
+
+/// Hard cap on a downloaded binary, independent of what the manifest claims.
+const MAX_BINARY_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Keys baked in at build time. See the module doc.
+const PINNED_KEYS_HEX: Option<&str> = option_env!("HUMMY_UPDATE_PUBKEYS");
+
+/// The signed envelope the cloud serves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedManifest {
+    /// The manifest JSON, verbatim — the exact bytes that were signed.
+    pub manifest: String,
+    /// Hex-encoded ed25519 signature over `manifest`.
+    pub signature: String,
+}
+
+/// One release, as described by a verified manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    pub channel: String,
+    /// `<arch>-<os>`, e.g. `x86_64-linux` (see [`current_target`]).
+    pub target: String,
+    pub url: String,
+    /// Lowercase hex sha256 of the binary.
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl SignedManifest {
+    /// Verify the signature against `keys` (any one may match), then parse.
+    pub fn verify(&self, keys: &[VerifyingKey]) -> Result<Manifest> {
+        if keys.is_empty() {
+            anyhow::bail!("no update signing key pinned in this build");
+        }
+        verify_signature(keys, self.manifest.as_bytes(), &self.signature, "manifest")?;
+        serde_json::from_str(&self.manifest).context("signed manifest is not valid JSON")
+    }
+}
+
+/// Check a hex ed25519 `signature` over the exact `bytes` against `keys` (any
+/// one may match). `what` names the signed thing in errors. Also used for
+/// other release artifacts signed with the update keys (`gmp3_profiles.toml`).
+pub(crate) fn verify_signature(
+    keys: &[VerifyingKey],
+    bytes: &[u8],
+    signature: &str,
+    what: &str,
+) -> Result<()> {
+    let sig_bytes: [u8; 64] = hex_decode(signature)
+        .with_context(|| format!("{what} signature is not hex"))?
+        .try_into()
+        .map_err(|_| anyhow!("{what} signature is not 64 bytes"))?;
+    let sig = Signature::from_bytes(&sig_bytes);
+    if !keys.iter().any(|k| k.verify_strict(bytes, &sig).is_ok()) {
+        anyhow::bail!("{what} signature does not verify against any pinned key");
+    }
+    Ok(())
+}
+
+/// What one update check did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    /// Already running the wanted version.
+    UpToDate,
+    /// A manifest was offered but deliberately not installed.
+    Skipped { version: String, reason: String },
+    /// The new binary is in place; the agent should restart.
+    Installed { from: String, to: String },
+}
+
+/// A binary downloaded and verified into `data_dir/updates/`, ready to swap.
+#[derive(Debug, Clone)]
+pub struct Staged {
+    pub manifest: Manifest,
+    pub path: PathBuf,
+}
+
+/// Persisted in `data_dir/updates/state.json`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct UpdateState {
+    #[serde(default)]
+    pending: Option<PendingSwap>,
+    /// Versions rolled back on this box. Never offered again.
+    #[serde(default)]
+    failed_versions: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PendingSwap {
+    version: String,
+    previous_version: String,
+    exe: PathBuf,
+    backup: PathBuf,
+    /// Health right before the swap; a regression from this triggers rollback.
+    baseline: Status,
+    #[serde(default)]
+    boots: u32,
+}
+
+/// Result of the boot-time checks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootVerdict {
+    /// No swap awaiting confirmation.
+    NothingPending,
+    /// Swap still awaiting [`confirm_or_rollback`].
+    Unconfirmed {
+        version: String,
+        boots: u32,
+    },
+    Confirmed {
+        version: String,
+    },
+    /// The previous binary is back in place; restart into it.
+    RolledBack {
+        version: String,
+        reason: String,
+    },
+}
+
+pub struct Updater {
+    http: reqwest::Client,
+    manifest_url: String,
+    settings: UpdateConfig,
+    keys: Vec<VerifyingKey>,
+    data_dir: PathBuf,
+    exe: PathBuf,
+    current_version: String,
+    target: String,
+}
+
+impl Updater {
+    /// Production updater for this process. `Ok(None)` when updates are
+    /// disabled in bridge.toml or this build pins no signing key.
+    pub fn from_config(cfg: &BridgeConfig) -> Result<Option<Self>> {
+        if !cfg.update.enabled {
+            info!("auto-update disabled in bridge.toml");
+            return Ok(None);
+        }
+        let keys = pinned_keys()?;
+        if keys.is_empty() {
+            warn!("auto-update disabled: this build pins no update signing key (HUMMY_UPDATE_PUBKEYS)");
+            return Ok(None);
+        }
+        // Same proxy / CA / pin settings as the cloud client: a site that
+        // needs a proxy for commands needs it for updates too.
+        let http = crate::network::Network::from_config(&cfg.network, &cfg.cloud_url)?
+            .http_client(
+                reqwest::Client::builder()
+                    .https_only(true)
+                    .timeout(Duration::from_secs(300))
+                    .connect_timeout(Duration::from_secs(10)),
+            )
+            .context("build update http client")?;
+        let exe = std::env::current_exe().context("locating the running executable")?;
+        Ok(Some(Self::new(
+            http,
+            cfg,
+            keys,
+            exe,
+            env!("CARGO_PKG_VERSION").to_string(),
+        )))
+    }
+
+    /// Explicit constructor — tests pass a plain-HTTP client, a scratch
+    /// "executable" and a throwaway key.
+    pub fn new(
+        http: reqwest::Client,
+        cfg: &BridgeConfig,
+        keys: Vec<VerifyingKey>,
+        exe: PathBuf,
+        current_version: String,
+    ) -> Self {
+        let manifest_url = cfg.update.manifest_url.clone().unwrap_or_else(|| {
+            format!(
+                "{}/v1/bridges/updates/manifest",
+                cfg.cloud_url.trim_end_matches('/')
+            )
+        });
+        Self {
+            http,
+            manifest_url,
+            settings: cfg.update.clone(),
+            keys,
+            data_dir: cfg.data_dir.clone(),
+            exe,
+            current_version,
+            target: current_target(),
+        }
+    }
+
+    /// Fetch and verify the manifest, decide whether it applies, and if so
+    /// download it into `data_dir/updates/`. `Err(UpdateOutcome)` carries the
+    /// reason nothing was staged.
+    pub async fn stage(&self) -> Result<std::result::Result<Staged, UpdateOutcome>> {
+        let manifest = self.fetch_manifest().await?;
+        if let Some(skip) = self.decide(&manifest)? {
+            return Ok(Err(skip));
+        }
+        let path = self.download(&manifest).await?;
+        Ok(Ok(Staged { manifest, path }))
+    }
+
+    /// Swap the staged binary in. `baseline` is the health status right now;
+    /// the new binary rolls back if it does worse.
+    pub fn install(&self, staged: &Staged, baseline: Status) -> Result<UpdateOutcome> {
+        if !cfg!(unix) {
+            anyhow::bail!("self-update is not supported on this platform");
+        }
+        let name = self
+            .exe
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow!("executable path {} has no file name", self.exe.display()))?;
+        let backup = self.exe.with_file_name(format!("{name}.previous"));
+        let incoming = self.exe.with_file_name(format!(".{name}.new"));
+
+        std::fs::copy(&self.exe, &backup).with_context(|| {
+            format!("backing up {} to {}", self.exe.display(), backup.display())
+        })?;
+        std::fs::copy(&staged.path, &incoming)
+            .with_context(|| format!("copying staged binary to {}", incoming.display()))?;
+        make_executable(&incoming)?;
+        std::fs::File::open(&incoming)?.sync_all()?;
+
+        // Record the swap BEFORE the rename: if we die in between, the next
+        // boot sees a pending version that is not the one running and clears it.
+        let mut state = load_state(&self.data_dir)?;
+        state.pending = Some(PendingSwap {
+            version: staged.manifest.version.clone(),
+            previous_version: self.current_version.clone(),
+            exe: self.exe.clone(),
+            backup,
+            baseline,
+            boots: 0,
+        });
+        save_state(&self.data_dir, &state)?;
+
+        std::fs::rename(&incoming, &self.exe)
+            .with_context(|| format!("swapping {} into place", self.exe.display()))?;
+        let _ = std::fs::remove_file(&staged.path);
+        info!(
+            from = %self.current_version,
+            to = %staged.manifest.version,
+            "update installed — restarting into the new binary"
+        );
+        Ok(UpdateOutcome::Installed {
+            from: self.current_version.clone(),
+            to: staged.manifest.version.clone(),
+        })
+    }
+
+    async fn fetch_manifest(&self) -> Result<Manifest> {
+        let mut query = vec![
+            ("channel", self.settings.channel.clone()),
+            ("target", self.target.clone()),
+            ("current", self.current_version.clone()),
+        ];
+        if let Some(pin) = &self.settings.pin {
+            query.push(("pin", pin.clone()));
+        }
+        let token = config::resolve_bearer_token().unwrap_or_default();
+        let resp = self
+            .http
+            .get(&self.manifest_url)
+            .query(&query)
+            .header("Authorization", format!("Bridge {token}"))
+            .send()
+            .await
+            .context("fetching update manifest")?;
+        if !resp.status().is_success() {
+            anyhow::bail!("update manifest: HTTP {}", resp.status().as_u16());
+        }
+        let signed: SignedManifest = resp.json().await.context("decoding update manifest")?;
+        signed.verify(&self.keys)
+    }
+
+    /// `Ok(Some(outcome))` when the verified manifest should NOT be installed.
+    fn decide(&self, m: &Manifest) -> Result<Option<UpdateOutcome>> {
+        // A signed manifest for the wrong channel/target is still a wrong
+        // answer — refuse it loudly rather than skip it quietly.
+        if m.channel != self.settings.channel {
+            anyhow::bail!(
+                "manifest is for channel '{}', this bridge follows '{}'",
+                m.channel,
+                self.settings.channel
+            );
+        }
+        if m.target != self.target {
+            anyhow::bail!(
+                "manifest is for target '{}', this bridge is '{}'",
+                m.target,
+                self.target
+            );
+        }
+        let skip = |reason: String| {
+            Ok(Some(UpdateOutcome::Skipped {
+                version: m.version.clone(),
+                reason,
+            }))
+        };
+        if m.version == self.current_version {
+            return Ok(Some(UpdateOutcome::UpToDate));
+        }
+        let state = load_state(&self.data_dir)?;
+        if state.failed_versions.contains(&m.version) {
+            return skip("rolled back on this bridge before".to_string());
+        }
+        match &self.settings.pin {
+            Some(pin) if *pin != m.version => skip(format!("bridge.toml pins {pin}")),
+            // A pin may move the bridge down as well as up.
+            Some(_) => Ok(None),
+            None => match compare_versions(&m.version, &self.current_version) {
+                Some(CmpOrdering::Greater) => Ok(None),
+                Some(_) => skip(format!("not newer than {}", self.current_version)),
+                None => skip("unparseable version".to_string()),
+            },
+        }
+    }
+
+    async fn download(&self, m: &Manifest) -> Result<PathBuf> {
+        if m.size > MAX_BINARY_BYTES {
+            anyhow::bail!(
+                "manifest size {} exceeds the {MAX_BINARY_BYTES}-byte cap",
+                m.size
+            );
+        }
+        let dir = updates_dir(&self.data_dir);
+        std::fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+        let partial = dir.join(format!("{}.partial", m.version));
+        let staged = dir.join(format!("{}.staged", m.version));
+
+        let result = async {
+            let mut resp = self
+                .http
+                .get(&m.url)
+                .send()
+                .await
+                .context("downloading update")?;
+            if !resp.status().is_success() {
+                anyhow::bail!("update download: HTTP {}", resp.status().as_u16());
+            }
+            let mut file = std::fs::File::create(&partial)
+                .with_context(|| format!("creating {}", partial.display()))?;
+            let mut hasher = Sha256::new();
+            let mut written: u64 = 0;
+            while let Some(chunk) = resp.chunk().await.context("reading update body")? {
+                written += chunk.len() as u64;
+                if written > m.size {
+                    anyhow::bail!("update body is larger than the manifest's {} bytes", m.size);
+                }
+                hasher.update(&chunk);
+                file.write_all(&chunk)?;
+            }
+            file.sync_all()?;
+            if written != m.size {
+                anyhow::bail!("update body is {written} bytes, manifest says {}", m.size);
+            }
+            let digest = hex_encode(&hasher.finalize());
+            if !digest.eq_ignore_ascii_case(&m.sha256) {
+                anyhow::bail!(
+                    "update sha256 mismatch (manifest {}, got {digest})",
+                    m.sha256
+                );
+            }
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e);
+        }
+        std::fs::rename(&partial, &staged)?;
+        Ok(staged)
+    }
+}
+
+/// Background update loop. Returns the task handle and a flag the main loop
+/// polls between commands; once set, the new binary is in place and the agent
+/// should exit with [`EXIT_RESTART`].
+pub fn spawn(
+    updater: Updater,
+    cfg: BridgeConfig,
+    cloud: CloudClient,
+) -> (JoinHandle<()>, Arc<AtomicBool>) {
+    let restart = Arc::new(AtomicBool::new(false));
+    let flag = restart.clone();
+    let every = Duration::from_secs(updater.settings.check_interval_minutes.max(1) * 60);
+    let handle = tokio::spawn(async move {
+        let mut tick = tokio::time::interval(every);
+        loop {
+            tick.tick().await;
+            match check_for_updates(&updater, &cfg, &cloud).await {
+                Ok(UpdateOutcome::Installed { .. }) => {
+                    flag.store(true, Ordering::SeqCst);
+                    return;
+                }
+                Ok(UpdateOutcome::Skipped { version, reason }) => {
+                    info!(%version, %reason, "update offered but skipped")
+                }
+                Ok(UpdateOutcome::UpToDate) => {}
+                Err(e) => warn!(error = %e, "update check failed"),
+            }
+        }
+    });
+    (handle, restart)
+}
+
+/// One full check: stage, measure the health baseline, install.
+pub async fn check_for_updates(
+    updater: &Updater,
+    cfg: &BridgeConfig,
+    cloud: &CloudClient,
+) -> Result<UpdateOutcome> {
+    let staged = match updater.stage().await? {
+        Ok(staged) => staged,
+        Err(outcome) => return Ok(outcome),
+    };
+    let baseline = health::check(cfg, cloud, config::resolve_bearer_token().is_some())
+        .await
+        .status;
+    updater.install(&staged, baseline)
+}
+
+/// First thing on boot: count this boot against a pending swap, and roll back
+/// a binary that has used up [`MAX_UNCONFIRMED_BOOTS`] without confirming.
+pub fn note_boot(data_dir: &Path, running_version: &str) -> Result<BootVerdict> {
+    let mut state = load_state(data_dir)?;
+    let Some(mut pending) = state.pending.take() else {
+        return Ok(BootVerdict::NothingPending);
+    };
+    if pending.version != running_version {
+        // We died between recording the swap and the rename, or the service
+        // manager started something else. Either way the swap did not happen.
+        warn!(
+            pending = %pending.version,
+            running = %running_version,
+            "update swap did not take effect — clearing it"
+        );
+        state.failed_versions.push(pending.version);
+        save_state(data_dir, &state)?;
+        return Ok(BootVerdict::NothingPending);
+    }
+    pending.boots += 1;
+    if pending.boots > MAX_UNCONFIRMED_BOOTS {
+        let reason = format!("no healthy confirmation after {} boots", pending.boots - 1);
+        return rollback(data_dir, state, pending, reason);
+    }
+    let verdict = BootVerdict::Unconfirmed {
+        version: pending.version.clone(),
+        boots: pending.boots,
+    };
+    state.pending = Some(pending);
+    save_state(data_dir, &state)?;
+    Ok(verdict)
+}
+
+/// Once the agent is up: keep the new binary if its health did not regress to
+/// `broken`, otherwise put the previous one back.
+pub fn confirm_or_rollback(data_dir: &Path, post_swap: Status) -> Result<BootVerdict> {
+    let mut state = load_state(data_dir)?;
+    let Some(pending) = state.pending.take() else {
+        return Ok(BootVerdict::NothingPending);
+    };
+    if post_swap == Status::Broken && pending.baseline != Status::Broken {
+        let reason = format!(
+            "health went from {:?} to Broken after the update",
+            pending.baseline
+        );
+        return rollback(data_dir, state, pending, reason);
+    }
+    let _ = std::fs::remove_file(&pending.backup);
+    save_state(data_dir, &state)?;
+    info!(version = %pending.version, "update confirmed healthy");
+    Ok(BootVerdict::Confirmed {
+        version: pending.version,
+    })
+}
+
+/// Whether a swap is waiting for [`confirm_or_rollback`].
+pub fn has_pending(data_dir: &Path) -> bool {
+    load_state(data_dir).is_ok_and(|s| s.pending.is_some())
+}
+
+fn rollback(
+    data_dir: &Path,
+    mut state: UpdateState,
+    pending: PendingSwap,
+    reason: String,
+) -> Result<BootVerdict> {
+    warn!(version = %pending.version, %reason, "rolling back update");
+    std::fs::rename(&pending.backup, &pending.exe).with_context(|| {
+        format!(
+            "restoring {} from {}",
+            pending.exe.display(),
+            pending.backup.display()
+        )
+    })?;
+    state.failed_versions.push(pending.version.clone());
+    save_state(data_dir, &state)?;
+    Ok(BootVerdict::RolledBack {
+        version: pending.version,
+        reason,
+    })
+}
+
+/// `<arch>-<os>` of this build, e.g. `x86_64-linux`.
+pub fn current_target() -> String {
+    format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS)
+}
+
+pub(crate) fn pinned_keys() -> Result<Vec<VerifyingKey>> {
+    parse_keys(PINNED_KEYS_HEX.unwrap_or(""))
+}
+
+fn parse_keys(list: &str) -> Result<Vec<VerifyingKey>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|k| !k.is_empty())
+        .map(|k| {
+            let bytes: [u8; 32] = hex_decode(k)?
+                .try_into()
+                .map_err(|_| anyhow!("update key {k} is not 32 bytes"))?;
+            VerifyingKey::from_bytes(&bytes).with_context(|| format!("update key {k}"))
+        })
+        .collect()
+}
+
+/// `a` vs `b` on their numeric `major.minor.patch` core; a pre-release
+/// (`-beta.1`) sorts below the release it precedes. `None` if either does not
+/// parse.
+fn compare_versions(a: &str, b: &str) -> Option<CmpOrdering> {
+    fn parse(v: &str) -> Option<(Vec<u64>, bool)> {
+        let (core, pre) = match v.split_once('-') {
+            Some((core, _)) => (core, true),
+            None => (v, false),
+        };
+        let parts = core
+            .split('.')
+            .map(|p| p.parse().ok())
+            .collect::<Option<Vec<u64>>>()?;
+        (parts.len() == 3).then_some((parts, pre))
+    }
+    let (a_core, a_pre) = parse(a)?;
+    let (b_core, b_pre) = parse(b)?;
+    Some(a_core.cmp(&b_core).then(b_pre.cmp(&a_pre)))
+}
+
+fn updates_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("updates")
+}
+
+fn load_state(data_dir: &Path) -> Result<UpdateState> {
+    let path = updates_dir(data_dir).join("state.json");
+    match std::fs::read_to_string(&path) {
+        Ok(s) => serde_json::from_str(&s).with_context(|| format!("parsing {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(UpdateState::default()),
+        Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+/// Write-then-rename so a crash never leaves a torn state file.
+fn save_state(data_dir: &Path, state: &UpdateState) -> Result<()> {
+    let dir = updates_dir(data_dir);
+    std::fs::create_dir_all(&dir)?;
+    let tmp = dir.join("state.json.tmp");
+    let mut f = std::fs::File::create(&tmp)?;
+    f.write_all(serde_json::to_string_pretty(state)?.as_bytes())?;
+    f.sync_all()?;
+    std::fs::rename(&tmp, dir.join("state.json"))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
 
-pub async fn check_for_updates() -> anyhow::Result<()> {
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> Result<()> {
     Ok(())
 }
+
+pub(crate) fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub(crate) fn hex_decode(s: &str) -> Result<Vec<u8>> {
+    let s = s.trim();
+    if !s.len().is_multiple_of(2) {
+        anyhow::bail!("odd-length hex");
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(s.get(i..i + 2).unwrap_or("zz"), 16)
+                .map_err(|_| anyhow!("invalid hex at offset {i}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Minimal HTTP/1.1 stand-in for the manifest endpoint and the download
+    /// CDN: serves fixed bodies by path and records every request target.
+    struct StandIn {
+        base: String,
+        routes: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl StandIn {
+        async fn start() -> Self {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let base = format!("http://{}", listener.local_addr().unwrap());
+            let routes: Arc<Mutex<HashMap<String, Vec<u8>>>> = Arc::default();
+            let requests: Arc<Mutex<Vec<String>>> = Arc::default();
+            let (table, seen) = (routes.clone(), requests.clone());
+            tokio::spawn(async move {
+                loop {
+                    let Ok((mut sock, _)) = listener.accept().await else {
+                        return;
+                    };
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 1024];
+                    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match sock.read(&mut chunk).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                        }
+                    }
+                    let head = String::from_utf8_lossy(&buf);
+                    let target = head.split_whitespace().nth(1).unwrap_or("").to_string();
+                    seen.lock().unwrap().push(target.clone());
+                    let path = target.split('?').next().unwrap_or("");
+                    let (status, body) = match table.lock().unwrap().get(path) {
+                        Some(b) => ("200 OK", b.clone()),
+                        None => ("404 Not Found", Vec::new()),
+                    };
+                    let head = format!(
+                        "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    let _ = sock.write_all(head.as_bytes()).await;
+                    let _ = sock.write_all(&body).await;
+                }
+            });
+            Self {
+                base,
+                routes,
+                requests,
+            }
+        }
+
+        fn route(&self, path: &str, body: Vec<u8>) {
+            self.routes.lock().unwrap().insert(path.to_string(), body);
+        }
+    }
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[7u8; 32])
+    }
+
+    fn sign(key: &SigningKey, manifest: &Manifest) -> Vec<u8> {
+        let inner = serde_json::to_string(manifest).unwrap();
+        let signed = SignedManifest {
+            signature: hex_encode(&key.sign(inner.as_bytes()).to_bytes()),
+            manifest: inner,
+        };
+        serde_json::to_vec(&signed).unwrap()
+    }
+
+    fn manifest_for(version: &str, url: String, binary: &[u8]) -> Manifest {
+        Manifest {
+            version: version.to_string(),
+            channel: "stable".to_string(),
+            target: current_target(),
+            url,
+            sha256: hex_encode(&Sha256::digest(binary)),
+            size: binary.len() as u64,
+        }
+    }
+
+    struct Site {
+        /// Held so the scratch tree outlives the test.
+        _dir: TempDir,
+        exe: PathBuf,
+        cfg: BridgeConfig,
+    }
+
+    fn site(update: UpdateConfig, manifest_url: &str) -> Site {
+        let dir = TempDir::new().unwrap();
+        let exe = dir.path().join("bin").join("hummytummy-local-bridge");
+        std::fs::create_dir_all(exe.parent().unwrap()).unwrap();
+        std::fs::write(&exe, b"old binary").unwrap();
+        let cfg = BridgeConfig {
+            cloud_url: "http://unused".to_string(),
+            bridge_id: "b1".to_string(),
+            provisioning_token: None,
+            data_dir: dir.path().join("data"),
+            update: UpdateConfig {
+                manifest_url: Some(manifest_url.to_string()),
+                ..update
+            },
+            local_api: None,
+            dispatch: Default::default(),
+            retry: Default::default(),
+            credentials: Default::default(),
+            command_signing: Default::default(),
+            network: Default::default(),
+            command_ttl: Default::default(),
+            metrics: None,
+        };
+        Site {
+            _dir: dir,
+            exe,
+            cfg,
+        }
+    }
+
+    fn updater(site: &Site) -> Updater {
+        Updater::new(
+            reqwest::Client::new(),
+            &site.cfg,
+            vec![signing_key().verifying_key()],
+            site.exe.clone(),
+            "0.1.0".to_string(),
+        )
+    }
+
+    /// Stand-in serving `binary` at `/bin` and a manifest for it, signed by
+    /// `key`, at `/manifest`.
+    async fn serve_release(version: &str, binary: &[u8], key: &SigningKey) -> StandIn {
+        let srv = StandIn::start().await;
+        srv.route("/bin", binary.to_vec());
+        let manifest = manifest_for(version, format!("{}/bin", srv.base), binary);
+        srv.route("/manifest", sign(key, &manifest));
+        srv
+    }
+
+    #[tokio::test]
+    async fn installs_a_signed_newer_release_and_keeps_a_backup() {
+        let binary = b"new binary v0.2.0".to_vec();
+        let srv = serve_release("0.2.0", &binary, &signing_key()).await;
+        let site = site(UpdateConfig::default(), &format!("{}/manifest", srv.base));
+        let up = updater(&site);
+
+        let staged = up.stage().await.unwrap().expect("newer release is staged");
+        assert!(staged.path.starts_with(site.cfg.data_dir.join("updates")));
+        let outcome = up.install(&staged, Status::Ok).unwrap();
+        assert_eq!(
+            outcome,
+            UpdateOutcome::Installed {
+                from: "0.1.0".to_string(),
+                to: "0.2.0".to_string()
+            }
+        );
+        assert_eq!(std::fs::read(&site.exe).unwrap(), binary);
+        assert_eq!(
+            std::fs::read(site.exe.with_file_name("hummytummy-local-bridge.previous")).unwrap(),
+            b"old binary"
+        );
+        assert!(has_pending(&site.cfg.data_dir));
+        let req = srv.requests.lock().unwrap()[0].clone();
+        assert!(req.contains("channel=stable"), "{req}");
+        assert!(req.contains("current=0.1.0"), "{req}");
+    }
+
+    #[tokio::test]
+    async fn manifest_signed_by_another_key_is_rejected() {
+        let srv = serve_release("0.2.0", b"evil", &SigningKey::from_bytes(&[9u8; 32])).await;
+        let site = site(UpdateConfig::default(), &format!("{}/manifest", srv.base));
+
+        let err = updater(&site).stage().await.unwrap_err();
+        assert!(err.to_string().contains("does not verify"), "{err}");
+        assert_eq!(std::fs::read(&site.exe).unwrap(), b"old binary");
+    }
+
+    #[test]
+    fn tampered_manifest_fails_verification() {
+        let key = signing_key();
+        let m = manifest_for("0.2.0", "https://cdn/bin".to_string(), b"x");
+        let mut signed: SignedManifest = serde_json::from_slice(&sign(&key, &m)).unwrap();
+        signed.manifest = signed.manifest.replace("0.2.0", "9.9.9");
+        assert!(signed.verify(&[key.verifying_key()]).is_err());
+        assert!(
+            signed.verify(&[]).is_err(),
+            "no pinned key means no update, never 'skip verification'"
+        );
+    }
+
+    #[tokio::test]
+    async fn digest_mismatch_stages_nothing() {
+        let srv = StandIn::start().await;
+        srv.route("/bin", b"corrupted in transit".to_vec());
+        let mut m = manifest_for("0.2.0", format!("{}/bin", srv.base), b"the real one");
+        m.size = b"corrupted in transit".len() as u64;
+        srv.route("/manifest", sign(&signing_key(), &m));
+        let site = site(UpdateConfig::default(), &format!("{}/manifest", srv.base));
+
+        let err = updater(&site).stage().await.unwrap_err();
+        assert!(err.to_string().contains("sha256 mismatch"), "{err}");
+        let leftovers: Vec<_> = std::fs::read_dir(site.cfg.data_dir.join("updates"))
+            .unwrap()
+            .collect();
+        assert!(leftovers.is_empty(), "partial download removed");
+    }
+
+    #[tokio::test]
+    async fn pin_and_channel_are_honoured() {
+        let srv = serve_release("0.2.0", b"v2", &signing_key()).await;
+        let url = format!("{}/manifest", srv.base);
+
+        let pinned = site(
+            UpdateConfig {
+                pin: Some("0.1.5".to_string()),
+                ..UpdateConfig::default()
+            },
+            &url,
+        );
+        match updater(&pinned).stage().await.unwrap() {
+            Err(UpdateOutcome::Skipped { reason, .. }) => assert!(reason.contains("pins 0.1.5")),
+            other => panic!("expected skip, got {other:?}"),
+        }
+
+        let beta = site(
+            UpdateConfig {
+                channel: "beta".to_string(),
+                ..UpdateConfig::default()
+            },
+            &url,
+        );
+        let err = updater(&beta).stage().await.unwrap_err();
+        assert!(err.to_string().contains("channel"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn broken_after_swap_rolls_back_and_blacklists_the_version() {
+        let srv = serve_release("0.2.0", b"bad build", &signing_key()).await;
+        let site = site(UpdateConfig::default(), &format!("{}/manifest", srv.base));
+        let up = updater(&site);
+        let staged = up.stage().await.unwrap().unwrap();
+        up.install(&staged, Status::Degraded).unwrap();
+
+        let data = &site.cfg.data_dir;
+        assert_eq!(
+            note_boot(data, "0.2.0").unwrap(),
+            BootVerdict::Unconfirmed {
+                version: "0.2.0".to_string(),
+                boots: 1
+            }
+        );
+        match confirm_or_rollback(data, Status::Broken).unwrap() {
+            BootVerdict::RolledBack { version, .. } => assert_eq!(version, "0.2.0"),
+            other => panic!("expected rollback, got {other:?}"),
+        }
+        assert_eq!(std::fs::read(&site.exe).unwrap(), b"old binary");
+        assert!(!has_pending(data));
+
+        match up.stage().await.unwrap() {
+            Err(UpdateOutcome::Skipped { reason, .. }) => assert!(reason.contains("rolled back")),
+            other => panic!("a rolled-back version must not be re-offered: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn healthy_after_swap_confirms_and_drops_the_backup() {
+        let srv = serve_release("0.2.0", b"good build", &signing_key()).await;
+        let site = site(UpdateConfig::default(), &format!("{}/manifest", srv.base));
+        let up = updater(&site);
+        let staged = up.stage().await.unwrap().unwrap();
+        up.install(&staged, Status::Ok).unwrap();
+
+        note_boot(&site.cfg.data_dir, "0.2.0").unwrap();
+        assert_eq!(
+            confirm_or_rollback(&site.cfg.data_dir, Status::Degraded).unwrap(),
+            BootVerdict::Confirmed {
+                version: "0.2.0".to_string()
+            }
+        );
+        assert!(!site
+            .exe
+            .with_file_name("hummytummy-local-bridge.previous")
+            .exists());
+        assert_eq!(std::fs::read(&site.exe).unwrap(), b"good build");
+    }
+
+    #[tokio::test]
+    async fn crash_looping_binary_is_rolled_back_on_boot() {
+        let srv = serve_release("0.2.0", b"crashes", &signing_key()).await;
+        let site = site(UpdateConfig::default(), &format!("{}/manifest", srv.base));
+        let up = updater(&site);
+        let staged = up.stage().await.unwrap().unwrap();
+        up.install(&staged, Status::Ok).unwrap();
+
+        for _ in 0..MAX_UNCONFIRMED_BOOTS {
+            assert!(matches!(
+                note_boot(&site.cfg.data_dir, "0.2.0").unwrap(),
+                BootVerdict::Unconfirmed { .. }
+            ));
+        }
+        assert!(matches!(
+            note_boot(&site.cfg.data_dir, "0.2.0").unwrap(),
+            BootVerdict::RolledBack { .. }
+        ));
+        assert_eq!(std::fs::read(&site.exe).unwrap(), b"old binary");
+    }
+
+    #[test]
+    fn versions_compare_numerically_with_prereleases_first() {
+        use CmpOrdering::*;
+        assert_eq!(compare_versions("0.10.0", "0.9.9"), Some(Greater));
+        assert_eq!(compare_versions("1.0.0-beta.1", "1.0.0"), Some(Less));
+        assert_eq!(compare_versions("1.0.0", "1.0.0"), Some(Equal));
+        assert_eq!(compare_versions("1.0", "1.0.0"), None);
+    }
+
+    #[test]
+    fn pinned_key_list_parses() {
+        let hex = hex_encode(signing_key().verifying_key().as_bytes());
+        assert_eq!(parse_keys(&format!(" {hex} ,")).unwrap().len(), 1);
+        assert!(parse_keys("abcd").is_err());
+        assert!(parse_keys("").unwrap().is_empty());
+    }
+}
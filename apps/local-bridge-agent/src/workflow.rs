@@ -0,0 +1,679 @@
+//! Composite `workflow` commands: several device steps run as one unit.
+//!
+//! The counter flow "approve the card, print the fiş, pop the drawer" used to be
+//! three cloud commands with three independent failures — a drawer could open
+//! for a declined card. A workflow carries the steps in one command:
+//!
+//! ```json
+//! { "kind": "workflow", "payload": { "steps": [
+//!     { "id": "charge", "kind": "charge_card",
+//!       "payload": { "protocol": "GMP3", "fiscalSerial": "SER-1", ... } },
+//!     { "id": "fis", "kind": "fiscal_receipt", "after": ["charge"],
+//!       "when": [{ "step": "charge", "result": { "approved": true } }],
+//!       "payload": { ... } },
+//!     { "id": "drawer", "kind": "open_drawer", "after": ["fis"],
+//!       "payload": { "target": "escpos", ... } }
+//! ] } }
+//! ```
+//!
+//! Steps form a small DAG: `after` names the steps that must settle first.
+//! Each runs through [`Registry::dispatch`] exactly like a standalone command,
+//! one at a time, in dependency order (declaration order among the ready
+//! ones). The workflow is queued on its first step's device; a step on another
+//! device (the drawer above) takes that device's lease in the registry, so it
+//! waits for the command the device is running instead of cutting into it. A step runs when every `when` condition holds — a condition names a
+//! step and the status (default `done`) and result fields it must have — or,
+//! with no `when`, when every step in `after` is `done`. Otherwise it is
+//! `skipped`, which in turn skips the steps that depend on it.
+//!
+//! Progress is persisted per step in `workflow_steps` before and after each
+//! dispatch, so a workflow interrupted by a crash or a failed step resumes at
+//! that step and never re-runs one that settled. The step it stopped on is
+//! dispatched again as a replay (`attempts > 1`), so the registry's money/fiscal
+//! replay guard applies to it unchanged, and a workflow that stopped on a
+//! money/fiscal step parks in `needs_review` (see
+//! [`CommandQueue::mark_failed`]).
+//!
+//! The workflow acks `done` once every step settled, with each step's outcome
+//! in `result.steps`. A step the driver could not run fails the workflow with
+//! [`StepFailed`], whose ack carries the same per-step progress so the cloud
+//! knows a charge went through even when the fiş did not print.
+
+use crate::{
+    command_queue::{
+        resolve_idempotency_key, CommandOutcome, CommandQueue, PendingCommand, WorkflowStepRecord,
+    },
+    drivers::{self, Registry},
+};
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use tracing::info;
+
+/// Command kind of a workflow.
+pub const KIND: &str = "workflow";
+
+/// A workflow payload, validated and in the order its steps run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workflow {
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Step {
+    pub id: String,
+    pub kind: String,
+    #[serde(default)]
+    pub payload: Value,
+    #[serde(default)]
+    pub after: Vec<String>,
+    #[serde(default)]
+    pub when: Vec<Condition>,
+}
+
+/// "Step `step` settled as `status`, and its result has these fields."
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Condition {
+    pub step: String,
+    #[serde(default = "done")]
+    pub status: String,
+    /// Top-level result fields that must be present with exactly these values.
+    #[serde(default)]
+    pub result: Option<serde_json::Map<String, Value>>,
+}
+
+fn done() -> String {
+    "done".to_string()
+}
+
+#[derive(Deserialize)]
+struct RawWorkflow {
+    steps: Vec<Step>,
+}
+
+impl Workflow {
+    /// Parse and validate a workflow payload: at least one step, unique ids,
+    /// every `after`/`when` reference to another step, no nested workflows and
+    /// no cycles.
+    pub fn parse(payload: &Value) -> Result<Self> {
+        let raw: RawWorkflow = serde_json::from_value(payload.clone())
+            .map_err(|e| anyhow::anyhow!("malformed workflow payload: {e}"))?;
+        if raw.steps.is_empty() {
+            bail!("workflow has no steps");
+        }
+        let mut ids = HashSet::new();
+        for step in &raw.steps {
+            if step.id.is_empty() || step.kind.is_empty() {
+                bail!("workflow step needs an id and a kind");
+            }
+            if step.kind == KIND {
+                bail!("workflow step '{}' is itself a workflow", step.id);
+            }
+            if !ids.insert(step.id.as_str()) {
+                bail!("workflow step id '{}' is used twice", step.id);
+            }
+        }
+        for step in &raw.steps {
+            if let Some(dep) = step.deps().find(|d| !ids.contains(d)) {
+                bail!(
+                    "workflow step '{}' depends on unknown step '{dep}'",
+                    step.id
+                );
+            }
+        }
+        // Topological order, earliest-declared ready step first.
+        let mut placed: HashSet<&str> = HashSet::new();
+        let mut order = Vec::with_capacity(raw.steps.len());
+        while order.len() < raw.steps.len() {
+            let next = raw.steps.iter().position(|s| {
+                !placed.contains(s.id.as_str()) && s.deps().all(|d| placed.contains(d))
+            });
+            let Some(i) = next else {
+                bail!("workflow steps depend on each other in a cycle");
+            };
+            placed.insert(raw.steps[i].id.as_str());
+            order.push(i);
+        }
+        Ok(Self {
+            steps: order.into_iter().map(|i| raw.steps[i].clone()).collect(),
+        })
+    }
+
+    /// Steps in the order they run.
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+}
+
+impl Step {
+    /// Every step this one waits for: `after` plus the steps its conditions
+    /// read.
+    fn deps(&self) -> impl Iterator<Item = &str> {
+        self.after
+            .iter()
+            .map(String::as_str)
+            .chain(self.when.iter().map(|c| c.step.as_str()))
+    }
+
+    /// `Ok` to run, `Err(reason)` to skip, given the settled steps so far.
+    fn should_run(&self, settled: &HashMap<String, WorkflowStepRecord>) -> Result<(), String> {
+        if self.when.is_empty() {
+            return match self
+                .after
+                .iter()
+                .find(|d| settled.get(*d).is_none_or(|r| r.status != "done"))
+            {
+                Some(dep) => Err(format!("step '{dep}' is not done")),
+                None => Ok(()),
+            };
+        }
+        for c in &self.when {
+            let Some(r) = settled.get(&c.step) else {
+                return Err(format!("step '{}' did not run", c.step));
+            };
+            if r.status != c.status {
+                return Err(format!(
+                    "step '{}' is {}, not {}",
+                    c.step, r.status, c.status
+                ));
+            }
+            for (field, want) in c.result.iter().flatten() {
+                let got = r.result.as_ref().and_then(|v| v.get(field));
+                if got != Some(want) {
+                    return Err(format!(
+                        "step '{}' result {field} is {}, not {want}",
+                        c.step,
+                        got.unwrap_or(&Value::Null)
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The standalone command this step dispatches as. Its id is
+    /// `<workflow id>/<step id>`, which is also the idempotency key a
+    /// money/fiscal step falls back to.
+    fn command(&self, workflow: &PendingCommand, attempts: i32) -> PendingCommand {
+        let mut cmd = PendingCommand {
+            id: format!("{}/{}", workflow.id, self.id),
+            kind: self.kind.clone(),
+            payload: self.payload.clone(),
+            priority: workflow.priority,
+            attempts,
+            idempotency_key: None,
+            origin: workflow.origin,
+        };
+        cmd.idempotency_key = resolve_idempotency_key(&cmd);
+        cmd
+    }
+}
+
+/// Dispatch shard of a workflow: that of its first step, so a charge-first
+/// workflow queues behind (and ahead of) the terminal's other commands. Steps
+/// on other devices take those devices' leases as they run.
+pub fn device_key(cmd: &PendingCommand) -> String {
+    match Workflow::parse(&cmd.payload) {
+        Ok(wf) => drivers::device_key(&wf.steps[0].command(cmd, 0)),
+        Err(_) => "unrouted".to_string(),
+    }
+}
+
+/// A step the driver could not run. The workflow stops there; retrying it
+/// resumes at this step.
+#[derive(Debug, thiserror::Error)]
+#[error("workflow step '{step}' ({kind}) failed: {error}")]
+pub struct StepFailed {
+    pub step: String,
+    pub kind: String,
+    pub error: String,
+    /// Per-step progress, as in a `done` ack's result.
+    pub progress: Value,
+    /// The step was a replay the registry refused
+    /// ([`drivers::ReplayRefused`]): it may have run, so this is not a failure.
+    pub replay_refused: bool,
+}
+
+impl StepFailed {
+    /// The `failed` outcome to ack, progress included.
+    pub fn outcome(&self) -> CommandOutcome {
+        CommandOutcome {
+            status: "failed".to_string(),
+            result: self.progress.clone(),
+            error: Some(self.to_string()),
+        }
+    }
+}
+
+/// Run (or resume) workflow `cmd`, step by step. `Err` is either a payload
+/// that is not a valid workflow or a [`StepFailed`].
+pub async fn run(
+    queue: &CommandQueue,
+    registry: &Registry,
+    cmd: &PendingCommand,
+) -> Result<CommandOutcome> {
+    let wf = Workflow::parse(&cmd.payload)?;
+    let mut settled: HashMap<String, WorkflowStepRecord> = queue
+        .workflow_steps(&cmd.id)
+        .await?
+        .into_iter()
+        .filter(|r| r.status != "running" && r.status != "error")
+        .map(|r| (r.step_id.clone(), r))
+        .collect();
+    if !settled.is_empty() {
+        info!(cmd = %cmd.id, settled = settled.len(), "resuming workflow");
+    }
+    for step in wf.steps() {
+        if settled.contains_key(&step.id) {
+            continue;
+        }
+        let (status, result, error) = match step.should_run(&settled) {
+            Err(reason) => ("skipped".to_string(), None, Some(reason)),
+            Ok(()) => {
+                let attempts = queue.begin_step(&cmd.id, &step.id, &step.kind).await?;
+                match registry.dispatch(&step.command(cmd, attempts)).await {
+                    Ok(outcome) => (outcome.status, Some(outcome.result), outcome.error),
+                    Err(e) => {
+                        let error = format!("{e:#}");
+                        let replay_refused = e.is::<drivers::ReplayRefused>();
+                        queue
+                            .settle_step(&cmd.id, &step.id, &step.kind, "error", None, Some(&error))
+                            .await?;
+                        let progress = progress(&wf, &queue.workflow_steps(&cmd.id).await?);
+                        return Err(StepFailed {
+                            step: step.id.clone(),
+                            kind: step.kind.clone(),
+                            error,
+                            progress,
+                            replay_refused,
+                        }
+                        .into());
+                    }
+                }
+            }
+        };
+        queue
+            .settle_step(
+                &cmd.id,
+                &step.id,
+                &step.kind,
+                &status,
+                result.as_ref(),
+                error.as_deref(),
+            )
+            .await?;
+        settled.insert(
+            step.id.clone(),
+            WorkflowStepRecord {
+                step_id: step.id.clone(),
+                kind: step.kind.clone(),
+                status,
+                attempts: 0,
+                result,
+                error,
+                updated_at: 0,
+            },
+        );
+    }
+    Ok(CommandOutcome {
+        status: "done".to_string(),
+        result: progress(&wf, &queue.workflow_steps(&cmd.id).await?),
+        error: None,
+    })
+}
+
+/// `{"steps": [...]}` in run order; a step that has not started is `pending`.
+fn progress(wf: &Workflow, records: &[WorkflowStepRecord]) -> Value {
+    let steps = wf
+        .steps()
+        .iter()
+        .map(|s| match records.iter().find(|r| r.step_id == s.id) {
+            Some(r) => json!({
+                "id": s.id,
+                "kind": s.kind,
+                "status": r.status,
+                "result": r.result,
+                "error": r.error,
+            }),
+            None => json!({ "id": s.id, "kind": s.kind, "status": "pending" }),
+        })
+        .collect::<Vec<_>>();
+    json!({ "steps": steps })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::LocalDriver;
+    use async_trait::async_trait;
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+
+    /// One fake driver per route. Records every step command it is handed;
+    /// a payload `"fail": true` makes it return an error, `"approved"` is
+    /// echoed into the result.
+    struct Device {
+        kind: &'static str,
+        ran: Arc<Mutex<Vec<PendingCommand>>>,
+    }
+
+    #[async_trait]
+    impl LocalDriver for Device {
+        fn kind(&self) -> &str {
+            self.kind
+        }
+        async fn execute(&self, cmd: &PendingCommand) -> Result<CommandOutcome> {
+            self.ran.lock().unwrap().push(cmd.clone());
+            if cmd.payload["fail"] == true {
+                bail!("{} offline", self.kind);
+            }
+            Ok(CommandOutcome {
+                status: "done".to_string(),
+                result: json!({ "approved": cmd.payload["approved"] }),
+                error: None,
+            })
+        }
+    }
+
+    struct Bench {
+        queue: CommandQueue,
+        registry: Registry,
+        ran: Arc<Mutex<Vec<PendingCommand>>>,
+        _dir: TempDir,
+    }
+
+    fn bench() -> Bench {
+        let dir = TempDir::new().unwrap();
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let registry = Registry::from_drivers(vec![
+            Box::new(Device {
+                kind: "gmp3",
+                ran: ran.clone(),
+            }),
+            Box::new(Device {
+                kind: "escpos",
+                ran: ran.clone(),
+            }),
+        ]);
+        Bench {
+            queue: CommandQueue::open(dir.path().join("q.db")).unwrap(),
+            registry,
+            ran,
+            _dir: dir,
+        }
+    }
+
+    impl Bench {
+        fn ran(&self) -> Vec<String> {
+            self.ran
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|c| c.id.clone())
+                .collect()
+        }
+    }
+
+    /// Charge → fiş (only if approved) → drawer.
+    fn counter(approved: bool, printer_fails: bool) -> PendingCommand {
+        PendingCommand {
+            id: "wf".to_string(),
+            kind: KIND.to_string(),
+            payload: json!({ "steps": [
+                { "id": "drawer", "kind": "open_drawer", "after": ["fis"],
+                  "payload": { "target": "escpos", "fail": printer_fails } },
+                { "id": "charge", "kind": "charge_card",
+                  "payload": { "protocol": "GMP3", "fiscalSerial": "SER-1", "approved": approved } },
+                { "id": "fis", "kind": "fiscal_receipt", "after": ["charge"],
+                  "when": [{ "step": "charge", "result": { "approved": true } }],
+                  "payload": { "protocol": "GMP3", "fiscalSerial": "SER-1" } },
+            ] }),
+            priority: 0,
+            attempts: 1,
+            idempotency_key: None,
+            origin: Default::default(),
+        }
+    }
+
+    fn statuses(outcome: &Value) -> Vec<(String, String)> {
+        outcome["steps"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| {
+                (
+                    s["id"].as_str().unwrap().to_string(),
+                    s["status"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    fn pairs(p: &[(&str, &str)]) -> Vec<(String, String)> {
+        p.iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn steps_run_in_dependency_order() {
+        let wf = Workflow::parse(&counter(true, false).payload).unwrap();
+        let ids: Vec<_> = wf.steps().iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, ["charge", "fis", "drawer"]);
+        assert_eq!(device_key(&counter(true, false)), "gmp3:SER-1");
+    }
+
+    /// A printer that logs when it starts and ends a command, holding one
+    /// with `"hold": true` until the test lets it go.
+    struct Printer {
+        gate: Arc<tokio::sync::Semaphore>,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl LocalDriver for Printer {
+        fn kind(&self) -> &str {
+            "escpos"
+        }
+        async fn execute(&self, cmd: &PendingCommand) -> Result<CommandOutcome> {
+            self.log.lock().unwrap().push(format!("start {}", cmd.id));
+            if cmd.payload["hold"] == true {
+                self.gate.acquire().await?.forget();
+            }
+            self.log.lock().unwrap().push(format!("end {}", cmd.id));
+            Ok(CommandOutcome {
+                status: "done".to_string(),
+                result: json!({}),
+                error: None,
+            })
+        }
+    }
+
+    /// The counter workflow is queued on the terminal, but its drawer step
+    /// reaches the printer: it waits for the ticket the printer is busy with
+    /// rather than running alongside it.
+    #[tokio::test]
+    async fn a_step_on_another_device_waits_for_that_devices_lease() {
+        let dir = TempDir::new().unwrap();
+        let queue = CommandQueue::open(dir.path().join("q.db")).unwrap();
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let registry = Registry::from_drivers(vec![
+            Box::new(Device {
+                kind: "gmp3",
+                ran: ran.clone(),
+            }),
+            Box::new(Printer {
+                gate: gate.clone(),
+                log: log.clone(),
+            }),
+        ]);
+        let wf = PendingCommand {
+            payload: json!({ "steps": [
+                { "id": "charge", "kind": "charge_card",
+                  "payload": { "protocol": "GMP3", "fiscalSerial": "SER-1", "approved": true } },
+                { "id": "drawer", "kind": "open_drawer", "after": ["charge"],
+                  "payload": { "target": "escpos" } },
+            ] }),
+            ..counter(true, false)
+        };
+        assert_eq!(device_key(&wf), "gmp3:SER-1");
+        let ticket = PendingCommand {
+            id: "ticket".to_string(),
+            kind: "print_receipt".to_string(),
+            payload: json!({ "target": "escpos", "hold": true }),
+            priority: 0,
+            attempts: 1,
+            idempotency_key: None,
+            origin: Default::default(),
+        };
+        queue.push(&wf).await.unwrap();
+        queue.pop_next().await.unwrap().unwrap();
+
+        let started = |entry: &str| log.lock().unwrap().iter().any(|e| e == entry);
+        let (printed, outcome, ()) = tokio::join!(
+            registry.dispatch(&ticket),
+            async {
+                while !started("start ticket") {
+                    tokio::task::yield_now().await;
+                }
+                run(&queue, &registry, &wf).await
+            },
+            async {
+                while ran.lock().unwrap().is_empty() {
+                    tokio::task::yield_now().await;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                assert!(!started("start wf/drawer"), "drawer cut into the ticket");
+                gate.add_permits(1);
+            },
+        );
+        printed.unwrap();
+        assert_eq!(outcome.unwrap().status, "done");
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "start ticket",
+                "end ticket",
+                "start wf/drawer",
+                "end wf/drawer"
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_workflows_are_refused() {
+        let err = |steps: Value| {
+            Workflow::parse(&json!({ "steps": steps }))
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(err(json!([])).contains("no steps"));
+        assert!(
+            err(json!([{ "id": "a", "kind": "x" }, { "id": "a", "kind": "y" }]))
+                .contains("used twice")
+        );
+        assert!(err(json!([{ "id": "a", "kind": "x", "after": ["b"] }])).contains("unknown"));
+        assert!(err(json!([
+            { "id": "a", "kind": "x", "after": ["b"] },
+            { "id": "b", "kind": "x", "after": ["a"] },
+        ]))
+        .contains("cycle"));
+        assert!(err(json!([{ "id": "a", "kind": "workflow" }])).contains("itself a workflow"));
+        assert!(Workflow::parse(&json!({ "steps": "nope" })).is_err());
+    }
+
+    #[tokio::test]
+    async fn approved_charge_prints_and_opens_the_drawer() {
+        let b = bench();
+        let cmd = counter(true, false);
+        let outcome = run(&b.queue, &b.registry, &cmd).await.unwrap();
+
+        assert_eq!(outcome.status, "done");
+        assert_eq!(
+            statuses(&outcome.result),
+            pairs(&[("charge", "done"), ("fis", "done"), ("drawer", "done")])
+        );
+        assert_eq!(outcome.result["steps"][0]["result"]["approved"], true);
+        assert_eq!(b.ran(), ["wf/charge", "wf/fis", "wf/drawer"]);
+        // Money steps carry a per-step idempotency key.
+        let charge = b.ran.lock().unwrap()[0].clone();
+        assert_eq!(charge.idempotency_key.as_deref(), Some("wf/charge"));
+        assert_eq!(charge.attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn declined_charge_skips_the_receipt_and_the_drawer() {
+        let b = bench();
+        let outcome = run(&b.queue, &b.registry, &counter(false, false))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            statuses(&outcome.result),
+            pairs(&[
+                ("charge", "done"),
+                ("fis", "skipped"),
+                ("drawer", "skipped")
+            ])
+        );
+        assert!(outcome.result["steps"][1]["error"]
+            .as_str()
+            .unwrap()
+            .contains("approved is false"));
+        assert_eq!(b.ran(), ["wf/charge"]);
+    }
+
+    /// The drawer fails after the charge and the fiş went through: the
+    /// failure ack says so, and the retry runs only the drawer.
+    #[tokio::test]
+    async fn a_failed_step_is_resumed_without_rerunning_settled_ones() {
+        let b = bench();
+        let err = run(&b.queue, &b.registry, &counter(true, true))
+            .await
+            .unwrap_err();
+        let failed = err.downcast_ref::<StepFailed>().expect("a step failure");
+        assert_eq!(failed.step, "drawer");
+        let ack = failed.outcome();
+        assert_eq!(ack.status, "failed");
+        assert_eq!(
+            statuses(&ack.result),
+            pairs(&[("charge", "done"), ("fis", "done"), ("drawer", "error")])
+        );
+
+        run(&b.queue, &b.registry, &counter(true, false))
+            .await
+            .unwrap();
+        assert_eq!(b.ran(), ["wf/charge", "wf/fis", "wf/drawer", "wf/drawer"]);
+        let drawer = b.ran.lock().unwrap()[3].clone();
+        assert_eq!(drawer.attempts, 2);
+    }
+
+    /// A crash after the charge was sent but before its outcome was written
+    /// leaves it `running`. The resumed workflow dispatches it as a replay,
+    /// which the registry refuses for a driver that cannot verify it — and the
+    /// workflow then parks instead of retrying.
+    #[tokio::test]
+    async fn a_crash_mid_charge_parks_the_workflow() {
+        let b = bench();
+        let mut cmd = counter(true, false);
+        b.queue.push(&cmd).await.unwrap();
+        b.queue.pop_next().await.unwrap().unwrap();
+        b.queue
+            .begin_step("wf", "charge", "charge_card")
+            .await
+            .unwrap();
+
+        cmd.attempts = 2;
+        let err = run(&b.queue, &b.registry, &cmd).await.unwrap_err();
+        assert!(format!("{err:#}").contains("refusing to replay"), "{err:#}");
+        assert!(err.downcast_ref::<StepFailed>().unwrap().replay_refused);
+        assert!(b.ran().is_empty(), "the terminal is never asked twice");
+
+        b.queue.mark_failed("wf", &err.to_string()).await.unwrap();
+        let row = b.queue.get("wf").await.unwrap().unwrap();
+        assert_eq!(row.status, "needs_review");
+    }
+}
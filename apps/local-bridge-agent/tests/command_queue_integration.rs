@@ -6,6 +6,7 @@
 //! "at least once" delivery.
 
 use hummytummy_local_bridge::command_queue::{CommandOutcome, CommandQueue, PendingCommand};
+use hummytummy_local_bridge::config::{Backoff, RetryConfig};
 use serde_json::json;
 use tempfile::TempDir;
 
@@ -16,6 +17,8 @@ fn make_cmd(id: &str, priority: i32) -> PendingCommand {
         payload: json!({ "target": "escpos" }),
         priority,
         attempts: 0,
+        idempotency_key: None,
+        origin: Default::default(),
     }
 }
 
@@ -72,7 +75,18 @@ async fn push_is_idempotent_on_id() {
 #[tokio::test]
 async fn mark_failed_requeues_until_cap() {
     let dir = TempDir::new().unwrap();
-    let q = CommandQueue::open(dir.path().join("q.db")).unwrap();
+    // Zero backoff: this is about the attempt cap, not the retry schedule.
+    let no_backoff = RetryConfig {
+        default: Backoff {
+            base_ms: 0,
+            max_ms: 0,
+            jitter: 0.0,
+        },
+        ..Default::default()
+    };
+    let q = CommandQueue::open(dir.path().join("q.db"))
+        .unwrap()
+        .with_retry(no_backoff);
     q.push(&make_cmd("flaky", 0)).await.unwrap();
 
     // First four fails leave status='queued' (attempts <= cap of 5).
@@ -104,3 +118,51 @@ async fn state_persists_across_handle_reopens() {
         assert_eq!(popped.id, "persisted");
     }
 }
+
+#[tokio::test]
+async fn crashed_charge_is_resolved_by_idempotent_replay() {
+    // A charge is dispatched, the device completes it, and the bridge dies
+    // before mark_done. On restart the row is requeued (it carries an
+    // idempotency key) and the gmp3 driver returns the device's recorded
+    // approval instead of charging the card a second time.
+    use hummytummy_local_bridge::drivers::Registry;
+
+    let dir = TempDir::new().unwrap();
+    std::fs::write(
+        dir.path().join("gmp3.toml"),
+        "[[device]]\nserial = \"SER-1\"\nmode = \"simulator\"\n",
+    )
+    .unwrap();
+    let charge = PendingCommand {
+        id: "charge-1".to_string(),
+        kind: "charge_card".to_string(),
+        payload: json!({
+            "protocol": "GMP3",
+            "vendorProfile": "paygo.sp630",
+            "fiscalSerial": "SER-1",
+            "amountCents": 5000,
+        }),
+        priority: 10,
+        attempts: 0,
+        idempotency_key: None,
+        origin: Default::default(),
+    };
+    let path = dir.path().join("q.db");
+    let first = {
+        let q = CommandQueue::open(&path).unwrap();
+        let drivers = Registry::init(dir.path()).await.unwrap();
+        q.push(&charge).await.unwrap();
+        let popped = q.pop_next().await.unwrap().unwrap();
+        drivers.dispatch(&popped).await.unwrap()
+        // ...crash: no mark_done.
+    };
+
+    let drivers = Registry::init(dir.path()).await.unwrap();
+    let q = CommandQueue::open_replaying(&path, |cmd| drivers.verifies_idempotency(cmd)).unwrap();
+    assert_eq!(q.needs_review_count().await.unwrap(), 0);
+    let replay = q.pop_next().await.unwrap().expect("requeued for replay");
+    assert_eq!(replay.attempts, 2);
+    let outcome = drivers.dispatch(&replay).await.unwrap();
+    assert_eq!(outcome.result["replayed"], true);
+    assert_eq!(outcome.result["rrn"], first.result["rrn"]);
+}
@@ -0,0 +1,90 @@
+//! End-to-end test of GMP-3 crash recovery against the device emulator.
+//!
+//! The bridge dies after the device approved a charge but before the reply
+//! reached it. On restart the queue requeues the keyed charge, the driver asks
+//! the device about the key before sending, and gets the approval back — the
+//! card is charged once.
+
+use hummytummy_local_bridge::command_queue::{CommandQueue, PendingCommand};
+use hummytummy_local_bridge::drivers::gmp3::codec::tag;
+use hummytummy_local_bridge::drivers::gmp3::emulator::{Emulator, Step};
+use hummytummy_local_bridge::drivers::gmp3::Gmp3Driver;
+use hummytummy_local_bridge::drivers::Registry;
+use serde_json::json;
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+fn registry(data_dir: &Path) -> Registry {
+    Registry::from_drivers(vec![Box::new(Gmp3Driver::load(data_dir).unwrap())])
+}
+
+#[tokio::test]
+async fn charge_cut_off_after_approval_is_recovered_without_charging_twice() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let emu = Arc::new(
+        Emulator::new("EMU-1")
+            .unwrap()
+            .with_script([Step::DropAfterApproval]),
+    );
+    emu.clone().spawn(listener);
+
+    let dir = TempDir::new().unwrap();
+    std::fs::write(
+        dir.path().join("gmp3.toml"),
+        format!(
+            "[[device]]\nserial = \"EMU-1\"\nmode = \"emulator\"\nhost = \"127.0.0.1\"\nport = {port}\ntimeout_secs = 5\n"
+        ),
+    )
+    .unwrap();
+    let db = dir.path().join("command_queue.db");
+
+    {
+        let q = CommandQueue::open(&db).unwrap();
+        q.push(&PendingCommand {
+            id: "chg-1".to_string(),
+            kind: "charge_card".to_string(),
+            payload: json!({
+                "protocol": "GMP3",
+                "vendorProfile": "paygo.sp630",
+                "fiscalSerial": "EMU-1",
+                "amountCents": 4250,
+                "currency": "TRY",
+                "orderId": "o-1",
+            }),
+            priority: 10,
+            attempts: 0,
+            idempotency_key: Some("order-o-1-charge-1".to_string()),
+            origin: Default::default(),
+        })
+        .await
+        .unwrap();
+        let cmd = q.pop_next().await.unwrap().unwrap();
+        assert_eq!(cmd.attempts, 1);
+        // The device approves and the connection drops before the reply: to
+        // the bridge it is an error. The process then dies before settling
+        // the row — it stays inflight.
+        assert!(registry(dir.path()).dispatch(&cmd).await.is_err());
+    }
+
+    // Restart: the gmp3 driver verifies keys, so recovery requeues the keyed
+    // charge for a verified replay.
+    let registry = registry(dir.path());
+    let q = CommandQueue::open_replaying(&db, |cmd| registry.verifies_idempotency(cmd)).unwrap();
+    let cmd = q.pop_next().await.unwrap().expect("keyed charge requeued");
+    assert_eq!(cmd.id, "chg-1");
+    assert_eq!(cmd.attempts, 2);
+
+    let out = registry.dispatch(&cmd).await.unwrap();
+    assert_eq!(out.result["replayed"], true);
+    assert_eq!(out.result["approved"], true);
+    assert!(out.result["rrn"].as_str().unwrap().starts_with("EMU"));
+    q.mark_done(&cmd.id, &out).await.unwrap();
+
+    let sales = emu.transactions();
+    assert_eq!(sales.len(), 1, "the card was charged once");
+    assert_eq!(sales[0].field_str(tag::AMOUNT), Some("4250"));
+    assert!(q.pop_next().await.unwrap().is_none());
+}